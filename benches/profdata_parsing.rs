@@ -10,6 +10,26 @@ pub fn cargo_profdata(c: &mut Criterion) {
     });
 }
 
-criterion_group!(benches, cargo_profdata);
+pub fn symtab_lookup(c: &mut Criterion) {
+    let profile = parse("./benches/data/cargo_testsuite.profdata").unwrap();
+    let hashes = profile.symtab().iter().map(|(x, _)| *x).collect::<Vec<_>>();
+
+    c.bench_function("symtab_lookup_owned", |b| {
+        b.iter(|| {
+            for hash in &hashes {
+                black_box(profile.symtab().get(*hash).cloned());
+            }
+        })
+    });
+    c.bench_function("symtab_lookup_str", |b| {
+        b.iter(|| {
+            for hash in &hashes {
+                black_box(profile.symtab().get_str(*hash));
+            }
+        })
+    });
+}
+
+criterion_group!(benches, cargo_profdata, symtab_lookup);
 
 criterion_main!(benches);
@@ -0,0 +1,15 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use llvm_profparser::{parse_bytes_as, ProfileFormat};
+
+const INDEXED_MAGIC: [u8; 8] = [0xff, 0x6c, 0x70, 0x72, 0x6f, 0x66, 0x69, 0x81];
+
+fuzz_target!(|data: &[u8]| {
+    // Always use the indexed magic so every input gets past format detection and into the header
+    // and hash table parsing
+    if data.len() >= INDEXED_MAGIC.len() {
+        let mut data = data.to_vec();
+        data[..INDEXED_MAGIC.len()].copy_from_slice(&INDEXED_MAGIC);
+        let _ = parse_bytes_as(&data, ProfileFormat::Binary);
+    }
+});
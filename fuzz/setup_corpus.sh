@@ -1,12 +1,17 @@
 #!/bin/bash
 
 # Here we'll just create an empty corpus folder and copy all the profile data
-# files into it.
+# files into it. The indexed profile target only gets the indexed profiles.
 
 mkdir -p corpus/profile_data/
+mkdir -p corpus/indexed_profile/
 
-cp ../tests/data/profdata/llvm-11/* corpus/profile_data/
-cp ../tests/data/profdata/llvm-12/* corpus/profile_data/
-cp ../tests/data/profdata/llvm-13/* corpus/profile_data/
-cp ../tests/data/profdata/llvm-14/* corpus/profile_data/
-cp ../tests/data/profdata/llvm-15/* corpus/profile_data/
+for dir in ../tests/data/profdata/*/; do
+    name=$(basename "$dir")
+    for file in "$dir"*.prof*; do
+        cp "$file" "corpus/profile_data/${name}_$(basename "$file")"
+    done
+    for file in "$dir"*.profdata; do
+        [ -e "$file" ] && cp "$file" "corpus/indexed_profile/${name}_$(basename "$file")"
+    done
+done
@@ -0,0 +1,231 @@
+use crate::instrumentation_profile::raw_profile::{RawInstrProf32, RawInstrProf64};
+use crate::instrumentation_profile::types::{InstrumentationProfile, Symtab};
+use crate::instrumentation_profile::InstrProfReader;
+#[cfg(feature = "archive")]
+use crate::parse_bytes;
+use crate::summary::ProfileSummary;
+use crate::{merge_parsed, merge_profiles, parse};
+use std::fs::{read_dir, File};
+use std::io;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+#[cfg(feature = "archive")]
+use tracing::debug;
+use tracing::warn;
+
+/// Extensions of the instrumentation profile files picked up from a directory
+const PROFILE_EXTENSIONS: [&str; 3] = ["profraw", "profdata", "proftext"];
+
+fn has_profile_extension(path: &Path) -> bool {
+    path.extension()
+        .and_then(|x| x.to_str())
+        .map(|x| PROFILE_EXTENSIONS.contains(&x))
+        .unwrap_or(false)
+}
+
+/// Finds the raw profiles written by a run in a directory, sorted by path. `LLVM_PROFILE_FILE`
+/// patterns with `%p` or `%m` give each process or binary its own file such as `prof-1234.profraw`
+/// or `default_8f3a1c2d4b5e6f70_0.profraw`, and the pattern may not end in `.profraw` at all. So
+/// rather than matching names every file is checked for the raw profile magic, empty files left
+/// by a process which didn't finish writing are skipped. Subdirectories aren't searched.
+pub fn discover_profraws(dir: impl AsRef<Path>) -> io::Result<Vec<PathBuf>> {
+    let mut files = vec![];
+    for entry in read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_file() && is_raw_profile(&path)? {
+            files.push(path);
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+fn is_raw_profile(path: &Path) -> io::Result<bool> {
+    let mut file = File::open(path)?;
+    let mut magic = [0u8; 8];
+    match file.read_exact(&mut magic) {
+        Ok(()) => {
+            Ok(RawInstrProf64::has_format(&magic[..]) || RawInstrProf32::has_format(&magic[..]))
+        }
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+/// Parses an input given as `<weight>,<filename>` like `llvm-profdata merge --weighted-input`, an
+/// input without a comma has a weight of 1. Weights must be positive integers.
+pub fn parse_weighted_input(input: &str) -> io::Result<(u64, PathBuf)> {
+    let (weight, name) = match input.split_once(',') {
+        None => return Ok((1, PathBuf::from(input))),
+        Some((_, name)) if name.contains(',') => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Unexpected weighting format, expected $weight,$name or just $name",
+            ))
+        }
+        Some(parts) => parts,
+    };
+    let weight = weight.parse::<u64>().map_err(|e| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Invalid weight: {}", e),
+        )
+    })?;
+    if weight < 1 {
+        Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "Weight must be positive integer",
+        ))
+    } else {
+        Ok((weight, PathBuf::from(name)))
+    }
+}
+
+/// Reads the list of inputs in a response file like the one given to `llvm-profdata merge -f`,
+/// one input per line optionally prefixed with a weight as `<weight>,<filename>`, see
+/// `parse_weighted_input`. Lines are trimmed, blank lines and lines starting with `#` are skipped.
+/// Relative paths are relative to the working directory like LLVM, not the response file.
+pub fn read_response_file(path: impl AsRef<Path>) -> io::Result<Vec<(u64, PathBuf)>> {
+    let path = path.as_ref();
+    let contents = std::fs::read_to_string(path).map_err(|e| {
+        io::Error::new(
+            e.kind(),
+            format!("couldn't read response file {}: {}", path.display(), e),
+        )
+    })?;
+    contents
+        .lines()
+        .enumerate()
+        .map(|(i, x)| (i, x.trim()))
+        .filter(|(_, x)| !x.is_empty() && !x.starts_with('#'))
+        .map(|(i, x)| {
+            parse_weighted_input(x).map_err(|e| {
+                io::Error::new(e.kind(), format!("{}:{}: {}", path.display(), i + 1, e))
+            })
+        })
+        .collect()
+}
+
+/// Merges the profiles listed in a response file, see `read_response_file`. Each profile is scaled
+/// by its weight before merging. This avoids command line length limits when there are thousands
+/// of inputs.
+pub fn merge_profiles_from_response_file(
+    path: impl AsRef<Path>,
+) -> io::Result<InstrumentationProfile> {
+    let mut profiles = vec![];
+    for (weight, input) in read_response_file(path)? {
+        let mut profile = parse(&input)?;
+        if weight > 1 {
+            if let Err(e) = profile.scale(weight, 1) {
+                warn!("{}", e);
+            }
+        }
+        profiles.push(profile);
+    }
+    merge_parsed(profiles, InstrumentationProfile::merge)
+}
+
+/// Merges the profiles in a tar archive without extracting it, entries which aren't files with a
+/// profraw, profdata or proftext extension are skipped. The profiles are merged in the order they
+/// appear in the archive.
+#[cfg(feature = "archive")]
+pub fn merge_profiles_from_tar<R: Read>(reader: R) -> io::Result<InstrumentationProfile> {
+    let mut archive = tar::Archive::new(reader);
+    let mut profiles = vec![];
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let path = entry.path()?.into_owned();
+        if !entry.header().entry_type().is_file() || !has_profile_extension(&path) {
+            debug!("Skipping {} in archive", path.display());
+            continue;
+        }
+        let mut data = vec![];
+        entry.read_to_end(&mut data)?;
+        let profile = parse_bytes(&data)
+            .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path.display(), e)))?;
+        profiles.push(profile);
+    }
+    merge_parsed(profiles, InstrumentationProfile::merge)
+}
+
+/// A set of profiles, either a single file or every profile in a directory such as the profraws
+/// written by a test suite. Profiles are only parsed when an operation needs them and the
+/// directory is listed at that point so files added after creating the set are included.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ProfileSet {
+    path: PathBuf,
+}
+
+impl ProfileSet {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self { path: path.into() }
+    }
+
+    /// The path the set was created from
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// The profile files in the set. For a directory these are the files with a profraw,
+    /// profdata or proftext extension sorted by path so operations happen in a stable order.
+    pub fn files(&self) -> io::Result<Vec<PathBuf>> {
+        if !self.path.is_dir() {
+            return Ok(vec![self.path.clone()]);
+        }
+        let mut files = vec![];
+        for entry in read_dir(&self.path)? {
+            let path = entry?.path();
+            if has_profile_extension(&path) && path.is_file() {
+                files.push(path);
+            }
+        }
+        files.sort();
+        Ok(files)
+    }
+
+    /// Parses each profile in the set in turn
+    pub fn profiles(
+        &self,
+    ) -> io::Result<impl Iterator<Item = io::Result<(PathBuf, InstrumentationProfile)>>> {
+        Ok(self.files()?.into_iter().map(|file| {
+            let profile = parse(&file)?;
+            Ok((file, profile))
+        }))
+    }
+
+    /// Merges every profile in the set, see `merge_profiles`
+    pub fn merge_all(&self) -> io::Result<InstrumentationProfile> {
+        merge_profiles(&self.files()?)
+    }
+
+    /// The non context sensitive summary of each profile in the set
+    pub fn summaries(&self) -> io::Result<Vec<(PathBuf, ProfileSummary)>> {
+        self.profiles()?
+            .map(|x| x.map(|(file, profile)| (file, profile.summary())))
+            .collect()
+    }
+
+    /// A symbol table containing the function names from every profile in the set
+    pub fn union_symtab(&self) -> io::Result<Symtab> {
+        let mut symtab = Symtab::default();
+        for profile in self.profiles()? {
+            let (_, profile) = profile?;
+            for (hash, name) in profile.symtab().iter() {
+                symtab.add_func_name_with_hash(name.clone(), *hash);
+            }
+        }
+        Ok(symtab)
+    }
+}
+
+impl From<&Path> for ProfileSet {
+    fn from(path: &Path) -> Self {
+        Self::new(path)
+    }
+}
+
+impl From<PathBuf> for ProfileSet {
+    fn from(path: PathBuf) -> Self {
+        Self::new(path)
+    }
+}
@@ -1,8 +1,8 @@
 use anyhow::Result;
 use clap::{Parser, Subcommand};
 use llvm_profparser::*;
-use std::fs;
-use std::path::PathBuf;
+use std::io;
+use std::path::{Path, PathBuf};
 use tracing_subscriber::filter::filter_fn;
 use tracing_subscriber::{Layer, Registry};
 
@@ -34,6 +34,9 @@ pub struct ShowCommand {
     /// order `source,dest`
     #[structopt(long = "path-equivalence")]
     path_remapping: Option<PathRemapping>,
+    /// Mark where each region starts and its execution count below the source lines
+    #[structopt(long = "region")]
+    show_regions: bool,
     /// Turn on debug logging
     #[structopt(long)]
     debug: bool,
@@ -56,23 +59,8 @@ impl ShowCommand {
         if let Some(remapping) = self.path_remapping.as_ref() {
             report.apply_remapping(remapping);
         }
-        for (path, result) in report.files.iter() {
-            // Read file to string
-            if let Ok(source) = fs::read_to_string(path) {
-                if report.files.len() > 1 {
-                    println!("{}", path.display());
-                }
-                for (line, source) in source.lines().enumerate() {
-                    print!("{: >5}|", line + 1);
-                    if let Some(hits) = result.hits_for_line(line + 1) {
-                        println!("{: >7}|{}", hits, source);
-                    } else {
-                        println!("       |{}", source);
-                    }
-                }
-                println!();
-            }
-        }
+        let stdout = io::stdout();
+        report.show_text(&mut stdout.lock(), Path::new(""), self.show_regions)?;
         Ok(())
     }
 }
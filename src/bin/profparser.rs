@@ -1,14 +1,16 @@
 use anyhow::Result;
 use clap::Parser;
-use llvm_profparser::instrumentation_profile::summary::*;
+use llvm_profparser::instrumentation_profile::csv::write_csv_records;
+use llvm_profparser::instrumentation_profile::overlap::*;
+use llvm_profparser::instrumentation_profile::show::ShowOptions;
+use llvm_profparser::instrumentation_profile::text_profile::TextInstrProf;
 use llvm_profparser::instrumentation_profile::types::*;
+use llvm_profparser::instrumentation_profile::validate::validate;
 use llvm_profparser::*;
-use std::cmp::Ordering;
-use std::collections::BinaryHeap;
 use std::fs::{File, OpenOptions};
 use std::io::{self, Write};
 use std::io::{Seek, SeekFrom};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use tracing_subscriber::filter::filter_fn;
 use tracing_subscriber::{Layer, Registry};
 
@@ -26,6 +28,14 @@ pub enum Command {
         #[command(flatten)]
         overlap: OverlapCommand,
     },
+    Summary {
+        #[command(flatten)]
+        summary: SummaryCommand,
+    },
+    Validate {
+        #[command(flatten)]
+        validate: ValidateCommand,
+    },
 }
 
 #[derive(Clone, Debug, Eq, PartialEq, Parser)]
@@ -40,8 +50,17 @@ pub struct ShowCommand {
     #[structopt(long = "all-functions")]
     all_functions: bool,
     /// Show instr profile data in text dump format
-    #[structopt(long = "text")]
+    #[structopt(long = "text", group = "format")]
     text: bool,
+    /// Show the counters of the shown functions as CSV with a row per function
+    #[structopt(long = "csv", group = "format")]
+    csv: bool,
+    /// Show the shown functions with their counters and value sites, and the summary as JSON
+    #[structopt(long = "json", group = "format")]
+    json: bool,
+    /// Show only the profile version and instrumentation level
+    #[structopt(long = "profile-version", group = "format")]
+    profile_version: bool,
     /// Show detailed profile summary
     #[structopt(long = "show_detailed_summary")]
     show_detailed_summary: bool,
@@ -54,9 +73,18 @@ pub struct ShowCommand {
     /// Show context sensitive counts
     #[structopt(long = "showcs")]
     showcs: bool,
-    /// Details for matching functions
-    #[structopt(long = "function")]
-    function: Option<String>,
+    /// Show indirect call site target values for shown functions
+    #[structopt(long = "ic-targets")]
+    ic_targets: bool,
+    /// Show the profiled sizes of the memory intrinsic calls for shown functions
+    #[structopt(long = "memop-sizes")]
+    memop_sizes: bool,
+    /// Show only the names of the functions which ran
+    #[structopt(long = "covered")]
+    covered: bool,
+    /// Details for functions whose name contains any of the comma separated patterns
+    #[structopt(long = "function", value_delimiter = ',')]
+    function: Vec<String>,
     /// Output file
     #[structopt(long = "output", short = 'o')]
     output: Option<String>,
@@ -88,13 +116,19 @@ pub struct MergeCommand {
     /// Input files to merge
     #[structopt(name = "<filename...>", long = "input", short = 'i')]
     input: Vec<PathBuf>,
+    /// More input files, `@<file>` reads the inputs listed in a response file
+    #[structopt(name = "<inputs...>")]
+    positional_input: Vec<PathBuf>,
+    /// Response file listing the input files one per line, `#` starts a comment line
+    #[structopt(long = "input-files", short = 'f')]
+    input_files: Vec<PathBuf>,
     /// Output file
     #[structopt(long = "output", short = 'o')]
     output: PathBuf,
     /// List of weights and filenames in `<weight>,<filename>` format
     #[structopt(long = "weighted-input", value_parser=try_parse_weighted)]
     weighted_input: Vec<(u64, String)>,
-    /// Number of merge threads to use (will autodetect by default)
+    /// Number of merge threads to use (will autodetect by default), 1 disables threading
     #[structopt(long = "num-threads", short = 'j')]
     jobs: Option<usize>,
     /// Turn on debug logging
@@ -123,6 +157,41 @@ pub struct OverlapCommand {
     /// Generate a sparse profile
     #[structopt(long = "sparse")]
     sparse: bool,
+    /// Print the overlap as JSON, functions are included with `--value-cutoff` or `--function`
+    #[structopt(long = "json")]
+    json: bool,
+    /// Turn on debug logging
+    #[structopt(long)]
+    debug: bool,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq, Parser)]
+pub struct SummaryCommand {
+    /// Profile to summarise
+    #[structopt(name = "<filename>")]
+    input: PathBuf,
+    /// Show the detailed summary of the block counts needed to reach each cutoff
+    #[structopt(long = "detailed")]
+    detailed: bool,
+    /// Comma separated cutoff percentages (times 10000) for the detailed summary
+    #[structopt(long = "cutoffs", value_delimiter = ',')]
+    cutoffs: Vec<u64>,
+    /// Print the summary as JSON
+    #[structopt(long = "json")]
+    json: bool,
+    /// Turn on debug logging
+    #[structopt(long)]
+    debug: bool,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq, Parser)]
+pub struct ValidateCommand {
+    /// Profile to check
+    #[structopt(name = "<filename>")]
+    input: PathBuf,
+    /// Treat warnings as failures
+    #[structopt(long = "strict")]
+    strict: bool,
     /// Turn on debug logging
     #[structopt(long)]
     debug: bool,
@@ -140,197 +209,91 @@ impl Opts {
             &Command::Show { ref show } => show.debug,
             &Command::Merge { ref merge } => merge.debug,
             &Command::Overlap { ref overlap } => overlap.debug,
+            &Command::Summary { ref summary } => summary.debug,
+            &Command::Validate { ref validate } => validate.debug,
         }
     }
 }
 
 fn try_parse_weighted(input: &str) -> Result<(u64, String), String> {
-    if !input.contains(',') {
-        Ok((1, input.to_string()))
-    } else {
-        let parts = input.split(',').collect::<Vec<_>>();
-        if parts.len() != 2 {
-            Err("Unexpected weighting format, expected $weight,$name or just $name".to_string())
-        } else {
-            let weight = parts[0]
-                .parse()
-                .map_err(|e| format!("Invalid weight: {}", e))?;
-            if weight < 1 {
-                Err("Weight must be positive integer".to_string())
-            } else {
-                Ok((weight, parts[1].to_string()))
-            }
-        }
-    }
-}
-
-fn check_function(name: Option<&String>, pattern: Option<&String>) -> bool {
-    match pattern {
-        Some(pat) => name.map(|x| x.contains(pat)).unwrap_or(false),
-        None => false,
-    }
-}
-
-#[derive(Clone, Debug, Eq)]
-struct HotFn {
-    name: String,
-    count: u64,
+    batch::parse_weighted_input(input)
+        .map(|(weight, name)| (weight, name.to_string_lossy().into_owned()))
+        .map_err(|e| e.to_string())
 }
 
-impl PartialOrd for HotFn {
-    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
-        Some(self.cmp(other))
+impl ShowCommand {
+    fn show_options(&self) -> ShowOptions {
+        ShowOptions::default()
+            .all_functions(self.all_functions)
+            .functions(self.function.clone())
+            .show_counts(self.show_counts)
+            .show_cs(self.showcs)
+            .topn(self.topn)
+            .value_cutoff(self.value_cutoff)
+            .only_list_below(self.only_list_below)
+            .detailed_summary(self.show_detailed_summary)
+            .detailed_summary_cutoffs(
+                self.detailed_summary_cutoffs
+                    .iter()
+                    .map(|x| *x as u64)
+                    .collect(),
+            )
+            .ic_targets(self.ic_targets)
+            .memop_sizes(self.memop_sizes)
+            .covered(self.covered)
     }
-}
 
-impl Ord for HotFn {
-    fn cmp(&self, other: &Self) -> Ordering {
-        // Do the reverse here
-        other.count.cmp(&self.count)
+    /// Dumps the shown functions in the text format and nothing else. Like llvm-profdata the
+    /// functions are in the order they're in the profile so the output matches it exactly.
+    fn write_text(&self, profile: &InstrumentationProfile) -> Result<()> {
+        let options = self.show_options();
+        let records = profile
+            .records()
+            .iter()
+            .filter(|func| options.is_selected(profile, func));
+        let mut writer = io::stdout().lock();
+        if profile.is_ir_level_profile() {
+            writeln!(writer, ":ir")?;
+        }
+        TextInstrProf.write_records(profile, records, &mut writer)?;
+        Ok(())
     }
-}
 
-impl PartialEq for HotFn {
-    fn eq(&self, other: &Self) -> bool {
-        self.count == other.count
+    /// Writes the shown functions as CSV, sorted like the `--all-functions` output
+    fn write_csv(&self, profile: &InstrumentationProfile) -> Result<()> {
+        let options = self.show_options();
+        let records = profile
+            .records_sorted()
+            .into_iter()
+            .filter(|func| options.is_selected(profile, func));
+        write_csv_records(records, &mut io::stdout().lock())?;
+        Ok(())
     }
-}
 
-impl ShowCommand {
     pub fn run(&self) -> Result<()> {
         let profile = parse(&self.input)?;
-        let mut summary = ProfileSummary::new();
-
-        let is_ir_instr = profile.is_ir_level_profile();
-        let mut hotties =
-            BinaryHeap::<HotFn>::with_capacity(self.topn.unwrap_or_default() as usize);
-        let mut shown_funcs = 0;
-        let mut below_cutoff_funcs = 0;
-        let topn = self.topn.unwrap_or_default();
-        for func in profile.records() {
-            if func.name.is_none() || func.hash.is_none() {
-                continue;
-            }
-            if is_ir_instr && func.has_cs_flag() != self.showcs {
-                continue;
-            }
-            let show =
-                self.all_functions || check_function(func.name.as_ref(), self.function.as_ref());
-
-            if show && self.text {
-                // TODO text format dump
-                continue;
-            }
-            summary.add_record(&func.record);
-
-            let (func_max, func_sum) = func.counts().iter().fold((0, 0u64), |acc, x| {
-                (*x.max(&acc.0), acc.1.saturating_add(*x))
-            });
-            if func_max < self.value_cutoff {
-                below_cutoff_funcs += 1;
-                if self.only_list_below {
-                    println!(
-                        "  {}: (Max = {} Sum = {})",
-                        func.name.as_ref().unwrap(),
-                        func_max,
-                        func_sum
-                    );
-                    continue;
-                }
-            } else if self.only_list_below {
-                continue;
-            }
-            if topn > 0 {
-                if hotties.len() == topn {
-                    let top = hotties.peek().unwrap();
-                    if top.count < func_max {
-                        hotties.pop();
-                        hotties.push(HotFn {
-                            name: func.name.as_ref().unwrap().to_string(),
-                            count: func_max,
-                        });
-                    }
-                } else {
-                    hotties.push(HotFn {
-                        name: func.name.as_ref().unwrap().to_string(),
-                        count: func_max,
-                    });
-                }
-            }
-            if show {
-                if shown_funcs == 0 {
-                    println!("Counters:");
-                }
-                shown_funcs += 1;
-                println!("  {}:", func.name.as_ref().unwrap());
-                println!("    Hash: {:#018x}", func.hash.unwrap());
-                println!("    Counters: {}", func.counts().len());
-                if !is_ir_instr {
-                    let counts = if func.counts().is_empty() {
-                        0
-                    } else {
-                        func.counts()[0]
-                    };
-                    println!("    Function count: {}", counts);
-                }
-                if self.show_counts {
-                    let start = if is_ir_instr { 0 } else { 1 };
-                    let counts = func
-                        .counts()
-                        .iter()
-                        .skip(start)
-                        .map(|x| x.to_string())
-                        .collect::<Vec<String>>()
-                        .join(", ");
-                    println!("    Block counts: [{}]", counts);
-                }
-            }
+        if self.text {
+            return self.write_text(&profile);
         }
-        if profile.get_level() == InstrumentationLevel::Ir {
-            println!(
-                "Instrumentation level: {}  entry_first = {}",
-                profile.get_level(),
-                // NOTE: in llvm 11 this is always false
-                profile.is_entry_first() as usize
-            );
-        } else {
-            println!("Instrumentation level: {}", profile.get_level());
+        if self.csv {
+            return self.write_csv(&profile);
         }
-        if self.all_functions || self.function.is_some() {
-            println!("Functions shown: {}", shown_funcs);
-        }
-        println!("Total functions: {}", summary.num_functions());
-        if self.value_cutoff > 0 {
-            println!(
-                "Number of functions with maximum count (< {} ): {}",
-                self.value_cutoff, below_cutoff_funcs
-            );
-            println!(
-                "Number of functions with maximum count (>= {}): {}",
-                self.value_cutoff,
-                summary.num_functions() - below_cutoff_funcs
-            );
+        if self.json {
+            profile
+                .display(self.show_options())
+                .write_json(&mut io::stdout().lock())?;
+            return Ok(());
         }
-        println!("Maximum function count: {}", summary.max_function_count());
-        println!(
-            "Maximum internal block count: {}",
-            summary.max_internal_block_count()
-        );
-        if let Some(topn) = self.topn {
-            println!(
-                "Top {} functions with the largest internal block counts: ",
-                topn
-            );
-            let hotties = hotties.into_sorted_vec();
-            for f in hotties.iter() {
-                println!("  {}, max count = {}", f.name, f.count);
+        if self.profile_version {
+            let mut writer = io::stdout().lock();
+            match profile.version() {
+                Some(version) => writeln!(writer, "Profile version: {}", version)?,
+                None => writeln!(writer, "Profile version: unknown")?,
             }
+            writeln!(writer, "Instrumentation level: {}", profile.profile_level())?;
+            return Ok(());
         }
-
-        if self.show_detailed_summary {
-            println!("Total number of blocks: ?");
-            println!("Total count: ?");
-        }
+        print!("{}", profile.display(self.show_options()));
         Ok(())
     }
 }
@@ -364,13 +327,161 @@ impl MergeCommand {
         Ok(())
     }
 
+    /// All the inputs with the response files expanded, weights in response files are ignored like
+    /// `--weighted-input`
+    fn inputs(&self) -> Result<Vec<PathBuf>> {
+        let response_file = |file: &Path| {
+            batch::read_response_file(file).map(|x| x.into_iter().map(|(_, path)| path))
+        };
+        let mut inputs = self.input.clone();
+        for input in &self.positional_input {
+            match input.to_str().and_then(|x| x.strip_prefix('@')) {
+                Some(file) => inputs.extend(response_file(Path::new(file))?),
+                None => inputs.push(input.clone()),
+            }
+        }
+        for file in &self.input_files {
+            inputs.extend(response_file(file)?);
+        }
+        Ok(inputs)
+    }
+
     fn run(&self) -> Result<()> {
+        let inputs = self.inputs()?;
         assert!(
-            !self.input.is_empty(),
+            !inputs.is_empty(),
             "No input files selected. See merge --help"
         );
-        let profile = merge_profiles(&self.input)?;
-        Self::zero_ranges(&self.input[0], &self.output, profile.records())?;
+        let jobs = match self.jobs {
+            Some(jobs) if jobs > 0 => jobs,
+            _ => std::thread::available_parallelism()
+                .map(|x| x.get())
+                .unwrap_or(1),
+        };
+        let profile = merge_profiles_with_threads(&inputs, jobs)?;
+        Self::zero_ranges(&inputs[0], &self.output, profile.records())?;
+        Ok(())
+    }
+}
+
+impl OverlapCommand {
+    fn run(&self) -> Result<()> {
+        let base = parse(&self.base_file)?;
+        let test = parse(&self.test_file)?;
+        let filter = OverlapFilter {
+            value_cutoff: self.value_cutoff.map(|x| x as u64).unwrap_or(u64::MAX),
+            name_filter: self.function.clone(),
+        };
+        let result = overlap_profiles(&base, &test, self.context_sensitive_counts, &filter);
+
+        let mut writer: Box<dyn Write> = match self.output.as_ref() {
+            Some(output) => Box::new(File::create(output)?),
+            None => Box::new(io::stdout()),
+        };
+        if self.json {
+            result.write_json(&mut writer)?;
+            return Ok(());
+        }
+        writeln!(
+            writer,
+            "Profile overlap infomation for base_profile: {} and test_profile: {}",
+            self.base_file.display(),
+            self.test_file.display()
+        )?;
+        result.program.dump(&mut writer)?;
+        for function in &result.functions {
+            function.dump(&mut writer)?;
+        }
+        Ok(())
+    }
+}
+
+impl SummaryCommand {
+    fn run(&self) -> Result<()> {
+        let profile = parse(&self.input)?;
+        let summary = if self.cutoffs.is_empty() {
+            profile.summary()
+        } else {
+            profile.summary_with_cutoffs(&self.cutoffs)
+        };
+
+        let stdout = io::stdout();
+        let mut writer = stdout.lock();
+        if self.json {
+            write!(
+                writer,
+                "{{\"total_functions\":{},\"max_function_count\":{},\"max_internal_block_count\":{},\"total_blocks\":{},\"total_count\":{}",
+                summary.num_fns,
+                summary.max_function_count,
+                summary.max_internal_count,
+                summary.num_counts,
+                summary.total_count
+            )?;
+            if self.detailed {
+                let entries = summary
+                    .detailed_summary
+                    .iter()
+                    .map(|x| {
+                        format!(
+                            "{{\"cutoff\":{},\"min_count\":{},\"num_counts\":{}}}",
+                            x.cutoff, x.min_count, x.num_counts
+                        )
+                    })
+                    .collect::<Vec<_>>();
+                write!(writer, ",\"detailed_summary\":[{}]", entries.join(","))?;
+            }
+            writeln!(writer, "}}")?;
+        } else {
+            writeln!(writer, "Total functions: {}", summary.num_fns)?;
+            writeln!(
+                writer,
+                "Maximum function count: {}",
+                summary.max_function_count
+            )?;
+            writeln!(
+                writer,
+                "Maximum internal block count: {}",
+                summary.max_internal_count
+            )?;
+            writeln!(writer, "Total number of blocks: {}", summary.num_counts)?;
+            writeln!(writer, "Total count: {}", summary.total_count)?;
+            if self.detailed {
+                writeln!(writer, "Detailed summary:")?;
+                for entry in &summary.detailed_summary {
+                    writeln!(
+                        writer,
+                        "{} blocks with count >= {} account for {} percentage of the total counts.",
+                        entry.num_counts,
+                        entry.min_count,
+                        entry.cutoff as f64 / 10_000.0
+                    )?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl ValidateCommand {
+    fn run(&self) -> Result<()> {
+        let data = std::fs::read(&self.input)?;
+        let issues = validate(&data);
+        let stdout = io::stdout();
+        let mut writer = stdout.lock();
+        for issue in &issues {
+            writeln!(writer, "{}", issue)?;
+        }
+        let failures = issues
+            .iter()
+            .filter(|x| self.strict || !x.is_warning())
+            .count();
+        if failures > 0 {
+            anyhow::bail!(
+                "{} failed validation with {} problems",
+                self.input.display(),
+                failures
+            );
+        }
         Ok(())
     }
 }
@@ -394,9 +505,9 @@ fn main() -> Result<()> {
     match opts.cmd {
         Command::Show { show } => show.run(),
         Command::Merge { merge } => merge.run(),
-        _ => {
-            panic!("Unsupported command");
-        }
+        Command::Overlap { overlap } => overlap.run(),
+        Command::Summary { summary } => summary.run(),
+        Command::Validate { validate } => validate.run(),
     }
 }
 
@@ -425,4 +536,24 @@ mod tests {
         assert!(try_parse_weighted("foo.profdata,1").is_err());
         assert!(try_parse_weighted("1,1,foo.profdata").is_err());
     }
+
+    #[test]
+    fn show_formats_are_exclusive() {
+        let show = |args: &[&str]| {
+            let mut command = vec!["profparser", "show", "-i", "default.profraw"];
+            command.extend(args);
+            Opts::try_parse_from(command)
+        };
+        assert!(show(&["--json"]).is_ok());
+        assert!(show(&["--json", "--all-functions"]).is_ok());
+        for (a, b) in [
+            ("--text", "--csv"),
+            ("--csv", "--json"),
+            ("--json", "--profile-version"),
+            ("--profile-version", "--text"),
+        ] {
+            let err = show(&[a, b]).unwrap_err();
+            assert_eq!(err.kind(), clap::error::ErrorKind::ArgumentConflict);
+        }
+    }
 }
@@ -1,15 +1,23 @@
+use crate::coverage::mcdc::*;
 use crate::coverage::reporting::*;
 use crate::coverage::*;
+use crate::instrumentation_profile::get_num_padding_bytes;
+use crate::instrumentation_profile::raw_profile::MemoryWidthExt;
 use crate::instrumentation_profile::types::*;
 use crate::util::*;
 use anyhow::{bail, Result};
 use nom::error::Error as NomError;
-use object::{Endian, Endianness, Object, ObjectSection, ReadCache, ReadRef, Section};
+use object::{
+    BinaryFormat, Endian, Endianness, Object, ObjectSection, ObjectSymbol, ReadCache, ReadRef,
+    Section,
+};
+use rustc_hash::FxHashSet;
+use std::cell::OnceCell;
 use std::convert::TryInto;
 use std::error::Error;
 use std::fmt;
 use std::fs;
-use std::io::BufReader;
+use std::io::{BufReader, Write};
 use std::path::{Path, PathBuf};
 use tracing::{debug, error, trace, warn};
 
@@ -31,6 +39,7 @@ pub struct CoverageMapping<'a> {
     object_files: &'a [PathBuf],
     allow_parsing_failures: bool,
     version: u64,
+    filenames: OnceCell<Vec<PathBuf>>,
 }
 
 #[derive(Copy, Clone, Debug)]
@@ -39,9 +48,74 @@ pub enum LlvmSection {
     ProfileNames,
     ProfileCounts,
     ProfileData,
+    /// MC/DC bitmap bytes, from raw profile version 9
+    ProfileBitmap,
     CoverageFunctions,
 }
 
+impl LlvmSection {
+    /// Name of the section in the given object file format, this matches
+    /// `getInstrProfSectionName` in LLVM. COFF object files have a `$M` suffix on the name which
+    /// the linker removes when it merges the sections, see `find_section`.
+    pub fn name(&self, format: BinaryFormat) -> &'static str {
+        match (format, self) {
+            (BinaryFormat::Coff | BinaryFormat::Pe, Self::CoverageMap) => ".lcovmap",
+            (BinaryFormat::Coff | BinaryFormat::Pe, Self::ProfileNames) => ".lprfn",
+            (BinaryFormat::Coff | BinaryFormat::Pe, Self::ProfileCounts) => ".lprfc",
+            (BinaryFormat::Coff | BinaryFormat::Pe, Self::ProfileData) => ".lprfd",
+            (BinaryFormat::Coff | BinaryFormat::Pe, Self::ProfileBitmap) => ".lprfb",
+            (BinaryFormat::Coff | BinaryFormat::Pe, Self::CoverageFunctions) => ".lcovfun",
+            (_, Self::CoverageMap) => "__llvm_covmap",
+            (_, Self::ProfileNames) => "__llvm_prf_names",
+            (_, Self::ProfileCounts) => "__llvm_prf_cnts",
+            (_, Self::ProfileData) => "__llvm_prf_data",
+            (_, Self::ProfileBitmap) => "__llvm_prf_bits",
+            (_, Self::CoverageFunctions) => "__llvm_covfun",
+        }
+    }
+
+    /// Segment the section is in for Mach-O files, the full name is `segment,section`
+    pub fn macho_segment(&self) -> &'static str {
+        match self {
+            Self::CoverageMap | Self::CoverageFunctions => "__LLVM_COV",
+            Self::ProfileNames | Self::ProfileCounts | Self::ProfileData | Self::ProfileBitmap => {
+                "__DATA"
+            }
+        }
+    }
+}
+
+/// Finds an LLVM section in an ELF, Mach-O or COFF/PE object file. In COFF object files the
+/// sections are grouped so `.lcovmap$M` is also accepted for `.lcovmap`, and in Mach-O files the
+/// section also has to be in the expected segment.
+fn find_section<'data, 'file, R>(
+    object_file: &'file object::File<'data, R>,
+    section: LlvmSection,
+) -> Option<Section<'data, 'file, R>>
+where
+    R: ReadRef<'data>,
+{
+    let format = object_file.format();
+    let name = section.name(format);
+    let in_segment = |x: &Section<'data, 'file, R>| match (format, x.segment_name()) {
+        (BinaryFormat::MachO, Ok(Some(segment))) => segment == section.macho_segment(),
+        _ => true,
+    };
+    object_file
+        .sections()
+        .filter(in_segment)
+        .find(|x| match x.name() {
+            Ok(found) => {
+                found == name
+                    || found
+                        .strip_prefix(name)
+                        .map(|x| x.starts_with('$'))
+                        .unwrap_or(false)
+            }
+            Err(_) => false,
+        })
+}
+
 #[derive(Copy, Clone, Debug)]
 pub enum SectionReadError {
     EmptySection(LlvmSection),
@@ -61,29 +135,163 @@ impl fmt::Display for SectionReadError {
 
 impl Error for SectionReadError {}
 
+/// Reads the raw profile version of an instrumented binary from `__llvm_profile_raw_version`,
+/// including the variant flags
+fn raw_profile_version<'data, R>(object_file: &object::File<'data, R>) -> Option<u64>
+where
+    R: ReadRef<'data>,
+{
+    let symbol = object_file.symbols().find(|x| {
+        matches!(
+            x.name(),
+            Ok("__llvm_profile_raw_version" | "___llvm_profile_raw_version")
+        )
+    })?;
+    let section = object_file.section_by_index(symbol.section_index()?).ok()?;
+    let offset = symbol.address().checked_sub(section.address())? as usize;
+    let bytes = section.data().ok()?.get(offset..)?.get(..8)?;
+    Some(
+        object_file
+            .endianness()
+            .read_u64_bytes(bytes.try_into().ok()?),
+    )
+}
+
+/// Size of a `__llvm_prf_data` record and the byte range of its value site counts for raw
+/// profile versions 5 to 10, matching how `RawInstrProf` reads them
+fn data_record_layout(
+    version: u64,
+    pointer_size: usize,
+) -> Option<(usize, std::ops::Range<usize>)> {
+    let value_sites = |start: usize, len: usize| start..(start + len);
+    match version {
+        5..=8 => Some((24 + 3 * pointer_size, value_sites(20 + 3 * pointer_size, 4))),
+        9 => {
+            let size = 28 + 4 * pointer_size;
+            let size = size + get_num_padding_bytes(size as u64) as usize;
+            Some((size, value_sites(20 + 4 * pointer_size, 4)))
+        }
+        10 => Some((32 + 4 * pointer_size, value_sites(20 + 4 * pointer_size, 6))),
+        _ => None,
+    }
+}
+
+/// Builds a raw profile from the `__llvm_prf_*` sections of an instrumented binary, for setups
+/// where the profile stays in the binary's data instead of being written out such as a
+/// continuous mode profile mapped onto the binary. The counters are whatever the
+/// `__llvm_prf_cnts` section holds, so for a binary that was never run they're all zero. The
+/// header is made from the section sizes and addresses in the same way the profiling runtime
+/// does, and value profiling site counts are cleared as the value data only exists at runtime.
+///
+/// Returns `None` if a section or the `__llvm_profile_raw_version` symbol is missing, or the raw
+/// profile version isn't 5 to 10.
+pub fn extract_embedded_profraw(object: &Path) -> Option<Vec<u8>> {
+    let binary_data = ReadCache::new(BufReader::new(fs::File::open(object).ok()?));
+    let object_file = object::File::parse(&binary_data).ok()?;
+    let endian = object_file.endianness();
+
+    let version = raw_profile_version(&object_file)?;
+    let version_num = version & !VARIANT_MASKS_ALL;
+    let (pointer_size, magic) = if object_file.is_64() {
+        (8, <u64 as MemoryWidthExt>::MAGIC)
+    } else {
+        (4, <u32 as MemoryWidthExt>::MAGIC)
+    };
+    let (record_size, value_sites) = data_record_layout(version_num, pointer_size)?;
+    let counter_size = if version & VARIANT_MASK_BYTE_COVERAGE != 0 {
+        1
+    } else {
+        8
+    };
+
+    let data = find_section(&object_file, LlvmSection::ProfileData)?;
+    let counters = find_section(&object_file, LlvmSection::ProfileCounts)?;
+    let names = find_section(&object_file, LlvmSection::ProfileNames)?;
+    let bitmap = find_section(&object_file, LlvmSection::ProfileBitmap);
+
+    let mut data_bytes = data.data().ok()?.to_vec();
+    let counter_bytes = counters.data().ok()?;
+    let name_bytes = names.data().ok()?;
+    let bitmap_bytes = match bitmap.as_ref() {
+        Some(bitmap) if version_num >= 9 => bitmap.data().ok()?,
+        _ => &[],
+    };
+    data_bytes.truncate(data_bytes.len() - data_bytes.len() % record_size);
+    for record in data_bytes.chunks_exact_mut(record_size) {
+        record[value_sites.clone()].fill(0);
+    }
+
+    // From version 8 the counter and bitmap pointers are relative to the data record
+    let (counters_delta, bitmap_delta) = if version_num >= 8 {
+        (
+            counters.address().wrapping_sub(data.address()),
+            bitmap
+                .as_ref()
+                .map(|x| x.address().wrapping_sub(data.address()))
+                .unwrap_or_default(),
+        )
+    } else {
+        (counters.address(), 0)
+    };
+
+    let mut header = vec![magic, version];
+    if version_num >= 7 {
+        // Binary IDs
+        header.push(0);
+    }
+    header.extend([
+        (data_bytes.len() / record_size) as u64,
+        0,
+        (counter_bytes.len() / counter_size) as u64,
+        get_num_padding_bytes(counter_bytes.len() as u64) as u64,
+    ]);
+    if version_num >= 9 {
+        header.extend([
+            bitmap_bytes.len() as u64,
+            get_num_padding_bytes(bitmap_bytes.len() as u64) as u64,
+        ]);
+    }
+    header.extend([name_bytes.len() as u64, counters_delta]);
+    if version_num >= 9 {
+        header.push(bitmap_delta);
+    }
+    header.push(names.address());
+    if version_num >= 10 {
+        // The vtable names aren't included
+        header.extend([0, 0]);
+    }
+    // The last value kind, vtable targets were added in version 10
+    header.push(if version_num >= 10 { 2 } else { 1 });
+
+    let mut profile = header
+        .into_iter()
+        .flat_map(|x| endian.write_u64_bytes(x))
+        .collect::<Vec<u8>>();
+    for section in [&data_bytes[..], counter_bytes, bitmap_bytes, name_bytes] {
+        profile.extend_from_slice(section);
+        let padding = get_num_padding_bytes(section.len() as u64);
+        profile.resize(profile.len() + padding as usize, 0);
+    }
+    Some(profile)
+}
+
 pub fn read_object_file(object: &Path, version: u64) -> Result<CoverageMappingInfo> {
     // I believe vnode sections added by llvm are unnecessary
 
     let binary_data = ReadCache::new(BufReader::new(fs::File::open(object)?));
     let object_file = object::File::parse(&binary_data)?;
 
-    let prof_counts = object_file
-        .section_by_name("__llvm_prf_cnts")
-        .or(object_file.section_by_name(".lprfc"))
+    let prof_counts = find_section(&object_file, LlvmSection::ProfileCounts)
         .and_then(|x| parse_profile_counters(object_file.endianness(), &x).ok());
 
     debug!("Parsed prf_cnts: {:?}", prof_counts);
 
-    let prof_data = object_file
-        .section_by_name("__llvm_prf_data")
-        .or(object_file.section_by_name(".lprfd"))
+    let prof_data = find_section(&object_file, LlvmSection::ProfileData)
         .and_then(|x| parse_profile_data(object_file.endianness(), &x).ok());
 
     debug!("Parsed prf_data section: {:?}", prof_data);
 
-    let cov_fun = object_file
-        .section_by_name("__llvm_covfun")
-        .or(object_file.section_by_name(".lcovfun"))
+    let cov_fun = find_section(&object_file, LlvmSection::CoverageFunctions)
         .map(|x| parse_coverage_functions(object_file.endianness(), &x))
         .ok_or(SectionReadError::MissingSection(
             LlvmSection::CoverageFunctions,
@@ -91,9 +299,7 @@ pub fn read_object_file(object: &Path, version: u64) -> Result<CoverageMappingIn
 
     debug!("Parsed covfun section: {:?}", cov_fun);
 
-    let cov_map = object_file
-        .section_by_name("__llvm_covmap")
-        .or(object_file.section_by_name(".lcovmap"))
+    let cov_map = find_section(&object_file, LlvmSection::CoverageMap)
         .map(|x| parse_coverage_mapping(object_file.endianness(), &x, version))
         .ok_or(SectionReadError::MissingSection(LlvmSection::CoverageMap))??;
 
@@ -123,6 +329,7 @@ impl<'a> CoverageMapping<'a> {
             object_files,
             allow_parsing_failures,
             version,
+            filenames: OnceCell::new(),
         })
     }
 
@@ -278,6 +485,78 @@ impl<'a> CoverageMapping<'a> {
         self.generate_subreport(|_| true)
     }
 
+    /// Line coverage of each source file using the counts in the profile, see
+    /// `CoverageReport::file_summary`
+    pub fn file_summary(&self) -> Result<Vec<FileSummary>> {
+        Ok(self.generate_report()?.file_summary())
+    }
+
+    /// Generates the report and writes the annotated source files, see
+    /// `CoverageReport::show_text`
+    pub fn show_text(
+        &self,
+        writer: &mut impl Write,
+        source_dir: &Path,
+        show_regions: bool,
+    ) -> Result<()> {
+        let report = self.generate_report()?;
+        report.show_text(writer, source_dir, show_regions)?;
+        Ok(())
+    }
+
+    /// Evaluates the MC/DC decisions in the object files using the bitmaps recorded in the
+    /// profile. Functions without a bitmap in the profile are treated as never executed.
+    pub fn mcdc(&self) -> Result<McdcReport> {
+        let layout = BitmapLayout::from_profile_version(self.version);
+        let mut report = McdcReport::default();
+        for result in self.mapping_info_iter() {
+            let info = result?;
+            for func in &info.cov_fun {
+                if !func
+                    .regions
+                    .iter()
+                    .any(|x| x.kind == RegionKind::MCDCDecision)
+                {
+                    continue;
+                }
+                let paths = info.get_files_from_id(func.header.filenames_ref);
+                let bitmap = self
+                    .profile
+                    .mcdc_bitmaps()
+                    .get(&func.header.name_hash)
+                    .map(|x| x.as_slice())
+                    .unwrap_or_default();
+                report
+                    .decisions
+                    .extend(function_decisions(func, &paths, bitmap, layout));
+            }
+        }
+        Ok(report)
+    }
+
+    /// Sorted list of the source files with regions in the coverage mapping of the object files,
+    /// the paths are the same as in the reports. The filename tables are read the first time this
+    /// is called and object files which can't be read are skipped.
+    pub fn filenames(&self) -> &[PathBuf] {
+        self.filenames.get_or_init(|| {
+            let mut filenames = FxHashSet::default();
+            for info in self.mapping_info_iter().filter_map(Result::ok) {
+                for func in &info.cov_fun {
+                    let paths = info.get_files_from_id(func.header.filenames_ref);
+                    filenames.extend(
+                        func.regions
+                            .iter()
+                            .filter_map(|region| paths.get(region.file_id))
+                            .cloned(),
+                    );
+                }
+            }
+            let mut filenames = filenames.into_iter().collect::<Vec<_>>();
+            filenames.sort_unstable();
+            filenames
+        })
+    }
+
     pub fn mapping_info_iter(&self) -> impl Iterator<Item = Result<CoverageMappingInfo>> + '_ {
         self.object_files
             .iter()
@@ -396,11 +675,6 @@ fn parse_coverage_functions<'data, R: ReadRef<'data>>(
                 expressions: exprs,
             });
 
-            // Todo set couners for expansion regions - counter of expansion region is the counter
-            // of the first region from the expanded file. This requires multiple passes to
-            // correctly propagate across all nested regions. N.B. I haven't seen any expansion
-            // regions in use so may not be an issue!
-
             bytes = data;
             let function_len = section_len - bytes.len(); // this should match header
 
@@ -433,9 +707,12 @@ fn parse_mapping_regions<'a>(
     expressions: &mut Vec<Expression>,
 ) -> IResult<&'a [u8], Vec<CounterMappingRegion>> {
     let mut mapping = vec![];
-    for i in file_indices {
+    // Index of the file in `file_indices` for each region, `expanded_file_id` uses these
+    let mut local_file_ids = vec![];
+    for (local_file_id, i) in file_indices.iter().enumerate() {
         let (data, regions_len) = parse_leb128(bytes)?;
         bytes = data;
+        local_file_ids.resize(local_file_ids.len() + regions_len as usize, local_file_id);
         let mut last_line = 0;
         for _ in 0..regions_len {
             let mut mcdc_params = None;
@@ -534,9 +811,34 @@ fn parse_mapping_regions<'a>(
             });
         }
     }
+    set_expansion_counters(&mut mapping, &local_file_ids, file_indices.len());
     Ok((bytes, mapping))
 }
 
+/// The counter of an expansion region is the counter of the first region in the file it expands,
+/// so a macro invocation gets the count of the expanded code. An expanded file can start with
+/// another expansion so this takes a pass per file to propagate counters through nested
+/// expansions. This is ported from `RawCoverageMappingReader::read`.
+fn set_expansion_counters(
+    mapping: &mut [CounterMappingRegion],
+    local_file_ids: &[usize],
+    num_files: usize,
+) {
+    let mut expansions = vec![None; num_files];
+    for _ in 1..num_files {
+        for (index, region) in mapping.iter().enumerate() {
+            if region.kind == RegionKind::Expansion {
+                expansions[region.expanded_file_id] = Some(index);
+            }
+        }
+        for (index, file_id) in local_file_ids.iter().enumerate() {
+            if let Some(expansion) = expansions[*file_id].take() {
+                mapping[expansion].count = mapping[index].count;
+            }
+        }
+    }
+}
+
 fn parse_profile_data<'data, R: ReadRef<'data>>(
     endian: Endianness,
     section: &Section<'data, '_, R>,
@@ -629,3 +931,86 @@ fn parse_counter(input: u64, exprs: &mut Vec<Expression>) -> Counter {
     };
     Counter { kind, id }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encode(values: &[u64]) -> Vec<u8> {
+        let mut bytes = vec![];
+        for value in values {
+            let mut value = *value;
+            loop {
+                let byte = (value & 0x7f) as u8;
+                value >>= 7;
+                if value == 0 {
+                    bytes.push(byte);
+                    break;
+                }
+                bytes.push(byte | 0x80);
+            }
+        }
+        bytes
+    }
+
+    #[test]
+    fn nested_expansion_counters() {
+        // Mapping like clang's for a macro using another macro:
+        //
+        // #define INNER(x) ((x) ? 1 : 0)
+        // #define OUTER(x) INNER(x)
+        // int main(int argc, char **argv) {
+        //     return OUTER(argc);
+        // }
+        //
+        // File 1 is the expansion of OUTER which starts with the expansion of INNER in file 2
+        let expansion = |file_id: u64| (file_id << 3) | 4;
+        let counter = |id: u64| (id << 2) | 1;
+        let bytes = encode(&[
+            // main.c: function body, OUTER(argc)
+            2,
+            counter(0),
+            3,
+            33,
+            2,
+            2,
+            expansion(1),
+            1,
+            12,
+            0,
+            17,
+            // OUTER: INNER(x)
+            1,
+            expansion(2),
+            2,
+            18,
+            0,
+            23,
+            // INNER: (x) ? 1 : 0 where 1 is counted
+            2,
+            counter(1),
+            1,
+            18,
+            0,
+            31,
+            counter(2),
+            0,
+            26,
+            0,
+            27,
+        ]);
+        let mut exprs = vec![];
+        let (rest, regions) = parse_mapping_regions(&bytes, &[0, 0, 0], &mut exprs).unwrap();
+        assert!(rest.is_empty());
+        assert_eq!(regions.len(), 5);
+        assert_eq!(regions[1].kind, RegionKind::Expansion);
+        assert_eq!(regions[1].expanded_file_id, 1);
+        assert_eq!(regions[1].count, Counter::instrumentation(1));
+        assert_eq!(regions[2].kind, RegionKind::Expansion);
+        assert_eq!(regions[2].expanded_file_id, 2);
+        assert_eq!(regions[2].count, Counter::instrumentation(1));
+        // Other regions keep their counters
+        assert_eq!(regions[0].count, Counter::instrumentation(0));
+        assert_eq!(regions[4].count, Counter::instrumentation(2));
+    }
+}
@@ -0,0 +1,646 @@
+use crate::coverage::*;
+use crate::instrumentation_profile::types::{DecisionParameters, MCDCParams};
+use std::path::PathBuf;
+use tracing::debug;
+
+/// The most test vectors a decision can have, this matches `HardMaxTVs` in LLVM
+const MAX_TEST_VECTORS: i64 = i32::MAX as i64;
+
+/// The most conditions a decision can have with `BitmapLayout::ConditionMask`, LLVM 18 rejects
+/// decisions with more as the bitmap needs a bit for every mask
+const MAX_MASK_CONDITIONS: usize = 6;
+
+/// How the test vectors of a decision are laid out in the function's bitmap
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
+pub enum BitmapLayout {
+    /// LLVM 18 (raw profile version 9). The decision's bitmap starts at byte `bitmap_idx` and the
+    /// index of a test vector is a mask of the conditions which evaluated to true.
+    ConditionMask,
+    /// LLVM 19 onwards. The decision's bitmap ends at bit `bitmap_idx` and each path through the
+    /// conditions gets a dense index.
+    TestVectorIndex,
+}
+
+impl BitmapLayout {
+    /// The layout used by a raw profile of the given version
+    pub fn from_profile_version(version: u64) -> Self {
+        if version <= 9 {
+            Self::ConditionMask
+        } else {
+            Self::TestVectorIndex
+        }
+    }
+}
+
+/// An executed combination of condition values for a decision
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+pub struct TestVector {
+    /// Value of each condition indexed by condition ID, `None` if short-circuiting meant the
+    /// condition wasn't evaluated
+    pub conditions: Vec<Option<bool>>,
+    /// The outcome of the decision
+    pub result: bool,
+}
+
+impl TestVector {
+    /// The conditions which were evaluated in both test vectors and have different values
+    fn differences<'a>(&'a self, other: &'a Self) -> impl Iterator<Item = usize> + 'a {
+        self.conditions
+            .iter()
+            .zip(other.conditions.iter())
+            .enumerate()
+            .filter(|(_, (a, b))| matches!((a, b), (Some(a), Some(b)) if a != b))
+            .map(|(id, _)| id)
+    }
+}
+
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+pub struct McdcCondition {
+    /// Condition ID used in the test vectors
+    pub id: usize,
+    pub loc: SourceLocation,
+    /// The condition is a constant so can't be shown to independently affect the outcome, folded
+    /// conditions are left out of the coverage percentage
+    pub folded: bool,
+    /// Indexes of two test vectors with different outcomes that only differ in this condition.
+    /// If this is present the condition is covered.
+    pub independence_pair: Option<(usize, usize)>,
+}
+
+impl McdcCondition {
+    pub fn is_covered(&self) -> bool {
+        self.independence_pair.is_some()
+    }
+}
+
+/// MC/DC results for a single boolean expression with multiple conditions
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+pub struct McdcDecision {
+    /// Name hash of the function containing the decision
+    pub name_hash: u64,
+    pub file: PathBuf,
+    pub loc: SourceLocation,
+    /// Conditions in the order llvm-cov lists them
+    pub conditions: Vec<McdcCondition>,
+    /// Test vectors executed, the ones with a false outcome come first
+    pub test_vectors: Vec<TestVector>,
+}
+
+impl McdcDecision {
+    /// Number of conditions which aren't folded
+    pub fn num_conditions(&self) -> usize {
+        self.conditions.iter().filter(|x| !x.folded).count()
+    }
+
+    /// Number of conditions which aren't folded and have an independence pair
+    pub fn covered_conditions(&self) -> usize {
+        self.conditions
+            .iter()
+            .filter(|x| !x.folded && x.is_covered())
+            .count()
+    }
+
+    /// Percentage of conditions covered, zero if every condition is folded
+    pub fn percent_covered(&self) -> f64 {
+        percent(self.covered_conditions(), self.num_conditions())
+    }
+}
+
+/// MC/DC coverage of every decision found in the object files
+#[derive(Debug, Clone, Default, Eq, PartialEq, Hash)]
+pub struct McdcReport {
+    pub decisions: Vec<McdcDecision>,
+}
+
+impl McdcReport {
+    /// Total number of conditions that aren't folded
+    pub fn num_conditions(&self) -> usize {
+        self.decisions.iter().map(|x| x.num_conditions()).sum()
+    }
+
+    pub fn covered_conditions(&self) -> usize {
+        self.decisions.iter().map(|x| x.covered_conditions()).sum()
+    }
+
+    /// Percentage of conditions covered across all decisions, this matches the MC/DC column of
+    /// `llvm-cov report --show-mcdc-summary`
+    pub fn percent_covered(&self) -> f64 {
+        percent(self.covered_conditions(), self.num_conditions())
+    }
+}
+
+fn percent(covered: usize, total: usize) -> f64 {
+    if total == 0 {
+        0.0
+    } else {
+        covered as f64 / total as f64 * 100.0
+    }
+}
+
+/// A decision region and the branch regions for its conditions. Ported from the
+/// `MCDCDecisionRecorder` in LLVM.
+struct DecisionRecord<'a> {
+    region: &'a CounterMappingRegion,
+    params: DecisionParameters,
+    branches: Vec<&'a CounterMappingRegion>,
+}
+
+impl<'a> DecisionRecord<'a> {
+    fn dominates(&self, branch: &CounterMappingRegion) -> bool {
+        let decision = &self.region.loc;
+        let start = (branch.loc.line_start, branch.loc.column_start);
+        let end = (branch.loc.line_end, branch.loc.column_end);
+        branch.file_id == self.region.file_id
+            && start >= (decision.line_start, decision.column_start)
+            && end <= (decision.line_end, decision.column_end)
+    }
+
+    /// Adds the branch if it's a condition of this decision, returns true if it was added
+    fn add_branch(&mut self, branch: &'a CounterMappingRegion, id: usize) -> bool {
+        if id >= self.params.num_conditions as usize
+            || self.branches.iter().any(|x| branch_id(x) == Some(id))
+            || !self.dominates(branch)
+        {
+            return false;
+        }
+        // LLVM puts the first condition at the front even though the others aren't in order
+        if id == 0 {
+            self.branches.insert(0, branch);
+        } else {
+            self.branches.push(branch);
+        }
+        true
+    }
+
+    fn is_complete(&self) -> bool {
+        self.branches.len() == self.params.num_conditions as usize
+    }
+}
+
+/// Condition IDs are stored plus one so zero can mean there's no next condition
+fn branch_ids(region: &CounterMappingRegion) -> Option<(usize, [Option<usize>; 2])> {
+    match region.mcdc_params {
+        Some(MCDCParams::Branch(params)) if params.id > 0 => {
+            let next = |x: i16| usize::try_from(x).ok().and_then(|x| x.checked_sub(1));
+            Some((
+                params.id as usize - 1,
+                [next(params.false_cond), next(params.true_cond)],
+            ))
+        }
+        _ => None,
+    }
+}
+
+fn branch_id(region: &CounterMappingRegion) -> Option<usize> {
+    branch_ids(region).map(|(id, _)| id)
+}
+
+/// Assigns every path through the conditions a test vector index. This is a port of
+/// `TVIdxBuilder` in LLVM which the instrumentation uses to pick the bit to set. Returns the index
+/// increment of each branch of each condition and the number of test vectors, or `None` if the
+/// conditions don't form a valid graph.
+fn test_vector_indices(next_ids: &[[Option<usize>; 2]]) -> Option<(Vec<[i64; 2]>, i64)> {
+    let n = next_ids.len();
+    let mut in_count = vec![0usize; n];
+    for next in next_ids.iter().flatten().flatten() {
+        *in_count.get_mut(*next)? += 1;
+    }
+    if n == 0 || in_count[0] != 0 {
+        return None;
+    }
+    let mut width = vec![0i64; n];
+    let mut indices = vec![[None; 2]; n];
+    // Sorted by widest first then the order they were found
+    let mut decisions = vec![];
+    let mut queue = std::collections::VecDeque::from([0]);
+    width[0] = 1;
+    while let Some(id) = queue.pop_front() {
+        for cond in 0..2 {
+            match next_ids[id][cond] {
+                None => decisions.push((-width[id], decisions.len(), id, cond)),
+                Some(next) => {
+                    indices[id][cond] = Some(width[next]);
+                    width[next] += width[id];
+                    if width[next] > MAX_TEST_VECTORS {
+                        return None;
+                    }
+                    in_count[next] -= 1;
+                    if in_count[next] == 0 {
+                        queue.push_back(next);
+                    }
+                }
+            }
+        }
+    }
+    decisions.sort_unstable();
+    let mut current = 0;
+    for (neg_width, _, id, cond) in decisions {
+        indices[id][cond] = Some(current);
+        current -= neg_width;
+        if current > MAX_TEST_VECTORS {
+            return None;
+        }
+    }
+    // Anything unassigned is unreachable or part of a cycle
+    let indices = indices
+        .into_iter()
+        .map(|x| Some([x[0]?, x[1]?]))
+        .collect::<Option<Vec<_>>>()?;
+    Some((indices, current))
+}
+
+struct TestVectorBuilder<'a> {
+    next_ids: &'a [[Option<usize>; 2]],
+    indices: &'a [[i64; 2]],
+    bitmap: &'a [u8],
+    /// First bit of the decision in the bitmap
+    bitmap_start: i64,
+    layout: BitmapLayout,
+    executed: [Vec<TestVector>; 2],
+}
+
+impl TestVectorBuilder<'_> {
+    fn is_executed(&self, tv_index: i64, tv: &[Option<bool>]) -> bool {
+        let bit = match self.layout {
+            BitmapLayout::ConditionMask => {
+                let mask = tv
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, x)| **x == Some(true))
+                    .try_fold(0i64, |acc, (id, _)| {
+                        1i64.checked_shl(id as u32).map(|x| acc | x)
+                    });
+                match mask {
+                    Some(mask) => self.bitmap_start + mask,
+                    None => return false,
+                }
+            }
+            BitmapLayout::TestVectorIndex => self.bitmap_start + tv_index,
+        };
+        usize::try_from(bit)
+            .ok()
+            .and_then(|bit| self.bitmap.get(bit / 8).map(|x| x & (1 << (bit % 8)) != 0))
+            .unwrap_or(false)
+    }
+
+    /// Walks every path through the conditions recording the ones that were executed
+    fn build(&mut self, tv: &mut Vec<Option<bool>>, id: usize, tv_index: i64) {
+        for cond in 0..2 {
+            tv[id] = Some(cond == 1);
+            let index = tv_index + self.indices[id][cond];
+            match self.next_ids[id][cond] {
+                Some(next) => self.build(tv, next, index),
+                None => {
+                    if self.is_executed(index, tv) {
+                        self.executed[cond].push(TestVector {
+                            conditions: tv.clone(),
+                            result: cond == 1,
+                        });
+                    }
+                }
+            }
+        }
+        tv[id] = None;
+    }
+}
+
+/// Evaluates the decision using the function's bitmap. Returns `None` if the conditions of the
+/// decision are malformed.
+fn evaluate_decision(
+    record: &DecisionRecord<'_>,
+    bitmap: &[u8],
+    layout: BitmapLayout,
+) -> Option<(Vec<McdcCondition>, Vec<TestVector>)> {
+    let n = record.params.num_conditions as usize;
+    let mut next_ids = vec![[None; 2]; n];
+    for branch in &record.branches {
+        let (id, next) = branch_ids(branch)?;
+        if next.iter().flatten().any(|x| *x >= n) {
+            return None;
+        }
+        next_ids[id] = next;
+    }
+    if layout == BitmapLayout::ConditionMask && n > MAX_MASK_CONDITIONS {
+        return None;
+    }
+    let (indices, num_test_vectors) = test_vector_indices(&next_ids)?;
+    let (bitmap_start, bitmap_end) = match layout {
+        BitmapLayout::ConditionMask => {
+            let start = record.params.bitmap_idx as i64 * 8;
+            (start, start + (1 << n))
+        }
+        BitmapLayout::TestVectorIndex => (
+            record.params.bitmap_idx as i64 - num_test_vectors,
+            record.params.bitmap_idx as i64,
+        ),
+    };
+    if bitmap_start < 0 {
+        return None;
+    }
+    let mut builder = TestVectorBuilder {
+        next_ids: &next_ids,
+        indices: &indices,
+        bitmap,
+        bitmap_start,
+        layout,
+        executed: [vec![], vec![]],
+    };
+    // The walk visits one path per test vector so only do it if the bitmap holds the decision,
+    // otherwise the function was never executed or the decision is malformed
+    if bitmap_end <= bitmap.len() as i64 * 8 {
+        builder.build(&mut vec![None; n], 0, 0);
+    } else if !bitmap.is_empty() {
+        return None;
+    }
+    let [mut test_vectors, executed_true] = builder.executed;
+    let num_false = test_vectors.len();
+    test_vectors.extend(executed_true);
+
+    // Pair each true outcome with each false outcome, if they only differ in one condition then
+    // that condition independently affects the outcome
+    let mut pairs = vec![None; n];
+    for (t, a) in test_vectors.iter().enumerate().skip(num_false) {
+        for (f, b) in test_vectors.iter().enumerate().take(num_false) {
+            let mut differences = a.differences(b);
+            if let (Some(id), None) = (differences.next(), differences.next()) {
+                pairs[id] = Some((f, t));
+            }
+        }
+    }
+
+    let conditions = record
+        .branches
+        .iter()
+        .map(|branch| {
+            let id = branch_id(branch).unwrap_or_default();
+            McdcCondition {
+                id,
+                loc: branch.loc.clone(),
+                folded: branch.count.is_zero() || branch.false_count.is_zero(),
+                independence_pair: pairs[id],
+            }
+        })
+        .collect();
+    Some((conditions, test_vectors))
+}
+
+/// Finds the decisions in a function and evaluates them with the function's bitmap bytes
+pub(crate) fn function_decisions(
+    func: &FunctionRecordV3,
+    paths: &[PathBuf],
+    bitmap: &[u8],
+    layout: BitmapLayout,
+) -> Vec<McdcDecision> {
+    let mut pending: Vec<DecisionRecord<'_>> = vec![];
+    let mut complete = vec![];
+    for region in &func.regions {
+        match (region.kind, region.mcdc_params) {
+            (RegionKind::MCDCDecision, Some(MCDCParams::Decision(params))) => {
+                pending.push(DecisionRecord {
+                    region,
+                    params,
+                    branches: vec![],
+                });
+            }
+            (RegionKind::MCDCBranch, _) => {
+                let id = match branch_id(region) {
+                    Some(id) => id,
+                    None => continue,
+                };
+                if let Some(index) = pending.iter_mut().position(|x| x.add_branch(region, id)) {
+                    if pending[index].is_complete() {
+                        complete.push(pending.remove(index));
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    if !pending.is_empty() {
+        debug!(
+            "{} MC/DC decisions without all their conditions",
+            pending.len()
+        );
+    }
+
+    let mut decisions = vec![];
+    for record in &complete {
+        match evaluate_decision(record, bitmap, layout) {
+            Some((conditions, test_vectors)) => decisions.push(McdcDecision {
+                name_hash: func.header.name_hash,
+                file: paths
+                    .get(record.region.file_id)
+                    .cloned()
+                    .unwrap_or_default(),
+                loc: record.region.loc.clone(),
+                conditions,
+                test_vectors,
+            }),
+            None => debug!("Malformed MC/DC decision at {:?}", record.region.loc),
+        }
+    }
+    decisions
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::instrumentation_profile::types::BranchParameters;
+
+    fn loc(column_start: usize, column_end: usize) -> SourceLocation {
+        SourceLocation {
+            line_start: 1,
+            column_start,
+            line_end: 1,
+            column_end,
+        }
+    }
+
+    fn decision(bitmap_idx: u32, num_conditions: u16) -> CounterMappingRegion {
+        CounterMappingRegion {
+            kind: RegionKind::MCDCDecision,
+            count: Counter::default(),
+            false_count: Counter::default(),
+            file_id: 0,
+            expanded_file_id: 0,
+            loc: loc(1, 100),
+            mcdc_params: Some(MCDCParams::Decision(DecisionParameters {
+                bitmap_idx,
+                num_conditions,
+            })),
+        }
+    }
+
+    /// IDs are as stored in the coverage mapping, so one based with zero for the end
+    fn branch(id: i16, true_cond: i16, false_cond: i16, column: usize) -> CounterMappingRegion {
+        CounterMappingRegion {
+            kind: RegionKind::MCDCBranch,
+            count: Counter::instrumentation(id as u64),
+            false_count: Counter::instrumentation(id as u64 + 10),
+            file_id: 0,
+            expanded_file_id: 0,
+            loc: loc(column, column + 1),
+            mcdc_params: Some(MCDCParams::Branch(BranchParameters {
+                id,
+                false_cond,
+                true_cond,
+            })),
+        }
+    }
+
+    fn function(regions: Vec<CounterMappingRegion>) -> FunctionRecordV3 {
+        FunctionRecordV3 {
+            header: FunctionRecordHeader {
+                name_hash: 1,
+                data_len: 0,
+                fn_hash: 2,
+                filenames_ref: 3,
+            },
+            regions,
+            expressions: vec![],
+        }
+    }
+
+    /// `a && b`
+    fn and_function(bitmap_idx: u32) -> FunctionRecordV3 {
+        function(vec![
+            decision(bitmap_idx, 2),
+            branch(1, 2, 0, 1),
+            branch(2, 0, 0, 6),
+        ])
+    }
+
+    fn evaluate(func: &FunctionRecordV3, bitmap: &[u8], layout: BitmapLayout) -> McdcDecision {
+        let mut decisions = function_decisions(func, &[PathBuf::from("lib.rs")], bitmap, layout);
+        assert_eq!(decisions.len(), 1);
+        decisions.remove(0)
+    }
+
+    #[test]
+    fn and_indices() {
+        // a false, a true b false, a true b true
+        let next_ids = [[None, Some(1)], [None, None]];
+        let (indices, num) = test_vector_indices(&next_ids).unwrap();
+        assert_eq!(num, 3);
+        assert_eq!(indices, vec![[0, 0], [1, 2]]);
+    }
+
+    #[test]
+    fn and_coverage() {
+        // Test vectors 1 (TF) and 2 (TT) only b is shown to be independent
+        let decision = evaluate(&and_function(3), &[0b110], BitmapLayout::TestVectorIndex);
+        assert_eq!(decision.test_vectors.len(), 2);
+        assert_eq!(decision.num_conditions(), 2);
+        assert_eq!(decision.covered_conditions(), 1);
+        assert!(!decision.conditions[0].is_covered());
+        assert!(decision.conditions[1].is_covered());
+        assert_eq!(decision.percent_covered(), 50.0);
+
+        // Adding a false lets a be paired with TT
+        let decision = evaluate(&and_function(3), &[0b111], BitmapLayout::TestVectorIndex);
+        assert_eq!(decision.percent_covered(), 100.0);
+        let (f, t) = decision.conditions[0].independence_pair.unwrap();
+        assert_eq!(decision.test_vectors[f].conditions, vec![Some(false), None]);
+        assert_eq!(
+            decision.test_vectors[t].conditions,
+            vec![Some(true), Some(true)]
+        );
+    }
+
+    #[test]
+    fn condition_mask_layout() {
+        // Bitmap index is in bytes and the test vector is the mask of true conditions, so TT is
+        // bit 3 and TF bit 1 of the second byte
+        let decision = evaluate(&and_function(1), &[0, 0b1010], BitmapLayout::ConditionMask);
+        assert_eq!(decision.test_vectors.len(), 2);
+        assert_eq!(decision.covered_conditions(), 1);
+    }
+
+    #[test]
+    fn or_and_coverage() {
+        // `(a || b) && c`
+        let func = function(vec![
+            decision(5, 3),
+            branch(1, 3, 2, 2),
+            branch(2, 3, 0, 7),
+            branch(3, 0, 0, 13),
+        ]);
+        let next_ids = [[Some(1), Some(2)], [None, Some(2)], [None, None]];
+        let (_, num) = test_vector_indices(&next_ids).unwrap();
+        assert_eq!(num, 5);
+        let all = evaluate(&func, &[0b11111], BitmapLayout::TestVectorIndex);
+        assert_eq!(all.test_vectors.len(), 5);
+        assert_eq!(all.percent_covered(), 100.0);
+
+        let none = evaluate(&func, &[0], BitmapLayout::TestVectorIndex);
+        assert!(none.test_vectors.is_empty());
+        assert_eq!(none.percent_covered(), 0.0);
+    }
+
+    #[test]
+    fn folded_conditions() {
+        let mut func = and_function(3);
+        func.regions[2].false_count = Counter::default();
+        let decision = evaluate(&func, &[0b110], BitmapLayout::TestVectorIndex);
+        assert_eq!(decision.num_conditions(), 1);
+        assert_eq!(decision.covered_conditions(), 0);
+        assert_eq!(decision.percent_covered(), 0.0);
+    }
+
+    #[test]
+    fn many_conditions() {
+        // A chain of `&&` has one test vector per condition plus one for all true
+        let n = 64;
+        let chain = |bitmap_idx| {
+            let mut regions = vec![decision(bitmap_idx, n as u16)];
+            for id in 1..=n {
+                let next = if id == n { 0 } else { id + 1 };
+                regions.push(branch(id, next, 0, id as usize + 1));
+            }
+            function(regions)
+        };
+        let bitmap = vec![0xff; 9];
+        let result = evaluate(&chain(n as u32 + 1), &bitmap, BitmapLayout::TestVectorIndex);
+        assert_eq!(result.test_vectors.len(), n as usize + 1);
+        assert_eq!(result.percent_covered(), 100.0);
+
+        // LLVM 18 only supports a mask of up to six conditions
+        assert!(function_decisions(
+            &chain(0),
+            &[PathBuf::from("lib.rs")],
+            &bitmap,
+            BitmapLayout::ConditionMask
+        )
+        .is_empty());
+    }
+
+    #[test]
+    fn bitmap_too_short() {
+        // Without a bitmap the decision wasn't executed, but a bitmap too short to hold the test
+        // vectors is malformed
+        let decision = evaluate(&and_function(3), &[], BitmapLayout::TestVectorIndex);
+        assert!(decision.test_vectors.is_empty());
+        assert_eq!(decision.num_conditions(), 2);
+        assert!(function_decisions(
+            &and_function(9),
+            &[],
+            &[0xff],
+            BitmapLayout::TestVectorIndex
+        )
+        .is_empty());
+        // Claims more test vectors than there are bits before the end of its bitmap
+        assert!(function_decisions(
+            &and_function(2),
+            &[],
+            &[0xff],
+            BitmapLayout::TestVectorIndex
+        )
+        .is_empty());
+    }
+
+    #[test]
+    fn incomplete_decision() {
+        let func = function(vec![decision(3, 2), branch(1, 2, 0, 1)]);
+        assert!(function_decisions(&func, &[], &[0xff], BitmapLayout::TestVectorIndex).is_empty());
+    }
+}
@@ -6,6 +6,7 @@ use std::path::PathBuf;
 use crate::instrumentation_profile::types::MCDCParams;
 
 pub mod coverage_mapping;
+pub mod mcdc;
 pub mod reporting;
 
 #[derive(Debug, Clone, Eq, PartialEq)]
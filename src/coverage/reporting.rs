@@ -1,5 +1,8 @@
 use crate::coverage::*;
 use std::collections::BTreeMap;
+use std::fs;
+use std::io::{self, Write};
+use std::path::Path;
 use std::str::FromStr;
 use thiserror::Error;
 
@@ -13,6 +16,27 @@ pub struct CoverageResult {
     pub hits: BTreeMap<SourceLocation, usize>,
 }
 
+/// Line coverage of a source file, the Lines columns of `llvm-cov report`
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub struct FileSummary {
+    pub path: PathBuf,
+    /// Lines with a count greater than zero
+    pub lines_covered: usize,
+    /// Lines with a count, see `CoverageResult::hits_for_line`
+    pub lines_total: usize,
+}
+
+impl FileSummary {
+    /// Percentage of the lines which are covered, 0 if the file has no lines with a count
+    pub fn percent_covered(&self) -> f64 {
+        if self.lines_total == 0 {
+            0.0
+        } else {
+            self.lines_covered as f64 * 100.0 / self.lines_total as f64
+        }
+    }
+}
+
 impl CoverageReport {
     pub fn apply_remapping(&mut self, remapping: &PathRemapping) {
         let inputs = self.files.keys().cloned().collect::<Vec<_>>();
@@ -28,6 +52,44 @@ impl CoverageReport {
             }
         }
     }
+
+    /// Line coverage of each file in the report, in path order. The lines counted are the ones
+    /// `show_text` gives a count
+    pub fn file_summary(&self) -> Vec<FileSummary> {
+        self.files
+            .iter()
+            .map(|(path, result)| {
+                let (lines_covered, lines_total) = result.line_coverage();
+                FileSummary {
+                    path: path.clone(),
+                    lines_covered,
+                    lines_total,
+                }
+            })
+            .collect()
+    }
+
+    /// Writes each source file annotated with the execution counts, like `llvm-cov show`.
+    /// Relative paths in the report are resolved against `source_dir` and files which can't be
+    /// read are skipped. With `show_regions` the start of each region is marked below the line
+    /// with its count.
+    pub fn show_text(
+        &self,
+        writer: &mut impl Write,
+        source_dir: &Path,
+        show_regions: bool,
+    ) -> io::Result<()> {
+        for (path, result) in self.files.iter() {
+            if let Ok(source) = fs::read_to_string(source_dir.join(path)) {
+                if self.files.len() > 1 {
+                    writeln!(writer, "{}", path.display())?;
+                }
+                result.write_annotated(&source, writer, show_regions)?;
+                writeln!(writer)?;
+            }
+        }
+        Ok(())
+    }
 }
 
 impl CoverageResult {
@@ -42,6 +104,21 @@ impl CoverageResult {
             .or_insert(count);
     }
 
+    /// Number of lines with a count greater than zero and the number of lines with a count
+    pub fn line_coverage(&self) -> (usize, usize) {
+        let last_line = self
+            .hits
+            .keys()
+            .map(|x| x.line_end)
+            .max()
+            .unwrap_or_default();
+        (1..=last_line)
+            .filter_map(|line| self.hits_for_line(line))
+            .fold((0, 0), |(covered, total), hits| {
+                (covered + (hits > 0) as usize, total + 1)
+            })
+    }
+
     /// For line coverage just finds first region that mentions this line
     pub fn hits_for_line(&self, line: usize) -> Option<usize> {
         self.hits
@@ -49,6 +126,40 @@ impl CoverageResult {
             .find(|(k, _)| k.line_start <= line && k.line_end >= line)
             .map(|(_, v)| *v)
     }
+
+    /// Writes the source with the line number and execution count before each line, lines
+    /// without a region have no count. With `show_regions` each line where regions start is
+    /// followed by a line with a `^count` marker under the first column of each region.
+    pub fn write_annotated(
+        &self,
+        source: &str,
+        writer: &mut impl Write,
+        show_regions: bool,
+    ) -> io::Result<()> {
+        for (line, source) in source.lines().enumerate() {
+            let line = line + 1;
+            match self.hits_for_line(line) {
+                Some(hits) => writeln!(writer, "{: >5}|{: >7}|{}", line, hits, source)?,
+                None => writeln!(writer, "{: >5}|       |{}", line, source)?,
+            }
+            if show_regions {
+                let mut markers = String::new();
+                for (loc, hits) in self.hits.iter().filter(|(k, _)| k.line_start == line) {
+                    let column = loc.column_start.saturating_sub(1);
+                    // Regions starting close together would overwrite each other so only the
+                    // first is shown
+                    if markers.is_empty() || column > markers.len() {
+                        markers.push_str(&" ".repeat(column - markers.len()));
+                        markers.push_str(&format!("^{}", hits));
+                    }
+                }
+                if !markers.is_empty() {
+                    writeln!(writer, "     |       |{}", markers)?;
+                }
+            }
+        }
+        Ok(())
+    }
 }
 
 #[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Error)]
@@ -100,6 +211,85 @@ impl FromStr for PathRemapping {
 mod tests {
     use super::*;
 
+    fn loc(
+        line_start: usize,
+        column_start: usize,
+        line_end: usize,
+        column_end: usize,
+    ) -> SourceLocation {
+        SourceLocation {
+            line_start,
+            column_start,
+            line_end,
+            column_end,
+        }
+    }
+
+    #[test]
+    fn annotated_source() {
+        let source = "fn main() {\n    if check() { run(); }\n}\n\nfn unused() {}\n";
+        let mut result = CoverageResult::default();
+        result.insert(loc(1, 11, 3, 2), 1);
+        result.insert(loc(2, 16, 2, 25), 0);
+        result.insert(loc(5, 13, 5, 15), 0);
+
+        let mut lines = vec![];
+        result.write_annotated(source, &mut lines, false).unwrap();
+        let expected = "    1|      1|fn main() {
+    2|      1|    if check() { run(); }
+    3|      1|}
+    4|       |
+    5|      0|fn unused() {}
+";
+        assert_eq!(String::from_utf8(lines).unwrap(), expected);
+
+        let mut regions = vec![];
+        result.write_annotated(source, &mut regions, true).unwrap();
+        let expected = "    1|      1|fn main() {
+     |       |          ^1
+    2|      1|    if check() { run(); }
+     |       |               ^0
+    3|      1|}
+    4|       |
+    5|      0|fn unused() {}
+     |       |            ^0
+";
+        assert_eq!(String::from_utf8(regions).unwrap(), expected);
+    }
+
+    #[test]
+    fn file_line_coverage() {
+        let mut result = CoverageResult::default();
+        result.insert(loc(1, 11, 3, 2), 1);
+        result.insert(loc(2, 16, 2, 25), 0);
+        result.insert(loc(5, 13, 5, 15), 0);
+        assert_eq!(result.line_coverage(), (3, 4));
+
+        let mut report = CoverageReport::default();
+        report.files.insert(PathBuf::from("lib.rs"), result);
+        report
+            .files
+            .insert(PathBuf::from("empty.rs"), CoverageResult::default());
+        let summary = report.file_summary();
+        assert_eq!(
+            summary,
+            vec![
+                FileSummary {
+                    path: PathBuf::from("empty.rs"),
+                    lines_covered: 0,
+                    lines_total: 0,
+                },
+                FileSummary {
+                    path: PathBuf::from("lib.rs"),
+                    lines_covered: 3,
+                    lines_total: 4,
+                }
+            ]
+        );
+        assert_eq!(summary[0].percent_covered(), 0.0);
+        assert_eq!(summary[1].percent_covered(), 75.0);
+    }
+
     #[test]
     fn report_remapping() {
         let mut report = CoverageReport::default();
@@ -40,7 +40,7 @@ fn read_value(
     version: u64,
     mut input: &[u8],
     data_len: usize,
-) -> ParseResult<'_, (u64, InstrProfRecord)> {
+) -> ParseResult<'_, Vec<(u64, InstrProfRecord)>> {
     if data_len % 8 != 0 {
         // Element is corrupted, it should be aligned
         let errors = vec![(
@@ -130,8 +130,13 @@ fn read_value(
             };
             if value_prof_data.num_value_kinds > 0 && version > 2 {
                 // If we actually want to change data in future get result.last_mut() and change it
-                // there
-                break;
+                // there. For now skip over it in case another record with the same name follows,
+                // the total size includes the two u32s already read
+                let skip = (value_prof_data.total_size as usize).saturating_sub(8);
+                if input.len().saturating_sub(skip) <= end_len {
+                    break;
+                }
+                input = &input[skip..];
             }
         }
     }
@@ -139,8 +144,7 @@ fn read_value(
         result.push((last_hash, InstrProfRecord::default()));
     }
     input = expected_end;
-    assert!(!result.is_empty());
-    Ok((input, result.remove(0)))
+    Ok((input, result))
 }
 
 impl HashTable {
@@ -157,6 +161,12 @@ impl HashTable {
         _offset: usize,
         bucket_start: usize,
     ) -> ParseResult<'a, Self> {
+        if bucket_start > input.len() {
+            return Err(nom::Err::Failure(VerboseError::from_error_kind(
+                &input[input.len()..],
+                ErrorKind::Eof,
+            )));
+        }
         let (bytes, num_buckets) = le_u64(&input[bucket_start..])?;
         debug!("Number of hashtable buckets: {}", num_buckets);
         let (_bytes, mut num_entries) = le_u64(bytes)?;
@@ -192,11 +202,19 @@ impl HashTable {
             let (bytes, lens) = read_key_data_len(bytes)?;
             let (bytes, key) = read_key(bytes, lens.key_len as usize)?;
             debug!("lengths: {:?} and key: {}", lens, key);
-            let (bytes, (hash, value)) = read_value(version, bytes, lens.data_len as usize)?;
-            debug!("hash: {}, value: {:?}", hash, value);
-            self.0.insert((hash, key.to_string()), value);
-            assert!(num_entries > 0);
-            num_entries -= 1;
+            // Functions with the same name but different function hashes share a key
+            let (bytes, values) = read_value(version, bytes, lens.data_len as usize)?;
+            for (hash, value) in values {
+                debug!("hash: {}, value: {:?}", hash, value);
+                self.0.insert((hash, key.to_string()), value);
+            }
+            num_entries = num_entries.checked_sub(1).ok_or_else(|| {
+                let errors = vec![(
+                    remaining,
+                    VerboseErrorKind::Context("hash table has more items than entries"),
+                )];
+                nom::Err::Failure(VerboseError { errors })
+            })?;
 
             remaining = bytes;
         }
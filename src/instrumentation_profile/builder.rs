@@ -0,0 +1,231 @@
+use crate::instrumentation_profile::types::*;
+use rustc_hash::FxHashMap;
+use tracing::warn;
+
+/// Constructs an `InstrumentationProfile` from scratch, for example to generate synthetic
+/// profiles for testing. Functions are keyed by name, adding a function with the same name twice
+/// replaces the earlier one.
+#[derive(Clone, Debug, Default)]
+pub struct ProfileBuilder {
+    version: Option<u64>,
+    is_ir: bool,
+    has_csir: bool,
+    is_entry_first: bool,
+    records: Vec<NamedInstrProfRecord>,
+    lookup: FxHashMap<String, usize>,
+}
+
+impl ProfileBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Creates a builder with space reserved for `capacity` functions, the built profile also
+    /// reserves space for them. This avoids reallocating when generating large profiles.
+    pub fn with_capacity(capacity: usize) -> Self {
+        Self {
+            records: Vec::with_capacity(capacity),
+            lookup: FxHashMap::with_capacity_and_hasher(capacity, Default::default()),
+            ..Default::default()
+        }
+    }
+
+    /// Sets the version of the built profile
+    pub fn version(&mut self, version: u64) -> &mut Self {
+        self.version = Some(version);
+        self
+    }
+
+    /// Marks the profile as an IR level profile instead of a front-end one
+    pub fn ir_level(&mut self, is_ir: bool) -> &mut Self {
+        self.is_ir = is_ir;
+        self
+    }
+
+    /// Marks the profile as containing context sensitive IR records, this implies IR level
+    pub fn csir_level(&mut self, has_csir: bool) -> &mut Self {
+        self.has_csir = has_csir;
+        if has_csir {
+            self.is_ir = true;
+        }
+        self
+    }
+
+    /// Marks the profile as having the function entry counter first
+    pub fn entry_first(&mut self, is_entry_first: bool) -> &mut Self {
+        self.is_entry_first = is_entry_first;
+        self
+    }
+
+    /// Adds a function with its structural hash and counters
+    pub fn add_function(
+        &mut self,
+        name: impl Into<String>,
+        func_hash: u64,
+        counters: Vec<u64>,
+    ) -> &mut Self {
+        let name = name.into();
+        let record = NamedInstrProfRecord {
+            name_hash: Some(compute_hash(&name)),
+            name: Some(name.clone()),
+            hash: Some(func_hash),
+            record: InstrProfRecord {
+                counts: counters,
+                ..Default::default()
+            },
+        };
+        match self.lookup.get(&name) {
+            Some(index) => self.records[*index] = record,
+            None => {
+                self.lookup.insert(name, self.records.len());
+                self.records.push(record);
+            }
+        }
+        self
+    }
+
+    /// Adds a value profiling site to a function which has already been added. Indirect call
+    /// and vtable target values are the name hash of the target, see `compute_hash`.
+    pub fn add_value_site(
+        &mut self,
+        name: &str,
+        kind: ValueKind,
+        values: Vec<InstrProfValueData>,
+    ) -> &mut Self {
+        match self.lookup.get(name) {
+            Some(index) => {
+                let data = self.records[*index]
+                    .record
+                    .data
+                    .get_or_insert_with(Default::default);
+                data.sites_mut(kind).push(values);
+            }
+            None => warn!("Adding value site to unknown function {}", name),
+        }
+        self
+    }
+
+    /// Builds the profile populating the symbol table with the function names
+    pub fn build(self) -> InstrumentationProfile {
+        let mut profile = InstrumentationProfile::new(
+            self.version,
+            self.has_csir,
+            self.is_ir,
+            self.is_entry_first,
+        );
+        profile.reserve(self.records.len());
+        for record in self.records {
+            profile
+                .symtab_mut()
+                .add_func_name(record.name_unchecked(), None);
+            profile.push_record(record);
+        }
+        profile
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::instrumentation_profile::text_profile::TextInstrProf;
+    use crate::instrumentation_profile::InstrProfWriter;
+    use crate::parse_bytes;
+
+    #[test]
+    fn build_and_reparse() {
+        let mut builder = ProfileBuilder::new();
+        builder
+            .ir_level(true)
+            .add_function("main", 0x1234, vec![1, 100, 50])
+            .add_function("foo", 0x5678, vec![20])
+            .add_function("bar", 0x9abc, vec![30])
+            .add_value_site(
+                "main",
+                ValueKind::IndirectCallTarget,
+                vec![
+                    InstrProfValueData {
+                        value: compute_hash("bar"),
+                        count: 30,
+                    },
+                    InstrProfValueData {
+                        value: compute_hash("foo"),
+                        count: 20,
+                    },
+                ],
+            )
+            .add_value_site(
+                "main",
+                ValueKind::MemOpSize,
+                vec![InstrProfValueData { value: 8, count: 4 }],
+            );
+        let profile = builder.build();
+
+        assert_eq!(profile.records().len(), 3);
+        assert_eq!(profile.symtab().len(), 3);
+        assert_eq!(
+            profile
+                .symtab()
+                .get(compute_hash("main"))
+                .map(|x| x.as_str()),
+            Some("main")
+        );
+
+        let mut text = vec![];
+        TextInstrProf.write(&profile, &mut text).unwrap();
+        let parsed = parse_bytes(&text).unwrap();
+
+        assert!(parsed.is_ir_level_profile());
+        assert!(!parsed.has_csir_level_profile());
+        assert_eq!(parsed.records().len(), profile.records().len());
+        for record in profile.records() {
+            let parsed_record = parsed.get_record(record.name.as_deref().unwrap());
+            assert_eq!(Some(record), parsed_record);
+        }
+    }
+
+    #[test]
+    fn large_profile() {
+        const NUM_FUNCTIONS: u64 = 100_000;
+        let mut builder = ProfileBuilder::new();
+        builder.ir_level(true);
+        for i in 0..NUM_FUNCTIONS {
+            builder.add_function(format!("_ZN4huge8functionE{}", i), i, vec![i, 1]);
+        }
+        let profile = builder.build();
+        assert_eq!(profile.symtab().len(), NUM_FUNCTIONS as usize);
+
+        let mut text = vec![];
+        TextInstrProf.write(&profile, &mut text).unwrap();
+        let parsed = parse_bytes(&text).unwrap();
+        assert_eq!(parsed.records().len(), NUM_FUNCTIONS as usize);
+        assert_eq!(parsed.symtab().len(), NUM_FUNCTIONS as usize);
+        let last = parsed
+            .find_record_by_name(&format!("_ZN4huge8functionE{}", NUM_FUNCTIONS - 1))
+            .unwrap();
+        assert_eq!(last.counts(), &[NUM_FUNCTIONS - 1, 1]);
+        assert_eq!(parsed.summary().num_fns, NUM_FUNCTIONS);
+        assert_eq!(parsed.summary().num_counts, NUM_FUNCTIONS * 2);
+    }
+
+    #[test]
+    fn reserved_capacity() {
+        const NUM_FUNCTIONS: u64 = 1000;
+        let build = |mut builder: ProfileBuilder| {
+            builder.ir_level(true);
+            for i in 0..NUM_FUNCTIONS {
+                builder.add_function(format!("function{}", i), i, vec![i, 1]);
+            }
+            builder.add_value_site(
+                "function1",
+                ValueKind::MemOpSize,
+                vec![InstrProfValueData { value: 8, count: 4 }],
+            );
+            builder.build()
+        };
+        let reserved = build(ProfileBuilder::with_capacity(NUM_FUNCTIONS as usize));
+        assert_eq!(reserved, build(ProfileBuilder::new()));
+        assert_eq!(reserved.records().len(), NUM_FUNCTIONS as usize);
+        // Reserving too little still works
+        assert_eq!(reserved, build(ProfileBuilder::with_capacity(10)));
+    }
+}
@@ -0,0 +1,106 @@
+//! CSV export of the counters for loading a profile into a spreadsheet. There's one row per
+//! function with the columns `name,hash,counters`, the function hash is written in hex like
+//! `llvm-profdata show` and the counters are joined with `;` as functions have different numbers
+//! of counters.
+use crate::instrumentation_profile::types::*;
+use std::io::{self, Write};
+
+const HEADER: &str = "name,hash,counters";
+
+/// Quotes a field if it contains anything which would break the row, C++ names with template
+/// arguments can contain commas
+fn escape_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+/// Writes the header row followed by a row for each record, records without a name are skipped
+pub fn write_csv_records<'a>(
+    records: impl IntoIterator<Item = &'a NamedInstrProfRecord>,
+    writer: &mut impl Write,
+) -> io::Result<()> {
+    writeln!(writer, "{}", HEADER)?;
+    for record in records {
+        let name = match record.name.as_deref() {
+            Some(name) => name,
+            None => continue,
+        };
+        let counters = record
+            .counts()
+            .iter()
+            .map(|x| x.to_string())
+            .collect::<Vec<_>>()
+            .join(";");
+        writeln!(
+            writer,
+            "{},{:#018x},{}",
+            escape_field(name),
+            record.hash_unchecked(),
+            counters
+        )?;
+    }
+    Ok(())
+}
+
+impl InstrumentationProfile {
+    /// Writes every function in the profile as CSV, in the same order as `records_sorted`
+    pub fn to_csv(&self, writer: &mut impl Write) -> io::Result<()> {
+        write_csv_records(self.records_sorted(), writer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::instrumentation_profile::builder::ProfileBuilder;
+
+    fn to_csv(profile: &InstrumentationProfile) -> String {
+        let mut csv = vec![];
+        profile.to_csv(&mut csv).unwrap();
+        String::from_utf8(csv).unwrap()
+    }
+
+    #[test]
+    fn rows_sorted_by_name() {
+        let mut builder = ProfileBuilder::new();
+        builder
+            .add_function("main", 0x1234, vec![1, 100, 50])
+            .add_function("foo", 0x5678, vec![20]);
+        assert_eq!(
+            to_csv(&builder.build()),
+            "name,hash,counters\nfoo,0x0000000000005678,20\nmain,0x0000000000001234,1;100;50\n"
+        );
+    }
+
+    #[test]
+    fn quoted_names() {
+        // Names which would break the row are quoted
+        let mut profile = InstrumentationProfile::default();
+        profile.push_record(NamedInstrProfRecord {
+            name: Some("pair<int, \"x\">".to_string()),
+            name_hash: None,
+            hash: Some(1),
+            record: InstrProfRecord {
+                counts: vec![3],
+                ..Default::default()
+            },
+        });
+        // Records without a name are skipped
+        profile.push_record(NamedInstrProfRecord {
+            name: None,
+            name_hash: Some(2),
+            hash: Some(2),
+            record: InstrProfRecord {
+                counts: vec![4],
+                ..Default::default()
+            },
+        });
+        assert_eq!(
+            to_csv(&profile),
+            "name,hash,counters\n\"pair<int, \"\"x\"\">\",0x0000000000000001,3\n"
+        );
+    }
+}
@@ -1,6 +1,7 @@
 use crate::hash_table::*;
 use crate::instrumentation_profile::*;
 use crate::summary::*;
+use crate::util::parse_string_ref;
 use anyhow::bail;
 use nom::{
     error::{ContextError, ErrorKind, ParseError},
@@ -8,7 +9,7 @@ use nom::{
 };
 use rustc_hash::FxHashMap;
 use std::convert::TryFrom;
-use tracing::debug;
+use tracing::{debug, debug_span};
 
 #[derive(Debug, Clone, Eq, PartialEq, Hash, Ord, PartialOrd)]
 pub struct IndexedInstrProf;
@@ -80,6 +81,23 @@ impl Header {
     pub fn is_ir_prof(&self) -> bool {
         (self.version & VARIANT_MASK_IR_PROF) > 0
     }
+
+    /// Whether the entry block counter comes first in the IR records
+    pub fn is_entry_first(&self) -> bool {
+        (self.version & VARIANT_MASK_INSTR_ENTRY) > 0
+    }
+
+    pub fn is_byte_coverage(&self) -> bool {
+        (self.version & VARIANT_MASK_BYTE_COVERAGE) > 0
+    }
+
+    pub fn fn_entry_only(&self) -> bool {
+        (self.version & VARIANT_MASK_FUNCTION_ENTRY_ONLY) > 0
+    }
+
+    pub fn has_memory_profile(&self) -> bool {
+        (self.version & VARIANT_MASK_MEMORY_PROFILE) > 0
+    }
 }
 
 fn parse_summary<'a>(
@@ -137,11 +155,11 @@ fn parse_summary<'a>(
             .unwrap_or_default();
         let num_counts = fields
             .get(&SummaryFieldKind::TotalNumBlocks)
-            .map(|x| *x as u32)
+            .copied()
             .unwrap_or_default();
         let num_fns = fields
             .get(&SummaryFieldKind::TotalNumFunctions)
-            .map(|x| *x as u32)
+            .copied()
             .unwrap_or_default();
         let summary = ProfileSummary {
             kind,
@@ -161,12 +179,70 @@ fn parse_summary<'a>(
     }
 }
 
+/// Reads the names of the vtables from version 12, `offset` is from the start of the profile. The
+/// section is the length of the names followed by the names in the same format as the names in a
+/// raw profile, which can be compressed.
+fn parse_vtable_names(input: &[u8], offset: u64) -> ParseResult<'_, Vec<String>> {
+    let (bytes, _) = take(offset)(input)?;
+    let (bytes, len) = le_u64(bytes)?;
+    let (bytes, mut section) = take(len)(bytes)?;
+    let mut names = vec![];
+    while !section.is_empty() {
+        let (remaining, joined) = parse_string_ref(section)?;
+        section = remaining;
+        names.extend(
+            joined
+                .split(INSTR_PROF_NAME_SEP)
+                .filter(|x| !x.is_empty())
+                .map(String::from),
+        );
+    }
+    Ok((bytes, names))
+}
+
+impl IndexedInstrProf {
+    /// Parses the header and binary IDs without reading the records, see `parse_header_only`. The
+    /// number of functions is the number of entries in the hash table.
+    pub fn parse_header_info(input: &[u8]) -> ParseResult<'_, ProfileHeaderInfo> {
+        let (bytes, header) = Self::parse_header(input)?;
+        // The hash table starts with the number of buckets and then the number of entries
+        let (table, _) = take(header.hash_offset)(input)?;
+        let (table, _num_buckets) = le_u64(table)?;
+        let (_, num_entries) = le_u64(table)?;
+        let binary_ids = match header.binary_id_offset {
+            Some(offset) if offset > 0 => {
+                let (ids, _) = take(offset)(input)?;
+                let (ids, len) = le_u64(ids)?;
+                let (_, ids) = take(len)(ids)?;
+                parse_binary_ids(ids, Endianness::Little)?.1
+            }
+            _ => vec![],
+        };
+        let info = ProfileHeaderInfo {
+            format: ProfileFormat::Binary,
+            version: Some(header.version()),
+            level: ProfileLevel::new(header.is_ir_prof(), header.is_csir_prof()),
+            is_entry_first: header.is_entry_first(),
+            is_byte_coverage: header.is_byte_coverage(),
+            fn_entry_only: header.fn_entry_only(),
+            memory_profiling: header.has_memory_profile(),
+            num_functions: Some(num_entries),
+            binary_ids,
+        };
+        Ok((bytes, info))
+    }
+}
+
 impl InstrProfReader for IndexedInstrProf {
     type Header = Header;
 
     fn parse_bytes(mut input: &[u8]) -> ParseResult<'_, InstrumentationProfile> {
+        let _span = debug_span!("indexed_profile", len = input.len()).entered();
         let (bytes, header) = Self::parse_header(input)?;
-        debug!("Parsed header: {:?}", header);
+        debug!(
+            offset = input.len() - bytes.len(),
+            "Parsed header: {:?}", header
+        );
         let (bytes, summary) = parse_summary(bytes, &header, false)?;
         debug!("Summary: {:?}", summary);
         let (bytes, cs_summary) = if header.is_csir_prof() {
@@ -174,22 +250,48 @@ impl InstrProfReader for IndexedInstrProf {
         } else {
             (bytes, None)
         };
-        debug!("cs_summary: {:?}", cs_summary);
+        debug!(
+            offset = input.len() - bytes.len(),
+            "cs_summary: {:?}", cs_summary
+        );
         let mut profile = InstrumentationProfile::new(
-            Some(header.version),
+            Some(header.version()),
             header.is_csir_prof(),
             header.is_ir_prof(),
-            false,
+            header.is_entry_first(),
         );
+        profile.is_byte_coverage = header.is_byte_coverage();
+        profile.fn_entry_only = header.fn_entry_only();
+        profile.memory_profiling = header.has_memory_profile();
 
         let table_start = input.len() - bytes.len();
-        let (bytes, table) = HashTable::parse(
-            header.version,
-            bytes,
-            table_start,
-            header.hash_offset as usize - table_start,
-        )?;
+        let bucket_start = usize::try_from(header.hash_offset)
+            .ok()
+            .and_then(|x| x.checked_sub(table_start))
+            .ok_or_else(|| {
+                let error = VerboseError::from_error_kind(bytes, ErrorKind::Satisfy);
+                nom::Err::Failure(VerboseError::add_context(
+                    bytes,
+                    "hash table offset is before the end of the header",
+                    error,
+                ))
+            })?;
+        let (bytes, table) = HashTable::parse(header.version(), bytes, table_start, bucket_start)?;
         debug!("Function hash table: {:?}", table);
+        debug!(
+            offset = input.len() - bytes.len(),
+            records = table.0.len(),
+            "Parsed hash table"
+        );
+        if let Some(offset) = header.vtable_offset.filter(|x| *x > 0) {
+            let (_, names) = parse_vtable_names(input, offset)?;
+            debug!("Parsed {} vtable names", names.len());
+            // Vtable targets are the MD5 of the vtable name like function names so they share the
+            // symbol table
+            for name in names {
+                profile.symtab.add_func_name(name, Some(Endianness::Little));
+            }
+        }
         input = bytes;
         for ((hash, name), v) in &table.0 {
             let name = name.to_string();
@@ -207,6 +309,8 @@ impl InstrProfReader for IndexedInstrProf {
             debug!("Parsed record {:?}", record);
             profile.push_record(record);
         }
+        profile.summary = summary;
+        profile.cs_summary = cs_summary;
         Ok((input, profile))
     }
 
@@ -237,15 +341,13 @@ impl InstrProfReader for IndexedInstrProf {
             } else {
                 (bytes, None)
             };
-
-            let (bytes, vtable_offset) = if version_num >= 12 {
+            let (bytes, temporary_prof_traces_offset) = if version_num >= 10 {
                 let (bytes, offset) = le_u64(bytes)?;
                 (bytes, Some(offset))
             } else {
                 (bytes, None)
             };
-
-            let (bytes, temporary_prof_traces_offset) = if version_num >= 10 {
+            let (bytes, vtable_offset) = if version_num >= 12 {
                 let (bytes, offset) = le_u64(bytes)?;
                 (bytes, Some(offset))
             } else {
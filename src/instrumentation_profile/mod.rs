@@ -2,18 +2,31 @@ use crate::instrumentation_profile::indexed_profile::*;
 use crate::instrumentation_profile::raw_profile::*;
 use crate::instrumentation_profile::text_profile::*;
 use crate::instrumentation_profile::types::*;
+use crate::util::{gunzip, is_gzip};
+use crate::ProfileFormat;
+use nom::bytes::complete::take;
+use nom::number::complete::u64 as nom_u64;
+use nom::number::Endianness;
 use nom::{error::VerboseError, Err, IResult};
 use std::fs::File;
 use std::io;
 use std::io::prelude::*;
 use std::path::Path;
-use tracing::trace;
+use tracing::{debug, debug_span};
 
+pub mod builder;
+pub mod csv;
 pub mod indexed_profile;
+pub mod overlap;
 pub mod raw_profile;
+pub mod show;
 pub mod summary;
 pub mod text_profile;
 pub mod types;
+pub mod validate;
+
+/// Separator between the names in the names sections of raw and indexed profiles
+pub(crate) const INSTR_PROF_NAME_SEP: char = '\u{1}';
 
 pub type ParseResult<'a, T> = IResult<&'a [u8], T, VerboseError<&'a [u8]>>;
 
@@ -21,46 +34,239 @@ pub const fn get_num_padding_bytes(len: u64) -> u8 {
     7 & (8 - (len % 8) as u8)
 }
 
+/// Writes the zero padding needed after `bytes_written` bytes to reach an 8 byte boundary, like
+/// the padding after sections in raw and indexed profiles. Returns the offset after the padding.
+pub fn align_to_8(writer: &mut impl Write, bytes_written: u64) -> io::Result<u64> {
+    let padding = get_num_padding_bytes(bytes_written);
+    writer.write_all(&[0; 8][..padding as usize])?;
+    Ok(bytes_written + padding as u64)
+}
+
+/// Reads the list of binary IDs in raw profiles and indexed profiles, each is a 64 bit length
+/// followed by the ID padded to 8 bytes
+pub(crate) fn parse_binary_ids(
+    mut input: &[u8],
+    endianness: Endianness,
+) -> ParseResult<'_, Vec<Vec<u8>>> {
+    let mut ids = vec![];
+    while !input.is_empty() {
+        let (bytes, len) = nom_u64(endianness)(input)?;
+        let (bytes, id) = take(len)(bytes)?;
+        let (bytes, _) = take(get_num_padding_bytes(len))(bytes)?;
+        ids.push(id.to_vec());
+        input = bytes;
+    }
+    Ok((input, ids))
+}
+
 pub fn parse(filename: impl AsRef<Path>) -> io::Result<InstrumentationProfile> {
+    let _span = debug_span!("parse", path = %filename.as_ref().display()).entered();
+    let buffer = read_profile(filename.as_ref())?;
+    parse_bytes(buffer.as_slice())
+}
+
+/// Reads a profile file into memory. Gzip compressed files are decompressed, and a directory gives
+/// an error pointing to the APIs for reading more than one profile.
+pub(crate) fn read_profile(filename: &Path) -> io::Result<Vec<u8>> {
+    if filename.is_dir() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "{} is a directory, use `merge_profiles` or `ProfileSet` to read the profiles in it",
+                filename.display()
+            ),
+        ));
+    }
     let mut buffer = Vec::new();
     let mut f = File::open(filename)?;
     f.read_to_end(&mut buffer)?;
-    parse_bytes(buffer.as_slice())
+    if is_gzip(&buffer) {
+        gunzip(&buffer).map_err(|e| {
+            io::Error::new(
+                e.kind(),
+                format!("couldn't decompress {}: {}", filename.display(), e),
+            )
+        })
+    } else {
+        Ok(buffer)
+    }
 }
 
 pub fn parse_bytes(data: &[u8]) -> io::Result<InstrumentationProfile> {
+    parse_bytes_with_options(data, &ParseOptions::default())
+}
+
+/// Options to make parsing more lenient, the defaults reject anything the parsers don't
+/// understand
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, Hash)]
+pub struct ParseOptions {
+    /// Skip value profiling data of kinds this crate doesn't know about with a warning instead of
+    /// failing. Useful for profiles from toolchains newer than the crate, the values of the known
+    /// kinds are still read.
+    pub skip_unknown_value_kinds: bool,
+}
+
+/// What's known about a profile from its header, see `parse_header_only`
+#[derive(Clone, Debug, Eq, PartialEq, Hash)]
+pub struct ProfileHeaderInfo {
+    /// Format of the profile, raw profiles with 32 and 64 bit pointers are both `Raw`
+    pub format: ProfileFormat,
+    /// Version with the variant flags masked out, `None` for text profiles
+    pub version: Option<u64>,
+    pub level: ProfileLevel,
+    pub is_entry_first: bool,
+    pub is_byte_coverage: bool,
+    pub fn_entry_only: bool,
+    pub memory_profiling: bool,
+    /// Number of functions, `None` for text profiles which would need every record reading to
+    /// count them. Indexed profiles count names, so functions with several hashes under one name
+    /// count once.
+    pub num_functions: Option<u64>,
+    /// Build IDs of the binaries which wrote the profile, raw profiles from version 7 and indexed
+    /// profiles from version 9 can have them
+    pub binary_ids: Vec<Vec<u8>>,
+}
+
+/// Reads only the header of the file for cataloguing profiles without parsing the records. The
+/// fields match the ones a full `parse` gives.
+pub fn parse_header_only(filename: impl AsRef<Path>) -> io::Result<ProfileHeaderInfo> {
+    let buffer = read_profile(filename.as_ref())?;
+    parse_bytes_header_only(buffer.as_slice())
+}
+
+/// Reads only the header of the profile like `parse_header_only`
+pub fn parse_bytes_header_only(data: &[u8]) -> io::Result<ProfileHeaderInfo> {
     let nom_res = if IndexedInstrProf::has_format(data) {
-        IndexedInstrProf::parse_bytes(data)
+        IndexedInstrProf::parse_header_info(data)
     } else if RawInstrProf64::has_format(data) {
-        RawInstrProf64::parse_bytes(data)
+        RawInstrProf64::parse_header_info(data)
     } else if RawInstrProf32::has_format(data) {
-        RawInstrProf32::parse_bytes(data)
+        RawInstrProf32::parse_header_info(data)
     } else if TextInstrProf::has_format(data) {
-        TextInstrProf::parse_bytes(data)
+        TextInstrProf::parse_header_info(data)
     } else {
-        return Err(io::Error::new(
-            io::ErrorKind::Other,
+        return Err(io::Error::other(
             "Unsupported instrumentation profile format",
         ));
     };
+    finish_parse(data, nom_res)
+}
+
+/// Parses the file like `parse` with the given options
+pub fn parse_with_options(
+    filename: impl AsRef<Path>,
+    options: &ParseOptions,
+) -> io::Result<InstrumentationProfile> {
+    let _span = debug_span!("parse", path = %filename.as_ref().display()).entered();
+    let buffer = read_profile(filename.as_ref())?;
+    parse_bytes_with_options(buffer.as_slice(), options)
+}
+
+/// Parses the bytes like `parse_bytes` with the given options
+pub fn parse_bytes_with_options(
+    data: &[u8],
+    options: &ParseOptions,
+) -> io::Result<InstrumentationProfile> {
+    match parse_detected(data, options) {
+        Some(nom_res) => finish_parse(data, nom_res),
+        None => Err(io::Error::new(
+            io::ErrorKind::Other,
+            "Unsupported instrumentation profile format",
+        )),
+    }
+}
+
+/// Runs the parser for the detected format, `None` if the format isn't recognised
+pub(crate) fn parse_detected<'a>(
+    data: &'a [u8],
+    options: &ParseOptions,
+) -> Option<ParseResult<'a, InstrumentationProfile>> {
+    if IndexedInstrProf::has_format(data) {
+        Some(IndexedInstrProf::parse_bytes(data))
+    } else if RawInstrProf64::has_format(data) {
+        Some(RawInstrProf64::parse_bytes(data))
+    } else if RawInstrProf32::has_format(data) {
+        Some(RawInstrProf32::parse_bytes(data))
+    } else if TextInstrProf::has_format(data) {
+        Some(TextInstrProf::parse_bytes_with_options(data, options))
+    } else {
+        None
+    }
+}
+
+/// Parses the file as the given format without trying to detect the format from the contents
+pub fn parse_as(
+    filename: impl AsRef<Path>,
+    format: ProfileFormat,
+) -> io::Result<InstrumentationProfile> {
+    let buffer = read_profile(filename.as_ref())?;
+    parse_bytes_as(buffer.as_slice(), format)
+}
+
+/// Parses the bytes as the given format without trying to detect the format from the contents.
+/// If the data doesn't match the format an `InvalidData` error is returned, and formats which
+/// aren't instrumentation profiles return an `Unsupported` error.
+pub fn parse_bytes_as(data: &[u8], format: ProfileFormat) -> io::Result<InstrumentationProfile> {
+    let mismatch = |expected: &str| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Data is not a valid {} profile", expected),
+        )
+    };
+    let nom_res = match format {
+        ProfileFormat::Binary if IndexedInstrProf::has_format(data) => {
+            IndexedInstrProf::parse_bytes(data)
+        }
+        ProfileFormat::Binary => return Err(mismatch("indexed")),
+        ProfileFormat::Raw if RawInstrProf64::has_format(data) => RawInstrProf64::parse_bytes(data),
+        ProfileFormat::Raw if RawInstrProf32::has_format(data) => RawInstrProf32::parse_bytes(data),
+        ProfileFormat::Raw => return Err(mismatch("raw")),
+        ProfileFormat::Text if TextInstrProf::has_format(data) => TextInstrProf::parse_bytes(data),
+        ProfileFormat::Text => return Err(mismatch("text")),
+        ProfileFormat::CompactBinary | ProfileFormat::ExtBinary | ProfileFormat::Gcc => {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!("{:?} is not an instrumentation profile format", format),
+            ));
+        }
+    };
+    finish_parse(data, nom_res)
+}
+
+fn finish_parse<T>(data: &[u8], nom_res: ParseResult<'_, T>) -> io::Result<T> {
     nom_res.map(|(_bytes, res)| res).map_err(|e| {
-        trace!("{}", e);
-        let verbose_error_message = |err: VerboseError<&[u8]>| {
-            err.errors
-                .iter()
-                .map(|(_, x)| format!("{:?}", x))
-                .collect::<Vec<String>>()
-                .join(" ")
-        };
-        let error_message = match e {
-            Err::Error(e) => format!("parser error: {}", verbose_error_message(e)),
-            Err::Failure(e) => format!("parser failure: {}", verbose_error_message(e)),
-            Err::Incomplete(_) => unreachable!("llvm_profparsers works on complete data"),
-        };
-        io::Error::new(io::ErrorKind::Other, error_message)
+        // The first error is the innermost so is where parsing stopped
+        if let Err::Error(inner) | Err::Failure(inner) = &e {
+            if let Some((remaining, _)) = inner.errors.first() {
+                debug!(
+                    offset = data.len() - remaining.len(),
+                    len = data.len(),
+                    "Parsing stopped: {}",
+                    verbose_error_message(inner)
+                );
+            }
+        }
+        match e {
+            Err::Error(e) => {
+                io::Error::other(format!("parser error: {}", verbose_error_message(&e)))
+            }
+            Err::Failure(e) => {
+                io::Error::other(format!("parser failure: {}", verbose_error_message(&e)))
+            }
+            // The streaming parsers ask for more data when the profile ends early
+            Err::Incomplete(_) => io::Error::new(io::ErrorKind::InvalidData, "truncated profile"),
+        }
     })
 }
 
+pub(crate) fn verbose_error_message(err: &VerboseError<&[u8]>) -> String {
+    err.errors
+        .iter()
+        .map(|(_, x)| format!("{:?}", x))
+        .collect::<Vec<String>>()
+        .join(" ")
+}
+
 pub trait InstrProfReader {
     type Header;
     /// Parse the profile no lazy parsing here!
@@ -75,3 +281,63 @@ pub trait InstrProfReader {
 pub trait InstrProfWriter {
     fn write(&self, profile: &InstrumentationProfile, writer: &mut impl Write) -> io::Result<()>;
 }
+
+/// Returns an `Unsupported` error for formats profiles can't be written in, only the text format
+/// can be written
+pub(crate) fn check_writable(format: ProfileFormat) -> io::Result<()> {
+    if format == ProfileFormat::Text {
+        Ok(())
+    } else {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!("writing {:?} profiles isn't supported", format),
+        ))
+    }
+}
+
+/// Writer which only counts the bytes written to it
+#[derive(Default)]
+struct ByteCounter(usize);
+
+impl Write for ByteCounter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0 += buf.len();
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl InstrumentationProfile {
+    /// The exact size in bytes of the profile written in the given format. The profile is fully
+    /// serialized to get the size so this costs as much time as writing it, only the output is
+    /// counted instead of kept so it doesn't need memory for the serialized profile. Only the text
+    /// format can be written so other formats return an `Unsupported` error.
+    pub fn estimate_size(&self, format: ProfileFormat) -> io::Result<usize> {
+        check_writable(format)?;
+        let mut counter = ByteCounter::default();
+        TextInstrProf.write(self, &mut counter)?;
+        Ok(counter.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn alignment_padding() {
+        for (len, expected) in [(0, 0), (1, 8), (7, 8), (8, 8), (9, 16), (15, 16), (16, 16)] {
+            let mut written = vec![0xff; len as usize];
+            assert_eq!(align_to_8(&mut written, len).unwrap(), expected);
+            assert_eq!(written.len() as u64, expected);
+            assert!(written[len as usize..].iter().all(|x| *x == 0));
+        }
+        // Offsets don't need to start from the beginning of the writer
+        let mut written = vec![];
+        assert_eq!(align_to_8(&mut written, 1021).unwrap(), 1024);
+        assert_eq!(written, vec![0; 3]);
+    }
+}
@@ -0,0 +1,551 @@
+//! Overlap between two instrumentation profiles, this follows the `OverlapStats` calculations in
+//! LLVM used by `llvm-profdata overlap`.
+use crate::instrumentation_profile::types::*;
+use crate::util::json_string;
+use rustc_hash::{FxHashMap, FxHashSet};
+use std::io::{self, Write};
+
+const VALUE_KINDS: [ValueKind; ValueKind::len()] = ValueKind::ALL;
+
+/// Either a sum of counts or the percentage of a sum of counts depending on which part of the
+/// `OverlapStats` it's in
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct CountSumOrPercent {
+    pub num_entries: f64,
+    pub count_sum: f64,
+    /// Indexed by `ValueKind`
+    pub value_counts: [f64; ValueKind::len()],
+}
+
+impl CountSumOrPercent {
+    fn accumulate(&mut self, record: &InstrProfRecord) {
+        self.num_entries += record.counts.len() as f64;
+        self.count_sum += record
+            .counts
+            .iter()
+            .fold(0u64, |acc, x| acc.saturating_add(*x)) as f64;
+        for kind in VALUE_KINDS {
+            let kind_sum = record
+                .value_sites(kind)
+                .iter()
+                .flatten()
+                .fold(0u64, |acc, x| acc.saturating_add(x.count));
+            self.value_counts[kind as usize] += kind_sum as f64;
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+pub enum OverlapLevel {
+    Program,
+    Function,
+}
+
+/// Controls which functions get function level overlap results
+#[derive(Clone, Debug, Eq, PartialEq, Hash)]
+pub struct OverlapFilter {
+    /// Report functions where the max count in the test profile is at least this value
+    pub value_cutoff: u64,
+    /// Report functions with names containing this string regardless of the value cutoff
+    pub name_filter: Option<String>,
+}
+
+impl Default for OverlapFilter {
+    fn default() -> Self {
+        Self {
+            value_cutoff: u64::MAX,
+            name_filter: None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct OverlapStats {
+    pub level: OverlapLevel,
+    /// Sums of the base profile
+    pub base: CountSumOrPercent,
+    /// Sums of the test profile
+    pub test: CountSumOrPercent,
+    /// Fraction of the counts which overlap
+    pub overlap: CountSumOrPercent,
+    /// Fraction of the test counts in functions where the structure doesn't match the base
+    pub mismatch: CountSumOrPercent,
+    /// Fraction of the test counts in functions not in the base
+    pub unique: CountSumOrPercent,
+    /// Whether the results are meaningful and should be reported
+    pub valid: bool,
+    /// Name of the function for function level stats
+    pub func_name: Option<String>,
+    /// Hash of the function for function level stats
+    pub func_hash: Option<u64>,
+}
+
+/// Result of comparing two profiles
+#[derive(Clone, Debug, PartialEq)]
+pub struct ProfileOverlap {
+    pub program: OverlapStats,
+    /// Function level stats for the functions selected by the `OverlapFilter`
+    pub functions: Vec<OverlapStats>,
+}
+
+/// Writes a score as JSON, scores are `NaN` when the test profile has no counts which JSON can't
+/// represent so they're written as `null`
+fn json_score(score: f64) -> String {
+    if score.is_finite() {
+        score.to_string()
+    } else {
+        "null".to_string()
+    }
+}
+
+impl ProfileOverlap {
+    /// Writes the edge counter overlap as a single line of JSON. The schema is:
+    ///
+    /// ```json
+    /// {
+    ///   "overlap": 0.6,
+    ///   "base_count": 500000,
+    ///   "test_count": 50000,
+    ///   "functions": [
+    ///     {"name": "main", "overlap": 1, "base_count": 200000, "test_count": 20000}
+    ///   ]
+    /// }
+    /// ```
+    ///
+    /// `overlap` is the overlap score as a fraction between 0 and 1 and the counts are the sums
+    /// of the edge counters in each profile. For the program these sums are over every compared
+    /// function and for a function they're its own counters. `functions` has the functions
+    /// selected by the `OverlapFilter` in the order of the test profile. Fields may be added in
+    /// future but the existing ones won't change.
+    pub fn write_json(&self, writer: &mut impl Write) -> io::Result<()> {
+        write!(
+            writer,
+            "{{\"overlap\":{},\"base_count\":{:.0},\"test_count\":{:.0},\"functions\":[",
+            json_score(self.program.overlap.count_sum),
+            self.program.base.count_sum,
+            self.program.test.count_sum
+        )?;
+        for (i, function) in self.functions.iter().enumerate() {
+            if i > 0 {
+                write!(writer, ",")?;
+            }
+            write!(
+                writer,
+                "{{\"name\":{},\"overlap\":{},\"base_count\":{:.0},\"test_count\":{:.0}}}",
+                json_string(function.func_name.as_deref().unwrap_or_default()),
+                json_score(function.overlap.count_sum),
+                function.base.count_sum,
+                function.test.count_sum
+            )?;
+        }
+        writeln!(writer, "]}}")
+    }
+}
+
+fn score(a: u64, b: u64, sum_a: f64, sum_b: f64) -> f64 {
+    if sum_a < 1.0 || sum_b < 1.0 {
+        0.0
+    } else {
+        (a as f64 / sum_a).min(b as f64 / sum_b)
+    }
+}
+
+fn is_included(
+    profile: &InstrumentationProfile,
+    record: &NamedInstrProfRecord,
+    is_cs: bool,
+) -> bool {
+    !profile.is_ir_level_profile() || record.has_cs_flag() == is_cs
+}
+
+impl OverlapStats {
+    pub fn new(level: OverlapLevel) -> Self {
+        Self {
+            level,
+            base: Default::default(),
+            test: Default::default(),
+            overlap: Default::default(),
+            mismatch: Default::default(),
+            unique: Default::default(),
+            valid: false,
+            func_name: None,
+            func_hash: None,
+        }
+    }
+
+    fn add_one_mismatch(&mut self, func: &CountSumOrPercent) {
+        self.mismatch.num_entries += 1.0;
+        self.mismatch.count_sum += func.count_sum / self.test.count_sum;
+        for i in 0..ValueKind::len() {
+            if self.test.value_counts[i] >= 1.0 {
+                self.mismatch.value_counts[i] += func.value_counts[i] / self.test.value_counts[i];
+            }
+        }
+    }
+
+    fn add_one_unique(&mut self, func: &CountSumOrPercent) {
+        self.unique.num_entries += 1.0;
+        self.unique.count_sum += func.count_sum / self.test.count_sum;
+        for i in 0..ValueKind::len() {
+            if self.test.value_counts[i] >= 1.0 {
+                self.unique.value_counts[i] += func.value_counts[i] / self.test.value_counts[i];
+            }
+        }
+    }
+
+    /// Writes out the stats in the same format as `llvm-profdata overlap`. Nothing is written if
+    /// the stats aren't valid.
+    pub fn dump(&self, writer: &mut impl Write) -> io::Result<()> {
+        if !self.valid {
+            return Ok(());
+        }
+        let entry_name = match self.level {
+            OverlapLevel::Program => {
+                writeln!(writer, "Program level:")?;
+                "functions"
+            }
+            OverlapLevel::Function => {
+                writeln!(writer, "Function level:")?;
+                writeln!(
+                    writer,
+                    "  Function: {} (Hash={})",
+                    self.func_name.as_deref().unwrap_or_default(),
+                    self.func_hash.unwrap_or_default()
+                )?;
+                "edge counters"
+            }
+        };
+        writeln!(
+            writer,
+            "  # of {} overlap: {}",
+            entry_name, self.overlap.num_entries
+        )?;
+        if self.mismatch.num_entries > 0.0 {
+            writeln!(
+                writer,
+                "  # of {} mismatch: {}",
+                entry_name, self.mismatch.num_entries
+            )?;
+        }
+        if self.unique.num_entries > 0.0 {
+            writeln!(
+                writer,
+                "  # of {} only in test_profile: {}",
+                entry_name, self.unique.num_entries
+            )?;
+        }
+        writeln!(
+            writer,
+            "  Edge profile overlap: {:.3}%",
+            self.overlap.count_sum * 100.0
+        )?;
+        if self.mismatch.num_entries > 0.0 {
+            writeln!(
+                writer,
+                "  Mismatched count percentage (Edge): {:.3}%",
+                self.mismatch.count_sum * 100.0
+            )?;
+        }
+        if self.unique.num_entries > 0.0 {
+            writeln!(
+                writer,
+                "  Percentage of Edge profile only in test_profile: {:.3}%",
+                self.unique.count_sum * 100.0
+            )?;
+        }
+        writeln!(
+            writer,
+            "  Edge profile base count sum: {:.0}",
+            self.base.count_sum
+        )?;
+        writeln!(
+            writer,
+            "  Edge profile test count sum: {:.0}",
+            self.test.count_sum
+        )?;
+        for kind in VALUE_KINDS {
+            let i = kind as usize;
+            if self.base.value_counts[i] < 1.0 && self.test.value_counts[i] < 1.0 {
+                continue;
+            }
+            let kind_name = match kind {
+                ValueKind::IndirectCallTarget => "IndirectCall",
+                ValueKind::MemOpSize => "MemOP",
+                ValueKind::VTableTarget => "VTable",
+            };
+            writeln!(
+                writer,
+                "  {} profile overlap: {:.3}%",
+                kind_name,
+                self.overlap.value_counts[i] * 100.0
+            )?;
+            if self.mismatch.num_entries > 0.0 {
+                writeln!(
+                    writer,
+                    "  Mismatched count percentage ({}): {:.3}%",
+                    kind_name,
+                    self.mismatch.value_counts[i] * 100.0
+                )?;
+            }
+            if self.unique.num_entries > 0.0 {
+                writeln!(
+                    writer,
+                    "  Percentage of {} profile only in test_profile: {:.3}%",
+                    kind_name,
+                    self.unique.value_counts[i] * 100.0
+                )?;
+            }
+            writeln!(
+                writer,
+                "  {} profile base count sum: {:.0}",
+                kind_name, self.base.value_counts[i]
+            )?;
+            writeln!(
+                writer,
+                "  {} profile test count sum: {:.0}",
+                kind_name, self.test.value_counts[i]
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Overlap of the value sites of one kind, the scores are relative to both the program and
+/// function level sums
+fn overlap_value_sites(
+    kind: ValueKind,
+    base: &InstrProfRecord,
+    test: &InstrProfRecord,
+    overlap: &mut OverlapStats,
+    func_overlap: &mut OverlapStats,
+) {
+    let i = kind as usize;
+    for (base_site, test_site) in base.value_sites(kind).iter().zip(test.value_sites(kind)) {
+        let mut base_site = base_site.clone();
+        let mut test_site = test_site.clone();
+        base_site.sort_by_key(|x| x.value);
+        test_site.sort_by_key(|x| x.value);
+
+        let mut score_sum = 0.0;
+        let mut func_score_sum = 0.0;
+        let mut base_iter = base_site.iter().peekable();
+        for test_value in &test_site {
+            while base_iter
+                .peek()
+                .map(|x| x.value < test_value.value)
+                .unwrap_or(false)
+            {
+                base_iter.next();
+            }
+            if let Some(base_value) = base_iter.peek() {
+                if base_value.value == test_value.value {
+                    score_sum += score(
+                        base_value.count,
+                        test_value.count,
+                        overlap.base.value_counts[i],
+                        overlap.test.value_counts[i],
+                    );
+                    func_score_sum += score(
+                        base_value.count,
+                        test_value.count,
+                        func_overlap.base.value_counts[i],
+                        func_overlap.test.value_counts[i],
+                    );
+                    base_iter.next();
+                }
+            }
+        }
+        overlap.overlap.value_counts[i] += score_sum;
+        func_overlap.overlap.value_counts[i] += func_score_sum;
+    }
+}
+
+fn overlap_records(
+    base: &InstrProfRecord,
+    test: &InstrProfRecord,
+    overlap: &mut OverlapStats,
+    func_overlap: &mut OverlapStats,
+    value_cutoff: u64,
+) {
+    func_overlap.base.accumulate(base);
+    let mismatch = base.counts.len() != test.counts.len()
+        || VALUE_KINDS
+            .iter()
+            .any(|kind| base.value_sites(*kind).len() != test.value_sites(*kind).len());
+    if mismatch {
+        overlap.add_one_mismatch(&func_overlap.test);
+        return;
+    }
+    for kind in VALUE_KINDS {
+        overlap_value_sites(kind, base, test, overlap, func_overlap);
+    }
+
+    let mut score_sum = 0.0;
+    let mut max_count = 0;
+    for (base_count, test_count) in base.counts.iter().zip(&test.counts) {
+        score_sum += score(
+            *base_count,
+            *test_count,
+            overlap.base.count_sum,
+            overlap.test.count_sum,
+        );
+        max_count = max_count.max(*test_count);
+    }
+    overlap.overlap.count_sum += score_sum;
+    overlap.overlap.num_entries += 1.0;
+
+    if max_count >= value_cutoff {
+        let func_score = base
+            .counts
+            .iter()
+            .zip(&test.counts)
+            .map(|(b, t)| {
+                score(
+                    *b,
+                    *t,
+                    func_overlap.base.count_sum,
+                    func_overlap.test.count_sum,
+                )
+            })
+            .sum();
+        func_overlap.overlap.count_sum = func_score;
+        func_overlap.overlap.num_entries = test.counts.len() as f64;
+        func_overlap.valid = true;
+    }
+}
+
+/// Computes how much the `test` profile overlaps with the `base` profile for both the edge
+/// counters and each kind of value profiling data. If `is_cs` is set only context sensitive
+/// records are compared, otherwise only non context sensitive records. Function level results
+/// are included for functions matching `filter`.
+pub fn overlap_profiles(
+    base: &InstrumentationProfile,
+    test: &InstrumentationProfile,
+    is_cs: bool,
+    filter: &OverlapFilter,
+) -> ProfileOverlap {
+    let mut overlap = OverlapStats::new(OverlapLevel::Program);
+    let mut base_funcs = 0;
+    for record in base
+        .records()
+        .iter()
+        .filter(|x| is_included(base, x, is_cs))
+    {
+        overlap.base.accumulate(&record.record);
+        base_funcs += 1;
+    }
+    overlap.base.num_entries = base_funcs as f64;
+    let mut test_funcs = 0;
+    for record in test
+        .records()
+        .iter()
+        .filter(|x| is_included(test, x, is_cs))
+    {
+        overlap.test.accumulate(&record.record);
+        test_funcs += 1;
+    }
+    overlap.test.num_entries = test_funcs as f64;
+    overlap.valid = true;
+
+    let mut base_names = FxHashSet::default();
+    let mut base_records = FxHashMap::default();
+    for record in base.records() {
+        if let Some(name) = record.name.as_deref() {
+            base_names.insert(name);
+            base_records.insert((name, record.hash_unchecked()), &record.record);
+        }
+    }
+
+    let mut functions = vec![];
+    for record in test
+        .records()
+        .iter()
+        .filter(|x| is_included(test, x, is_cs))
+    {
+        let name = match record.name.as_deref() {
+            Some(name) => name,
+            None => continue,
+        };
+        let mut func_overlap = OverlapStats::new(OverlapLevel::Function);
+        func_overlap.func_name = Some(name.to_string());
+        func_overlap.func_hash = record.hash;
+        func_overlap.test.accumulate(&record.record);
+
+        if !base_names.contains(name) {
+            overlap.add_one_unique(&func_overlap.test);
+            continue;
+        }
+        if func_overlap.test.count_sum < 1.0 {
+            overlap.overlap.num_entries += 1.0;
+            continue;
+        }
+        let base_record = match base_records.get(&(name, record.hash_unchecked())) {
+            Some(base_record) => base_record,
+            None => {
+                overlap.add_one_mismatch(&func_overlap.test);
+                continue;
+            }
+        };
+        let value_cutoff = match filter.name_filter.as_deref() {
+            Some(pattern) if !pattern.is_empty() && name.contains(pattern) => 0,
+            _ => filter.value_cutoff,
+        };
+        overlap_records(
+            base_record,
+            &record.record,
+            &mut overlap,
+            &mut func_overlap,
+            value_cutoff,
+        );
+        if func_overlap.valid {
+            functions.push(func_overlap);
+        }
+    }
+
+    ProfileOverlap {
+        program: overlap,
+        functions,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::instrumentation_profile::builder::ProfileBuilder;
+
+    #[test]
+    fn partial_overlap() {
+        let mut base = ProfileBuilder::new();
+        base.add_function("foo", 1, vec![50, 50])
+            .add_function("bar", 2, vec![100])
+            .add_function("baz", 3, vec![1]);
+        let base = base.build();
+        let mut test = ProfileBuilder::new();
+        test.add_function("foo", 1, vec![100, 0])
+            .add_function("bar", 4, vec![50])
+            .add_function("qux", 5, vec![50]);
+        let test = test.build();
+
+        let result = overlap_profiles(&base, &test, false, &OverlapFilter::default());
+        let stats = result.program;
+        assert_eq!(stats.base.count_sum, 201.0);
+        assert_eq!(stats.test.count_sum, 200.0);
+        // foo scores min(50/201, 100/200) + min(50/201, 0)
+        assert!((stats.overlap.count_sum - 50.0 / 201.0).abs() < 1e-9);
+        assert_eq!(stats.overlap.num_entries, 1.0);
+        assert_eq!(stats.mismatch.num_entries, 1.0);
+        assert_eq!(stats.mismatch.count_sum, 0.25);
+        assert_eq!(stats.unique.num_entries, 1.0);
+        assert_eq!(stats.unique.count_sum, 0.25);
+        assert!(result.functions.is_empty());
+
+        let filter = OverlapFilter {
+            value_cutoff: 0,
+            name_filter: None,
+        };
+        let result = overlap_profiles(&base, &test, false, &filter);
+        assert_eq!(result.functions.len(), 1);
+        assert_eq!(result.functions[0].func_name.as_deref(), Some("foo"));
+        assert_eq!(result.functions[0].overlap.count_sum, 0.5);
+    }
+}
@@ -5,17 +5,18 @@ use core::hash::Hash;
 use nom::bytes::complete::take;
 use nom::error::ParseError;
 use nom::lib::std::ops::RangeFrom;
-use nom::number::streaming::{u16 as nom_u16, u32 as nom_u32, u64 as nom_u64};
+use nom::number::streaming::{u16 as nom_u16, u32 as nom_u32, u64 as nom_u64, u8 as nom_u8};
 use nom::number::Endianness;
 use nom::{
     error::{ContextError, ErrorKind},
     Err,
 };
 use nom::{InputIter, InputLength, Slice};
+use rustc_hash::FxHashMap;
 use std::convert::TryInto;
 use std::fmt::{Debug, Display};
 use std::mem::size_of;
-use tracing::{debug, error, trace};
+use tracing::{debug, debug_span, error, trace};
 
 #[derive(Debug, Clone, Copy, Eq, PartialEq, Hash, Ord, PartialOrd)]
 pub enum RawProfileError {
@@ -37,9 +38,11 @@ pub enum RawProfileError {
     EmptyRawProfile,
 }
 
-const INSTR_PROF_NAME_SEP: char = '\u{1}';
-
+/// Raw profile from a target with 32 bit pointers. The width is only for the pointers in the data
+/// records, LLVM has no 32 bit counters. Counters are always 64 bit, or a byte with single byte
+/// coverage, as given by the version flags in the header.
 pub type RawInstrProf32 = RawInstrProf<u32>;
+/// Raw profile from a target with 64 bit pointers
 pub type RawInstrProf64 = RawInstrProf<u64>;
 
 #[derive(Debug, Clone, Eq, PartialEq)]
@@ -70,6 +73,10 @@ pub struct Header {
     pub bitmap_delta: u64,
     pub num_vtables: u64,
     pub vnames_size: u64,
+    /// Size of the value profiling data section, only version 2 has one. Later versions put the
+    /// value data after the names
+    pub value_data_len: u64,
+    pub value_data_delta: u64,
 }
 
 impl Header {
@@ -116,26 +123,110 @@ impl Header {
 #[derive(Debug, Clone, Eq, PartialEq, Hash, Ord, PartialOrd)]
 pub struct ProfileData<T> {
     name_ref: u64,
+    /// Before version 3 the name wasn't hashed, the record points to it in the names section
+    /// along with its length. `name_ref` is filled in once the names are read
+    name_ptr: Option<(T, u32)>,
     func_hash: u64,
     counter_ptr: T,
     bitmap_ptr: Option<T>,
     function_addr: T,
     values_ptr_expr: T,
     num_counters: u32,
-    /// This might just be two values?
-    num_value_sites: [u16; ValueKind::MemOpSize as usize + 1],
+    /// Number of value sites for each value kind, vtable targets were added in version 10
+    num_value_sites: [u16; ValueKind::len()],
     num_bitmap_bytes: u32,
 }
 
-impl<T> ProfileData<T> {
-    fn len(&self) -> usize {
-        16 + 4 + (2 * (ValueKind::MemOpSize as usize + 1)) + 3 * size_of::<T>()
-    }
+/// Where a function's counters live in a raw profile. See [`RawProfileLayout`] for how the
+/// offset is calculated.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash, Ord, PartialOrd)]
+pub struct FunctionLayout {
+    /// Truncated MD5 hash of the function name, this is the key into the symbol table
+    pub name_ref: u64,
+    /// Structural hash of the function
+    pub func_hash: u64,
+    /// `CounterPtr` as written by the profiling runtime
+    pub counter_ptr: u64,
+    /// Number of counters the function has
+    pub num_counters: u32,
+    /// Offset in bytes of the function's first counter from the start of the counters section
+    pub counters_offset: i64,
+    /// Number of MC/DC bitmap bytes the function has
+    pub num_bitmap_bytes: u32,
+    /// Offset in bytes of the function's MC/DC bitmap from the start of the bitmap section,
+    /// calculated from `BitmapPtr` and `bitmap_delta` like the counters. `None` if the function
+    /// has no bitmap bytes.
+    pub bitmap_offset: Option<i64>,
+}
+
+/// The location of each section in a raw profile along with the values from the header needed
+/// to relocate them back to addresses in the instrumented binary. All offsets are in bytes from
+/// the start of the file.
+///
+/// Since raw profile version 8 (LLVM 13) the runtime writes `CounterPtr` relative to the address
+/// of the data record it's in, and `counters_delta` is the address of the counters section minus
+/// the address of the data section. Each data record is `data_record_size` bytes further away
+/// from the counters so for the nth record:
+///
+/// ```text
+/// counters_offset = counter_ptr - (counters_delta - n * data_record_size)
+/// ```
+///
+/// Before version 8 both are absolute addresses and it's just `counter_ptr - counters_delta`.
+/// Adding `counters_offset` to the address of the binary's `__llvm_prf_cnts` section gives the
+/// address of the function's counters. `names_delta` is the address of `__llvm_prf_names` in the
+/// running binary.
+///
+/// Version 9 (LLVM 18) added a section of MC/DC bitmap bytes between the counters and the names,
+/// mirroring `__llvm_prf_bits`. `BitmapPtr` is relative to the data record in the same way so a
+/// function's `bitmap_offset` is found with `bitmap_delta` in place of `counters_delta`. Older
+/// profiles have an empty bitmap section.
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+pub struct RawProfileLayout {
+    /// Version of the raw profile with the variant flags masked out
+    pub version: u64,
+    /// Counters section address minus the data section address (absolute counters address
+    /// before version 8)
+    pub counters_delta: u64,
+    /// Address of the names section when the profile was written
+    pub names_delta: u64,
+    /// Offset of the data section, after the header and binary IDs
+    pub data_offset: usize,
+    /// Size of a single record in the data section including any padding
+    pub data_record_size: usize,
+    /// Offset of the counters section
+    pub counters_offset: usize,
+    /// Length of the counters section without padding
+    pub counters_len: usize,
+    /// Bitmap section address minus the data section address, 0 before version 9
+    pub bitmap_delta: u64,
+    /// Offset of the MC/DC bitmap section, straight after the counters and their padding
+    pub bitmap_offset: usize,
+    /// Length of the bitmap section without padding
+    pub bitmap_len: usize,
+    /// Offset of the names section
+    pub names_offset: usize,
+    /// Length of the names section without padding
+    pub names_len: usize,
+    /// Whether the profile was written in continuous mode, see `Header::is_continuous_mode`
+    pub continuous_mode: bool,
+    /// Layout of each function in data section order
+    pub functions: Vec<FunctionLayout>,
 }
 
 impl Header {
+    /// Size in bytes of the counters section including the padding after it
     pub fn max_counters_len(&self) -> i64 {
-        ((8 * self.counters_len) + self.padding_bytes_after_counters) as i64
+        ((self.counter_size() as u64 * self.counters_len) + self.padding_bytes_after_counters)
+            as i64
+    }
+
+    /// Whether the profile was written in continuous mode (`%c` in `LLVM_PROFILE_FILE`). There's
+    /// no flag for this in the header, but only continuous mode page aligns the counters section
+    /// so it can be mmapped. Otherwise there's never padding before the counters and the padding
+    /// after is only to an 8 byte boundary.
+    pub fn is_continuous_mode(&self) -> bool {
+        self.padding_bytes_before_counters > 0 || self.padding_bytes_after_counters >= 8
     }
 }
 
@@ -143,7 +234,7 @@ impl Header {
 /// we ever move beyond 64 bit systems this code will have to change to Into<u128> or whatever the
 /// next thing is.
 pub trait MemoryWidthExt:
-    Debug + Copy + Clone + Eq + PartialEq + Hash + Ord + PartialOrd + Display + Into<u64>
+    Debug + Copy + Clone + Default + Eq + PartialEq + Hash + Ord + PartialOrd + Display + Into<u64>
 {
     const MAGIC: u64;
 
@@ -206,6 +297,15 @@ impl<T> RawInstrProf<T>
 where
     T: MemoryWidthExt,
 {
+    /// Relative pointers can be negative so need sign extending from the pointer width
+    fn sign_extend(offset: u64) -> i64 {
+        if size_of::<T>() == 4 {
+            offset as u32 as i32 as i64
+        } else {
+            offset as i64
+        }
+    }
+
     fn read_raw_counts<'a>(
         header: &Header,
         data: &ProfileData<T>,
@@ -214,6 +314,7 @@ where
         initial: &[u8],
     ) -> ParseResult<'a, InstrProfRecord> {
         let max_counters = header.max_counters_len();
+        let counters_size = data.num_counters as i64 * header.counter_size() as i64;
         // From LLVM coverage mapping version 8 relative counter offsets are allowed which can be
         // signed
         // num 2 max 24 offset 7 counters len 3
@@ -227,10 +328,10 @@ where
             || max_counters < 0
             || counter_offset < 0
             || counter_offset as u64 >= (header.counters_len * header.counter_size() as u64)
-            || data.num_counters as i64 > max_counters
+            || counters_size > max_counters
             || (header.version < 8 && counter_offset < 0)
             || counter_offset > max_counters
-            || counter_offset + data.num_counters as i64 > max_counters
+            || counter_offset + counters_size > max_counters
         {
             error!("consistency check for reading counts failed");
             //Err(Err::Failure(Error::new(bytes, ErrorKind::Satisfy))) TODO
@@ -248,12 +349,13 @@ where
             )))
         } else {
             let mut counts = Vec::<u64>::with_capacity(data.num_counters as usize);
-            let bytes_before = bytes;
             bytes = &bytes[(counter_offset as usize)..];
+            let bytes_before = bytes;
             for _ in 0..(data.num_counters as usize) {
                 let counter = if header.has_byte_coverage() {
-                    let counter = bytes[0];
-                    bytes = &bytes[1..];
+                    // Single byte counters are cleared when the code runs
+                    let (b, counter) = nom_u8(bytes)?;
+                    bytes = b;
                     (counter == 0) as u64
                 } else {
                     let (b, counter) = nom_u64(header.endianness)(bytes)?;
@@ -276,6 +378,198 @@ where
         }
     }
 
+    /// Skips the binary IDs and parses the data section, the input should start immediately
+    /// after the header.
+    fn parse_data_section<'a>(
+        bytes: &'a [u8],
+        header: &Header,
+    ) -> ParseResult<'a, Vec<ProfileData<T>>> {
+        if bytes.len() < header.binary_ids_len as usize {
+            return Err(nom::Err::Failure(VerboseError::from_error_kind(
+                &bytes[bytes.len()..],
+                ErrorKind::Eof,
+            )));
+        }
+        let mut input = &bytes[(header.binary_ids_len as usize)..];
+        let mut data_section = vec![];
+        for _ in 0..header.data_len {
+            let (bytes, data) = ProfileData::<T>::parse(input, header)?;
+            debug!("Parsed data section {:?}", data);
+            data_section.push(data);
+            if header.version() > 8 || header.version() == 2 {
+                // The struct is padded to the alignment of its u64 fields
+                let read = input.len() - bytes.len();
+                let (bytes, _) = take(get_num_padding_bytes(read as u64))(bytes)?;
+                input = bytes;
+            } else {
+                input = bytes;
+            }
+        }
+        Ok((input, data_section))
+    }
+
+    /// Parses the header and binary IDs without reading the data section, see
+    /// `parse_header_only`
+    pub fn parse_header_info(input: &[u8]) -> ParseResult<'_, ProfileHeaderInfo> {
+        let (bytes, header) = Self::parse_header(input)?;
+        let (bytes, binary_ids) = take(header.binary_ids_len as usize)(bytes)?;
+        let (_, binary_ids) = parse_binary_ids(binary_ids, header.endianness)?;
+        let has_flags = header.version() > 7;
+        let info = ProfileHeaderInfo {
+            format: ProfileFormat::Raw,
+            version: Some(header.version()),
+            level: ProfileLevel::new(header.ir_profile(), header.csir_profile()),
+            is_entry_first: false,
+            is_byte_coverage: has_flags && header.has_byte_coverage(),
+            fn_entry_only: has_flags && header.function_entry_only(),
+            memory_profiling: has_flags && header.memory_profile(),
+            num_functions: Some(header.data_len),
+            binary_ids,
+        };
+        Ok((bytes, info))
+    }
+
+    /// Parses the header and data section of a raw profile to work out where each section lives
+    /// in the file and where each function's counters are. This doesn't read the counters or
+    /// names so is cheap even for large profiles.
+    pub fn parse_layout(input: &[u8]) -> ParseResult<'_, RawProfileLayout> {
+        let (bytes, header) = Self::parse_header(input)?;
+        let header_len = input.len() - bytes.len();
+        let (bytes, mut data_section) = Self::parse_data_section(bytes, &header)?;
+        let data_offset = header_len + header.binary_ids_len as usize;
+        let data_len = input.len() - bytes.len() - data_offset;
+        let data_record_size = if data_section.is_empty() {
+            0
+        } else {
+            data_len / data_section.len()
+        };
+        let counters_offset =
+            data_offset + data_len + header.padding_bytes_before_counters as usize;
+        let counters_len = header.counters_len as usize * header.counter_size();
+        let bitmap_offset =
+            counters_offset + counters_len + header.padding_bytes_after_counters as usize;
+        let bitmap_len = header.num_bitmap_bytes as usize;
+        let names_offset =
+            bitmap_offset + bitmap_len + header.padding_bytes_after_bitmap_bytes as usize;
+        if header.version() < 3 {
+            // The name hashes have to be computed from the names
+            let names = input
+                .get(names_offset..)
+                .and_then(|x| x.get(..header.names_len as usize))
+                .unwrap_or_default();
+            Self::read_pointer_names(&header, &mut data_section, names)?;
+        }
+
+        let mut counters_delta = header.counters_delta;
+        let mut bitmap_delta = header.bitmap_delta;
+        let mut functions = Vec::with_capacity(data_section.len());
+        for data in &data_section {
+            let counter_ptr: u64 = data.counter_ptr.into();
+            let bitmap_offset = data
+                .bitmap_ptr
+                .filter(|_| data.num_bitmap_bytes > 0)
+                .map(|x| Self::sign_extend(x.into().wrapping_sub(bitmap_delta)));
+            functions.push(FunctionLayout {
+                name_ref: data.name_ref,
+                func_hash: data.func_hash,
+                counter_ptr,
+                num_counters: data.num_counters,
+                counters_offset: Self::sign_extend(counter_ptr.wrapping_sub(counters_delta)),
+                num_bitmap_bytes: data.num_bitmap_bytes,
+                bitmap_offset,
+            });
+            if header.version() >= 8 {
+                counters_delta = counters_delta.wrapping_sub(data_record_size as u64);
+                bitmap_delta = bitmap_delta.wrapping_sub(data_record_size as u64);
+            }
+        }
+        let layout = RawProfileLayout {
+            version: header.version(),
+            counters_delta: header.counters_delta,
+            names_delta: header.names_delta,
+            data_offset,
+            data_record_size,
+            counters_offset,
+            counters_len,
+            bitmap_delta: header.bitmap_delta,
+            bitmap_offset,
+            bitmap_len,
+            names_offset,
+            names_len: header.names_len as usize,
+            continuous_mode: header.is_continuous_mode(),
+            functions,
+        };
+        Ok((bytes, layout))
+    }
+
+    /// Reads the MC/DC bitmap bytes of each function from the bitmap section. Like the counters
+    /// the bitmap pointers are relative to the data record so the delta decreases for each one.
+    fn read_bitmap_bytes<'a>(
+        header: &Header,
+        data_section: &[ProfileData<T>],
+        data_record_size: usize,
+        bitmap: &'a [u8],
+    ) -> ParseResult<'a, FxHashMap<u64, Vec<u8>>> {
+        let mut bitmaps = FxHashMap::default();
+        let mut bitmap_delta = header.bitmap_delta;
+        for data in data_section {
+            if let Some(bitmap_ptr) = data.bitmap_ptr.filter(|_| data.num_bitmap_bytes > 0) {
+                let offset = Self::sign_extend(bitmap_ptr.into().wrapping_sub(bitmap_delta));
+                let len = data.num_bitmap_bytes as usize;
+                if offset < 0 || offset as usize + len > bitmap.len() {
+                    let inner = VerboseError::from_error_kind(bitmap, ErrorKind::Satisfy);
+                    return Err(Err::Failure(VerboseError::add_context(
+                        bitmap,
+                        "MC/DC bitmap outside of the bitmap section",
+                        inner,
+                    )));
+                }
+                let offset = offset as usize;
+                bitmaps.insert(data.name_ref, bitmap[offset..(offset + len)].to_vec());
+            }
+            bitmap_delta = bitmap_delta.wrapping_sub(data_record_size as u64);
+        }
+        Ok((&bitmap[bitmap.len()..], bitmaps))
+    }
+
+    /// Reads the names of version 1 and 2 profiles where the names section is the names
+    /// concatenated without separators and each data record points to its name. The records are
+    /// given the name hash used from version 3 so they can be looked up like newer profiles.
+    fn read_pointer_names<'a>(
+        header: &Header,
+        data_section: &mut [ProfileData<T>],
+        names: &'a [u8],
+    ) -> ParseResult<'a, Symtab> {
+        let mut symtab = Symtab::default();
+        for data in data_section.iter_mut() {
+            let (name_ptr, name_size) = match data.name_ptr {
+                Some(x) => x,
+                None => continue,
+            };
+            let start = name_ptr.into().wrapping_sub(header.names_delta) as usize;
+            let name = start
+                .checked_add(name_size as usize)
+                .and_then(|end| names.get(start..end))
+                .and_then(|x| std::str::from_utf8(x).ok());
+            match name {
+                Some(name) => {
+                    debug!("Symbol name parsed: '{}'", name);
+                    data.name_ref = compute_hash(name);
+                    symtab.add_func_name_with_hash(name.to_string(), data.name_ref);
+                }
+                None => {
+                    let inner = VerboseError::from_error_kind(names, ErrorKind::Satisfy);
+                    return Err(Err::Failure(VerboseError::add_context(
+                        names,
+                        "function name outside of the names section",
+                        inner,
+                    )));
+                }
+            }
+        }
+        Ok((&names[names.len()..], symtab))
+    }
+
     fn read_value_profiling_data<'a>(
         header: &Header,
         data: &ProfileData<T>,
@@ -294,19 +588,38 @@ where
     }
 }
 
-impl<T> InstrProfReader for RawInstrProf<T>
+impl<T> RawInstrProf<T>
 where
     T: MemoryWidthExt,
 {
-    type Header = Header;
-
-    fn parse_bytes(mut input: &[u8]) -> ParseResult<'_, InstrumentationProfile> {
+    /// Parses a raw profile where the counters were relocated by `bias` bytes at runtime without
+    /// the header accounting for it, the bias is subtracted from `counters_delta` before the
+    /// counter pointers are resolved. Normally the header holds everything needed to relocate
+    /// the counters so this is only needed when it doesn't, such as embedded setups where the
+    /// profile is assembled from a counters section dumped from a different address than the one
+    /// the data records point to. A bias of 0 is the same as `parse_bytes`.
+    pub fn parse_bytes_with_bias(
+        mut input: &[u8],
+        bias: u64,
+    ) -> ParseResult<'_, InstrumentationProfile> {
         let initial = input;
+        let offset = |remaining: &[u8]| initial.len() - remaining.len();
         if !input.is_empty() {
+            let _span =
+                debug_span!("raw_profile", bits = size_of::<T>() * 8, len = input.len()).entered();
             let mut result = InstrumentationProfile::default();
-            let (bytes, header) = Self::parse_header(input)?;
+            let (bytes, mut header) = Self::parse_header(input)?;
+            debug!(
+                offset = offset(bytes),
+                version = header.version(),
+                "Parsed header"
+            );
+            header.counters_delta = header.counters_delta.wrapping_sub(bias);
             // LLVM 11 and 12 are version 5. LLVM 13 is version 7
             let version_num = header.version();
+            if header.is_continuous_mode() {
+                debug!("Profile was written in continuous mode");
+            }
             result.version = Some(version_num);
             result.is_ir = header.ir_profile();
             result.has_csir = header.csir_profile();
@@ -315,26 +628,29 @@ where
                 result.fn_entry_only = header.function_entry_only();
                 result.memory_profiling = header.memory_profile();
             }
-            if bytes.len() < header.binary_ids_len as usize {
-                return Err(nom::Err::Failure(VerboseError::from_error_kind(
-                    &bytes[bytes.len()..],
-                    ErrorKind::Eof,
-                )));
-            }
-            input = &bytes[(header.binary_ids_len as usize)..];
-            let mut data_section = vec![];
-            for _ in 0..header.data_len {
-                let (bytes, data) = ProfileData::<T>::parse(input, &header)?;
-                debug!("Parsed data section {:?}", data);
-                data_section.push(data);
-                if version_num > 8 {
-                    let (bytes, v) = take(4usize)(bytes)?; // TODO WHAT AM I MISSING HERE?
-                    debug!("Got those padding? bytes {:?}", v);
-                    input = bytes;
-                } else {
-                    input = bytes;
+            // A corrupt binary IDs length can point past the end of the file
+            let data_start = match bytes.len().checked_sub(header.binary_ids_len as usize) {
+                Some(start) => start,
+                None => {
+                    return Err(Err::Failure(VerboseError::from_error_kind(
+                        &bytes[bytes.len()..],
+                        ErrorKind::Eof,
+                    )))
                 }
-            }
+            };
+            let (bytes, mut data_section) = Self::parse_data_section(bytes, &header)?;
+            debug!(
+                offset = offset(bytes),
+                records = data_section.len(),
+                "Parsed data section"
+            );
+            // The size of a record is whatever the data section parsing consumed for each one
+            let data_record_size = if data_section.is_empty() {
+                0
+            } else {
+                (data_start - bytes.len()) / data_section.len()
+            };
+            input = bytes;
             let bytes = match take(header.padding_bytes_before_counters as usize)(input) {
                 Ok((b, _)) => b,
                 Err(e) => {
@@ -345,72 +661,110 @@ where
             input = bytes;
             let mut counters = vec![];
             let mut counters_delta = header.counters_delta;
-
-            // Okay so the counters section looks a bit hairy. So as a brief explanation.
-            // 1. The base offset is from CountersStart pointer to entry of the record. Meaning
-            //    doing a nom type parsing we need to keep track of the total offset as counter
-            //    records can be offset in the middle of the counter list.
-            // 2. Also there may be some padding bytes before the last counter and end of counters
-            //    section. This needs to be applied as well as padding_bytes_after_counters for
-            //    total padding
-            let mut total_offset = 0;
-            let remaining_before_counters = input.len();
+            let counter_size = header.counter_size() as i64;
+
+            // Where each function's counters are depends on the version:
+            // 1. Before version 8 the counters are read in data section order, which is how the
+            //    runtime lays them out. `CounterPtr` is an absolute address until LLVM 13 which
+            //    made it relative without changing the version so version 7 can be either.
+            // 2. From version 8 `CounterPtr` is relative to its data record so it's relocated
+            //    with `counters_delta`, which moves back by a data record for each record, see
+            //    `RawProfileLayout`. This doesn't assume the counters are in data section order,
+            //    which they aren't once the linker reorders them.
+            let counters_start = input;
+            let mut sequential_offset = 0;
             for data in &data_section {
-                let counters_offset = if header.version() == 8 {
-                    // Why oh why!?
-                    (data.counter_ptr.into() as i64 - counters_delta as i64) - total_offset
+                let counters_offset = if header.version() >= 8 {
+                    Self::sign_extend(data.counter_ptr.into().wrapping_sub(counters_delta))
                 } else {
-                    0
+                    sequential_offset
                 };
-                let (bytes, record) =
-                    Self::read_raw_counts(&header, data, counters_offset, input, initial)?;
+                let (_, record) =
+                    Self::read_raw_counts(&header, data, counters_offset, counters_start, initial)?;
                 debug!("Read counter record {:?}", record);
-                total_offset +=
-                    counters_offset + (record.counts.len() * header.counter_size()) as i64;
-                counters_delta -= data.len() as u64;
+                sequential_offset = counters_offset + record.counts.len() as i64 * counter_size;
+                counters_delta = counters_delta.wrapping_sub(data_record_size as u64);
                 counters.push(record);
-                input = bytes;
             }
-            let counters_end = header.padding_bytes_after_counters as usize
-                + (header.counters_len as usize * header.counter_size())
-                - (remaining_before_counters - input.len());
             debug!("Applying padding bytes after counters");
-            let (bytes, _) = take(counters_end)(input)?;
+            let (bytes, _) = take(header.max_counters_len() as usize)(counters_start)?;
+            debug!(
+                offset = offset(bytes),
+                counters = header.counters_len,
+                "Read counters section"
+            );
+            let (_, bitmap) = take(header.num_bitmap_bytes as usize)(bytes)?;
+            let (_, bitmaps) =
+                Self::read_bitmap_bytes(&header, &data_section, data_record_size, bitmap)?;
+            result.mcdc_bitmaps = bitmaps;
+            // In continuous mode the bitmap is page aligned as well so the padding can be larger
+            // than the bitmap itself
+            let (bytes, _) = take(
+                header.num_bitmap_bytes as usize + header.padding_bytes_after_bitmap_bytes as usize,
+            )(bytes)?;
             input = bytes;
-            let end_length = input.len() - header.names_len as usize;
-            let mut symtab = Symtab::default();
-            while input.len() > end_length {
-                let (new_bytes, names) = parse_string_ref(input)?;
-                debug!(
-                    "Complete names string: '{}'. Read {} bytes",
-                    names,
-                    input.len() - new_bytes.len()
-                );
-                input = new_bytes;
-                for name in names.split(INSTR_PROF_NAME_SEP) {
-                    debug!("Symbol name parsed: '{}'", name);
-                    symtab.add_func_name(name.to_string(), Some(header.endianness));
+            let symtab = if header.version() < 3 {
+                let (bytes, names) = take(header.names_len as usize)(input)?;
+                input = bytes;
+                let (_, symtab) = Self::read_pointer_names(&header, &mut data_section, names)?;
+                symtab
+            } else {
+                // Fails if the profile ends before the names section does
+                take(header.names_len as usize)(input)?;
+                let end_length = input.len() - header.names_len as usize;
+                let mut symtab = Symtab::default();
+                while input.len() > end_length {
+                    let (new_bytes, names) = parse_string_ref(input)?;
+                    debug!(
+                        "Complete names string: '{}'. Read {} bytes",
+                        names,
+                        input.len() - new_bytes.len()
+                    );
+                    input = new_bytes;
+                    // A zeroed names section parses as empty strings which aren't real names
+                    for name in names.split(INSTR_PROF_NAME_SEP).filter(|x| !x.is_empty()) {
+                        debug!("Symbol name parsed: '{}'", name);
+                        symtab.add_func_name(name.to_string(), Some(header.endianness));
+                    }
                 }
-            }
+                symtab
+            };
+            debug!(
+                offset = offset(input),
+                names = symtab.names.len(),
+                "Read names section"
+            );
             let padding = get_num_padding_bytes(header.names_len);
             let (bytes, _) = take(padding)(input)?;
+            // Version 2 keeps the value data in its own section, it predates the per function value
+            // data read below and isn't supported. Fail rather than silently drop the values
+            if header.value_data_len > 0 {
+                let inner = VerboseError::from_error_kind(bytes, ErrorKind::Verify);
+                return Err(Err::Failure(VerboseError::add_context(
+                    bytes,
+                    "version 2 value profiling data isn't supported",
+                    inner,
+                )));
+            }
             input = bytes;
 
             for (data, mut record) in data_section.iter().zip(counters.drain(..)) {
-                let (bytes, _) =
-                    Self::read_value_profiling_data(&header, data, input, &mut record)?;
-                input = bytes;
+                if header.version() >= 3 {
+                    let (bytes, _) =
+                        Self::read_value_profiling_data(&header, data, input, &mut record)?;
+                    input = bytes;
+                }
+                // Previously this function calculated the function hash itself to be
+                // ultra-defensive against the profraw format changing hash calculation method
+                // so we try not to rely on reimplementing it. However, md5::compute was more
+                // expensive than initially assumed and using the precomputed one reduces
+                // runtime by 25% on benchmarks.
+                //
+                // If the name is missing, e.g. the names section was stripped to be recovered
+                // from debug info later, the record is still kept by its hashes so the counters
+                // aren't lost
                 let name = symtab.names.get(&data.name_ref).cloned();
-                let (hash, name_hash) = if name.is_some() {
-                    // Previously this function calculated the function hash itself to be
-                    // ultra-defensive against the profraw format changing hash calculation method
-                    // so we try not to rely on reimplementing it. However, md5::compute was more
-                    // expensive than initially assumed and using the precomputed one reduces
-                    // runtime by 25% on benchmarks
-                    (Some(data.func_hash), Some(data.name_ref))
-                } else {
-                    (None, None)
-                };
+                let (hash, name_hash) = (Some(data.func_hash), Some(data.name_ref));
                 debug!("Parsed record: {:?} {:?} {:?}", name, hash, record);
 
                 result.push_record(NamedInstrProfRecord {
@@ -420,6 +774,11 @@ where
                     record,
                 });
             }
+            debug!(
+                offset = offset(input),
+                records = result.records().len(),
+                "Read value profiling data"
+            );
             result.symtab = symtab;
             Ok((input, result))
         } else {
@@ -427,6 +786,17 @@ where
             todo!()
         }
     }
+}
+
+impl<T> InstrProfReader for RawInstrProf<T>
+where
+    T: MemoryWidthExt,
+{
+    type Header = Header;
+
+    fn parse_bytes(input: &[u8]) -> ParseResult<'_, InstrumentationProfile> {
+        Self::parse_bytes_with_bias(input, 0)
+    }
 
     fn parse_header(input: &[u8]) -> ParseResult<'_, Self::Header> {
         if Self::has_format(input) {
@@ -439,9 +809,18 @@ where
                 (bytes, 0)
             };
             let (bytes, data_len) = nom_u64(endianness)(bytes)?;
-            let (bytes, padding_bytes_before_counters) = nom_u64(endianness)(bytes)?;
+            // The padding around the counters was added with continuous mode in version 5
+            let (bytes, padding_bytes_before_counters) = if (version & !VARIANT_MASKS_ALL) >= 5 {
+                nom_u64(endianness)(bytes)?
+            } else {
+                (bytes, 0)
+            };
             let (bytes, counters_len) = nom_u64(endianness)(bytes)?;
-            let (bytes, padding_bytes_after_counters) = nom_u64(endianness)(bytes)?;
+            let (bytes, padding_bytes_after_counters) = if (version & !VARIANT_MASKS_ALL) >= 5 {
+                nom_u64(endianness)(bytes)?
+            } else {
+                (bytes, 0)
+            };
 
             let (bytes, num_bitmap_bytes, padding_bytes_after_bitmap_bytes) =
                 if (version & !VARIANT_MASKS_ALL) >= 9 {
@@ -472,7 +851,20 @@ where
                 (bytes, 0, 0)
             };
 
-            let (bytes, value_kind_last) = nom_u64(endianness)(bytes)?;
+            // Version 1 has no value profiling
+            let (bytes, value_kind_last) = if (version & !VARIANT_MASKS_ALL) >= 2 {
+                nom_u64(endianness)(bytes)?
+            } else {
+                (bytes, 0)
+            };
+
+            let (bytes, value_data_len, value_data_delta) = if (version & !VARIANT_MASKS_ALL) == 2 {
+                let (bytes, value_data_len) = nom_u64(endianness)(bytes)?;
+                let (bytes, value_data_delta) = nom_u64(endianness)(bytes)?;
+                (bytes, value_data_len, value_data_delta)
+            } else {
+                (bytes, 0, 0)
+            };
 
             let result = Header {
                 endianness,
@@ -491,6 +883,8 @@ where
                 bitmap_delta,
                 num_vtables,
                 vnames_size,
+                value_data_len,
+                value_data_delta,
             };
             debug!("Read header {:?}", result);
             Ok((bytes, result))
@@ -525,6 +919,10 @@ where
         let endianness = header.endianness;
         let parse = T::nom_parse_fn(endianness);
 
+        if header.version() < 3 {
+            return Self::parse_with_name_ptr(bytes, header);
+        }
+
         let (bytes, name_ref) = nom_u64(endianness)(bytes)?;
         let (bytes, func_hash) = nom_u64(endianness)(bytes)?;
         let (bytes, counter_ptr) = parse(bytes)?;
@@ -539,6 +937,14 @@ where
         let (bytes, num_counters) = nom_u32(endianness)(bytes)?;
         let (bytes, value_0) = nom_u16(endianness)(bytes)?;
         let (bytes, value_1) = nom_u16(endianness)(bytes)?;
+        let (bytes, value_2) = if header.version() >= 10 {
+            // Three u16s so there's padding to align the bitmap bytes count
+            let (bytes, value_2) = nom_u16(endianness)(bytes)?;
+            let (bytes, _) = take(2usize)(bytes)?;
+            (bytes, value_2)
+        } else {
+            (bytes, 0)
+        };
         let (bytes, num_bitmap_bytes) = if header.version() > 8 {
             nom_u32(endianness)(bytes)?
         } else {
@@ -549,15 +955,57 @@ where
             bytes,
             Self {
                 name_ref,
+                name_ptr: None,
                 func_hash,
                 counter_ptr,
                 bitmap_ptr,
                 function_addr,
                 values_ptr_expr,
                 num_counters,
-                num_value_sites: [value_0, value_1],
+                num_value_sites: [value_0, value_1, value_2],
                 num_bitmap_bytes,
             },
         ))
     }
+
+    /// Parses the data records of versions 1 and 2 which start with the name size and pointer
+    /// instead of the name hash. Version 1 has no value profiling so ends after the counters
+    /// pointer, version 2 only profiles indirect call targets.
+    fn parse_with_name_ptr<'a>(
+        bytes: &'a [u8],
+        header: &Header,
+    ) -> IResult<&'a [u8], Self, VerboseError<&'a [u8]>> {
+        let endianness = header.endianness;
+        let parse = T::nom_parse_fn(endianness);
+
+        let (bytes, name_size) = nom_u32(endianness)(bytes)?;
+        let (bytes, num_counters) = nom_u32(endianness)(bytes)?;
+        let (bytes, func_hash) = nom_u64(endianness)(bytes)?;
+        let (bytes, name_ptr) = parse(bytes)?;
+        let (bytes, counter_ptr) = parse(bytes)?;
+        let (bytes, function_addr, values_ptr_expr, value_0) = if header.version() == 2 {
+            let (bytes, function_addr) = parse(bytes)?;
+            let (bytes, values_ptr_expr) = parse(bytes)?;
+            let (bytes, value_0) = nom_u16(endianness)(bytes)?;
+            (bytes, function_addr, values_ptr_expr, value_0)
+        } else {
+            (bytes, T::default(), T::default(), 0)
+        };
+
+        Ok((
+            bytes,
+            Self {
+                name_ref: 0,
+                name_ptr: Some((name_ptr, name_size)),
+                func_hash,
+                counter_ptr,
+                bitmap_ptr: None,
+                function_addr,
+                values_ptr_expr,
+                num_counters,
+                num_value_sites: [value_0, 0, 0],
+                num_bitmap_bytes: 0,
+            },
+        ))
+    }
 }
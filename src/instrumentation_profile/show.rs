@@ -0,0 +1,694 @@
+//! The human readable listing of `profparser show`, which matches the output of
+//! `llvm-profdata show`. `InstrumentationProfile::display` formats it with options and the
+//! `Display` impl of the profile gives the output without any flags.
+use crate::instrumentation_profile::summary::ProfileSummary;
+use crate::instrumentation_profile::types::*;
+use crate::summary::DEFAULT_CUTOFFS;
+use crate::util::json_string;
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+use std::fmt;
+use std::io::{self, Write};
+
+/// The flags of `show` which change the listing. New flags can be added so outside of this crate
+/// it's built from `ShowOptions::default()` with the setters, e.g.
+/// `ShowOptions::default().all_functions(true).show_counts(true)`
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub struct ShowOptions {
+    /// List every function, sorted by name so the output doesn't depend on the input format
+    pub all_functions: bool,
+    /// List the functions whose names contain any of these patterns
+    pub functions: Vec<String>,
+    /// Show the block counts of the listed functions
+    pub show_counts: bool,
+    /// Show the context sensitive records of IR profiles instead of the others
+    pub show_cs: bool,
+    /// Show this many functions with the largest counts
+    pub topn: Option<usize>,
+    /// Count the functions with a maximum count below this
+    pub value_cutoff: u64,
+    /// Only list the functions with a maximum count below `value_cutoff`
+    pub only_list_below: bool,
+    /// Show the detailed summary
+    pub detailed_summary: bool,
+    /// Cutoffs for the detailed summary, `DEFAULT_CUTOFFS` if empty
+    pub detailed_summary_cutoffs: Vec<u64>,
+    /// Show the indirect call targets of the listed functions and statistics of the call sites
+    pub ic_targets: bool,
+    /// Show the memory intrinsic sizes of the listed functions and statistics of the call sites
+    pub memop_sizes: bool,
+    /// Only list the names of the functions which ran, see
+    /// `InstrumentationProfile::coverage_status`
+    pub covered: bool,
+}
+
+impl ShowOptions {
+    /// Sets `all_functions`
+    pub fn all_functions(mut self, all_functions: bool) -> Self {
+        self.all_functions = all_functions;
+        self
+    }
+
+    /// Sets `functions`
+    pub fn functions(mut self, functions: Vec<String>) -> Self {
+        self.functions = functions;
+        self
+    }
+
+    /// Sets `show_counts`
+    pub fn show_counts(mut self, show_counts: bool) -> Self {
+        self.show_counts = show_counts;
+        self
+    }
+
+    /// Sets `show_cs`
+    pub fn show_cs(mut self, show_cs: bool) -> Self {
+        self.show_cs = show_cs;
+        self
+    }
+
+    /// Sets `topn`
+    pub fn topn(mut self, topn: Option<usize>) -> Self {
+        self.topn = topn;
+        self
+    }
+
+    /// Sets `value_cutoff`
+    pub fn value_cutoff(mut self, value_cutoff: u64) -> Self {
+        self.value_cutoff = value_cutoff;
+        self
+    }
+
+    /// Sets `only_list_below`
+    pub fn only_list_below(mut self, only_list_below: bool) -> Self {
+        self.only_list_below = only_list_below;
+        self
+    }
+
+    /// Sets `detailed_summary`
+    pub fn detailed_summary(mut self, detailed_summary: bool) -> Self {
+        self.detailed_summary = detailed_summary;
+        self
+    }
+
+    /// Sets `detailed_summary_cutoffs`
+    pub fn detailed_summary_cutoffs(mut self, detailed_summary_cutoffs: Vec<u64>) -> Self {
+        self.detailed_summary_cutoffs = detailed_summary_cutoffs;
+        self
+    }
+
+    /// Sets `ic_targets`
+    pub fn ic_targets(mut self, ic_targets: bool) -> Self {
+        self.ic_targets = ic_targets;
+        self
+    }
+
+    /// Sets `memop_sizes`
+    pub fn memop_sizes(mut self, memop_sizes: bool) -> Self {
+        self.memop_sizes = memop_sizes;
+        self
+    }
+
+    /// Sets `covered`
+    pub fn covered(mut self, covered: bool) -> Self {
+        self.covered = covered;
+        self
+    }
+
+    /// Whether the record is listed with these options. Records without a name or function hash
+    /// are never listed
+    pub fn is_selected(
+        &self,
+        profile: &InstrumentationProfile,
+        record: &NamedInstrProfRecord,
+    ) -> bool {
+        self.is_shown_kind(profile, record)
+            && (self.all_functions || self.matches_function(record.name.as_deref()))
+    }
+
+    fn is_shown_kind(
+        &self,
+        profile: &InstrumentationProfile,
+        record: &NamedInstrProfRecord,
+    ) -> bool {
+        record.name.is_some()
+            && record.hash.is_some()
+            && !(profile.is_ir_level_profile() && record.has_cs_flag() != self.show_cs)
+    }
+
+    fn matches_function(&self, name: Option<&str>) -> bool {
+        match name {
+            Some(name) => self.functions.iter().any(|pat| name.contains(pat.as_str())),
+            None => false,
+        }
+    }
+}
+
+/// Formats a profile like `show`, see `InstrumentationProfile::display`
+#[derive(Clone, Debug)]
+pub struct ShowProfile<'a> {
+    profile: &'a InstrumentationProfile,
+    options: ShowOptions,
+}
+
+#[derive(Clone, Debug, Eq)]
+struct HotFn {
+    name: String,
+    count: u64,
+}
+
+impl PartialOrd for HotFn {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for HotFn {
+    fn cmp(&self, other: &Self) -> Ordering {
+        // Do the reverse here
+        other.count.cmp(&self.count)
+    }
+}
+
+impl PartialEq for HotFn {
+    fn eq(&self, other: &Self) -> bool {
+        self.count == other.count
+    }
+}
+
+/// Counts of the value sites of one kind in the listed functions, like `ValueSitesStats` in
+/// llvm-profdata
+#[derive(Clone, Debug, Default)]
+struct ValueSitesStats {
+    total_sites: usize,
+    sites_with_values: usize,
+    total_values: usize,
+    /// Number of sites with each number of values, the first entry is for sites with one value
+    histogram: Vec<usize>,
+}
+
+impl ValueSitesStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "  Total number of sites: {}", self.total_sites)?;
+        writeln!(
+            f,
+            "  Total number of sites with values: {}",
+            self.sites_with_values
+        )?;
+        writeln!(
+            f,
+            "  Total number of profiled values: {}",
+            self.total_values
+        )?;
+        writeln!(f, "  Value sites histogram:\n\tNumTargets, SiteCount")?;
+        for (i, count) in self.histogram.iter().enumerate() {
+            if *count > 0 {
+                writeln!(f, "\t{}, {}", i + 1, count)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Writes each value of the sites as `[ site, value, count ] (percentage of the site)` and adds
+/// the sites to the statistics
+fn fmt_value_sites(
+    f: &mut fmt::Formatter<'_>,
+    profile: &InstrumentationProfile,
+    record: &InstrProfRecord,
+    kind: ValueKind,
+    stats: &mut ValueSitesStats,
+) -> fmt::Result {
+    let sites = record.value_sites(kind);
+    stats.total_sites += sites.len();
+    for (i, site) in sites.iter().enumerate() {
+        stats.total_values += site.len();
+        if !site.is_empty() {
+            stats.sites_with_values += 1;
+            if site.len() > stats.histogram.len() {
+                stats.histogram.resize(site.len(), 0);
+            }
+            stats.histogram[site.len() - 1] += 1;
+        }
+        let site_sum = site
+            .iter()
+            .fold(0u64, |acc, x| acc.saturating_add(x.count))
+            .max(1);
+        for value in site {
+            write!(f, "\t[ {:2}, ", i)?;
+            match kind {
+                ValueKind::MemOpSize => write!(f, "{:4}", value.value)?,
+                _ => write!(f, "{}", profile.value_target(kind, value.value))?,
+            }
+            writeln!(
+                f,
+                ", {:10} ] ({:.2}%)",
+                value.count,
+                value.count as f64 * 100.0 / site_sum as f64
+            )?;
+        }
+    }
+    Ok(())
+}
+
+impl fmt::Display for ShowProfile<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let profile = self.profile;
+        let options = &self.options;
+        let mut summary = ProfileSummary::new();
+
+        let is_ir_instr = profile.is_ir_level_profile();
+        let topn = options.topn.unwrap_or_default();
+        let mut hotties = BinaryHeap::<HotFn>::with_capacity(topn);
+        let mut shown_funcs = 0;
+        let mut below_cutoff_funcs = 0;
+        let mut ic_stats = ValueSitesStats::default();
+        let mut memop_stats = ValueSitesStats::default();
+        // Sort when showing everything so the output doesn't depend on the input format
+        let records = if options.all_functions {
+            profile.records_sorted()
+        } else {
+            profile.records().iter().collect()
+        };
+        for func in records {
+            if !options.is_shown_kind(profile, func) {
+                continue;
+            }
+            let name = func.name.as_deref().unwrap_or_default();
+            let show = options.all_functions || options.matches_function(Some(name));
+            summary.add_record(&func.record);
+            if options.covered {
+                if profile.coverage_status(&func.record) == CoverageStatus::Covered {
+                    writeln!(f, "{}", name)?;
+                }
+                continue;
+            }
+
+            let (func_max, func_sum) = func.counts().iter().fold((0, 0u64), |acc, x| {
+                (*x.max(&acc.0), acc.1.saturating_add(*x))
+            });
+            if func_max < options.value_cutoff {
+                below_cutoff_funcs += 1;
+                if options.only_list_below {
+                    writeln!(f, "  {}: (Max = {} Sum = {})", name, func_max, func_sum)?;
+                    continue;
+                }
+            } else if options.only_list_below {
+                continue;
+            }
+            if topn > 0 {
+                if hotties.len() == topn {
+                    let top = hotties.peek().unwrap();
+                    if top.count < func_max {
+                        hotties.pop();
+                        hotties.push(HotFn {
+                            name: name.to_string(),
+                            count: func_max,
+                        });
+                    }
+                } else {
+                    hotties.push(HotFn {
+                        name: name.to_string(),
+                        count: func_max,
+                    });
+                }
+            }
+            if show {
+                if shown_funcs == 0 {
+                    writeln!(f, "Counters:")?;
+                }
+                shown_funcs += 1;
+                writeln!(f, "  {}:", name)?;
+                writeln!(f, "    Hash: {:#018x}", func.hash_unchecked())?;
+                writeln!(f, "    Counters: {}", func.counts().len())?;
+                if !is_ir_instr {
+                    let counts = func.counts().first().copied().unwrap_or_default();
+                    writeln!(f, "    Function count: {}", counts)?;
+                }
+                if options.ic_targets {
+                    writeln!(
+                        f,
+                        "    Indirect Call Site Count: {}",
+                        func.record.value_site_count(ValueKind::IndirectCallTarget)
+                    )?;
+                }
+                let num_memop_calls = func.record.value_site_count(ValueKind::MemOpSize);
+                if options.memop_sizes && num_memop_calls > 0 {
+                    writeln!(
+                        f,
+                        "    Number of Memory Intrinsics Calls: {}",
+                        num_memop_calls
+                    )?;
+                }
+                if options.show_counts {
+                    let start = if is_ir_instr { 0 } else { 1 };
+                    let counts = func
+                        .counts()
+                        .iter()
+                        .skip(start)
+                        .map(|x| x.to_string())
+                        .collect::<Vec<String>>()
+                        .join(", ");
+                    writeln!(f, "    Block counts: [{}]", counts)?;
+                }
+                if options.ic_targets {
+                    writeln!(f, "    Indirect Target Results:")?;
+                    fmt_value_sites(
+                        f,
+                        profile,
+                        &func.record,
+                        ValueKind::IndirectCallTarget,
+                        &mut ic_stats,
+                    )?;
+                }
+                if options.memop_sizes && num_memop_calls > 0 {
+                    writeln!(f, "    Memory Intrinsic Size Results:")?;
+                    fmt_value_sites(
+                        f,
+                        profile,
+                        &func.record,
+                        ValueKind::MemOpSize,
+                        &mut memop_stats,
+                    )?;
+                }
+            }
+        }
+        if options.covered {
+            return Ok(());
+        }
+        if profile.get_level() == InstrumentationLevel::Ir {
+            writeln!(
+                f,
+                "Instrumentation level: {}  entry_first = {}",
+                profile.get_level(),
+                // NOTE: in llvm 11 this is always false
+                profile.is_entry_first() as usize
+            )?;
+        } else {
+            writeln!(f, "Instrumentation level: {}", profile.get_level())?;
+        }
+        if options.all_functions || !options.functions.is_empty() {
+            writeln!(f, "Functions shown: {}", shown_funcs)?;
+        }
+        writeln!(f, "Total functions: {}", summary.num_functions())?;
+        if options.value_cutoff > 0 {
+            writeln!(
+                f,
+                "Number of functions with maximum count (< {} ): {}",
+                options.value_cutoff, below_cutoff_funcs
+            )?;
+            writeln!(
+                f,
+                "Number of functions with maximum count (>= {}): {}",
+                options.value_cutoff,
+                summary.num_functions() - below_cutoff_funcs
+            )?;
+        }
+        writeln!(
+            f,
+            "Maximum function count: {}",
+            summary.max_function_count()
+        )?;
+        writeln!(
+            f,
+            "Maximum internal block count: {}",
+            summary.max_internal_block_count()
+        )?;
+        if let Some(topn) = options.topn {
+            writeln!(
+                f,
+                "Top {} functions with the largest internal block counts: ",
+                topn
+            )?;
+            for hot in hotties.into_sorted_vec() {
+                writeln!(f, "  {}, max count = {}", hot.name, hot.count)?;
+            }
+        }
+        if shown_funcs > 0 && options.ic_targets {
+            writeln!(f, "Statistics for indirect call sites profile:")?;
+            ic_stats.fmt(f)?;
+        }
+        if shown_funcs > 0 && options.memop_sizes {
+            writeln!(f, "Statistics for memory intrinsic calls sizes profile:")?;
+            memop_stats.fmt(f)?;
+        }
+
+        if options.detailed_summary {
+            writeln!(f, "Total number of blocks: {}", summary.num_counts())?;
+            writeln!(f, "Total count: {}", summary.total_count())?;
+            let cutoffs = if options.detailed_summary_cutoffs.is_empty() {
+                &DEFAULT_CUTOFFS[..]
+            } else {
+                &options.detailed_summary_cutoffs[..]
+            };
+            writeln!(f, "Detailed summary:")?;
+            for entry in summary.detailed_summary(cutoffs) {
+                writeln!(
+                    f,
+                    "{} blocks with count >= {} account for {} percentage of the total counts.",
+                    entry.num_counts,
+                    entry.min_count,
+                    entry.cutoff as f64 / 10_000.0
+                )?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl ShowProfile<'_> {
+    /// The records listed by `fmt`, sorted by name when showing every function
+    fn listed_records(&self) -> Vec<&NamedInstrProfRecord> {
+        let records = if self.options.all_functions {
+            self.profile.records_sorted()
+        } else {
+            self.profile.records().iter().collect()
+        };
+        records
+            .into_iter()
+            .filter(|x| self.options.is_selected(self.profile, x))
+            .collect()
+    }
+
+    /// Writes the listed functions and the summary as JSON, the output of
+    /// `profparser show --json`. The functions are the ones `fmt` lists, so none unless
+    /// `all_functions` is set or `functions` has patterns. The output is a single object:
+    ///
+    /// ```text
+    /// {
+    ///   "version": 8,
+    ///   "level": "Front-end",
+    ///   "functions": [
+    ///     {
+    ///       "name": "main",
+    ///       "hash": 24,
+    ///       "counters": [1, 5],
+    ///       "value_sites": {
+    ///         "indirect_call_targets": [[{"value": 9712, "count": 5, "target": "foo"}]],
+    ///         "memop_sizes": [],
+    ///         "vtable_targets": []
+    ///       }
+    ///     }
+    ///   ],
+    ///   "summary": {
+    ///     "total_functions": 1,
+    ///     "max_function_count": 1,
+    ///     "max_internal_block_count": 5,
+    ///     "total_blocks": 2,
+    ///     "total_count": 6
+    ///   }
+    /// }
+    /// ```
+    ///
+    /// * `version` is the profile version, `null` if the format doesn't have one.
+    /// * `level` is the instrumentation level as printed by `show --profile-version`.
+    /// * `hash` is the function hash and it, the counters and the values are unsigned 64 bit
+    ///   integers so may need parsing as such.
+    /// * `value_sites` has every value kind, each site is a list of its values. Indirect call and
+    ///   vtable targets have a `target` with the name of the function or vtable, `null` if it
+    ///   isn't in the symbol table.
+    /// * `summary` covers the same records as the text summary and uses the field names of
+    ///   `profparser summary --json`.
+    pub fn write_json(&self, writer: &mut impl Write) -> io::Result<()> {
+        let profile = self.profile;
+        let mut summary = ProfileSummary::new();
+        for record in profile
+            .records()
+            .iter()
+            .filter(|x| self.options.is_shown_kind(profile, x))
+        {
+            summary.add_record(&record.record);
+        }
+        let version = match profile.version() {
+            Some(version) => version.to_string(),
+            None => "null".to_string(),
+        };
+        write!(
+            writer,
+            "{{\"version\":{},\"level\":{},\"functions\":[",
+            version,
+            json_string(&profile.profile_level().to_string())
+        )?;
+        for (i, record) in self.listed_records().into_iter().enumerate() {
+            if i > 0 {
+                write!(writer, ",")?;
+            }
+            let counters = record
+                .counts()
+                .iter()
+                .map(|x| x.to_string())
+                .collect::<Vec<_>>()
+                .join(",");
+            write!(
+                writer,
+                "{{\"name\":{},\"hash\":{},\"counters\":[{}],\"value_sites\":{{",
+                json_string(record.name.as_deref().unwrap_or_default()),
+                record.hash_unchecked(),
+                counters
+            )?;
+            for (j, kind) in ValueKind::ALL.iter().enumerate() {
+                let key = match kind {
+                    ValueKind::IndirectCallTarget => "indirect_call_targets",
+                    ValueKind::MemOpSize => "memop_sizes",
+                    ValueKind::VTableTarget => "vtable_targets",
+                };
+                let sites = record
+                    .record
+                    .value_sites(*kind)
+                    .iter()
+                    .map(|site| {
+                        let values = site
+                            .iter()
+                            .map(|x| json_value(profile, *kind, x))
+                            .collect::<Vec<_>>();
+                        format!("[{}]", values.join(","))
+                    })
+                    .collect::<Vec<_>>();
+                if j > 0 {
+                    write!(writer, ",")?;
+                }
+                write!(writer, "\"{}\":[{}]", key, sites.join(","))?;
+            }
+            write!(writer, "}}}}")?;
+        }
+        writeln!(
+            writer,
+            "],\"summary\":{{\"total_functions\":{},\"max_function_count\":{},\"max_internal_block_count\":{},\"total_blocks\":{},\"total_count\":{}}}}}",
+            summary.num_functions(),
+            summary.max_function_count(),
+            summary.max_internal_block_count(),
+            summary.num_counts(),
+            summary.total_count()
+        )
+    }
+}
+
+/// A value profiling value as a JSON object, see `ShowProfile::write_json`
+fn json_value(
+    profile: &InstrumentationProfile,
+    kind: ValueKind,
+    value: &InstrProfValueData,
+) -> String {
+    let target = match profile.value_target(kind, value.value) {
+        ValueTarget::Name(name) => format!(",\"target\":{}", json_string(&name)),
+        ValueTarget::External(_) => ",\"target\":null".to_string(),
+        ValueTarget::Value(_) => String::new(),
+    };
+    format!(
+        "{{\"value\":{},\"count\":{}{}}}",
+        value.value, value.count, target
+    )
+}
+
+impl InstrumentationProfile {
+    /// Formats the profile like `profparser show` with the given options
+    pub fn display(&self, options: ShowOptions) -> ShowProfile<'_> {
+        ShowProfile {
+            profile: self,
+            options,
+        }
+    }
+}
+
+/// The output of `profparser show` without any flags, the instrumentation level and summary
+/// counts. Use `display` to list functions and their counts.
+impl fmt::Display for InstrumentationProfile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.display(ShowOptions::default()).fmt(f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::instrumentation_profile::builder::ProfileBuilder;
+
+    fn profile() -> InstrumentationProfile {
+        let mut builder = ProfileBuilder::new();
+        builder
+            .version(8)
+            .add_function("main", 24, vec![1, 5])
+            .add_function("foo", 48, vec![5])
+            .add_value_site(
+                "main",
+                ValueKind::IndirectCallTarget,
+                vec![InstrProfValueData {
+                    value: compute_hash("foo"),
+                    count: 5,
+                }],
+            )
+            .add_value_site(
+                "main",
+                ValueKind::MemOpSize,
+                vec![InstrProfValueData { value: 8, count: 1 }],
+            );
+        builder.build()
+    }
+
+    #[test]
+    fn display_without_flags() {
+        let profile = profile();
+        assert_eq!(
+            profile.to_string(),
+            profile.display(ShowOptions::default()).to_string()
+        );
+        assert_eq!(
+            profile.to_string(),
+            "Instrumentation level: Front-end\n\
+             Total functions: 2\n\
+             Maximum function count: 5\n\
+             Maximum internal block count: 5\n"
+        );
+    }
+
+    #[test]
+    fn json_value_sites() {
+        let options = ShowOptions {
+            functions: vec!["main".to_string()],
+            ..Default::default()
+        };
+        let mut json = vec![];
+        profile().display(options).write_json(&mut json).unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&json).unwrap();
+        assert_eq!(json["version"], 8);
+        assert_eq!(json["functions"].as_array().unwrap().len(), 1);
+
+        let main = &json["functions"][0];
+        assert_eq!(main["name"], "main");
+        assert_eq!(main["hash"], 24);
+        assert_eq!(main["counters"], serde_json::json!([1, 5]));
+        // Call targets are resolved through the symbol table, memop sizes have no target
+        let site = &main["value_sites"]["indirect_call_targets"][0];
+        assert_eq!(site[0]["target"], "foo");
+        assert_eq!(site[0]["count"], 5);
+        assert!(main["value_sites"]["memop_sizes"][0][0]
+            .get("target")
+            .is_none());
+        assert_eq!(main["value_sites"]["vtable_targets"], serde_json::json!([]));
+
+        // The summary covers every function, not only the listed ones
+        assert_eq!(json["summary"]["total_functions"], 2);
+        assert_eq!(json["summary"]["total_count"], 11);
+    }
+}
@@ -1,5 +1,11 @@
 use crate::instrumentation_profile::types::*;
+use crate::summary::{self, HotnessThresholds, Kind, ProfileSummaryEntry, DEFAULT_CUTOFFS};
 use std::collections::BTreeMap;
+use std::sync::OnceLock;
+
+/// Scale the detailed summary cutoffs are expressed in, a cutoff of 1_000_000 is 100% of the
+/// total count
+const CUTOFF_DENOMINATOR: u128 = 1_000_000;
 
 #[derive(Clone, Debug, Default)]
 pub struct ProfileSummary {
@@ -16,14 +22,23 @@ impl ProfileSummary {
         Self::default()
     }
 
+    /// Adds the counters of a record. Records with a pseudo count, where the entry count is
+    /// `u64::MAX` (hot) or `u64::MAX - 1` (warm), only mark the function's temperature and are
+    /// skipped like in LLVM.
     pub fn add_record(&mut self, record: &InstrProfRecord) {
-        if !record.counts.is_empty() {
+        if record.pseudo_count().is_some() {
+            return;
+        }
+        if let Some(entry_count) = record.entry_count() {
             self.num_functions += 1;
-            self.add_count(record.counts[0]);
-            if record.counts[0] > self.max_function_count {
-                self.max_function_count = record.counts[0];
+            self.add_count(entry_count);
+            self.max_function_count = self.max_function_count.max(entry_count);
+            for count in &record.counts[1..] {
+                self.add_count(*count);
+            }
+            if let Some(max) = record.max_internal_block_count() {
+                self.max_internal_block_count = self.max_internal_block_count.max(max);
             }
-            self.add_internal_counts(&record.counts[1..]);
         }
     }
 
@@ -38,24 +53,305 @@ impl ProfileSummary {
             .or_insert(1);
     }
 
-    fn add_internal_counts(&mut self, counts: &[u64]) {
-        for count in counts {
-            self.add_count(*count);
-            if *count > self.max_internal_block_count {
-                self.max_internal_block_count = *count;
+    /// Histogram of all the counters added. Buckets are powers of two with the lower bound of each
+    /// bucket returned alongside the number of counters in it, counters of zero get their own
+    /// bucket. Only non-empty buckets are returned and they're in ascending order.
+    pub fn counter_histogram(&self) -> Vec<(u64, usize)> {
+        let mut histogram: Vec<(u64, usize)> = vec![];
+        for (count, frequency) in &self.count_frequencies {
+            let bucket = match count {
+                0 => 0,
+                x => 1 << (63 - x.leading_zeros()),
+            };
+            match histogram.last_mut() {
+                Some((lower, n)) if *lower == bucket => *n += frequency,
+                _ => histogram.push((bucket, *frequency)),
             }
         }
+        histogram
+    }
+
+    /// Computes the detailed summary for the given cutoffs, each entry gives the minimum count
+    /// and number of counters needed to account for `cutoff / 1_000_000` of the total count. This
+    /// matches the `ProfileSummaryBuilder` in LLVM.
+    pub fn detailed_summary(&self, cutoffs: &[u64]) -> Vec<ProfileSummaryEntry> {
+        let mut cutoffs = cutoffs.to_vec();
+        cutoffs.sort_unstable();
+        let mut result = Vec::with_capacity(cutoffs.len());
+        let mut iter = self.count_frequencies.iter().rev();
+        let mut current_sum = 0u128;
+        let mut num_counts = 0;
+        let mut min_count = 0;
+        for cutoff in cutoffs {
+            let desired = (self.total_count as u128 * cutoff as u128) / CUTOFF_DENOMINATOR;
+            while current_sum < desired {
+                match iter.next() {
+                    Some((count, frequency)) => {
+                        min_count = *count;
+                        current_sum += *count as u128 * *frequency as u128;
+                        num_counts += *frequency as u64;
+                    }
+                    None => break,
+                }
+            }
+            result.push(ProfileSummaryEntry {
+                cutoff,
+                min_count,
+                num_counts,
+            });
+        }
+        result
     }
 
     pub fn num_functions(&self) -> usize {
         self.num_functions
     }
 
+    /// The largest entry count, see `InstrProfRecord::entry_count`
     pub fn max_function_count(&self) -> u64 {
         self.max_function_count
     }
 
+    /// The largest count of any counter but the entry count, see
+    /// `InstrProfRecord::max_internal_block_count`
     pub fn max_internal_block_count(&self) -> u64 {
         self.max_internal_block_count
     }
+
+    pub fn total_count(&self) -> u64 {
+        self.total_count
+    }
+
+    pub fn max_count(&self) -> u64 {
+        self.max_count
+    }
+
+    /// Number of counters added across all the records
+    pub fn num_counts(&self) -> usize {
+        self.count_frequencies.values().sum()
+    }
+
+    /// Converts into the summary stored in indexed profiles with a detailed summary for the
+    /// given cutoffs
+    pub fn to_summary(&self, kind: Kind, cutoffs: &[u64]) -> summary::ProfileSummary {
+        summary::ProfileSummary {
+            kind,
+            total_count: self.total_count,
+            max_count: self.max_count,
+            max_internal_count: self.max_internal_block_count,
+            max_function_count: self.max_function_count,
+            num_counts: self.num_counts() as u64,
+            num_fns: self.num_functions as u64,
+            partial: false,
+            partial_profile_ratio: 0.0,
+            detailed_summary: self.detailed_summary(cutoffs),
+        }
+    }
+}
+
+/// Hotness thresholds of the summary and the context sensitive summary, computed on first use as
+/// that visits every counter. Always compares equal so it doesn't affect profile equality
+#[derive(Clone, Debug, Default)]
+pub(crate) struct HotnessCache(OnceLock<[HotnessThresholds; 2]>);
+
+impl PartialEq for HotnessCache {
+    fn eq(&self, _: &Self) -> bool {
+        true
+    }
+}
+
+impl Eq for HotnessCache {}
+
+impl InstrumentationProfile {
+    /// Sum of the counters in the non context sensitive records, this is the "Total count"
+    /// reported by `llvm-profdata show --detailed-summary`. Unlike `summary` this is always
+    /// computed from the records so a stored summary from before the records changed isn't used.
+    pub fn total_count(&self) -> u64 {
+        self.compute_summary(Kind::Instr, &[]).total_count
+    }
+
+    /// Whether the profile file contained a summary. Only indexed profiles store one and it's
+    /// dropped once the records are modified by merging or adding records.
+    pub fn has_stored_summary(&self) -> bool {
+        self.summary.is_some()
+    }
+
+    /// The profile summary of the non context sensitive records. If the profile has a stored
+    /// summary that's returned, otherwise it's computed from the records using the default
+    /// cutoffs.
+    pub fn summary(&self) -> summary::ProfileSummary {
+        match self.summary.as_ref() {
+            Some(summary) => summary.clone(),
+            None => self.compute_summary(Kind::Instr, &DEFAULT_CUTOFFS),
+        }
+    }
+
+    /// The profile summary of the non context sensitive records computed from the records with a
+    /// detailed summary for the given cutoffs, a stored summary isn't used
+    pub fn summary_with_cutoffs(&self, cutoffs: &[u64]) -> summary::ProfileSummary {
+        self.compute_summary(Kind::Instr, cutoffs)
+    }
+
+    /// The profile summary of the context sensitive records, stored or computed like `summary`
+    pub fn cs_summary(&self) -> summary::ProfileSummary {
+        match self.cs_summary.as_ref() {
+            Some(summary) => summary.clone(),
+            None => self.compute_summary(Kind::CsInstr, &DEFAULT_CUTOFFS),
+        }
+    }
+
+    pub(crate) fn clear_stored_summary(&mut self) {
+        self.summary = None;
+        self.cs_summary = None;
+        self.hotness = HotnessCache::default();
+    }
+
+    /// The hot and cold count thresholds of `summary`, or `cs_summary` for context sensitive
+    /// records. Cached until the records change
+    pub fn hotness_thresholds(&self, context_sensitive: bool) -> HotnessThresholds {
+        let thresholds = self.hotness.0.get_or_init(|| {
+            [
+                self.summary().hotness_thresholds(),
+                self.cs_summary().hotness_thresholds(),
+            ]
+        });
+        thresholds[context_sensitive as usize]
+    }
+
+    /// Whether the function is hot. Like `isFunctionHotInCallGraph` in LLVM it's hot if any of its
+    /// counters reaches the hot threshold, or it has the hot pseudo count. False if the function
+    /// isn't in the profile
+    pub fn is_hot(&self, name: &str) -> bool {
+        let record = match self.find_record_by_name(name) {
+            Some(record) => record,
+            None => return false,
+        };
+        match record.record.pseudo_count() {
+            Some(pseudo) => pseudo == PseudoCount::Hot,
+            None => {
+                let hot = self.hotness_thresholds(self.is_cs_record(record)).hot;
+                record.counts().iter().any(|x| *x >= hot)
+            }
+        }
+    }
+
+    /// Whether the function is cold, none of its counters are above the cold threshold. Functions
+    /// with a pseudo count are never cold. False if the function isn't in the profile
+    pub fn is_cold(&self, name: &str) -> bool {
+        let record = match self.find_record_by_name(name) {
+            Some(record) => record,
+            None => return false,
+        };
+        if record.record.pseudo_count().is_some() {
+            return false;
+        }
+        let cold = self.hotness_thresholds(self.is_cs_record(record)).cold;
+        record.counts().iter().all(|x| *x <= cold)
+    }
+
+    fn is_cs_record(&self, record: &NamedInstrProfRecord) -> bool {
+        self.is_ir_level_profile() && record.has_cs_flag()
+    }
+
+    fn compute_summary(&self, kind: Kind, cutoffs: &[u64]) -> summary::ProfileSummary {
+        let want_cs = kind == Kind::CsInstr;
+        let mut builder = ProfileSummary::new();
+        for record in self.records() {
+            if self.is_cs_record(record) == want_cs {
+                builder.add_record(&record.record);
+            }
+        }
+        builder.to_summary(kind, cutoffs)
+    }
+
+    /// Log2 bucketed histogram of every counter in the profile, see
+    /// `ProfileSummary::counter_histogram`
+    pub fn counter_histogram(&self) -> Vec<(u64, usize)> {
+        let mut summary = ProfileSummary::new();
+        for record in self.records() {
+            summary.add_record(&record.record);
+        }
+        summary.counter_histogram()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn summary(counts: Vec<u64>) -> ProfileSummary {
+        let mut summary = ProfileSummary::new();
+        summary.add_record(&InstrProfRecord {
+            counts,
+            ..Default::default()
+        });
+        summary
+    }
+
+    #[test]
+    fn histogram_buckets() {
+        let summary = summary(vec![0, 1, 2, 3, 4, 7, 8, 1000, 1023, 1024, u64::MAX, 0]);
+        assert_eq!(
+            summary.counter_histogram(),
+            vec![
+                (0, 2),
+                (1, 1),
+                (2, 2),
+                (4, 2),
+                (8, 1),
+                (512, 2),
+                (1024, 1),
+                (1 << 63, 1)
+            ]
+        );
+        assert_eq!(summary.num_counts(), 12);
+    }
+
+    #[test]
+    fn detailed_summary() {
+        // Total of 100, the 50 alone covers 50%
+        let summary = summary(vec![50, 20, 10, 10, 5, 5]);
+        let entries = summary.detailed_summary(&[500_000, 700_000, 900_000, 1_000_000]);
+        let found = entries
+            .iter()
+            .map(|x| (x.min_count, x.num_counts))
+            .collect::<Vec<_>>();
+        assert_eq!(found, vec![(50, 1), (20, 2), (10, 4), (5, 6)]);
+    }
+
+    #[test]
+    fn pseudo_counts_skipped() {
+        let mut summary = summary(vec![10, 5]);
+        for entry in [u64::MAX, u64::MAX - 1] {
+            summary.add_record(&InstrProfRecord {
+                counts: vec![entry, 100],
+                ..Default::default()
+            });
+        }
+        assert_eq!(summary.num_functions(), 1);
+        assert_eq!(summary.total_count(), 15);
+        assert_eq!(summary.max_function_count(), 10);
+    }
+
+    #[test]
+    fn empty_summary() {
+        let empty = ProfileSummary::new();
+        let zeroes = summary(vec![0, 0, 0]);
+        for summary in [&empty, &zeroes] {
+            assert_eq!(summary.total_count(), 0);
+            assert_eq!(summary.max_function_count(), 0);
+            assert_eq!(summary.max_internal_block_count(), 0);
+            for entry in summary.detailed_summary(&DEFAULT_CUTOFFS) {
+                assert_eq!((entry.min_count, entry.num_counts), (0, 0));
+            }
+        }
+        assert_eq!(empty.num_functions(), 0);
+        assert!(empty.counter_histogram().is_empty());
+
+        let profile = InstrumentationProfile::default();
+        let stored = profile.summary();
+        assert_eq!(stored.num_fns, 0);
+        assert_eq!(stored.total_count, 0);
+        assert_eq!(stored.detailed_summary.len(), DEFAULT_CUTOFFS.len());
+    }
 }
@@ -1,7 +1,10 @@
 use crate::instrumentation_profile::types::*;
-use crate::instrumentation_profile::{InstrProfReader, ParseResult};
+use crate::instrumentation_profile::{
+    InstrProfReader, InstrProfWriter, ParseOptions, ParseResult, ProfileHeaderInfo,
+};
+use crate::ProfileFormat;
 use nom::branch::alt;
-use nom::bytes::complete::{tag, tag_no_case, take_until, take_while1};
+use nom::bytes::complete::{tag, tag_no_case, take_till, take_until, take_while1};
 use nom::character::{
     complete::{line_ending, one_of},
     is_digit, is_hex_digit,
@@ -11,7 +14,9 @@ use nom::error::{ErrorKind, ParseError, VerboseError};
 use nom::multi::*;
 use nom::sequence::*;
 use nom::*;
-use std::io::Read;
+use rustc_hash::FxHashMap;
+use std::io::{self, Read, Write};
+use tracing::{debug, debug_span, warn};
 
 const IR_TAG: &[u8] = b"ir";
 const FE_TAG: &[u8] = b"fe";
@@ -19,6 +24,11 @@ const CSIR_TAG: &[u8] = b"csir";
 const ENTRY_TAG: &[u8] = b"entry_first";
 const NOT_ENTRY_TAG: &[u8] = b"not_entry_first";
 const EXTERNAL_SYMBOL: &[u8] = b"** External Symbol **";
+/// UTF-8 byte order mark, some editors and scripts put this at the start of text files
+const UTF8_BOM: &[u8] = b"\xEF\xBB\xBF";
+/// Start of the optional checksum trailer. This isn't part of the LLVM format, it's a comment so
+/// other readers ignore it and the rest of the line is the hex MD5 of everything before it.
+const CHECKSUM_TRAILER: &str = "# Checksum (MD5): ";
 
 #[derive(Debug, Clone, Eq, PartialEq, Hash, Ord, PartialOrd)]
 pub struct TextInstrProf;
@@ -30,6 +40,51 @@ pub struct Header {
     entry_first: bool,
 }
 
+/// Checks the checksum trailer if the last line of the file is one
+fn verify_checksum(input: &[u8]) -> Option<bool> {
+    let content = input.strip_suffix(b"\n").unwrap_or(input);
+    let content = content.strip_suffix(b"\r").unwrap_or(content);
+    let start = content
+        .iter()
+        .rposition(|x| *x == b'\n')
+        .map(|x| x + 1)
+        .unwrap_or_default();
+    let expected = content[start..].strip_prefix(CHECKSUM_TRAILER.as_bytes())?;
+    let actual = format!("{:x}", md5::compute(&input[..start]));
+    Some(expected.eq_ignore_ascii_case(actual.as_bytes()))
+}
+
+/// Result of checking the checksum trailer, `None` without a trailer. It describes the file the
+/// profile was read from rather than the profile so it always compares equal
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct ChecksumStatus(pub(crate) Option<bool>);
+
+impl PartialEq for ChecksumStatus {
+    fn eq(&self, _: &Self) -> bool {
+        true
+    }
+}
+
+impl Eq for ChecksumStatus {}
+
+/// Passes writes through while hashing them for the checksum trailer
+struct ChecksumWriter<'a, W> {
+    inner: &'a mut W,
+    context: md5::Context,
+}
+
+impl<W: Write> Write for ChecksumWriter<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = self.inner.write(buf)?;
+        self.context.consume(&buf[..len]);
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
 fn check_tag(data: &[u8], tag: &[u8]) -> bool {
     if let Ok(data) = std::str::from_utf8(data) {
         let tag = std::str::from_utf8(tag).unwrap_or_default();
@@ -67,13 +122,19 @@ fn strip_whitespace(s: &[u8]) -> ParseResult<'_, ()> {
     one_of(&b" \n\r\t"[..])(s).map(|(b, _)| (b, ()))
 }
 
+/// Takes the rest of the line stopping at the first line ending. Looking for the first `\r` and
+/// then the first `\n` instead would scan the rest of the input for every line of a file without
+/// `\r`s.
+fn till_line_ending(s: &[u8]) -> ParseResult<'_, &[u8]> {
+    take_till(|c| c == b'\r' || c == b'\n')(s)
+}
+
 fn strip_comments(s: &[u8]) -> ParseResult<'_, ()> {
-    delimited(
-        tag(b"#"),
-        alt((take_until("\r"), take_until("\n"))),
-        line_ending,
-    )(s)
-    .map(|(b, _)| (b, ()))
+    delimited(tag(b"#"), till_line_ending, line_ending)(s).map(|(b, _)| (b, ()))
+}
+
+fn strip_bom(s: &[u8]) -> &[u8] {
+    s.strip_prefix(UTF8_BOM).unwrap_or(s)
 }
 
 fn skip_to_content(s: &[u8]) -> ParseResult<'_, ()> {
@@ -86,8 +147,7 @@ fn match_header_tags(s: &[u8]) -> ParseResult<'_, &[u8]> {
         tag_no_case(FE_TAG),
         tag_no_case(CSIR_TAG),
         tag_no_case(ENTRY_TAG),
-        take_until("\r"),
-        take_until("\n"),
+        till_line_ending,
     ))(s)
 }
 
@@ -117,11 +177,40 @@ fn memop_value_site(s: &[u8]) -> ParseResult<'_, (u64, u64)> {
         .map(|(b, v)| (b, (str_to_digit(v.0), str_to_digit(v.2))))
 }
 
-fn read_value_profile_data(mut input: &[u8]) -> ParseResult<'_, Option<Box<ValueProfDataRecord>>> {
+/// Skips the sites of a value kind, the input should start at the number of sites. Every kind
+/// writes its values as `value:count` so they can be skipped without knowing the kind.
+fn skip_value_sites(input: &[u8]) -> ParseResult<'_, ()> {
+    let (mut input, _) = skip_to_content(input)?;
+    let (bytes, n_sites) = match read_digit(input) {
+        Ok(s) => s,
+        Err(_) => return Ok((input, ())),
+    };
+    input = bytes;
+    for _ in 0..n_sites {
+        let (bytes, _) = skip_to_content(input)?;
+        let (bytes, n_val_data) = read_digit(bytes)?;
+        input = bytes;
+        for _ in 0..n_val_data {
+            let (bytes, _) = skip_to_content(input)?;
+            let (bytes, _) = indirect_value_site(bytes)?;
+            input = bytes;
+        }
+    }
+    Ok((input, ()))
+}
+
+/// Reads the value profiling data of a function, like LLVM the names of indirect call and vtable
+/// targets are added to the symbol table
+fn read_value_profile_data<'a>(
+    mut input: &'a [u8],
+    options: &ParseOptions,
+    symtab: &mut Symtab,
+) -> ParseResult<'a, Option<Box<ValueProfDataRecord>>> {
     if let Ok((bytes, n_kinds)) = read_digit(input) {
         let mut record = Box::<ValueProfDataRecord>::default();
         // We have value profiling data!
-        if n_kinds == 0 || n_kinds > ValueKind::len() as u64 {
+        if n_kinds == 0 || (n_kinds > ValueKind::len() as u64 && !options.skip_unknown_value_kinds)
+        {
             // TODO I am malformed
             return Err(nom::Err::Failure(VerboseError::from_error_kind(
                 bytes,
@@ -135,6 +224,12 @@ fn read_value_profile_data(mut input: &[u8]) -> ParseResult<'_, Option<Box<Value
             let kind = match kind {
                 0 => ValueKind::IndirectCallTarget,
                 1 => ValueKind::MemOpSize,
+                2 => ValueKind::VTableTarget,
+                _ if options.skip_unknown_value_kinds => {
+                    warn!("Skipping value profiling data of unknown kind {}", kind);
+                    input = skip_value_sites(bytes2)?.0;
+                    continue;
+                }
                 _ => {
                     return Err(nom::Err::Failure(VerboseError::from_error_kind(
                         bytes,
@@ -159,12 +254,16 @@ fn read_value_profile_data(mut input: &[u8]) -> ParseResult<'_, Option<Box<Value
                 for _k in 0..n_val_data {
                     let (bytes, _) = skip_to_content(input)?;
                     input = match kind {
-                        ValueKind::IndirectCallTarget => {
+                        ValueKind::IndirectCallTarget | ValueKind::VTableTarget => {
                             let (bytes, (sym, count)) = indirect_value_site(bytes)?;
                             let value = if sym == EXTERNAL_SYMBOL {
                                 0
                             } else {
-                                compute_hash(sym)
+                                let hash = compute_hash(sym);
+                                if let Ok(name) = std::str::from_utf8(sym) {
+                                    symtab.add_func_name_with_hash(name.to_string(), hash);
+                                }
+                                hash
                             };
                             site_records.push(InstrProfValueData { value, count });
                             bytes
@@ -176,10 +275,7 @@ fn read_value_profile_data(mut input: &[u8]) -> ParseResult<'_, Option<Box<Value
                         }
                     };
                 }
-                match kind {
-                    ValueKind::IndirectCallTarget => record.indirect_callsites.push(site_records),
-                    ValueKind::MemOpSize => record.mem_op_sizes.push(site_records),
-                }
+                record.sites_mut(kind).push(site_records);
             }
         }
         Ok((input, Some(record)))
@@ -188,9 +284,21 @@ fn read_value_profile_data(mut input: &[u8]) -> ParseResult<'_, Option<Box<Value
     }
 }
 
-impl InstrProfReader for TextInstrProf {
-    type Header = Header;
-    fn parse_bytes(mut input: &[u8]) -> ParseResult<'_, InstrumentationProfile> {
+impl TextInstrProf {
+    /// Parses the profile with the given options, `parse_bytes` uses the default options
+    pub fn parse_bytes_with_options<'a>(
+        mut input: &'a [u8],
+        options: &ParseOptions,
+    ) -> ParseResult<'a, InstrumentationProfile> {
+        let _span = debug_span!("text_profile", len = input.len()).entered();
+        // Tools which pad files to a block boundary leave zero bytes after the last record
+        let end = input
+            .iter()
+            .rposition(|x| *x != 0)
+            .map(|x| x + 1)
+            .unwrap_or_default();
+        input = &input[..end];
+        let checksum = verify_checksum(input);
         let (bytes, header) = Self::parse_header(input)?;
         let (bytes, _) = skip_to_content(bytes)?;
         input = bytes;
@@ -200,6 +308,10 @@ impl InstrProfReader for TextInstrProf {
             header.is_ir_level,
             header.entry_first,
         );
+        result.checksum = ChecksumStatus(checksum);
+        if checksum == Some(false) {
+            warn!("Profile checksum doesn't match, the file may be truncated or corrupted");
+        }
         while !input.is_empty() {
             // function name (demangled)
             let (bytes, name) = read_line(input)?;
@@ -229,7 +341,7 @@ impl InstrProfReader for TextInstrProf {
                     }
                 }
             }
-            let (bytes, data) = read_value_profile_data(input)?;
+            let (bytes, data) = read_value_profile_data(input, options, &mut result.symtab)?;
             let record = InstrProfRecord {
                 counts: counters,
                 zero: false,
@@ -251,11 +363,39 @@ impl InstrProfReader for TextInstrProf {
                 Err(_) => &bytes[(bytes.len())..],
             };
         }
+        debug!(records = result.records().len(), "Parsed records");
         Ok((bytes, result))
     }
+}
+
+impl TextInstrProf {
+    /// Parses the header tags, see `parse_header_only`. Text profiles don't record the number of
+    /// functions or binary IDs.
+    pub fn parse_header_info(input: &[u8]) -> ParseResult<'_, ProfileHeaderInfo> {
+        let (bytes, header) = Self::parse_header(input)?;
+        let info = ProfileHeaderInfo {
+            format: ProfileFormat::Text,
+            version: None,
+            level: ProfileLevel::new(header.is_ir_level, header.has_csir),
+            is_entry_first: header.entry_first,
+            is_byte_coverage: false,
+            fn_entry_only: false,
+            memory_profiling: false,
+            num_functions: None,
+            binary_ids: vec![],
+        };
+        Ok((bytes, info))
+    }
+}
+
+impl InstrProfReader for TextInstrProf {
+    type Header = Header;
+    fn parse_bytes(input: &[u8]) -> ParseResult<'_, InstrumentationProfile> {
+        Self::parse_bytes_with_options(input, &ParseOptions::default())
+    }
 
     fn parse_header(input: &[u8]) -> ParseResult<'_, Self::Header> {
-        let (input, _) = skip_to_content(input)?;
+        let (input, _) = skip_to_content(strip_bom(input))?;
         let (bytes, names) = parse_header_tags(input)?;
         let mut is_ir_level = false;
         let mut has_csir = false;
@@ -288,15 +428,122 @@ impl InstrProfReader for TextInstrProf {
     fn has_format(mut input: impl Read) -> bool {
         // looking at the code it looks like with file memory buffers in llvm it sets the buffer
         // size to the size of the file meaning it checks all the characters
-        let mut s = String::new();
-        if input.read_to_string(&mut s).is_ok() {
-            s.is_ascii()
+        let mut s = Vec::new();
+        if input.read_to_end(&mut s).is_ok() {
+            strip_bom(&s).is_ascii()
         } else {
             false
         }
     }
 }
 
+impl InstrProfWriter for TextInstrProf {
+    /// Writes the profile in the same layout as `llvm-profdata show --text`. Functions are sorted
+    /// by name and hash so the output is deterministic.
+    fn write(&self, profile: &InstrumentationProfile, writer: &mut impl Write) -> io::Result<()> {
+        if profile.is_ir_level_profile() {
+            writeln!(writer, "# IR level Instrumentation Flag\n:ir")?;
+        }
+        if profile.has_csir_level_profile() {
+            writeln!(writer, "# CSIR level Instrumentation Flag\n:csir")?;
+        }
+        if profile.is_entry_first() {
+            writeln!(
+                writer,
+                "# Always instrument the function entry block\n:entry_first"
+            )?;
+        }
+        self.write_records(profile, profile.records_sorted(), writer)
+    }
+}
+
+impl TextInstrProf {
+    /// Writes the profile like `write` followed by a checksum trailer. The trailer is a comment
+    /// so the file can still be read by llvm-profdata, when this crate reads it the checksum is
+    /// verified and the result is available from `InstrumentationProfile::checksum_ok`.
+    pub fn write_with_checksum(
+        &self,
+        profile: &InstrumentationProfile,
+        writer: &mut impl Write,
+    ) -> io::Result<()> {
+        let mut hashed = ChecksumWriter {
+            inner: writer,
+            context: md5::Context::new(),
+        };
+        self.write(profile, &mut hashed)?;
+        let digest = hashed.context.finalize();
+        writeln!(writer, "{}{:x}", CHECKSUM_TRAILER, digest)
+    }
+
+    /// Writes the given records of the profile without the header, each function is written
+    /// exactly like `llvm-profdata show --text` does. Records without a name are skipped.
+    pub fn write_records<'a>(
+        &self,
+        profile: &InstrumentationProfile,
+        records: impl IntoIterator<Item = &'a NamedInstrProfRecord>,
+        writer: &mut impl Write,
+    ) -> io::Result<()> {
+        // Indirect call targets are stored as the name hash
+        let mut names = FxHashMap::default();
+        for record in profile.records() {
+            if let Some((hash, name)) = record.name_hash.zip(record.name.as_deref()) {
+                names.insert(hash, name);
+            }
+        }
+        for (hash, name) in profile.symtab().iter() {
+            names.entry(*hash).or_insert(name.as_str());
+        }
+
+        for record in records.into_iter().filter(|x| x.name.is_some()) {
+            writeln!(writer, "{}", record.name_unchecked())?;
+            writeln!(writer, "# Func Hash:\n{}", record.hash_unchecked())?;
+            writeln!(writer, "# Num Counters:\n{}", record.counts().len())?;
+            writeln!(writer, "# Counter Values:")?;
+            for count in record.counts() {
+                writeln!(writer, "{}", count)?;
+            }
+            let sites = match record.record.data.as_ref() {
+                Some(data) => ValueKind::ALL.map(|kind| (kind, data.sites(kind))),
+                None => {
+                    writeln!(writer)?;
+                    continue;
+                }
+            };
+            let num_kinds = sites.iter().filter(|(_, x)| !x.is_empty()).count();
+            if num_kinds > 0 {
+                writeln!(writer, "# Num Value Kinds:\n{}", num_kinds)?;
+            }
+            for (kind, kind_sites) in sites.iter().filter(|(_, x)| !x.is_empty()) {
+                let descr = match kind {
+                    ValueKind::IndirectCallTarget => "IPVK_IndirectCallTarget",
+                    ValueKind::MemOpSize => "IPVK_MemOPSize",
+                    ValueKind::VTableTarget => "IPVK_VTableTarget",
+                };
+                writeln!(writer, "# ValueKind = {}:\n{}", descr, *kind as usize)?;
+                writeln!(writer, "# NumValueSites:\n{}", kind_sites.len())?;
+                for site in kind_sites.iter() {
+                    writeln!(writer, "{}", site.len())?;
+                    for value in site {
+                        match kind {
+                            ValueKind::IndirectCallTarget | ValueKind::VTableTarget => {
+                                let name = names.get(&value.value).copied().unwrap_or_else(|| {
+                                    std::str::from_utf8(EXTERNAL_SYMBOL).unwrap_or_default()
+                                });
+                                writeln!(writer, "{}:{}", name, value.count)?;
+                            }
+                            ValueKind::MemOpSize => {
+                                writeln!(writer, "{}:{}", value.value, value.count)?;
+                            }
+                        }
+                    }
+                }
+            }
+            writeln!(writer)?;
+        }
+        Ok(())
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
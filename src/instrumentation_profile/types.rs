@@ -1,9 +1,15 @@
+use crate::instrumentation_profile::summary::HotnessCache;
+use crate::instrumentation_profile::text_profile::ChecksumStatus;
+use crate::summary::ProfileSummary;
 use core::ops::Range;
 use nom::number::Endianness;
-use rustc_hash::FxHashMap;
-use std::cmp::Ordering;
+use rustc_hash::{FxHashMap, FxHashSet};
+use std::cmp::{Ordering, Reverse};
+use std::collections::HashSet;
 use std::convert::TryInto;
 use std::fmt;
+use std::hash::{Hash, Hasher};
+use thiserror::Error;
 
 /// ~VARIANT_MASKS_ALL & Header.version is the version number
 pub(crate) const VARIANT_MASKS_ALL: u64 = 0xff00_0000_0000_0000;
@@ -12,21 +18,179 @@ pub(crate) const VARIANT_MASK_IR_PROF: u64 = 1u64 << 56;
 /// This is taken from `llvm/include/llvm/ProfileData/InstrProfileData.inc`
 pub(crate) const VARIANT_MASK_CSIR_PROF: u64 = 1u64 << 57;
 /// This is taken from `llvm/include/llvm/ProfileData/InstrProfileData.inc`
+pub(crate) const VARIANT_MASK_INSTR_ENTRY: u64 = 1u64 << 58;
+/// This is taken from `llvm/include/llvm/ProfileData/InstrProfileData.inc`
 pub(crate) const VARIANT_MASK_BYTE_COVERAGE: u64 = 1u64 << 60;
 /// This is taken from `llvm/include/llvm/ProfileData/InstrProfileData.inc`
 pub(crate) const VARIANT_MASK_FUNCTION_ENTRY_ONLY: u64 = 1u64 << 61;
 /// This is taken from `llvm/include/llvm/ProfileData/InstrProfileData.inc`
 pub(crate) const VARIANT_MASK_MEMORY_PROFILE: u64 = 1u64 << 62;
 
+/// The kinds of value profiling data, the discriminants match `IPVK_*` in LLVM
 #[derive(Debug, Copy, Clone, PartialEq, Eq, Ord, PartialOrd, Hash)]
+#[non_exhaustive]
 pub enum ValueKind {
     IndirectCallTarget = 0,
     MemOpSize = 1,
+    VTableTarget = 2,
 }
 
 impl ValueKind {
+    /// Every value kind in discriminant order
+    pub const ALL: [ValueKind; 3] = [
+        ValueKind::IndirectCallTarget,
+        ValueKind::MemOpSize,
+        ValueKind::VTableTarget,
+    ];
+
     pub const fn len() -> usize {
-        2
+        Self::ALL.len()
+    }
+}
+
+impl fmt::Display for ValueKind {
+    /// Uses the same names as llvm-profdata
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ValueKind::IndirectCallTarget => write!(f, "Indirect Call Targets"),
+            ValueKind::MemOpSize => write!(f, "Memory Intrinsics"),
+            ValueKind::VTableTarget => write!(f, "VTable Targets"),
+        }
+    }
+}
+
+/// Problems where an operation on profile data still completed but the result lost some
+/// precision. llvm-profdata reports these as warnings and carries on.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Error)]
+pub enum InstrProfWarning {
+    /// A counter exceeded `u64::MAX` and was saturated. The index is of the first counter to
+    /// overflow.
+    #[error(
+        "counter {index} overflowed in function {}",
+        .function.as_deref().unwrap_or("<unknown>")
+    )]
+    CounterOverflow {
+        function: Option<String>,
+        index: usize,
+    },
+    /// The records had a different number of counters and the merged in counters were padded
+    /// with zeros or truncated to the expected count
+    #[error(
+        "function {} has {found} counters but {expected} were expected, merged the counters resized to {expected}",
+        .function.as_deref().unwrap_or("<unknown>")
+    )]
+    CountsResized {
+        function: Option<String>,
+        expected: usize,
+        found: usize,
+    },
+}
+
+/// Errors from operations which combine or modify instrumentation records such as merging,
+/// adding and scaling. Where the operation can identify the function or counter involved it's
+/// included as context.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Error)]
+pub enum InstrProfError {
+    /// The records have a different number of counters so can't be combined
+    #[error(
+        "function {} has {found} counters but {expected} were expected",
+        .function.as_deref().unwrap_or("<unknown>")
+    )]
+    CountMismatch {
+        function: Option<String>,
+        expected: usize,
+        found: usize,
+    },
+    /// The records share a name but the function hash differs so they're from different versions
+    /// of the function
+    #[error(
+        "function {} has hash {found:#018x} but {expected:#018x} was expected",
+        .function.as_deref().unwrap_or("<unknown>")
+    )]
+    HashMismatch {
+        function: Option<String>,
+        expected: u64,
+        found: u64,
+    },
+    /// The records have a different number of value profiling sites for a value kind
+    #[error(
+        "function {} has {found} {kind:?} value sites but {expected} were expected",
+        .function.as_deref().unwrap_or("<unknown>")
+    )]
+    ValueSiteCountMismatch {
+        function: Option<String>,
+        kind: ValueKind,
+        expected: usize,
+        found: usize,
+    },
+    /// A scale with a zero denominator was requested
+    #[error("invalid scale factor {numerator}/{denominator}")]
+    InvalidScale { numerator: u64, denominator: u64 },
+    /// The profiles come from different kinds of instrumentation, e.g. front-end and IR, so the
+    /// counters don't mean the same thing
+    #[error(transparent)]
+    KindMismatch(#[from] IncompatibilityReason),
+    /// Only one of the records has a pseudo count so the real counts can't be combined with it
+    #[error(
+        "function {} has a pseudo count in only one of the profiles",
+        .function.as_deref().unwrap_or("<unknown>")
+    )]
+    PseudoCountMismatch { function: Option<String> },
+    /// The operation completed but with a loss of precision
+    #[error(transparent)]
+    Warning(#[from] InstrProfWarning),
+}
+
+impl InstrProfError {
+    /// Returns true if the operation still completed and this is only a warning
+    pub fn is_warning(&self) -> bool {
+        matches!(self, Self::Warning(_))
+    }
+
+    /// Record level operations don't know the name of the function, this fills it in where
+    /// it's missing.
+    pub(crate) fn with_function(mut self, name: Option<&str>) -> Self {
+        let function = match &mut self {
+            Self::CountMismatch { function, .. }
+            | Self::HashMismatch { function, .. }
+            | Self::ValueSiteCountMismatch { function, .. }
+            | Self::PseudoCountMismatch { function }
+            | Self::Warning(InstrProfWarning::CounterOverflow { function, .. })
+            | Self::Warning(InstrProfWarning::CountsResized { function, .. }) => function,
+            Self::InvalidScale { .. } | Self::KindMismatch(_) => return self,
+        };
+        if function.is_none() {
+            *function = name.map(|x| x.to_string());
+        }
+        self
+    }
+}
+
+/// Why two profiles can't be merged or compared, see
+/// `InstrumentationProfile::assert_compatible_with`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Error)]
+pub enum IncompatibilityReason {
+    /// One profile is from front-end instrumentation and the other from IR instrumentation so the
+    /// counters are for different things
+    #[error("{expected} and {found} instrumentation profiles can't be combined")]
+    Level {
+        expected: InstrumentationLevel,
+        found: InstrumentationLevel,
+    },
+    /// One profile only counts function entries and the other counts every block
+    #[error(
+        "{} profiles can't be combined with {} profiles",
+        entry_only_name(*.expected),
+        entry_only_name(*.found)
+    )]
+    FunctionEntryOnly { expected: bool, found: bool },
+}
+
+fn entry_only_name(fn_entry_only: bool) -> &'static str {
+    if fn_entry_only {
+        "function entry only"
+    } else {
+        "block"
     }
 }
 
@@ -80,6 +244,11 @@ impl Symtab {
         self.names.get(&hash)
     }
 
+    /// `get` as a `&str`
+    pub fn get_str(&self, hash: u64) -> Option<&str> {
+        self.get(hash).map(String::as_str)
+    }
+
     pub fn iter(&self) -> impl Iterator<Item = (&u64, &String)> {
         self.names.iter()
     }
@@ -100,7 +269,76 @@ impl fmt::Display for InstrumentationLevel {
     }
 }
 
-#[derive(Debug, Default, Clone, Eq, PartialEq)]
+/// The level of instrumentation the profile was generated with, context sensitive profiles are
+/// also IR level profiles
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Ord, PartialOrd, Hash)]
+pub enum ProfileLevel {
+    FrontEnd,
+    Ir,
+    ContextSensitiveIr,
+}
+
+impl ProfileLevel {
+    pub(crate) fn new(is_ir: bool, has_csir: bool) -> Self {
+        if has_csir {
+            Self::ContextSensitiveIr
+        } else if is_ir {
+            Self::Ir
+        } else {
+            Self::FrontEnd
+        }
+    }
+}
+
+impl fmt::Display for ProfileLevel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::FrontEnd => write!(f, "Front-end"),
+            Self::Ir => write!(f, "IR"),
+            Self::ContextSensitiveIr => write!(f, "Context sensitive IR"),
+        }
+    }
+}
+
+/// How the counters of a function in more than one profile are combined by
+/// `InstrumentationProfile::merge_with_options`
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, Hash)]
+pub enum Reduce {
+    /// Add the counts together like `llvm-profdata merge`, saturating on overflow
+    #[default]
+    Sum,
+    /// Keep the largest count, i.e. how hot the code was in the hottest run
+    Max,
+}
+
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, Hash)]
+pub struct MergeOptions {
+    pub reduce: Reduce,
+    /// Merge profiles from different kinds of instrumentation instead of returning
+    /// `InstrProfError::KindMismatch`, the merged profile keeps the kind of the one merged into
+    pub allow_kind_mismatch: bool,
+    /// Skip inputs to `merge_profiles_with_options` which are the same file or have the same
+    /// contents as an earlier input so they aren't counted twice
+    pub dedup_inputs: bool,
+    /// Scale the inputs to `merge_profiles_with_options` so each contributes the same total count
+    /// before they're combined, so a long running workload doesn't swamp shorter ones. With `T_i`
+    /// the total count of input `i` and `T_max` the largest total, every counter of input `i`
+    /// becomes `floor(count * T_max / T_i)`. Inputs without any counts are left alone.
+    pub normalize: bool,
+    /// Merge functions whose records have a different number of counters, which happens with
+    /// profiles from mismatched compiler versions, instead of returning
+    /// `InstrProfError::CountMismatch`. The counters being merged in are padded with zeros or
+    /// truncated to the length of the record they're merged into and a
+    /// `InstrProfWarning::CountsResized` warning is returned.
+    pub resize_mismatched_counts: bool,
+    /// Keep at most this many functions in the result of `merge_profiles_with_options`, evicting
+    /// the coldest ones as in `InstrumentationProfile::retain_hottest`
+    pub max_functions: Option<usize>,
+}
+
+type CombineRecords = fn(&mut InstrProfRecord, &InstrProfRecord) -> Result<(), InstrProfError>;
+
+#[derive(Debug, Default, Clone, PartialEq)]
 pub struct InstrumentationProfile {
     pub(crate) version: Option<u64>,
     pub(crate) has_csir: bool,
@@ -111,9 +349,26 @@ pub struct InstrumentationProfile {
     pub(crate) memory_profiling: bool,
     records: Vec<NamedInstrProfRecord>,
     record_name_lookup: FxHashMap<String, usize>,
+    /// Records without a name keyed by their name hash and function hash
+    unnamed_record_lookup: FxHashMap<(Option<u64>, Option<u64>), usize>,
+    /// Summary stored in the profile file, cleared if the records change
+    pub(crate) summary: Option<ProfileSummary>,
+    /// Context sensitive summary stored in the profile file, cleared if the records change
+    pub(crate) cs_summary: Option<ProfileSummary>,
+    /// MC/DC bitmap bytes keyed by function name hash
+    pub(crate) mcdc_bitmaps: FxHashMap<u64, Vec<u8>>,
+    /// Whether the checksum trailer of the file matched its contents, cleared by merging
+    pub(crate) checksum: ChecksumStatus,
+    /// Hot and cold thresholds derived from the summaries
+    pub(crate) hotness: HotnessCache,
+    /// Prefer `symtab()` and `symtab_mut()`, the field is kept public for compatibility
     pub symtab: Symtab,
 }
 
+// `ProfileSummary` isn't `Eq` because of `partial_profile_ratio`, but the summaries stored in a
+// profile are only made by the indexed profile parser which doesn't read a ratio and sets it to 0
+impl Eq for InstrumentationProfile {}
+
 impl InstrumentationProfile {
     pub fn new(version: Option<u64>, has_csir: bool, is_ir: bool, is_entry_first: bool) -> Self {
         Self {
@@ -133,12 +388,29 @@ impl InstrumentationProfile {
         *self.version.as_ref().unwrap()
     }
 
+    /// True if the file the profile was read from ended with a checksum trailer, these are only
+    /// written by `TextInstrProf::write_with_checksum`. Merged profiles have no checksum.
+    pub fn has_checksum(&self) -> bool {
+        self.checksum.0.is_some()
+    }
+
+    /// False if the file had a checksum trailer which doesn't match the contents so the file was
+    /// modified after it was written. Files without a checksum are fine, and truncating a file
+    /// removes the trailer, so check `has_checksum` as well when a trailer is expected.
+    pub fn checksum_ok(&self) -> bool {
+        self.checksum.0.unwrap_or(true)
+    }
+
+    pub fn profile_level(&self) -> ProfileLevel {
+        ProfileLevel::new(self.is_ir, self.has_csir)
+    }
+
     pub fn is_ir_level_profile(&self) -> bool {
-        self.is_ir
+        self.profile_level() != ProfileLevel::FrontEnd
     }
 
     pub fn has_csir_level_profile(&self) -> bool {
-        self.has_csir
+        self.profile_level() == ProfileLevel::ContextSensitiveIr
     }
 
     pub fn is_entry_first(&self) -> bool {
@@ -161,13 +433,155 @@ impl InstrumentationProfile {
         &self.records
     }
 
+    /// The records sorted by name and then function hash. Unlike `records` the order doesn't
+    /// depend on the format the profile was read from.
+    pub fn records_sorted(&self) -> Vec<&NamedInstrProfRecord> {
+        let mut records = self.records.iter().collect::<Vec<_>>();
+        records.sort_by(|a, b| (&a.name, a.hash).cmp(&(&b.name, b.hash)));
+        records
+    }
+
+    /// The function names and a row of counters for each function, for loading the counts into
+    /// numeric tooling. Rows are in the same order as `records_sorted` and have as many columns as
+    /// the function has counters. Records without a name are skipped.
+    pub fn counter_matrix(&self) -> (Vec<String>, Vec<Vec<u64>>) {
+        self.records_sorted()
+            .into_iter()
+            .filter_map(|x| Some((x.name.clone()?, x.counts().to_vec())))
+            .unzip()
+    }
+
+    /// The name and structural function hash of each record, in the same order as `records`.
+    /// Names missing from the record are looked up in the symbol table by the name hash, records
+    /// whose name or function hash can't be found are skipped.
+    pub fn func_hashes(&self) -> impl Iterator<Item = (String, u64)> + '_ {
+        self.records.iter().filter_map(move |x| {
+            let name = match &x.name {
+                Some(name) => name.clone(),
+                None => self.symtab.get(x.name_hash?)?.clone(),
+            };
+            Some((name, x.func_hash()?))
+        })
+    }
+
+    /// The MC/DC bitmap bytes of each function keyed by the function name hash, the same key as
+    /// the symbol table. Each bit records a test vector (combination of conditions) that was
+    /// executed. Only raw profiles of binaries built with MC/DC coverage have bitmaps, for other
+    /// profiles this is empty.
+    pub fn mcdc_bitmaps(&self) -> &FxHashMap<u64, Vec<u8>> {
+        &self.mcdc_bitmaps
+    }
+
+    /// Symbol table mapping hashes back to function names
+    pub fn symtab(&self) -> &Symtab {
+        &self.symtab
+    }
+
+    pub fn symtab_mut(&mut self) -> &mut Symtab {
+        &mut self.symtab
+    }
+
     pub fn push_record(&mut self, record: NamedInstrProfRecord) {
-        if let Some(name) = record.name.clone() {
-            self.record_name_lookup.insert(name, self.records.len());
-        }
+        self.clear_stored_summary();
+        self.index_record(&record, self.records.len());
         self.records.push(record);
     }
 
+    fn index_record(&mut self, record: &NamedInstrProfRecord, index: usize) {
+        match record.name.clone() {
+            Some(name) => {
+                self.record_name_lookup.insert(name, index);
+            }
+            None => {
+                self.unnamed_record_lookup
+                    .entry((record.name_hash, record.hash))
+                    .or_insert(index);
+            }
+        }
+    }
+
+    /// Keeps only the records the predicate returns true for. The symbol table isn't changed as
+    /// it can also hold the names of indirect call targets.
+    pub fn retain_records<F>(&mut self, f: F)
+    where
+        F: FnMut(&NamedInstrProfRecord) -> bool,
+    {
+        self.clear_stored_summary();
+        self.records.retain(f);
+        self.record_name_lookup.clear();
+        self.unnamed_record_lookup.clear();
+        let records = std::mem::take(&mut self.records);
+        for (index, record) in records.iter().enumerate() {
+            self.index_record(record, index);
+        }
+        self.records = records;
+    }
+
+    /// Keeps the `n` functions with the largest maximum counter, i.e. the hottest block, removing
+    /// the rest and returning how many were removed. Functions with the same maximum are ranked
+    /// by their total count and then name and function hash so the result doesn't depend on the
+    /// record order. The kept records stay in their original order and, like `retain_records`,
+    /// the symbol table isn't changed.
+    pub fn retain_hottest(&mut self, n: usize) -> usize {
+        let len = self.records.len();
+        if len <= n {
+            return 0;
+        }
+        let mut ranked = (0..len).collect::<Vec<_>>();
+        ranked.sort_by_cached_key(|index| {
+            let record = &self.records[*index];
+            let max = record.counts().iter().copied().max().unwrap_or_default();
+            let total = record
+                .counts()
+                .iter()
+                .fold(0u64, |acc, x| acc.saturating_add(*x));
+            (
+                Reverse(max),
+                Reverse(total),
+                record.name.clone(),
+                record.hash,
+                record.name_hash,
+            )
+        });
+        let mut keep = vec![false; len];
+        for index in &ranked[..n] {
+            keep[*index] = true;
+        }
+        let mut keep = keep.into_iter();
+        self.retain_records(|_| keep.next().unwrap_or_default());
+        len - n
+    }
+
+    /// Reserves space for at least `additional` more records and their symbol table names, to avoid
+    /// reallocating while adding many records
+    pub fn reserve(&mut self, additional: usize) {
+        self.records.reserve(additional);
+        self.record_name_lookup.reserve(additional);
+        self.unnamed_record_lookup.reserve(additional);
+        self.symtab.names.reserve(additional);
+    }
+
+    /// Releases the spare capacity left in the records, symbol table and MC/DC bitmaps after
+    /// parsing or removing records. Worth calling before keeping a profile around for a long time.
+    pub fn shrink_to_fit(&mut self) {
+        for record in &mut self.records {
+            record.record.counts.shrink_to_fit();
+            if let Some(data) = record.record.data.as_mut() {
+                for kind in ValueKind::ALL {
+                    let sites = data.sites_mut(kind);
+                    sites.iter_mut().for_each(Vec::shrink_to_fit);
+                    sites.shrink_to_fit();
+                }
+            }
+        }
+        self.records.shrink_to_fit();
+        self.record_name_lookup.shrink_to_fit();
+        self.unnamed_record_lookup.shrink_to_fit();
+        self.symtab.names.shrink_to_fit();
+        self.mcdc_bitmaps.values_mut().for_each(Vec::shrink_to_fit);
+        self.mcdc_bitmaps.shrink_to_fit();
+    }
+
     pub fn find_record_by_name(&self, name: &str) -> Option<&NamedInstrProfRecord> {
         self.record_name_lookup.get(name).map(|x| &self.records[*x])
     }
@@ -179,70 +593,389 @@ impl InstrumentationProfile {
 
     pub fn find_record_by_name_mut(&mut self, name: &str) -> Option<&mut NamedInstrProfRecord> {
         if let Some(index) = self.record_name_lookup.get(name) {
+            // The counts may change so the thresholds derived from them can't be kept
+            self.hotness = HotnessCache::default();
             Some(&mut self.records[*index])
         } else {
             None
         }
     }
 
-    /// Byte coverage switches things around to make `0` equivalent to coverage and !0 uncovered it
-    /// seems. This currently is not supported but also not output by any rust tools (to my
-    /// knowledge)
+    /// Renames a function keeping its function hash and counters. The symbol table and any
+    /// indirect call targets referring to the function are updated to the new name. Returns false
+    /// if there's no function called `old` or a function called `new` already exists.
+    pub fn rename_function(&mut self, old: &str, new: &str) -> bool {
+        if self.record_name_lookup.contains_key(new) {
+            return false;
+        }
+        let index = match self.record_name_lookup.remove(old) {
+            Some(index) => index,
+            None => return false,
+        };
+        self.record_name_lookup.insert(new.to_string(), index);
+
+        let old_hash = compute_hash(old);
+        let new_hash = compute_hash(new);
+        let record = &mut self.records[index];
+        let old_name_hash = record.name_hash.unwrap_or(old_hash);
+        record.name = Some(new.to_string());
+        record.name_hash = Some(new_hash);
+
+        // Some formats key the symbol table on the function hash instead of the name hash so
+        // only the name hash keys change
+        let keys = self
+            .symtab
+            .iter()
+            .filter(|(_, name)| name.as_str() == old)
+            .map(|(key, _)| *key)
+            .collect::<Vec<_>>();
+        for key in keys {
+            self.symtab.names.remove(&key);
+            let key = if key == old_name_hash || key == old_hash {
+                new_hash
+            } else {
+                key
+            };
+            self.symtab.add_func_name_with_hash(new.to_string(), key);
+        }
+
+        for value in self
+            .records
+            .iter_mut()
+            .filter_map(|x| x.record.data.as_mut())
+            .flat_map(|x| x.indirect_callsites.iter_mut())
+            .flatten()
+        {
+            if value.value == old_name_hash || value.value == old_hash {
+                value.value = new_hash;
+            }
+        }
+        true
+    }
+
+    /// Same as `is_single_byte_coverage`
     pub fn is_byte_coverage(&self) -> bool {
         self.is_byte_coverage
     }
 
+    /// Whether the profile came from a binary built with single byte coverage
+    /// (`-mllvm -enable-single-byte-coverage`). The raw counters are a byte which is cleared when
+    /// the code runs, they're read as a count of 1 for covered and 0 for not covered so the
+    /// counts are only booleans.
+    pub fn is_single_byte_coverage(&self) -> bool {
+        self.is_byte_coverage
+    }
+
     pub fn fn_entry_only(&self) -> bool {
         self.fn_entry_only
     }
 
-    pub fn merge(&mut self, other: &Self) {
+    /// Checks whether the profiles can be merged or compared without combining any records, this
+    /// is the check `merge` does before merging. Like llvm-profdata, front-end and IR profiles
+    /// can't be combined and neither can profiles which only count function entries and ones with
+    /// every counter. Context sensitive profiles can be combined with ones that aren't, and an
+    /// empty profile is compatible with anything. The profile versions don't matter as every
+    /// version is read into the same records.
+    pub fn assert_compatible_with(&self, other: &Self) -> Result<(), IncompatibilityReason> {
+        if self.records.is_empty() || other.records.is_empty() {
+            Ok(())
+        } else if self.is_ir != other.is_ir {
+            Err(IncompatibilityReason::Level {
+                expected: self.get_level(),
+                found: other.get_level(),
+            })
+        } else if self.fn_entry_only != other.fn_entry_only {
+            Err(IncompatibilityReason::FunctionEntryOnly {
+                expected: self.fn_entry_only,
+                found: other.fn_entry_only,
+            })
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Checks the profiles are compatible with `assert_compatible_with` unless mismatches are
+    /// allowed. An empty profile takes on the kind of the other.
+    fn merge_kind(&mut self, other: &Self, allow_mismatch: bool) -> Result<(), InstrProfError> {
+        if self.records.is_empty() {
+            self.is_ir = other.is_ir;
+            self.fn_entry_only = other.fn_entry_only;
+        } else if !allow_mismatch {
+            self.assert_compatible_with(other)?;
+        }
+        self.has_csir |= other.has_csir;
+        Ok(())
+    }
+
+    /// Scales the counters and value profiling counts of every record by
+    /// `numerator / denominator`, see `InstrProfRecord::scale`. Records are still scaled after one
+    /// saturates, the first error is returned.
+    pub fn scale(&mut self, numerator: u64, denominator: u64) -> Result<(), InstrProfError> {
+        if denominator == 0 {
+            return Err(InstrProfError::InvalidScale {
+                numerator,
+                denominator,
+            });
+        }
+        self.clear_stored_summary();
+        let mut result = Ok(());
+        for record in &mut self.records {
+            let res = record
+                .record
+                .scale(numerator, denominator)
+                .map_err(|e| e.with_function(record.name.as_deref()));
+            if result.is_ok() {
+                result = res;
+            }
+        }
+        result
+    }
+
+    /// Merges the records of another profile into this one. Records which can't be merged are
+    /// skipped and merging carries on, the first error encountered is returned. Profiles of
+    /// incompatible kinds aren't merged and `InstrProfError::KindMismatch` is returned.
+    pub fn merge(&mut self, other: &Self) -> Result<(), InstrProfError> {
+        self.merge_kind(other, false)?;
+        self.merge_with(other, InstrProfRecord::merge)
+    }
+
+    /// Merges another profile like `merge` but the counters and value profiling counts of
+    /// functions in both profiles are combined as set by the options
+    pub fn merge_with_options(
+        &mut self,
+        other: &Self,
+        options: &MergeOptions,
+    ) -> Result<(), InstrProfError> {
+        self.merge_kind(other, options.allow_kind_mismatch)?;
+        let combine: CombineRecords = match options.reduce {
+            Reduce::Sum => InstrProfRecord::add,
+            Reduce::Max => InstrProfRecord::max,
+        };
+        if options.resize_mismatched_counts {
+            self.merge_with(other, |own, other| own.combine_resized(other, combine))
+        } else {
+            self.merge_with(other, combine)
+        }
+    }
+
+    fn merge_with<F>(&mut self, other: &Self, combine: F) -> Result<(), InstrProfError>
+    where
+        F: Fn(&mut InstrProfRecord, &InstrProfRecord) -> Result<(), InstrProfError>,
+    {
+        self.clear_stored_summary();
+        self.checksum = ChecksumStatus::default();
         if self.version.is_none() && other.version.is_some() {
             self.version = other.version;
         }
+        // Like llvm-profdata a test vector is covered if it's covered in either profile
+        for (hash, bitmap) in &other.mcdc_bitmaps {
+            let own = self.mcdc_bitmaps.entry(*hash).or_default();
+            if own.len() < bitmap.len() {
+                own.resize(bitmap.len(), 0);
+            }
+            for (own, other) in own.iter_mut().zip(bitmap) {
+                *own |= *other;
+            }
+        }
+        let mut result = Ok(());
         for func in &other.records {
-            self.merge_record(func);
+            let res = self.merge_record_with(func, &combine);
+            if result.is_ok() {
+                result = res;
+            }
         }
+        result
     }
 
-    pub fn merge_record(&mut self, record: &NamedInstrProfRecord) {
+    pub fn merge_record(&mut self, record: &NamedInstrProfRecord) -> Result<(), InstrProfError> {
+        self.merge_record_with(record, InstrProfRecord::merge)
+    }
+
+    fn merge_record_with<F>(
+        &mut self,
+        record: &NamedInstrProfRecord,
+        combine: F,
+    ) -> Result<(), InstrProfError>
+    where
+        F: Fn(&mut InstrProfRecord, &InstrProfRecord) -> Result<(), InstrProfError>,
+    {
+        self.clear_stored_summary();
         if let Some(hash) = record.name_hash.as_ref() {
-            let added = if self.symtab.contains(*hash) {
-                // Find the record and merge things. 0 hashed records should have no counters in the
-                // code and otherwise we'll ignore the change that truncated md5 hashes can collide
-                if let Some(rec) = record
+            // Find the record and merge things. 0 hashed records should have no counters in the
+            // code and otherwise we'll ignore the change that truncated md5 hashes can collide
+            let existing = if record.name.is_none() {
+                // Without a name the hashes are all that identify the function
+                self.unnamed_record_lookup
+                    .get(&(record.name_hash, record.hash))
+                    .copied()
+            } else if self.symtab.contains(*hash)
+                || record
+                    .hash
+                    .map(|x| self.symtab.contains(x))
+                    .unwrap_or(false)
+            {
+                record
                     .name
-                    .as_ref()
-                    .and_then(|x| self.find_record_by_name_mut(x))
-                {
-                    rec.record.merge(&record.record);
-                    true
-                } else {
-                    false
+                    .as_deref()
+                    .and_then(|x| self.record_name_lookup.get(x).copied())
+            } else {
+                None
+            };
+            // Functions can share a name with a different function hash, i.e. static functions in
+            // different translation units. Like llvm-profdata these are kept as separate records
+            let existing =
+                existing.and_then(|index| match (self.records[index].hash, record.hash) {
+                    (Some(expected), Some(found)) if expected != found => {
+                        self.records.iter().position(|x| {
+                            x.hash == record.hash && x.name.is_some() && x.name == record.name
+                        })
+                    }
+                    _ => Some(index),
+                });
+            match existing {
+                Some(index) => {
+                    let rec = &mut self.records[index];
+                    combine(&mut rec.record, &record.record)
+                        .map_err(|e| e.with_function(record.name.as_deref()))?;
                 }
-            } else if let Some(alt_hash) = record.hash {
-                if self.symtab.contains(alt_hash) {
-                    if let Some(rec) = record
-                        .name
-                        .as_ref()
-                        .and_then(|x| self.find_record_by_name_mut(x))
-                    {
-                        rec.record.merge(&record.record);
-                        true
-                    } else {
-                        false
+                None => {
+                    if let Some(name) = record.name.as_ref() {
+                        self.symtab.names.insert(*hash, name.clone());
                     }
-                } else {
-                    false
+                    self.push_record(record.clone());
                 }
-            } else {
-                false
-            };
-            if !added {
-                self.symtab.names.insert(*hash, record.name_unchecked());
-                self.push_record(record.clone());
             }
         }
+        Ok(())
+    }
+
+    /// A new profile with the functions from both profiles, functions in both have their counts
+    /// and value profiling data added together like `Reduce::Sum`. The first error from merging a
+    /// function is returned.
+    pub fn union(&self, other: &Self) -> Result<Self, InstrProfError> {
+        let mut result = self.clone();
+        let options = MergeOptions {
+            reduce: Reduce::Sum,
+            ..Default::default()
+        };
+        result.merge_with_options(other, &options)?;
+        Ok(result)
+    }
+
+    /// A new profile with only the functions of this profile which are also in `other`, matched
+    /// by name and function hash. The counts are kept from this profile.
+    pub fn intersect(&self, other: &Self) -> Self {
+        let mut result = Self {
+            version: self.version,
+            has_csir: self.has_csir,
+            is_ir: self.is_ir,
+            is_entry_first: self.is_entry_first,
+            is_byte_coverage: self.is_byte_coverage,
+            fn_entry_only: self.fn_entry_only,
+            memory_profiling: self.memory_profiling,
+            ..Default::default()
+        };
+        let in_other = other
+            .records
+            .iter()
+            .filter(|x| x.name.is_some())
+            .map(|x| (x.name.as_deref(), x.hash))
+            .collect::<FxHashSet<_>>();
+        for record in &self.records {
+            if record.name.is_some() && in_other.contains(&(record.name.as_deref(), record.hash)) {
+                result.push_record(record.clone());
+            }
+        }
+        for (hash, name) in self.symtab.iter() {
+            if result.record_name_lookup.contains_key(name) {
+                result.symtab.add_func_name_with_hash(name.clone(), *hash);
+            }
+        }
+        result.mcdc_bitmaps = self
+            .mcdc_bitmaps
+            .iter()
+            .filter(|(hash, _)| {
+                result
+                    .symtab
+                    .get(**hash)
+                    .map(|x| result.record_name_lookup.contains_key(x))
+                    .unwrap_or(false)
+            })
+            .map(|(hash, bitmap)| (*hash, bitmap.clone()))
+            .collect();
+        result
+    }
+
+    /// The value profiling kinds with at least one value recorded in any of the records. Sites
+    /// without any values don't count, so a profile built with value profiling that never hit a
+    /// site returns an empty set.
+    pub fn value_profiling_kinds_present(&self) -> HashSet<ValueKind> {
+        let mut kinds = HashSet::new();
+        for data in self.records.iter().filter_map(|x| x.record.data.as_ref()) {
+            for kind in ValueKind::ALL {
+                if !kinds.contains(&kind) && data.sites(kind).iter().any(|x| !x.is_empty()) {
+                    kinds.insert(kind);
+                }
+            }
+            if kinds.len() == ValueKind::len() {
+                break;
+            }
+        }
+        kinds
+    }
+
+    /// Coalesces repeated targets in the value profiling sites of every record, see
+    /// `InstrProfRecord::dedup_value_sites`. Merging doesn't do this itself so call it once after
+    /// a series of merges.
+    pub fn merge_dedup_value_sites(&mut self) {
+        for record in &mut self.records {
+            record.record.dedup_value_sites();
+        }
+    }
+
+    /// The `n` targets with the highest counts at each value profiling site of the given kind in
+    /// the record, sorted by descending count like `llvm-profdata show --ic-targets` lists them.
+    /// Targets with the same count are kept in ascending order of value. Indirect call and vtable
+    /// targets are resolved to names with the symbol table.
+    pub fn top_targets(
+        &self,
+        record: &InstrProfRecord,
+        kind: ValueKind,
+        n: usize,
+    ) -> Vec<Vec<(ValueTarget, u64)>> {
+        record
+            .value_sites(kind)
+            .iter()
+            .map(|site| {
+                let mut site = site.clone();
+                site.sort_unstable();
+                site.sort_by_key(|x| Reverse(x.count));
+                site.into_iter()
+                    .take(n)
+                    .map(|x| (self.value_target(kind, x.value), x.count))
+                    .collect()
+            })
+            .collect()
+    }
+
+    /// Resolves a value recorded at a value profiling site of the given kind
+    pub fn value_target(&self, kind: ValueKind, value: u64) -> ValueTarget {
+        match kind {
+            ValueKind::IndirectCallTarget | ValueKind::VTableTarget => match self.symtab.get(value)
+            {
+                Some(name) => ValueTarget::Name(name.clone()),
+                None => ValueTarget::External(value),
+            },
+            ValueKind::MemOpSize => ValueTarget::Value(value),
+        }
+    }
+
+    /// Removes the value profiling data from every record so only the counters remain. Useful
+    /// when writing a smaller profile or one that doesn't reveal call targets.
+    pub fn strip_value_profiling(&mut self) {
+        for record in &mut self.records {
+            record.record.clear_value_data();
+        }
     }
 
     /// Gets the instrumentation record for the give function
@@ -252,16 +985,84 @@ impl InstrumentationProfile {
             .find(|x| x.name.as_deref() == Some(name))
     }
 
+    /// The source file of a function with internal linkage. LLVM prefixes the names of these
+    /// functions with their file so functions with the same name in different files don't clash,
+    /// as `file.c:name` for front-end instrumentation or `file.c;name` for IR instrumentation. The
+    /// name can be given with or without the prefix. `None` if the function isn't in the profile or
+    /// has external linkage so there's no file in the name.
+    pub fn source_file(&self, name: &str) -> Option<&str> {
+        self.records
+            .iter()
+            .filter_map(|x| x.name.as_deref())
+            .find_map(|full_name| {
+                let (file, function) = split_source_file(full_name)?;
+                (full_name == name || function == name).then_some(file)
+            })
+    }
+
+    /// The counters of the given function
+    pub fn counters_for(&self, name: &str) -> Option<&[u64]> {
+        self.get_record(name).map(|x| x.counts())
+    }
+
+    /// The counters of the function with the given name hash, see `compute_hash`
+    pub fn counters_for_hash(&self, hash: u64) -> Option<&[u64]> {
+        self.records
+            .iter()
+            .find(|x| x.name_hash == Some(hash))
+            .map(|x| x.counts())
+    }
+
+    /// The profile count of the record given the instrumentation of this profile, see
+    /// `InstrProfRecord::profile_count`
+    pub fn profile_count(&self, record: &InstrProfRecord) -> Option<u64> {
+        record.profile_count(
+            self.is_entry_first() || self.fn_entry_only(),
+            self.is_ir_level_profile(),
+        )
+    }
+
+    /// Whether the function of the record ran, which is whether any counter is non-zero like
+    /// `llvm-profdata show --covered`. For single byte coverage profiles (see
+    /// `is_single_byte_coverage`) the counters are only flags so this is all they tell, a count
+    /// of 1 doesn't mean the code ran once. Records with a pseudo count are covered.
+    pub fn coverage_status(&self, record: &InstrProfRecord) -> CoverageStatus {
+        if record.counts.iter().any(|x| *x > 0) {
+            CoverageStatus::Covered
+        } else {
+            CoverageStatus::NotCovered
+        }
+    }
+
+    /// The names of the functions which ran in the order of the records, see `coverage_status`
+    pub fn covered_functions(&self) -> impl Iterator<Item = &str> + '_ {
+        self.records
+            .iter()
+            .filter(move |x| self.coverage_status(&x.record) == CoverageStatus::Covered)
+            .filter_map(|x| x.name.as_deref())
+    }
+
     /// Returns true if there are no instrumentation records associated with the profile
     pub fn is_empty(&self) -> bool {
         self.records.is_empty() && self.symtab.is_empty()
     }
 }
 
+/// A function's profile record along with the information identifying the function. There are
+/// two hashes:
+///
+/// * The name hash, the truncated MD5 of the function name. This is what the symbol table is keyed
+///   on and what `__llvm_covfun` records refer to the function by (`FunctionRecordHeader::name_hash`).
+/// * The function hash, a structural hash of the function's control flow computed by the compiler.
+///   This changes whenever the function's code changes so it's used to check the counters match the
+///   coverage mapping (`FunctionRecordHeader::fn_hash`) and is what `llvm-profdata show` prints as
+///   `Hash:`. See `func_hash`.
 #[derive(Clone, Debug, Default, Eq, PartialEq, Hash)]
 pub struct NamedInstrProfRecord {
     pub name: Option<String>,
+    /// Truncated MD5 hash of the name, the key for the function in the symbol table
     pub name_hash: Option<u64>,
+    /// Structural hash of the function, prefer `func_hash` for reading it
     pub hash: Option<u64>,
     pub record: InstrProfRecord,
 }
@@ -271,13 +1072,7 @@ impl NamedInstrProfRecord {
     const CS_FLAG_IN_FUNC_HASH: u64 = 60;
 
     pub fn num_value_sites(&self, valuekind: ValueKind) -> usize {
-        use ValueKind::*;
-        let record_data = self.record.data.as_ref();
-        match valuekind {
-            IndirectCallTarget => record_data.map(|x| x.indirect_callsites.len()),
-            MemOpSize => record_data.map(|x| x.mem_op_sizes.len()),
-        }
-        .unwrap_or_default()
+        self.record.value_site_count(valuekind)
     }
 
     pub fn has_cs_flag(&self) -> bool {
@@ -294,6 +1089,13 @@ impl NamedInstrProfRecord {
         &self.record.counts
     }
 
+    /// The structural function hash, not to be confused with the name hash which is used to look
+    /// up names in the symbol table. Compare this to `FunctionRecordHeader::fn_hash` to check a
+    /// coverage mapping record matches the profile.
+    pub fn func_hash(&self) -> Option<u64> {
+        self.hash
+    }
+
     pub fn hash_unchecked(&self) -> u64 {
         self.hash.unwrap_or_default()
     }
@@ -301,9 +1103,61 @@ impl NamedInstrProfRecord {
     pub fn name_unchecked(&self) -> String {
         self.name.clone().unwrap_or_default()
     }
+
+    /// Signed per-counter difference from this record to `other`, see
+    /// `InstrProfRecord::counter_delta`. The function hashes must also match.
+    pub fn counter_delta(&self, other: &Self) -> Result<Vec<i64>, InstrProfError> {
+        let expected = self.hash_unchecked();
+        let found = other.hash_unchecked();
+        if expected != found {
+            return Err(InstrProfError::HashMismatch {
+                function: self.name.clone(),
+                expected,
+                found,
+            });
+        }
+        self.record
+            .counter_delta(&other.record)
+            .map_err(|e| e.with_function(self.name.as_deref()))
+    }
 }
 
-#[derive(Clone, Debug, Default, Eq, PartialEq, Hash)]
+/// Entry counts LLVM writes for functions which only have a hot or warm attribute and no real
+/// counts, the other counters are zero
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Ord, PartialOrd, Hash)]
+pub enum PseudoCount {
+    Warm,
+    Hot,
+}
+
+impl PseudoCount {
+    const HOT_VALUE: u64 = u64::MAX;
+    const WARM_VALUE: u64 = u64::MAX - 1;
+}
+
+/// Whether a function ran, see `InstrumentationProfile::coverage_status`
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum CoverageStatus {
+    Covered,
+    NotCovered,
+}
+
+/// Splits a function name into the file prefix and the function name. IR names use `;` as the
+/// separator which doesn't appear in names, front-end names use `:` and the last one is used so
+/// Windows paths with a drive letter still work.
+fn split_source_file(name: &str) -> Option<(&str, &str)> {
+    let (file, function) = name.rsplit_once(';').or_else(|| name.rsplit_once(':'))?;
+    if file.is_empty() || function.is_empty() {
+        None
+    } else {
+        Some((file, function))
+    }
+}
+
+/// Equality and hashing only look at the profile content: `counts_bytes_offset` is ignored, no
+/// value data is the same as empty value data and the values in a site can be in any order. This
+/// lets records read from different formats compare equal.
+#[derive(Clone, Debug, Default)]
 pub struct InstrProfRecord {
     pub counts: Vec<u64>,
     pub data: Option<Box<ValueProfDataRecord>>,
@@ -311,11 +1165,163 @@ pub struct InstrProfRecord {
     pub counts_bytes_offset: Range<isize>,
 }
 
+impl PartialEq for InstrProfRecord {
+    fn eq(&self, other: &Self) -> bool {
+        self.counts == other.counts
+            && self.zero == other.zero
+            && self.canonical_value_data() == other.canonical_value_data()
+    }
+}
+
+impl Eq for InstrProfRecord {}
+
+impl Hash for InstrProfRecord {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.counts.hash(state);
+        self.zero.hash(state);
+        self.canonical_value_data().hash(state);
+    }
+}
+
 impl InstrProfRecord {
-    pub fn merge(&mut self, other: &Self) {
-        if self.counts.len() != other.counts.len() {
+    /// The `(value, count)` pairs of every site for each kind with the pairs in a site sorted
+    fn canonical_value_data(&self) -> Vec<Vec<Vec<(u64, u64)>>> {
+        ValueKind::ALL
+            .iter()
+            .map(|kind| {
+                self.value_sites(*kind)
+                    .iter()
+                    .map(|site| {
+                        let mut values =
+                            site.iter().map(|x| (x.value, x.count)).collect::<Vec<_>>();
+                        values.sort_unstable();
+                        values
+                    })
+                    .collect()
+            })
+            .collect()
+    }
+
+    /// The value profiling sites of the given kind, empty if the record has no value data
+    pub fn value_sites(&self, kind: ValueKind) -> &[Vec<InstrProfValueData>] {
+        match self.data.as_ref() {
+            Some(data) => data.sites(kind),
+            None => &[],
+        }
+    }
+
+    /// The number of value profiling sites of the given kind, 0 if the record has no value data
+    pub fn value_site_count(&self, kind: ValueKind) -> usize {
+        self.value_sites(kind).len()
+    }
+
+    /// Sums the counts of targets which appear more than once in a value profiling site so each
+    /// target appears once, then sorts the targets by descending count like LLVM does after
+    /// merging. Targets with the same count are kept in ascending order of value.
+    pub fn dedup_value_sites(&mut self) {
+        let data = match self.data.as_mut() {
+            Some(data) => data,
+            None => return,
+        };
+        for kind in ValueKind::ALL {
+            for site in data.sites_mut(kind) {
+                site.sort_unstable();
+                site.dedup_by(|value, kept| {
+                    let duplicate = value.value == kept.value;
+                    if duplicate {
+                        kept.count = kept.count.saturating_add(value.count);
+                    }
+                    duplicate
+                });
+                site.sort_by_key(|x| Reverse(x.count));
+            }
+        }
+    }
+
+    /// Keeps only the first `n` counters, records with `n` or fewer counters are unchanged. Value
+    /// profiling sites aren't tied to counters so they're kept. `counts_bytes_offset` is
+    /// shortened to the kept counters so zeroing it in the raw file only clears those. The
+    /// function hash still describes all the counters, so the record no longer matches the
+    /// function's coverage mapping.
+    pub fn truncate_counters(&mut self, n: usize) {
+        let len = self.counts.len();
+        if n >= len {
             return;
         }
+        let counter_size = self.counts_bytes_offset.len() / len;
+        self.counts_bytes_offset.end = self.counts_bytes_offset.start + (n * counter_size) as isize;
+        self.counts.truncate(n);
+    }
+
+    /// Removes all the value profiling sites keeping the counters
+    pub fn clear_value_data(&mut self) {
+        self.data = None;
+    }
+
+    /// The function's entry count, the first counter, or `None` if there are no counters. Like
+    /// LLVM's profile summary this is also used for IR profiles without entry first
+    /// instrumentation, where the first counter is whichever block the compiler instrumented
+    /// first. For records with a pseudo count this is the pseudo count's value.
+    pub fn entry_count(&self) -> Option<u64> {
+        self.counts.first().copied()
+    }
+
+    /// The largest count of the internal blocks, which are all the counters after the entry
+    /// count. `None` if there's only the entry counter. This is the per record value of the
+    /// profile summary's maximum internal block count.
+    pub fn max_internal_block_count(&self) -> Option<u64> {
+        self.counts.iter().skip(1).copied().max()
+    }
+
+    /// The count LLVM gives the function when the profile is used, its entry count:
+    ///
+    /// * Front-end instrumentation always counts the function body first so it's the first
+    ///   counter.
+    /// * IR instrumentation with `entry_first` (`-pgo-instrument-entry`, and function entry only
+    ///   profiles) also puts the entry block's counter first.
+    /// * Otherwise IR counters are on the edges of the control flow graph and LLVM only recovers
+    ///   the entry count by propagating the counts through the graph, so this is `None`.
+    ///
+    /// Records with a pseudo count have no real counts, LLVM only marks the function hot or warm,
+    /// so they and records without counters are also `None`. See
+    /// `InstrumentationProfile::profile_count` to use the flags of a profile.
+    pub fn profile_count(&self, entry_first: bool, is_ir: bool) -> Option<u64> {
+        if self.pseudo_count().is_some() || (is_ir && !entry_first) {
+            None
+        } else {
+            self.entry_count()
+        }
+    }
+
+    /// The pseudo count of the record if the entry count is one
+    pub fn pseudo_count(&self) -> Option<PseudoCount> {
+        match self.counts.first() {
+            Some(&PseudoCount::HOT_VALUE) => Some(PseudoCount::Hot),
+            Some(&PseudoCount::WARM_VALUE) => Some(PseudoCount::Warm),
+            _ => None,
+        }
+    }
+
+    /// Pseudo counts aren't real counts so like LLVM they're never added together, if both
+    /// records have one the hottest is kept. A pseudo count can't be combined with real counts.
+    fn merge_pseudo_count(&mut self, other: &Self) -> Result<(), InstrProfError> {
+        match (self.pseudo_count(), other.pseudo_count()) {
+            (Some(own), Some(other)) => {
+                self.counts[0] = match own.max(other) {
+                    PseudoCount::Hot => PseudoCount::HOT_VALUE,
+                    PseudoCount::Warm => PseudoCount::WARM_VALUE,
+                };
+                Ok(())
+            }
+            _ => Err(InstrProfError::PseudoCountMismatch { function: None }),
+        }
+    }
+
+    pub fn merge(&mut self, other: &Self) -> Result<(), InstrProfError> {
+        self.check_compatible(other)?;
+        if self.pseudo_count().is_some() || other.pseudo_count().is_some() {
+            return self.merge_pseudo_count(other);
+        }
         for (own, other_count) in self.counts.iter_mut().zip(other.counts.iter()) {
             let set_to_null = *own == *other_count;
 
@@ -323,23 +1329,183 @@ impl InstrProfRecord {
                 self.zero = true;
             }
         }
-        // TODO merge the data
-        if let Some((own, other)) = self.data.as_mut().zip(other.data.as_ref()) {
-            if own.indirect_callsites.len() == other.indirect_callsites.len() {
-                for (own, other) in own
-                    .indirect_callsites
-                    .iter_mut()
-                    .zip(other.indirect_callsites.iter())
-                {
-                    merge_site_records(own, other);
+        self.combine_value_data(other, merge_site_records);
+        Ok(())
+    }
+
+    /// Adds the counts and value profiling data of another record for the same function to this
+    /// one. Counters saturate on overflow, when this happens the addition still completes and a
+    /// warning is returned.
+    pub fn add(&mut self, other: &Self) -> Result<(), InstrProfError> {
+        self.check_compatible(other)?;
+        if self.pseudo_count().is_some() || other.pseudo_count().is_some() {
+            return self.merge_pseudo_count(other);
+        }
+        let mut overflow = None;
+        for (index, (own, other_count)) in self.counts.iter_mut().zip(&other.counts).enumerate() {
+            *own = own.checked_add(*other_count).unwrap_or_else(|| {
+                overflow.get_or_insert(index);
+                u64::MAX
+            });
+        }
+        self.combine_value_data(other, add_site_records);
+        match overflow {
+            Some(index) => Err(InstrProfWarning::CounterOverflow {
+                function: None,
+                index,
+            }
+            .into()),
+            None => Ok(()),
+        }
+    }
+
+    /// Keeps the larger of each counter and value profiling count of another record for the same
+    /// function. Values only in the other record are added.
+    pub fn max(&mut self, other: &Self) -> Result<(), InstrProfError> {
+        self.check_compatible(other)?;
+        if self.pseudo_count().is_some() || other.pseudo_count().is_some() {
+            return self.merge_pseudo_count(other);
+        }
+        for (own, other_count) in self.counts.iter_mut().zip(&other.counts) {
+            *own = (*own).max(*other_count);
+        }
+        self.combine_value_data(other, max_site_records);
+        Ok(())
+    }
+
+    /// Scales the counts and value profiling counts by `numerator / denominator`. Counters which
+    /// no longer fit in a `u64` saturate and a warning is returned.
+    pub fn scale(&mut self, numerator: u64, denominator: u64) -> Result<(), InstrProfError> {
+        if denominator == 0 {
+            return Err(InstrProfError::InvalidScale {
+                numerator,
+                denominator,
+            });
+        }
+        let scale = |count: u64| -> Option<u64> {
+            ((count as u128 * numerator as u128) / denominator as u128)
+                .try_into()
+                .ok()
+        };
+        let mut overflow = None;
+        for (index, count) in self.counts.iter_mut().enumerate() {
+            *count = scale(*count).unwrap_or_else(|| {
+                overflow.get_or_insert(index);
+                u64::MAX
+            });
+        }
+        if let Some(data) = self.data.as_mut() {
+            for value in data
+                .indirect_callsites
+                .iter_mut()
+                .chain(data.mem_op_sizes.iter_mut())
+                .chain(data.vtable_targets.iter_mut())
+                .flatten()
+            {
+                value.count = scale(value.count).unwrap_or(u64::MAX);
+            }
+        }
+        match overflow {
+            Some(index) => Err(InstrProfWarning::CounterOverflow {
+                function: None,
+                index,
+            }
+            .into()),
+            None => Ok(()),
+        }
+    }
+
+    /// Signed difference of each counter in `other` from the counter in this record, so a
+    /// positive value means the count went up. Differences that don't fit in an `i64` saturate.
+    /// The records must have the same number of counters.
+    pub fn counter_delta(&self, other: &Self) -> Result<Vec<i64>, InstrProfError> {
+        if self.counts.len() != other.counts.len() {
+            return Err(InstrProfError::CountMismatch {
+                function: None,
+                expected: self.counts.len(),
+                found: other.counts.len(),
+            });
+        }
+        let delta = self
+            .counts
+            .iter()
+            .zip(&other.counts)
+            .map(|(own, other)| {
+                let diff = i128::from(*other) - i128::from(*own);
+                diff.clamp(i64::MIN.into(), i64::MAX.into()) as i64
+            })
+            .collect();
+        Ok(delta)
+    }
+
+    /// Combines another record with this one even if the number of counters differs, the other
+    /// record's counters are padded with zeros or truncated to the length of this one's. When they
+    /// had to be resized a `CountsResized` warning is returned unless combining failed.
+    fn combine_resized(
+        &mut self,
+        other: &Self,
+        combine: CombineRecords,
+    ) -> Result<(), InstrProfError> {
+        let expected = self.counts.len();
+        let found = other.counts.len();
+        if expected == found {
+            return combine(self, other);
+        }
+        let mut resized = other.clone();
+        resized.counts.resize(expected, 0);
+        combine(self, &resized)?;
+        Err(InstrProfWarning::CountsResized {
+            function: None,
+            expected,
+            found,
+        }
+        .into())
+    }
+
+    /// Combines the value profiling data of another record with this one site by site. If only
+    /// the other record has value data it's copied over rather than dropped.
+    fn combine_value_data(
+        &mut self,
+        other: &Self,
+        combine: fn(&mut InstrProfValueSiteRecord, &InstrProfValueSiteRecord),
+    ) {
+        match (self.data.as_mut(), other.data.as_ref()) {
+            (Some(own), Some(other)) => {
+                for kind in ValueKind::ALL {
+                    for (own, other) in own.sites_mut(kind).iter_mut().zip(other.sites(kind)) {
+                        combine(own, other);
+                    }
                 }
             }
-            if own.mem_op_sizes.len() == other.mem_op_sizes.len() {
-                for (own, other) in own.mem_op_sizes.iter_mut().zip(other.mem_op_sizes.iter()) {
-                    merge_site_records(own, other);
+            (None, Some(other)) => self.data = Some(other.clone()),
+            _ => {}
+        }
+    }
+
+    /// Checks the counters and value sites line up so the records can be combined
+    fn check_compatible(&self, other: &Self) -> Result<(), InstrProfError> {
+        if self.counts.len() != other.counts.len() {
+            return Err(InstrProfError::CountMismatch {
+                function: None,
+                expected: self.counts.len(),
+                found: other.counts.len(),
+            });
+        }
+        if let Some((own, other)) = self.data.as_ref().zip(other.data.as_ref()) {
+            for kind in ValueKind::ALL {
+                let expected = own.sites(kind).len();
+                let found = other.sites(kind).len();
+                if expected != found {
+                    return Err(InstrProfError::ValueSiteCountMismatch {
+                        function: None,
+                        kind,
+                        expected,
+                        found,
+                    });
                 }
             }
         }
+        Ok(())
     }
 }
 
@@ -347,6 +1513,25 @@ impl InstrProfRecord {
 pub struct ValueProfDataRecord {
     pub indirect_callsites: Vec<InstrProfValueSiteRecord>,
     pub mem_op_sizes: Vec<InstrProfValueSiteRecord>,
+    pub vtable_targets: Vec<InstrProfValueSiteRecord>,
+}
+
+impl ValueProfDataRecord {
+    pub fn sites(&self, kind: ValueKind) -> &Vec<InstrProfValueSiteRecord> {
+        match kind {
+            ValueKind::IndirectCallTarget => &self.indirect_callsites,
+            ValueKind::MemOpSize => &self.mem_op_sizes,
+            ValueKind::VTableTarget => &self.vtable_targets,
+        }
+    }
+
+    pub fn sites_mut(&mut self, kind: ValueKind) -> &mut Vec<InstrProfValueSiteRecord> {
+        match kind {
+            ValueKind::IndirectCallTarget => &mut self.indirect_callsites,
+            ValueKind::MemOpSize => &mut self.mem_op_sizes,
+            ValueKind::VTableTarget => &mut self.vtable_targets,
+        }
+    }
 }
 
 type InstrProfValueSiteRecord = Vec<InstrProfValueData>;
@@ -383,12 +1568,58 @@ fn merge_site_records(dst: &mut InstrProfValueSiteRecord, src: &InstrProfValueSi
     }
 }
 
+/// Adds the counts of matching values together, values only present in `src` are appended
+fn add_site_records(dst: &mut InstrProfValueSiteRecord, src: &InstrProfValueSiteRecord) {
+    for value in src {
+        match dst.iter_mut().find(|x| x.value == value.value) {
+            Some(existing) => existing.count = existing.count.saturating_add(value.count),
+            None => dst.push(value.clone()),
+        }
+    }
+    dst.sort_unstable();
+}
+
+/// Keeps the larger count of matching values, values only present in `src` are appended
+fn max_site_records(dst: &mut InstrProfValueSiteRecord, src: &InstrProfValueSiteRecord) {
+    for value in src {
+        match dst.iter_mut().find(|x| x.value == value.value) {
+            Some(existing) => existing.count = existing.count.max(value.count),
+            None => dst.push(value.clone()),
+        }
+    }
+    dst.sort_unstable();
+}
+
 #[derive(Clone, Debug, Default, Eq, Hash)]
 pub struct InstrProfValueData {
     pub value: u64,
     pub count: u64,
 }
 
+/// A value profiling target with its name resolved where the value is a name hash
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum ValueTarget {
+    /// Indirect call or vtable target found in the symbol table
+    Name(String),
+    /// Name hash of an indirect call or vtable target missing from the symbol table, usually a
+    /// function in another binary
+    External(u64),
+    /// Values which aren't name hashes such as memory operation sizes
+    Value(u64),
+}
+
+/// Formats targets the same as the text profile, missing targets are shown as
+/// `** External Symbol **`
+impl fmt::Display for ValueTarget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Name(name) => write!(f, "{}", name),
+            Self::External(_) => write!(f, "** External Symbol **"),
+            Self::Value(value) => write!(f, "{}", value),
+        }
+    }
+}
+
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub struct ValueProfData {
     pub(crate) total_size: u32,
@@ -441,3 +1672,625 @@ pub enum MCDCParams {
     Branch(BranchParameters),
     Decision(DecisionParameters),
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(counts: Vec<u64>) -> InstrProfRecord {
+        InstrProfRecord {
+            counts,
+            ..Default::default()
+        }
+    }
+
+    fn named_record(name: &str, hash: u64, counts: Vec<u64>) -> NamedInstrProfRecord {
+        NamedInstrProfRecord {
+            name: Some(name.to_string()),
+            name_hash: Some(compute_hash(name)),
+            hash: Some(hash),
+            record: record(counts),
+        }
+    }
+
+    #[test]
+    fn compatibility() {
+        let profile = |is_ir: bool, has_csir: bool, fn_entry_only: bool| {
+            let mut profile = InstrumentationProfile::new(Some(10), has_csir, is_ir, false);
+            profile.fn_entry_only = fn_entry_only;
+            profile.push_record(named_record("foo", 1, vec![1, 2]));
+            profile
+        };
+        let front_end = profile(false, false, false);
+        let ir = profile(true, false, false);
+        let cs_ir = profile(true, true, false);
+        let ir_entry_only = profile(true, false, true);
+
+        assert_eq!(ir.assert_compatible_with(&ir), Ok(()));
+        assert_eq!(ir.assert_compatible_with(&cs_ir), Ok(()));
+        assert_eq!(cs_ir.assert_compatible_with(&ir), Ok(()));
+
+        let level = front_end.assert_compatible_with(&cs_ir).unwrap_err();
+        assert_eq!(
+            level,
+            IncompatibilityReason::Level {
+                expected: InstrumentationLevel::FrontEnd,
+                found: InstrumentationLevel::Ir
+            }
+        );
+        assert_eq!(
+            level.to_string(),
+            "Front-end and IR instrumentation profiles can't be combined"
+        );
+
+        let entry_only = ir.assert_compatible_with(&ir_entry_only).unwrap_err();
+        assert_eq!(
+            entry_only,
+            IncompatibilityReason::FunctionEntryOnly {
+                expected: false,
+                found: true
+            }
+        );
+        assert_eq!(
+            entry_only.to_string(),
+            "block profiles can't be combined with function entry only profiles"
+        );
+
+        // An empty profile takes on the kind of the other when merged
+        let empty = InstrumentationProfile::default();
+        assert_eq!(empty.assert_compatible_with(&ir_entry_only), Ok(()));
+        assert_eq!(front_end.assert_compatible_with(&empty), Ok(()));
+
+        // The check matches what merging does
+        assert!(front_end.clone().merge(&ir).is_err());
+        assert!(ir.clone().merge(&ir_entry_only).is_err());
+        assert!(ir.clone().merge(&cs_ir).is_ok());
+    }
+
+    #[test]
+    fn profile_count() {
+        let counts = record(vec![3, 7, 5]);
+        // Front-end profiles always count the function first
+        assert_eq!(counts.profile_count(false, false), Some(3));
+        assert_eq!(counts.profile_count(true, false), Some(3));
+        assert_eq!(counts.profile_count(true, true), Some(3));
+        // IR edge counters need the control flow graph to find the entry count
+        assert_eq!(counts.profile_count(false, true), None);
+
+        let profile = |is_ir: bool, is_entry_first: bool, fn_entry_only: bool| {
+            let mut profile = InstrumentationProfile::new(Some(10), false, is_ir, is_entry_first);
+            profile.fn_entry_only = fn_entry_only;
+            profile
+        };
+        assert_eq!(profile(false, false, false).profile_count(&counts), Some(3));
+        assert_eq!(profile(true, true, false).profile_count(&counts), Some(3));
+        assert_eq!(profile(true, false, true).profile_count(&counts), Some(3));
+        assert_eq!(profile(true, false, false).profile_count(&counts), None);
+
+        for (entry_first, is_ir) in [(false, false), (true, true), (false, true)] {
+            assert_eq!(record(vec![]).profile_count(entry_first, is_ir), None);
+            let hot = record(vec![PseudoCount::HOT_VALUE, 0]);
+            assert_eq!(hot.profile_count(entry_first, is_ir), None);
+        }
+    }
+
+    #[test]
+    fn add_records() {
+        let mut a = record(vec![1, 2, 3]);
+        a.add(&record(vec![4, 5, 6])).unwrap();
+        assert_eq!(a.counts, vec![5, 7, 9]);
+    }
+
+    #[test]
+    fn truncate_counters() {
+        let mut a = record(vec![1, 2, 3, 4]);
+        a.counts_bytes_offset = 16..48;
+        a.data = Some(Box::new(ValueProfDataRecord {
+            indirect_callsites: vec![vec![InstrProfValueData { value: 1, count: 2 }]],
+            ..Default::default()
+        }));
+        a.truncate_counters(2);
+        assert_eq!(a.counts, vec![1, 2]);
+        assert_eq!(a.counts_bytes_offset, 16..32);
+        assert_eq!(a.value_site_count(ValueKind::IndirectCallTarget), 1);
+
+        // Truncating to at least the current length changes nothing
+        a.truncate_counters(5);
+        assert_eq!(a.counts, vec![1, 2]);
+        assert_eq!(a.counts_bytes_offset, 16..32);
+
+        a.truncate_counters(0);
+        assert!(a.counts.is_empty());
+        assert!(a.counts_bytes_offset.is_empty());
+    }
+
+    #[test]
+    fn counter_deltas() {
+        let a = named_record("foo", 10, vec![5, 2, 0, u64::MAX]);
+        let b = named_record("foo", 10, vec![3, 2, 7, 0]);
+        assert_eq!(a.counter_delta(&b).unwrap(), vec![-2, 0, 7, i64::MIN]);
+        assert_eq!(b.counter_delta(&a).unwrap(), vec![2, 0, -7, i64::MAX]);
+
+        let err = a
+            .counter_delta(&named_record("foo", 11, vec![5, 2, 0, 0]))
+            .unwrap_err();
+        assert_eq!(
+            err,
+            InstrProfError::HashMismatch {
+                function: Some("foo".to_string()),
+                expected: 10,
+                found: 11
+            }
+        );
+        let err = a
+            .counter_delta(&named_record("foo", 10, vec![5, 2]))
+            .unwrap_err();
+        assert_eq!(
+            err,
+            InstrProfError::CountMismatch {
+                function: Some("foo".to_string()),
+                expected: 4,
+                found: 2
+            }
+        );
+    }
+
+    #[test]
+    fn count_mismatch() {
+        let mut a = record(vec![1, 2, 3]);
+        let err = a.add(&record(vec![1, 2])).unwrap_err();
+        assert_eq!(
+            err,
+            InstrProfError::CountMismatch {
+                function: None,
+                expected: 3,
+                found: 2
+            }
+        );
+        assert_eq!(a.counts, vec![1, 2, 3]);
+        assert!(a.merge(&record(vec![1])).is_err());
+    }
+
+    #[test]
+    fn counter_overflow() {
+        let mut a = record(vec![1, u64::MAX, u64::MAX]);
+        let err = a.add(&record(vec![1, 1, 1])).unwrap_err();
+        assert!(err.is_warning());
+        assert_eq!(
+            err,
+            InstrProfWarning::CounterOverflow {
+                function: None,
+                index: 1
+            }
+            .into()
+        );
+        assert_eq!(a.counts, vec![2, u64::MAX, u64::MAX]);
+    }
+
+    #[test]
+    fn value_site_mismatch() {
+        let mut a = record(vec![1]);
+        a.data = Some(Box::new(ValueProfDataRecord {
+            indirect_callsites: vec![vec![]],
+            mem_op_sizes: vec![],
+            vtable_targets: vec![],
+        }));
+        let mut b = record(vec![1]);
+        b.data = Some(Box::default());
+        let err = a.add(&b).unwrap_err();
+        assert_eq!(
+            err,
+            InstrProfError::ValueSiteCountMismatch {
+                function: None,
+                kind: ValueKind::IndirectCallTarget,
+                expected: 1,
+                found: 0,
+            }
+        );
+    }
+
+    #[test]
+    fn merge_value_data_into_plain_record() {
+        let profiled = || {
+            let mut record = record(vec![3, 1]);
+            record.data = Some(Box::new(ValueProfDataRecord {
+                indirect_callsites: vec![vec![InstrProfValueData {
+                    value: 0x1234,
+                    count: 3,
+                }]],
+                mem_op_sizes: vec![],
+                vtable_targets: vec![],
+            }));
+            record
+        };
+        let combines: [CombineRecords; 3] = [
+            InstrProfRecord::add,
+            InstrProfRecord::max,
+            InstrProfRecord::merge,
+        ];
+        for combine in combines {
+            // Value data is kept whichever side of the merge it's on
+            let mut plain = record(vec![2, 2]);
+            combine(&mut plain, &profiled()).unwrap();
+            assert_eq!(plain.data, profiled().data);
+
+            let mut with_data = profiled();
+            combine(&mut with_data, &record(vec![2, 2])).unwrap();
+            assert_eq!(with_data.data, profiled().data);
+        }
+
+        let mut plain = record(vec![2, 2]);
+        plain.add(&profiled()).unwrap();
+        assert_eq!(plain.counts, vec![5, 3]);
+    }
+
+    #[test]
+    fn top_value_site_targets() {
+        let value = |value, count| InstrProfValueData { value, count };
+        let mut profile = InstrumentationProfile::default();
+        for name in ["foo", "bar", "baz"] {
+            profile.symtab.add_func_name(name.to_string(), None);
+        }
+        let mut main = named_record("main", 1, vec![10]);
+        main.record.data = Some(Box::new(ValueProfDataRecord {
+            indirect_callsites: vec![
+                vec![
+                    value(compute_hash("foo"), 5),
+                    value(compute_hash("bar"), 20),
+                    value(7, 1),
+                    value(compute_hash("baz"), 5),
+                ],
+                vec![],
+            ],
+            mem_op_sizes: vec![vec![value(8, 3), value(16, 4)]],
+            vtable_targets: vec![],
+        }));
+        let record = &main.record;
+
+        let (first, second) = if compute_hash("foo") < compute_hash("baz") {
+            ("foo", "baz")
+        } else {
+            ("baz", "foo")
+        };
+        let name = |name: &str| ValueTarget::Name(name.to_string());
+        assert_eq!(
+            profile.top_targets(record, ValueKind::IndirectCallTarget, 3),
+            vec![
+                vec![(name("bar"), 20), (name(first), 5), (name(second), 5)],
+                vec![]
+            ]
+        );
+        let all = profile.top_targets(record, ValueKind::IndirectCallTarget, 10);
+        assert_eq!(all[0].len(), 4);
+        assert_eq!(all[0][3], (ValueTarget::External(7), 1));
+        assert_eq!(all[0][3].0.to_string(), "** External Symbol **");
+        assert_eq!(
+            profile.top_targets(record, ValueKind::MemOpSize, 1),
+            vec![vec![(ValueTarget::Value(16), 4)]]
+        );
+        assert!(profile
+            .top_targets(record, ValueKind::VTableTarget, 1)
+            .is_empty());
+    }
+
+    #[test]
+    fn dedup_value_site_targets() {
+        let value = |value, count| InstrProfValueData { value, count };
+        let mut profile = InstrumentationProfile::default();
+        let mut foo = named_record("foo", 1, vec![10]);
+        foo.record.data = Some(Box::new(ValueProfDataRecord {
+            indirect_callsites: vec![
+                vec![
+                    value(3, 5),
+                    value(1, 2),
+                    value(3, 4),
+                    value(2, 9),
+                    value(1, 7),
+                ],
+                vec![],
+            ],
+            mem_op_sizes: vec![vec![value(8, u64::MAX), value(8, 1), value(16, 1)]],
+            vtable_targets: vec![],
+        }));
+        profile.push_record(foo);
+        profile.push_record(named_record("bar", 2, vec![1]));
+
+        profile.merge_dedup_value_sites();
+        let foo = &profile.get_record("foo").unwrap().record;
+        let site = |kind, index: usize| {
+            foo.value_sites(kind)[index]
+                .iter()
+                .map(|x| (x.value, x.count))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            site(ValueKind::IndirectCallTarget, 0),
+            [(1, 9), (2, 9), (3, 9)]
+        );
+        assert!(site(ValueKind::IndirectCallTarget, 1).is_empty());
+        assert_eq!(site(ValueKind::MemOpSize, 0), [(8, u64::MAX), (16, 1)]);
+        assert!(profile.get_record("bar").unwrap().record.data.is_none());
+    }
+
+    #[test]
+    fn scale_records() {
+        let mut a = record(vec![10, 20, u64::MAX]);
+        assert_eq!(
+            a.scale(1, 0),
+            Err(InstrProfError::InvalidScale {
+                numerator: 1,
+                denominator: 0
+            })
+        );
+        let err = a.scale(3, 2).unwrap_err();
+        assert!(err.is_warning());
+        assert_eq!(a.counts, vec![15, 30, u64::MAX]);
+    }
+
+    #[test]
+    fn rename_function() {
+        let mut profile = InstrumentationProfile::default();
+        profile
+            .merge_record(&named_record("foo", 1, vec![1, 2]))
+            .unwrap();
+        let mut caller = named_record("bar", 2, vec![1]);
+        caller.record.data = Some(Box::new(ValueProfDataRecord {
+            indirect_callsites: vec![vec![InstrProfValueData {
+                value: compute_hash("foo"),
+                count: 3,
+            }]],
+            mem_op_sizes: vec![],
+            vtable_targets: vec![],
+        }));
+        profile.merge_record(&caller).unwrap();
+
+        assert!(!profile.rename_function("missing", "baz"));
+        assert!(!profile.rename_function("foo", "bar"));
+        assert!(profile.rename_function("foo", "baz"));
+
+        assert!(profile.get_record("foo").is_none());
+        assert!(profile.find_record_by_name("foo").is_none());
+        let renamed = profile.get_record("baz").unwrap();
+        assert_eq!(renamed.hash, Some(1));
+        assert_eq!(renamed.counts(), &[1, 2]);
+        assert_eq!(profile.find_record_by_name("baz"), Some(renamed));
+        assert_eq!(
+            profile.symtab.get(compute_hash("baz")).map(|x| x.as_str()),
+            Some("baz")
+        );
+        assert!(!profile.symtab.contains(compute_hash("foo")));
+
+        let caller = profile.get_record("bar").unwrap();
+        let targets = &caller.record.data.as_ref().unwrap().indirect_callsites[0];
+        assert_eq!(targets[0].value, compute_hash("baz"));
+    }
+
+    #[test]
+    fn shrink_after_retain() {
+        let mut profile = InstrumentationProfile::default();
+        for i in 0..100 {
+            let name = format!("fn_{}", i);
+            profile.push_record(named_record(&name, i, vec![i; 4]));
+            profile.symtab.add_func_name(name, None);
+        }
+        profile.retain_records(|x| x.hash_unchecked() % 10 == 0);
+        assert_eq!(profile.records().len(), 10);
+        assert!(profile.records.capacity() >= 100);
+        assert!(profile.record_name_lookup.capacity() >= 100);
+
+        profile.shrink_to_fit();
+        assert!(profile.records.capacity() < 100);
+        assert!(profile.record_name_lookup.capacity() < 100);
+        // Lookups still work after the records move
+        for i in (0..100).step_by(10) {
+            let name = format!("fn_{}", i);
+            assert_eq!(profile.find_record_by_name(&name).unwrap().counts(), [i; 4]);
+            assert_eq!(
+                profile
+                    .find_record_by_hash(compute_hash(&name))
+                    .unwrap()
+                    .hash,
+                Some(i)
+            );
+        }
+        assert!(profile.find_record_by_name("fn_1").is_none());
+    }
+
+    #[test]
+    fn merge_unnamed_records() {
+        let unnamed = |name_hash: u64, hash: u64, counts: Vec<u64>| NamedInstrProfRecord {
+            name: None,
+            name_hash: Some(name_hash),
+            hash: Some(hash),
+            record: record(counts),
+        };
+        let mut profile = InstrumentationProfile::default();
+        for i in 0..100 {
+            profile.push_record(unnamed(i, i + 1, vec![i]));
+        }
+        // Only records with both hashes the same are combined
+        let mut other = InstrumentationProfile::default();
+        for i in 0..100 {
+            other.push_record(unnamed(i, i + 1, vec![1]));
+            other.push_record(unnamed(i, i + 2, vec![1]));
+        }
+        profile.merge_with(&other, InstrProfRecord::add).unwrap();
+        assert_eq!(profile.records().len(), 200);
+        assert_eq!(profile.records()[10].counts(), [11]);
+
+        // The lookup follows the records when they move
+        profile.retain_records(|x| x.name_hash.unwrap_or_default() % 2 == 1);
+        profile
+            .merge_record_with(&unnamed(11, 12, vec![5]), InstrProfRecord::add)
+            .unwrap();
+        assert_eq!(profile.records().len(), 100);
+        assert_eq!(profile.records()[5].counts(), [17]);
+    }
+
+    #[test]
+    fn merge_mismatched_counts() {
+        let mut base = InstrumentationProfile::default();
+        base.merge_record(&named_record("foo", 1, vec![1, 2, 3]))
+            .unwrap();
+        let mut shorter = InstrumentationProfile::default();
+        shorter
+            .merge_record(&named_record("foo", 1, vec![10, 20]))
+            .unwrap();
+        let mut longer = InstrumentationProfile::default();
+        longer
+            .merge_record(&named_record("foo", 1, vec![10, 20, 30, 40]))
+            .unwrap();
+
+        // Strict by default, the record is left alone
+        let mut strict = base.clone();
+        let err = strict
+            .merge_with_options(&shorter, &MergeOptions::default())
+            .unwrap_err();
+        assert_eq!(
+            err,
+            InstrProfError::CountMismatch {
+                function: Some("foo".to_string()),
+                expected: 3,
+                found: 2
+            }
+        );
+        assert_eq!(strict.get_record("foo").unwrap().counts(), [1, 2, 3]);
+
+        let options = MergeOptions {
+            reduce: Reduce::Sum,
+            resize_mismatched_counts: true,
+            ..Default::default()
+        };
+        let mut lenient = base.clone();
+        let err = lenient.merge_with_options(&shorter, &options).unwrap_err();
+        assert!(err.is_warning());
+        assert_eq!(
+            err,
+            InstrProfWarning::CountsResized {
+                function: Some("foo".to_string()),
+                expected: 3,
+                found: 2
+            }
+            .into()
+        );
+        assert_eq!(lenient.get_record("foo").unwrap().counts(), [11, 22, 3]);
+
+        let err = lenient.merge_with_options(&longer, &options).unwrap_err();
+        assert!(err.is_warning());
+        assert_eq!(lenient.get_record("foo").unwrap().counts(), [21, 42, 33]);
+
+        // Matching records don't warn
+        lenient.merge_with_options(&base, &options).unwrap();
+        assert_eq!(lenient.get_record("foo").unwrap().counts(), [22, 44, 36]);
+    }
+
+    #[test]
+    fn profile_merge_errors() {
+        let mut base = InstrumentationProfile::default();
+        base.merge_record(&named_record("foo", 1, vec![1, 2]))
+            .unwrap();
+        base.merge_record(&named_record("bar", 2, vec![1])).unwrap();
+
+        // Same name with a different function hash is a different function
+        base.merge_record(&named_record("foo", 3, vec![1, 2, 3]))
+            .unwrap();
+        assert_eq!(base.records().len(), 3);
+        base.merge_record(&named_record("foo", 3, vec![1, 2, 3]))
+            .unwrap();
+        assert_eq!(base.records().len(), 3);
+
+        let mut other = InstrumentationProfile::default();
+        other
+            .merge_record(&named_record("foo", 1, vec![1, 2, 3]))
+            .unwrap();
+        other
+            .merge_record(&named_record("baz", 4, vec![1]))
+            .unwrap();
+        let err = base.merge(&other).unwrap_err();
+        assert_eq!(
+            err,
+            InstrProfError::CountMismatch {
+                function: Some("foo".to_string()),
+                expected: 2,
+                found: 3,
+            }
+        );
+        // Merging carries on past the bad record
+        assert!(base.get_record("baz").is_some());
+    }
+
+    #[test]
+    fn merge_reductions() {
+        let profile = |records: &[NamedInstrProfRecord]| {
+            let mut profile = InstrumentationProfile::default();
+            for record in records {
+                profile.merge_record(record).unwrap();
+            }
+            profile
+        };
+        let mut indirect = named_record("foo", 1, vec![1, 8, 3]);
+        indirect.record.data = Some(Box::new(ValueProfDataRecord {
+            indirect_callsites: vec![vec![InstrProfValueData { value: 1, count: 6 }]],
+            ..Default::default()
+        }));
+        let mut other_indirect = named_record("foo", 1, vec![4, 2, 3]);
+        other_indirect.record.data = Some(Box::new(ValueProfDataRecord {
+            indirect_callsites: vec![vec![
+                InstrProfValueData { value: 1, count: 2 },
+                InstrProfValueData { value: 2, count: 1 },
+            ]],
+            ..Default::default()
+        }));
+        let a = profile(&[indirect, named_record("bar", 2, vec![5])]);
+        let b = profile(&[other_indirect, named_record("baz", 3, vec![7])]);
+
+        let reduce = |reduce| {
+            let mut merged = a.clone();
+            merged
+                .merge_with_options(
+                    &b,
+                    &MergeOptions {
+                        reduce,
+                        ..Default::default()
+                    },
+                )
+                .unwrap();
+            merged
+        };
+        let sum = reduce(Reduce::default());
+        let foo = &sum.get_record("foo").unwrap().record;
+        assert_eq!(foo.counts, vec![5, 10, 6]);
+        let site = &foo.value_sites(ValueKind::IndirectCallTarget)[0];
+        let values = site.iter().map(|x| (x.value, x.count)).collect::<Vec<_>>();
+        assert_eq!(values, vec![(1, 8), (2, 1)]);
+
+        let max = reduce(Reduce::Max);
+        let foo = &max.get_record("foo").unwrap().record;
+        assert_eq!(foo.counts, vec![4, 8, 3]);
+        let site = &foo.value_sites(ValueKind::IndirectCallTarget)[0];
+        let values = site.iter().map(|x| (x.value, x.count)).collect::<Vec<_>>();
+        assert_eq!(values, vec![(1, 6), (2, 1)]);
+
+        // Functions in only one profile are kept as they are for either reduction
+        for merged in [&sum, &max] {
+            assert_eq!(merged.get_record("bar").unwrap().counts(), &[5]);
+            assert_eq!(merged.get_record("baz").unwrap().counts(), &[7]);
+        }
+    }
+
+    #[test]
+    fn value_kind_display() {
+        let names = ValueKind::ALL
+            .iter()
+            .map(|x| x.to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            names,
+            [
+                "Indirect Call Targets",
+                "Memory Intrinsics",
+                "VTable Targets"
+            ]
+        );
+    }
+}
@@ -0,0 +1,181 @@
+//! Integrity checks for instrumentation profiles. Unlike parsing which stops at the first problem
+//! this reports what it can find so corrupt profiles can be rejected with a useful message.
+use crate::instrumentation_profile::{parse_detected, verbose_error_message, ParseOptions};
+use nom::Err;
+use rustc_hash::FxHashSet;
+use std::fmt;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Ord, PartialOrd, Hash)]
+pub enum Severity {
+    /// The profile is still usable but something about it is suspect
+    Warning,
+    /// The profile can't be read
+    Error,
+}
+
+/// A problem found while validating a profile
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct ValidationIssue {
+    pub severity: Severity,
+    /// Offset in bytes from the start of the data, if the problem can be tied to a location
+    pub offset: Option<usize>,
+    pub message: String,
+}
+
+impl ValidationIssue {
+    fn error(offset: Option<usize>, message: String) -> Self {
+        Self {
+            severity: Severity::Error,
+            offset,
+            message,
+        }
+    }
+
+    fn warning(offset: Option<usize>, message: String) -> Self {
+        Self {
+            severity: Severity::Warning,
+            offset,
+            message,
+        }
+    }
+
+    pub fn is_warning(&self) -> bool {
+        self.severity == Severity::Warning
+    }
+}
+
+impl fmt::Display for ValidationIssue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let severity = match self.severity {
+            Severity::Warning => "warning",
+            Severity::Error => "error",
+        };
+        match self.offset {
+            Some(offset) => write!(f, "{} at offset {:#x}: {}", severity, offset, self.message),
+            None => write!(f, "{}: {}", severity, self.message),
+        }
+    }
+}
+
+/// Checks the profile data, an empty list means no problems were found. Parse failures are
+/// errors and are reported at the offset the parser gave up at, anything odd about a profile
+/// which parsed is a warning.
+pub fn validate(data: &[u8]) -> Vec<ValidationIssue> {
+    let nom_res = match parse_detected(data, &ParseOptions::default()) {
+        Some(res) => res,
+        None => {
+            return vec![ValidationIssue::error(
+                Some(0),
+                "unsupported instrumentation profile format".to_string(),
+            )]
+        }
+    };
+    let profile = match nom_res {
+        Ok((_, profile)) => profile,
+        Err(Err::Error(e)) | Err(Err::Failure(e)) => {
+            let offset = e
+                .errors
+                .first()
+                .and_then(|(input, _)| offset_in(data, input));
+            return vec![ValidationIssue::error(
+                offset,
+                format!("parser failure: {}", verbose_error_message(&e)),
+            )];
+        }
+        Err(Err::Incomplete(_)) => {
+            return vec![ValidationIssue::error(
+                Some(data.len()),
+                "profile is truncated".to_string(),
+            )];
+        }
+    };
+
+    let mut issues = vec![];
+    let mut seen = FxHashSet::default();
+    for record in profile.records() {
+        let name = match record.name.as_deref() {
+            Some(name) => name.to_string(),
+            None => {
+                let name = format!("{:#018x}", record.name_hash.unwrap_or_default());
+                issues.push(ValidationIssue::warning(
+                    None,
+                    format!("function {} has no name in the symbol table", name),
+                ));
+                name
+            }
+        };
+        if !seen.insert((record.name_hash, record.hash)) {
+            issues.push(ValidationIssue::warning(
+                None,
+                format!(
+                    "function {} with hash {:#018x} appears more than once",
+                    name,
+                    record.hash.unwrap_or_default()
+                ),
+            ));
+        }
+    }
+    issues
+}
+
+/// Offset of `input` in `data`, `None` if the parser was working on another buffer such as
+/// decompressed names
+fn offset_in(data: &[u8], input: &[u8]) -> Option<usize> {
+    let start = data.as_ptr() as usize;
+    let pos = input.as_ptr() as usize;
+    if pos >= start && pos + input.len() <= start + data.len() {
+        Some(pos - start)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn valid_profile() {
+        assert!(validate(b":ir\nfoo\n1\n1\n5\n").is_empty());
+    }
+
+    #[test]
+    fn unknown_format() {
+        let issues = validate(&[0xff; 16]);
+        assert_eq!(issues.len(), 1);
+        assert_eq!(issues[0].severity, Severity::Error);
+        assert_eq!(issues[0].offset, Some(0));
+    }
+
+    #[test]
+    fn parse_failure_offset() {
+        // foo says it has 2 counters but the data ends after the first
+        let issues = validate(b":ir\nfoo\n1\n2\n5\n");
+        assert_eq!(issues.len(), 1);
+        assert_eq!(issues[0].severity, Severity::Error);
+        assert_eq!(issues[0].offset, Some(14));
+        assert!(issues[0].message.starts_with("parser failure"));
+    }
+
+    #[test]
+    fn duplicate_function() {
+        let issues = validate(b":ir\nfoo\n1\n1\n5\n\nfoo\n1\n1\n7\n");
+        assert_eq!(issues.len(), 1);
+        assert!(issues[0].is_warning());
+        assert_eq!(
+            issues[0].to_string(),
+            "warning: function foo with hash 0x0000000000000001 appears more than once"
+        );
+    }
+
+    #[test]
+    fn display() {
+        let error = ValidationIssue::error(Some(300), "profile is truncated".to_string());
+        assert_eq!(
+            error.to_string(),
+            "error at offset 0x12c: profile is truncated"
+        );
+        let warning = ValidationIssue::warning(None, "something is odd".to_string());
+        assert_eq!(warning.to_string(), "warning: something is odd");
+    }
+}
@@ -1,19 +1,34 @@
-use crate::instrumentation_profile::types::InstrumentationProfile;
-use std::path::Path;
+use crate::instrumentation_profile::text_profile::TextInstrProf;
+use crate::instrumentation_profile::types::{
+    InstrProfError, InstrumentationProfile, MergeOptions, Symtab,
+};
+use crate::instrumentation_profile::{check_writable, read_profile, InstrProfWriter};
+use rustc_hash::FxHashSet;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use tracing::{debug, debug_span, warn};
 
+pub mod batch;
 pub mod coverage;
 mod hash_table;
 pub mod instrumentation_profile;
 pub mod summary;
 pub mod util;
 
-pub use crate::instrumentation_profile::{parse, parse_bytes};
+pub use crate::instrumentation_profile::{
+    parse, parse_as, parse_bytes, parse_bytes_as, parse_bytes_header_only,
+    parse_bytes_with_options, parse_header_only, parse_with_options, ParseOptions,
+    ProfileHeaderInfo,
+};
 pub use coverage::coverage_mapping::CoverageMapping;
 pub use coverage::reporting::*;
 pub use coverage::*;
 
 #[derive(Debug, Clone, Copy, Eq, PartialEq, Hash, Ord, PartialOrd)]
 pub enum ProfileFormat {
+    /// Raw profile as written by an instrumented binary (profraw)
+    Raw,
+    /// Indexed profile (profdata)
     Binary,
     CompactBinary,
     ExtBinary,
@@ -25,18 +40,218 @@ pub fn merge_profiles<T>(files: &[T]) -> std::io::Result<InstrumentationProfile>
 where
     T: AsRef<Path>,
 {
-    if files.is_empty() {
+    let mut profiles = vec![];
+    for input in files {
+        let profile = parse(input)?;
+        profiles.push(profile);
+    }
+    merge_parsed(profiles, InstrumentationProfile::merge)
+}
+
+/// Reads each input and unions the symbol tables without merging any of the records, this is
+/// much cheaper than `merge_profiles` when only the function names are needed. If a hash maps to
+/// different names in different inputs a warning is emitted and the first name seen is kept.
+pub fn merge_symtabs<T>(files: &[T]) -> std::io::Result<Symtab>
+where
+    T: AsRef<Path>,
+{
+    let mut symtab = Symtab::default();
+    for input in files {
+        let profile = parse(input)?;
+        for (hash, name) in profile.symtab().iter() {
+            match symtab.get(*hash) {
+                Some(existing) if existing != name => warn!(
+                    "Hash collision for {:#x} between {} and {} in {}",
+                    hash,
+                    existing,
+                    name,
+                    input.as_ref().display()
+                ),
+                Some(_) => {}
+                None => symtab.add_func_name_with_hash(name.clone(), *hash),
+            }
+        }
+    }
+    Ok(symtab)
+}
+
+/// Merges the profiles like `merge_profiles` and writes the result to `writer` in the given
+/// format. Each input is parsed and folded into the result before the next one is read, so only
+/// the merged profile and a single input are in memory instead of every parsed input, and the
+/// output is written directly rather than serialized in memory first.
+///
+/// The merged profile itself can't be streamed: any input can add to any function, so no
+/// function is final until every input has been read, and the indexed format also needs the
+/// complete on-disk hash table. Only the text format can be written, other formats return an
+/// `Unsupported` error before any input is read.
+pub fn merge_profiles_to_writer<T>(
+    files: &[T],
+    format: ProfileFormat,
+    writer: &mut impl Write,
+) -> std::io::Result<()>
+where
+    T: AsRef<Path>,
+{
+    check_writable(format)?;
+    let mut merged: Option<InstrumentationProfile> = None;
+    for input in files {
+        let profile = parse(input)?;
+        match merged.as_mut() {
+            Some(base) => merge_into(base, &profile, &mut InstrumentationProfile::merge)?,
+            None => merged = Some(profile),
+        }
+    }
+    TextInstrProf.write(&merged.unwrap_or_default(), writer)
+}
+
+/// Profiles merged by `merge_profiles_with_options`
+#[derive(Debug, Clone, Default)]
+pub struct MergedProfiles {
+    pub profile: InstrumentationProfile,
+    /// Inputs which weren't merged because they're the same file or have the same contents as an
+    /// earlier input, always empty unless `MergeOptions::dedup_inputs` is set
+    pub skipped_duplicates: Vec<PathBuf>,
+    /// Number of functions removed to stay within `MergeOptions::max_functions`
+    pub evicted_functions: usize,
+}
+
+/// Merges the profiles combining the records as set by the options
+pub fn merge_profiles_with_options<T>(
+    files: &[T],
+    options: &MergeOptions,
+) -> std::io::Result<MergedProfiles>
+where
+    T: AsRef<Path>,
+{
+    let mut profiles = vec![];
+    let mut skipped_duplicates = vec![];
+    let mut seen_paths = FxHashSet::default();
+    let mut seen_contents = FxHashSet::default();
+    for input in files {
+        let input = input.as_ref();
+        if options.dedup_inputs {
+            let data = read_profile(input)?;
+            let path = input.canonicalize()?;
+            if !seen_paths.insert(path) || !seen_contents.insert(md5::compute(&data).0) {
+                skipped_duplicates.push(input.to_path_buf());
+                continue;
+            }
+            profiles.push(parse_bytes(&data)?);
+        } else {
+            profiles.push(parse(input)?);
+        }
+    }
+    if options.normalize {
+        normalize_profiles(&mut profiles);
+    }
+    let mut profile = merge_parsed(profiles, |base, profile| {
+        base.merge_with_options(profile, options)
+    })?;
+    let evicted_functions = match options.max_functions {
+        Some(max) => profile.retain_hottest(max),
+        None => 0,
+    };
+    if evicted_functions > 0 {
+        warn!(
+            "Evicted the {} coldest functions to keep {} functions",
+            evicted_functions,
+            profile.records().len()
+        );
+    }
+    Ok(MergedProfiles {
+        profile,
+        skipped_duplicates,
+        evicted_functions,
+    })
+}
+
+/// Scales the profiles to the same total count as the largest, see `MergeOptions::normalize`
+fn normalize_profiles(profiles: &mut [InstrumentationProfile]) {
+    let totals = profiles.iter().map(|x| x.total_count()).collect::<Vec<_>>();
+    let target = totals.iter().copied().max().unwrap_or_default();
+    for (profile, total) in profiles.iter_mut().zip(totals) {
+        if total > 0 && total != target {
+            if let Err(e) = profile.scale(target, total) {
+                warn!("{}", e);
+            }
+        }
+    }
+}
+
+/// Merges the profiles parsing the inputs on up to `num_threads` threads. The profiles are still
+/// merged in input order so the result is the same for any thread count, a count of 0 or 1 parses
+/// everything on the calling thread.
+pub fn merge_profiles_with_threads<T>(
+    files: &[T],
+    num_threads: usize,
+) -> std::io::Result<InstrumentationProfile>
+where
+    T: AsRef<Path> + Sync,
+{
+    if num_threads <= 1 || files.len() <= 1 {
+        return merge_profiles(files);
+    }
+    let chunk_size = files.len().div_ceil(num_threads);
+    let profiles = std::thread::scope(|s| {
+        let handles = files
+            .chunks(chunk_size)
+            .map(|chunk| s.spawn(move || chunk.iter().map(parse).collect::<Result<Vec<_>, _>>()))
+            .collect::<Vec<_>>();
+        let mut profiles = Vec::with_capacity(files.len());
+        for handle in handles {
+            let chunk = handle
+                .join()
+                .map_err(|_| std::io::Error::other("Merge thread panicked"))??;
+            profiles.extend(chunk);
+        }
+        Ok::<_, std::io::Error>(profiles)
+    })?;
+    merge_parsed(profiles, InstrumentationProfile::merge)
+}
+
+fn merge_parsed<F>(
+    mut profiles: Vec<InstrumentationProfile>,
+    mut merge: F,
+) -> std::io::Result<InstrumentationProfile>
+where
+    F: FnMut(&mut InstrumentationProfile, &InstrumentationProfile) -> Result<(), InstrProfError>,
+{
+    let _span = debug_span!("merge", inputs = profiles.len()).entered();
+    if profiles.is_empty() {
         Ok(InstrumentationProfile::default())
     } else {
-        let mut profiles = vec![];
-        for input in files {
-            let profile = parse(input)?;
-            profiles.push(profile);
-        }
         let mut base = profiles.remove(0);
-        for profile in &profiles {
-            base.merge(profile);
+        for (i, profile) in profiles.iter().enumerate() {
+            merge_into(&mut base, profile, &mut merge)?;
+            debug!(
+                input = i + 1,
+                records = profile.records().len(),
+                merged_records = base.records().len(),
+                "Merged profile"
+            );
         }
         Ok(base)
     }
 }
+
+/// Merges one profile into another, profiles of different kinds are an error but other problems
+/// only affect single functions so are warned about
+fn merge_into<F>(
+    base: &mut InstrumentationProfile,
+    profile: &InstrumentationProfile,
+    merge: &mut F,
+) -> std::io::Result<()>
+where
+    F: FnMut(&mut InstrumentationProfile, &InstrumentationProfile) -> Result<(), InstrProfError>,
+{
+    match merge(base, profile) {
+        Err(e @ InstrProfError::KindMismatch(_)) => {
+            Err(std::io::Error::new(std::io::ErrorKind::InvalidData, e))
+        }
+        Err(e) => {
+            warn!("{}", e);
+            Ok(())
+        }
+        Ok(()) => Ok(()),
+    }
+}
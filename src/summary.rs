@@ -10,6 +10,24 @@ pub const DEFAULT_CUTOFFS: [u64; 16] = [
     999000, 999900, 999990, 999999,
 ];
 
+/// Cutoff LLVM's `ProfileSummaryInfo` uses for hot counts, the largest counts making up 99% of
+/// the total count are hot
+pub const HOT_CUTOFF: u64 = 990000;
+
+/// Cutoff LLVM's `ProfileSummaryInfo` uses for cold counts, counts outside the largest ones making
+/// up 99.9999% of the total count are cold
+pub const COLD_CUTOFF: u64 = 999999;
+
+/// Count thresholds for classifying counters as hot or cold, see
+/// `ProfileSummary::hotness_thresholds`
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub struct HotnessThresholds {
+    /// Counts at or above this are hot
+    pub hot: u64,
+    /// Counts at or below this are cold
+    pub cold: u64,
+}
+
 /// The type of the profile summary
 #[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub enum Kind {
@@ -35,9 +53,113 @@ pub struct ProfileSummary {
     pub max_count: u64,
     pub max_internal_count: u64,
     pub max_function_count: u64,
-    pub num_counts: u32,
-    pub num_fns: u32,
+    pub num_counts: u64,
+    pub num_fns: u64,
     pub partial: bool,
     pub partial_profile_ratio: f64,
     pub detailed_summary: Vec<ProfileSummaryEntry>,
 }
+
+/// Change from one summary to another, each value is the other summary's minus this one's so a
+/// positive value means it went up. Differences which don't fit in an `i64` saturate.
+#[derive(Clone, Debug, Default, Eq, PartialEq, Hash)]
+pub struct SummaryDiff {
+    pub total_count: i64,
+    pub max_count: i64,
+    pub max_internal_count: i64,
+    pub max_function_count: i64,
+    pub num_counts: i64,
+    pub num_fns: i64,
+    /// Changes for the cutoffs present in both summaries, in this summary's cutoff order
+    pub detailed_summary: Vec<ProfileSummaryEntryDiff>,
+}
+
+/// Change in the entry for a cutoff between two summaries
+#[derive(Clone, Debug, Default, Eq, PartialEq, Hash)]
+pub struct ProfileSummaryEntryDiff {
+    pub cutoff: u64,
+    pub min_count: i64,
+    pub num_counts: i64,
+}
+
+impl SummaryDiff {
+    /// True if nothing changed between the summaries
+    pub fn is_unchanged(&self) -> bool {
+        let Self {
+            total_count,
+            max_count,
+            max_internal_count,
+            max_function_count,
+            num_counts,
+            num_fns,
+            detailed_summary,
+        } = self;
+        [
+            *total_count,
+            *max_count,
+            *max_internal_count,
+            *max_function_count,
+            *num_counts,
+            *num_fns,
+        ]
+        .iter()
+        .all(|x| *x == 0)
+            && detailed_summary
+                .iter()
+                .all(|x| x.min_count == 0 && x.num_counts == 0)
+    }
+}
+
+impl ProfileSummary {
+    /// The minimum count of the first detailed summary entry with a cutoff at or above `cutoff`,
+    /// like `getEntryForPercentile` in LLVM. `None` if there's no such entry
+    pub fn count_for_cutoff(&self, cutoff: u64) -> Option<u64> {
+        self.detailed_summary
+            .iter()
+            .find(|x| x.cutoff >= cutoff)
+            .map(|x| x.min_count)
+    }
+
+    /// The hot and cold count thresholds for `HOT_CUTOFF` and `COLD_CUTOFF`. If the detailed
+    /// summary doesn't reach a cutoff nothing is hot or cold respectively
+    pub fn hotness_thresholds(&self) -> HotnessThresholds {
+        HotnessThresholds {
+            hot: self.count_for_cutoff(HOT_CUTOFF).unwrap_or(u64::MAX),
+            cold: self.count_for_cutoff(COLD_CUTOFF).unwrap_or(0),
+        }
+    }
+
+    /// Compares the summary against another, useful for catching large changes in the hotness
+    /// distribution between runs
+    pub fn compare(&self, other: &ProfileSummary) -> SummaryDiff {
+        let detailed_summary = self
+            .detailed_summary
+            .iter()
+            .filter_map(|entry| {
+                let other = other
+                    .detailed_summary
+                    .iter()
+                    .find(|x| x.cutoff == entry.cutoff)?;
+                Some(ProfileSummaryEntryDiff {
+                    cutoff: entry.cutoff,
+                    min_count: delta(entry.min_count, other.min_count),
+                    num_counts: delta(entry.num_counts, other.num_counts),
+                })
+            })
+            .collect();
+        SummaryDiff {
+            total_count: delta(self.total_count, other.total_count),
+            max_count: delta(self.max_count, other.max_count),
+            max_internal_count: delta(self.max_internal_count, other.max_internal_count),
+            max_function_count: delta(self.max_function_count, other.max_function_count),
+            num_counts: delta(self.num_counts, other.num_counts),
+            num_fns: delta(self.num_fns, other.num_fns),
+            detailed_summary,
+        }
+    }
+}
+
+fn delta(from: u64, to: u64) -> i64 {
+    let diff = i128::from(to) - i128::from(from);
+    diff.clamp(i64::MIN.into(), i64::MAX.into()) as i64
+}
@@ -1,12 +1,82 @@
-use flate2::read::ZlibDecoder;
+#[cfg(feature = "compression")]
+use flate2::read::{GzDecoder, ZlibDecoder};
 use nom::{
     error::{ContextError, ErrorKind, ParseError},
     IResult,
 };
+use std::io;
+#[cfg(feature = "compression")]
 use std::io::Read;
 use std::path::{Path, PathBuf};
 use tracing::debug;
 
+/// Context added to the parse error when a compressed section can't be inflated
+#[cfg(feature = "compression")]
+const DECOMPRESS_ERROR: &str = "invalid deflate stream";
+/// Context added to the parse error when a compressed section can't be inflated
+#[cfg(not(feature = "compression"))]
+const DECOMPRESS_ERROR: &str =
+    "zlib compressed data found but the `compression` feature is disabled";
+
+/// Inflates a zlib compressed buffer. This returns `None` if the stream is invalid or if the
+/// `compression` feature isn't enabled.
+#[cfg(feature = "compression")]
+pub(crate) fn decompress(input: &[u8], uncompressed_size: usize) -> Option<Vec<u8>> {
+    let mut decoder = ZlibDecoder::new(input);
+    let mut output = Vec::with_capacity(uncompressed_size);
+    decoder.read_to_end(&mut output).ok().map(|_| output)
+}
+
+/// Inflates a zlib compressed buffer. This returns `None` if the stream is invalid or if the
+/// `compression` feature isn't enabled.
+#[cfg(not(feature = "compression"))]
+pub(crate) fn decompress(_input: &[u8], _uncompressed_size: usize) -> Option<Vec<u8>> {
+    None
+}
+
+/// Whether the data starts with the gzip magic number
+pub(crate) fn is_gzip(input: &[u8]) -> bool {
+    input.starts_with(&[0x1f, 0x8b])
+}
+
+/// Quotes a string for JSON output, escaping quotes, backslashes and control characters
+pub(crate) fn json_string(s: &str) -> String {
+    let mut res = String::with_capacity(s.len() + 2);
+    res.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => res.push_str("\\\""),
+            '\\' => res.push_str("\\\\"),
+            '\n' => res.push_str("\\n"),
+            '\r' => res.push_str("\\r"),
+            '\t' => res.push_str("\\t"),
+            c if c.is_control() => res.push_str(&format!("\\u{:04x}", c as u32)),
+            c => res.push(c),
+        }
+    }
+    res.push('"');
+    res
+}
+
+/// Decompresses a gzip file such as a `.profraw.gz`
+#[cfg(feature = "compression")]
+pub(crate) fn gunzip(input: &[u8]) -> io::Result<Vec<u8>> {
+    let mut decoder = GzDecoder::new(input);
+    let mut output = Vec::new();
+    decoder.read_to_end(&mut output)?;
+    Ok(output)
+}
+
+/// Decompresses a gzip file such as a `.profraw.gz`, this always fails as the `compression`
+/// feature isn't enabled
+#[cfg(not(feature = "compression"))]
+pub(crate) fn gunzip(_input: &[u8]) -> io::Result<Vec<u8>> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "gzip compressed profile found but the `compression` feature is disabled",
+    ))
+}
+
 pub fn parse_leb128<'a, E>(mut input: &'a [u8]) -> IResult<&'a [u8], u64, E>
 where
     E: ParseError<&'a [u8]> + ContextError<&'a [u8]>,
@@ -36,38 +106,41 @@ where
             Err(nom::Err::Error(E::from_error_kind(input, ErrorKind::Eof)))
         } else {
             let compressed_size = compressed_size as usize;
-            let mut decoder = ZlibDecoder::new(&input[..compressed_size]);
-            let mut output = vec![];
-            if decoder.read_to_end(&mut output).is_ok() {
-                let name = String::from_utf8(output);
-                let name = name.unwrap();
+            if let Some(output) = decompress(&input[..compressed_size], uncompressed_size as usize)
+            {
+                let name = string_from_utf8(input, output)?;
                 Ok((&input[compressed_size..], name))
             } else {
                 let inner = E::from_error_kind(input, ErrorKind::Satisfy);
                 Err(nom::Err::Failure(E::add_context(
                     input,
-                    "invalid deflate stream",
+                    DECOMPRESS_ERROR,
                     inner,
                 )))
             }
         }
     } else {
         let uncompressed_size = uncompressed_size as usize;
-        match String::from_utf8(input[..uncompressed_size].to_vec()) {
-            Ok(name) => Ok((&input[uncompressed_size..], name)),
-            Err(_e) => {
-                debug!("Invalid UTF-8 string");
-                let inner = E::from_error_kind(input, ErrorKind::Satisfy);
-                Err(nom::Err::Error(E::add_context(
-                    input,
-                    "invalid utf-8 string",
-                    inner,
-                )))
-            }
+        if uncompressed_size > input.len() {
+            debug!("Unexpected EOF parsing a string ref");
+            return Err(nom::Err::Error(E::from_error_kind(input, ErrorKind::Eof)));
         }
+        let name = string_from_utf8(input, input[..uncompressed_size].to_vec())?;
+        Ok((&input[uncompressed_size..], name))
     }
 }
 
+fn string_from_utf8<'a, E>(input: &'a [u8], bytes: Vec<u8>) -> Result<String, nom::Err<E>>
+where
+    E: ParseError<&'a [u8]> + ContextError<&'a [u8]>,
+{
+    String::from_utf8(bytes).map_err(|_e| {
+        debug!("Invalid UTF-8 string");
+        let inner = E::from_error_kind(input, ErrorKind::Satisfy);
+        nom::Err::Error(E::add_context(input, "invalid utf-8 string", inner))
+    })
+}
+
 /// Parses a list of paths - this is currently only used in parsing the sections in an instrumented
 /// object file, and due to CWD joining is different to the other string parsing implemented
 pub fn parse_path_list<'a, E>(input: &'a [u8], version: u64) -> IResult<&'a [u8], Vec<PathBuf>, E>
@@ -91,9 +164,11 @@ where
             let (input, values) = parse_uncompressed_file_list::<E>(input, list_length, version)?;
             Ok((input, values))
         } else {
-            let mut decoder = ZlibDecoder::new(&input[..compressed_size]);
-            let mut output = Vec::with_capacity(uncompressed_size);
-            decoder.read_to_end(&mut output).unwrap();
+            let output =
+                decompress(&input[..compressed_size], uncompressed_size).ok_or_else(|| {
+                    let inner = E::from_error_kind(input, ErrorKind::Satisfy);
+                    nom::Err::Failure(E::add_context(input, DECOMPRESS_ERROR, inner))
+                })?;
             // Use context error to
             let values = parse_uncompressed_string_list::<()>(&output)
                 .map(|(_, v)| v.iter().map(PathBuf::from).collect())
@@ -165,3 +240,79 @@ where
     }
     Ok((input, res))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Encodes an uncompressed string ref the same way `__llvm_prf_names` does
+    fn encode_string_ref(s: impl AsRef<[u8]>) -> Vec<u8> {
+        let s = s.as_ref();
+        let mut res = vec![];
+        leb128::write::unsigned(&mut res, s.len() as u64).unwrap();
+        leb128::write::unsigned(&mut res, 0).unwrap();
+        res.extend_from_slice(s);
+        res
+    }
+
+    /// Encodes a zlib compressed string ref the same way `__llvm_prf_names` does
+    #[cfg(feature = "compression")]
+    fn encode_compressed_string_ref(s: impl AsRef<[u8]>) -> Vec<u8> {
+        use flate2::{write::ZlibEncoder, Compression};
+        use std::io::Write;
+
+        let s = s.as_ref();
+        let mut encoder = ZlibEncoder::new(vec![], Compression::default());
+        encoder.write_all(s).unwrap();
+        let compressed = encoder.finish().unwrap();
+
+        let mut res = vec![];
+        leb128::write::unsigned(&mut res, s.len() as u64).unwrap();
+        leb128::write::unsigned(&mut res, compressed.len() as u64).unwrap();
+        res.extend_from_slice(&compressed);
+        res
+    }
+
+    #[test]
+    fn uncompressed_string_ref() {
+        let data = encode_string_ref("main\u{1}foo");
+        let (rest, name) = parse_string_ref::<nom::error::Error<_>>(&data).unwrap();
+        assert!(rest.is_empty());
+        assert_eq!(name, "main\u{1}foo");
+    }
+
+    #[test]
+    #[cfg(feature = "compression")]
+    fn compressed_string_ref() {
+        let data = encode_compressed_string_ref("main\u{1}foo\u{1}bar");
+        let (rest, name) = parse_string_ref::<nom::error::Error<_>>(&data).unwrap();
+        assert!(rest.is_empty());
+        assert_eq!(name, "main\u{1}foo\u{1}bar");
+    }
+
+    #[test]
+    fn corrupt_compressed_string_ref() {
+        let mut data = encode_string_ref("main");
+        // Claim 4 bytes of compressed data which isn't a valid zlib stream
+        data[1] = 4;
+        assert!(parse_string_ref::<nom::error::Error<_>>(&data).is_err());
+    }
+
+    #[test]
+    fn truncated_string_ref() {
+        let mut data = encode_string_ref("main");
+        data.pop();
+        assert!(parse_string_ref::<nom::error::Error<_>>(&data).is_err());
+    }
+
+    #[test]
+    fn invalid_utf8_string_ref() {
+        let data = encode_string_ref([b'm', 0xff, 0xfe]);
+        assert!(parse_string_ref::<nom::error::Error<_>>(&data).is_err());
+        #[cfg(feature = "compression")]
+        {
+            let data = encode_compressed_string_ref([b'm', 0xff, 0xfe]);
+            assert!(parse_string_ref::<nom::error::Error<_>>(&data).is_err());
+        }
+    }
+}
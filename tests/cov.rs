@@ -1,5 +1,9 @@
 use cargo_metadata::Message;
-use llvm_profparser::{parse, CoverageMapping};
+use llvm_profparser::coverage::coverage_mapping::{extract_embedded_profraw, read_object_file};
+use llvm_profparser::instrumentation_profile::types::{
+    compute_hash, InstrProfRecord, InstrumentationProfile, NamedInstrProfRecord,
+};
+use llvm_profparser::{parse, parse_bytes, CoverageMapping};
 use pretty_assertions::assert_eq;
 use regex::Regex;
 
@@ -265,3 +269,252 @@ fn check_mapping_consistency() {
         assert_eq!(expected_len, counts);
     }
 }
+
+/// `tests/data/objects/pick.rs` compiled to an object file for a target of each object format with
+/// `rustc -C instrument-coverage -Z no-profiler-runtime --emit=obj`
+fn fixture_object(format: &str) -> PathBuf {
+    get_project_dir("objects").join(format!("pick-{}.o", format))
+}
+
+#[test]
+fn coff_coverage_mapping() {
+    let elf = read_object_file(&fixture_object("elf"), 10).unwrap();
+    let coff = read_object_file(&fixture_object("coff"), 10).unwrap();
+    assert_eq!(coff.cov_fun.len(), 1);
+    assert_eq!(coff.cov_fun[0].header.name_hash, compute_hash("pick"));
+    assert_eq!(coff, elf);
+}
+
+#[test]
+fn macho_coverage_mapping() {
+    let elf = read_object_file(&fixture_object("elf"), 10).unwrap();
+    let macho = read_object_file(&fixture_object("macho"), 10).unwrap();
+    assert_eq!(macho.cov_fun.len(), 1);
+    assert_eq!(macho, elf);
+
+    // `pick(true)` called three times and `pick(false)` once
+    let func = &macho.cov_fun[0].header;
+    let mut profile = InstrumentationProfile::new(Some(10), false, true, false);
+    profile
+        .symtab_mut()
+        .add_func_name_with_hash("pick".to_string(), func.name_hash);
+    profile.push_record(NamedInstrProfRecord {
+        name: Some("pick".to_string()),
+        name_hash: Some(func.name_hash),
+        hash: Some(func.fn_hash),
+        record: InstrProfRecord {
+            counts: vec![4, 3],
+            ..Default::default()
+        },
+    });
+    let report = |format| {
+        let objects = [fixture_object(format)];
+        CoverageMapping::new(&objects, &profile, false)
+            .unwrap()
+            .generate_report()
+            .unwrap()
+    };
+    let source_path = PathBuf::from("tests/data/objects/pick.rs");
+    let macho_report = report("macho");
+    let source = macho_report.files.get(&source_path).unwrap();
+    let hits = [17, 18, 19, 21]
+        .iter()
+        .map(|x| source.hits_for_line(*x))
+        .collect::<Vec<_>>();
+    assert_eq!(hits, vec![Some(4), Some(4), Some(3), Some(1)]);
+    assert_eq!(macho_report.files.len(), 1);
+    assert_eq!(source.hits, report("elf").files[&source_path].hits);
+}
+
+#[test]
+fn coverage_mapping_filenames() {
+    // The filename tables in these objects are zlib compressed
+    let profile = InstrumentationProfile::new(Some(10), false, true, false);
+    for format in ["elf", "coff", "macho"] {
+        let objects = [fixture_object(format)];
+        let mapping = CoverageMapping::new(&objects, &profile, false).unwrap();
+        assert_eq!(
+            mapping.filenames(),
+            &[PathBuf::from("tests/data/objects/pick.rs")]
+        );
+    }
+
+    let project = get_project_dir("cov");
+    let objects = [project.join("simple_project")];
+    let profile = parse(project.join("simple_project.profraw")).unwrap();
+    let mapping = CoverageMapping::new(&objects, &profile, false).unwrap();
+    let root = PathBuf::from("/home/daniel/personal/tarpaulin/tests/data/simple_project/src");
+    assert_eq!(
+        mapping.filenames(),
+        &[root.join("lib.rs"), root.join("unused.rs")]
+    );
+}
+
+#[test]
+fn file_summary_matches_llvm_cov() {
+    let dir = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/data/matches");
+    let profile = parse(dir.join("merged.profdata")).unwrap();
+    let objects = [dir.join("matches_bin")];
+    let mapping = CoverageMapping::new(&objects, &profile, false).unwrap();
+    let summary = mapping.file_summary().unwrap();
+
+    // Lines and covered lines in the `llvm-cov show` output
+    let expected = fs::read_to_string(dir.join("matches.stdout")).unwrap();
+    let counts = expected
+        .lines()
+        .filter_map(|x| x.split('|').nth(1)?.trim().parse::<usize>().ok())
+        .collect::<Vec<_>>();
+    assert_eq!(summary.len(), 1);
+    assert!(summary[0].path.ends_with("src/lib.rs"));
+    assert_eq!(summary[0].lines_total, counts.len());
+    assert_eq!(
+        summary[0].lines_covered,
+        counts.iter().filter(|x| **x > 0).count()
+    );
+    assert_eq!(summary[0].percent_covered(), 100.0);
+
+    // `llvm-cov report` for a project with a partially covered file and an unused one
+    let project = get_project_dir("cov");
+    let profile = parse(project.join("simple_project.profraw")).unwrap();
+    let objects = [project.join("simple_project")];
+    let mapping = CoverageMapping::new(&objects, &profile, false).unwrap();
+    let summary = mapping.file_summary().unwrap();
+    let report = fs::read_to_string(project.join("simple_project.report")).unwrap();
+    let rows = report
+        .lines()
+        .filter(|x| x.contains(".rs "))
+        .map(|x| x.split_whitespace().collect::<Vec<_>>())
+        .collect::<Vec<_>>();
+    assert_eq!(summary.len(), rows.len());
+    for row in &rows {
+        let file = summary.iter().find(|x| x.path.ends_with(row[0])).unwrap();
+        // The lines columns are the total, missed and percentage covered
+        let lines_total = row[7].parse::<usize>().unwrap();
+        let lines_missed = row[8].parse::<usize>().unwrap();
+        assert_eq!(file.lines_total, lines_total, "{}", row[0]);
+        assert_eq!(file.lines_covered, lines_total - lines_missed, "{}", row[0]);
+        assert_eq!(
+            format!("{:.2}%", file.percent_covered()),
+            row[9],
+            "{}",
+            row[0]
+        );
+    }
+    let lib = summary.iter().find(|x| x.path.ends_with("lib.rs")).unwrap();
+    assert_eq!(lib.percent_covered(), 90.0);
+}
+
+#[test]
+fn embedded_profraw() {
+    let project = get_project_dir("cov");
+    let bytes = extract_embedded_profraw(&project.join("simple_project")).unwrap();
+    let embedded = parse_bytes(&bytes).unwrap();
+    let profile = parse(project.join("simple_project.profraw")).unwrap();
+
+    // The binary was never run so the functions match the profile but all the counts are zero
+    let functions = |profile: &InstrumentationProfile| {
+        profile
+            .records_sorted()
+            .into_iter()
+            .map(|x| (x.name.clone(), x.hash, x.counts().len()))
+            .collect::<Vec<_>>()
+    };
+    assert!(!embedded.records().is_empty());
+    assert_eq!(functions(&embedded), functions(&profile));
+    assert!(embedded
+        .records()
+        .iter()
+        .all(|x| x.counts().iter().all(|c| *c == 0)));
+
+    assert!(extract_embedded_profraw(&fixture_object("elf")).is_none());
+}
+
+/// Builds and runs `tests/data/mcdc/decisions.c` with MC/DC instrumentation. Returns `None` if
+/// there's no clang which supports `-fcoverage-mcdc` (LLVM 18 onwards).
+fn run_mcdc() -> io::Result<Option<Run>> {
+    let project = get_project_dir("mcdc");
+    let binary = project.join("decisions");
+    let build = match Command::new("clang")
+        .args(&[
+            "-fprofile-instr-generate",
+            "-fcoverage-mapping",
+            "-fcoverage-mcdc",
+        ])
+        .arg(project.join("decisions.c"))
+        .arg("-o")
+        .arg(&binary)
+        .output()
+    {
+        Ok(build) => build,
+        Err(_) => return Ok(None),
+    };
+    if !build.status.success() {
+        return Ok(None);
+    }
+
+    let profraw = project.join("decisions.profraw");
+    let run = Command::new(&binary)
+        .env("LLVM_PROFILE_FILE", &profraw)
+        .output()?;
+    assert!(run.status.success());
+    Ok(Some(Run { profraw, binary }))
+}
+
+#[test]
+fn mcdc_summary_matches_llvm_cov() {
+    let run = match run_mcdc().unwrap() {
+        Some(run) => run,
+        None => {
+            println!("Skipping as clang doesn't support -fcoverage-mcdc");
+            return;
+        }
+    };
+    let profile = parse(&run.profraw).unwrap();
+    let objects = [run.binary.clone()];
+    let mapping = CoverageMapping::new(&objects, &profile, false).unwrap();
+    let report = mapping.mcdc().unwrap();
+
+    // `all` has two covered conditions, `some` two of three and `none` was never called
+    assert_eq!(report.decisions.len(), 3);
+    assert_eq!(report.num_conditions(), 8);
+    assert_eq!(report.covered_conditions(), 4);
+
+    let profdata = run.profraw.with_extension("profdata");
+    let merge = Command::new("llvm-profdata")
+        .args(&["merge", "-sparse", "-o"])
+        .args(&[&profdata, &run.profraw])
+        .output()
+        .unwrap();
+    assert!(
+        merge.status.success(),
+        "{}",
+        String::from_utf8_lossy(&merge.stderr)
+    );
+    let llvm_report = Command::new("llvm-cov")
+        .args(&["report", "--show-mcdc-summary", "--instr-profile"])
+        .args(&[&profdata, &run.binary])
+        .output()
+        .unwrap();
+    assert!(
+        llvm_report.status.success(),
+        "{}",
+        String::from_utf8_lossy(&llvm_report.stderr)
+    );
+
+    // The last three columns of the total are the MC/DC conditions, missed and cover
+    let printout = get_printout(&llvm_report.stdout);
+    let total = printout
+        .iter()
+        .find(|x| x.starts_with("TOTAL"))
+        .expect("No total in the llvm-cov report");
+    let columns = total.split_whitespace().collect::<Vec<_>>();
+    let missed = report.num_conditions() - report.covered_conditions();
+    assert_eq!(
+        columns[columns.len() - 3..],
+        [
+            report.num_conditions().to_string(),
+            missed.to_string(),
+            format!("{:.2}%", report.percent_covered()),
+        ]
+    );
+}
@@ -0,0 +1,41 @@
+#include <stdio.h>
+#include <stdlib.h>
+
+/* Every condition of `all` is shown to be independent */
+int all(int a, int b) {
+    if (a && b) {
+        return 1;
+    }
+    return 0;
+}
+
+/* `c` is never false so only `a` and `b` are covered */
+int some(int a, int b, int c) {
+    if ((a || b) && c) {
+        return 1;
+    }
+    return 0;
+}
+
+/* Never called so none of the conditions are covered */
+int none(int a, int b, int c) {
+    if (a && b && c) {
+        return 1;
+    }
+    return 0;
+}
+
+int main(int argc, char **argv) {
+    int count = 0;
+    count += all(0, 1);
+    count += all(1, 0);
+    count += all(1, 1);
+    count += some(1, 0, 1);
+    count += some(0, 1, 1);
+    count += some(0, 0, 1);
+    if (argc > 1) {
+        count += none(atoi(argv[1]), 1, 1);
+    }
+    printf("%d\n", count);
+    return 0;
+}
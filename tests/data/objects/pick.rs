@@ -0,0 +1,23 @@
+#![feature(no_core, lang_items)]
+#![no_core]
+#![crate_type = "lib"]
+
+#[lang = "pointee_sized"]
+pub trait PointeeSized {}
+#[lang = "meta_sized"]
+pub trait MetaSized: PointeeSized {}
+#[lang = "sized"]
+pub trait Sized: MetaSized {}
+#[lang = "copy"]
+pub trait Copy {}
+impl Copy for bool {}
+impl Copy for u32 {}
+
+#[no_mangle]
+pub extern "C" fn pick(x: bool) -> u32 {
+    if x {
+        1
+    } else {
+        2
+    }
+}
@@ -1,8 +1,30 @@
-use llvm_profparser::{merge_profiles, parse, parse_bytes};
+#[cfg(feature = "archive")]
+use llvm_profparser::batch::merge_profiles_from_tar;
+use llvm_profparser::batch::{
+    discover_profraws, merge_profiles_from_response_file, read_response_file, ProfileSet,
+};
+use llvm_profparser::instrumentation_profile::overlap::{overlap_profiles, OverlapFilter};
+use llvm_profparser::instrumentation_profile::raw_profile::RawInstrProf64;
+use llvm_profparser::instrumentation_profile::show::ShowOptions;
+use llvm_profparser::instrumentation_profile::text_profile::TextInstrProf;
+use llvm_profparser::instrumentation_profile::types::{
+    compute_hash, CoverageStatus, IncompatibilityReason, InstrProfError, InstrProfRecord,
+    InstrumentationLevel, InstrumentationProfile, MergeOptions, NamedInstrProfRecord, ProfileLevel,
+    ValueKind,
+};
+use llvm_profparser::instrumentation_profile::{InstrProfReader, InstrProfWriter};
+use llvm_profparser::summary::HotnessThresholds;
+use llvm_profparser::{
+    merge_profiles, merge_profiles_to_writer, merge_profiles_with_options, merge_symtabs, parse,
+    parse_as, parse_bytes, parse_header_only, parse_with_options, ParseOptions, ProfileFormat,
+};
 use serde::Deserialize;
+use std::collections::hash_map::DefaultHasher;
 use std::collections::{HashMap, HashSet};
+use std::convert::TryInto;
 use std::ffi::OsStr;
 use std::fs::read_dir;
+use std::hash::{Hash, Hasher};
 use std::io::BufRead as _;
 use std::iter::FromIterator as _;
 use std::path::PathBuf;
@@ -148,6 +170,15 @@ fn get_data_dir(llvm_version: u8) -> PathBuf {
         .join(format!("llvm-{llvm_version}"))
 }
 
+/// Runs the profparser binary with the arguments, paths are passed with `Path::to_str`
+fn profparser(args: &[&str]) -> std::process::Output {
+    assert_cmd::Command::cargo_bin("profparser")
+        .unwrap()
+        .args(args)
+        .output()
+        .unwrap()
+}
+
 fn check_merge_command(files: &[PathBuf], id: &str, rustc_version: &str) {
     let llvm_output = PathBuf::from(format!("llvm_{}.profdata", id));
     let names = files
@@ -460,6 +491,29 @@ fn multi_app_profraw_merging() {
     }
 }
 
+#[test]
+fn multi_app_symtab_union() {
+    let dir = data_root_dir().join("misc").join("multibin_merge");
+    let inputs = [
+        dir.join("bin_1.profraw"),
+        dir.join("bin_2.1.profraw"),
+        dir.join("bin_2.2.profraw"),
+        dir.join("bin_2.3.profraw"),
+    ];
+
+    let symtab = merge_symtabs(&inputs).unwrap();
+    let merged = merge_profiles(&inputs).unwrap();
+
+    assert!(!symtab.is_empty());
+    assert_eq!(&symtab, merged.symtab());
+    for input in &inputs {
+        let profile = parse(input).unwrap();
+        for (hash, name) in profile.symtab().iter() {
+            assert_eq!(symtab.get(*hash), Some(name));
+        }
+    }
+}
+
 #[test]
 fn profraw_merging() {
     let premerge_1 = data_root_dir().join("misc").join("premerge_1.profraw");
@@ -473,6 +527,752 @@ fn profraw_merging() {
     assert_eq!(merged.records(), expected_merged.records());
 }
 
+#[test]
+#[cfg(feature = "compression")]
+fn compressed_names() {
+    let mut seen = 0;
+    for llvm_version in 11..=21 {
+        let file = get_data_dir(llvm_version).join("compressed.profraw");
+        if !file.exists() {
+            continue;
+        }
+        seen += 1;
+        let profile = parse(&file).unwrap();
+        assert!(!profile.records().is_empty());
+        for record in profile.records() {
+            let name = record
+                .name
+                .as_ref()
+                .unwrap_or_else(|| panic!("Unnamed record in {}", file.display()));
+            assert_eq!(profile.symtab.get(record.name_hash.unwrap()), Some(name));
+        }
+    }
+    assert!(seen > 0);
+}
+
+#[test]
+fn parse_with_format_hint() {
+    let raw = data_root_dir().join("misc").join("premerge_1.profraw");
+    let indexed = data_root_dir().join("misc").join("merged.profdata");
+
+    let hinted = parse_as(&raw, ProfileFormat::Raw).unwrap();
+    assert_eq!(hinted.records(), parse(&raw).unwrap().records());
+    let hinted = parse_as(&indexed, ProfileFormat::Binary).unwrap();
+    assert_eq!(hinted.records(), parse(&indexed).unwrap().records());
+
+    let err = parse_as(&raw, ProfileFormat::Binary).unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+    assert!(err.to_string().contains("indexed"));
+    let err = parse_as(&indexed, ProfileFormat::Raw).unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+    assert!(err.to_string().contains("raw"));
+    let err = parse_as(&raw, ProfileFormat::Gcc).unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::Unsupported);
+}
+
+/// Rewrites a raw profile into the layout used in continuous mode where the counters section
+/// starts and ends on a page boundary.
+fn to_continuous_layout(data: &[u8], page_size: usize) -> Vec<u8> {
+    let (_, layout) = RawInstrProf64::parse_layout(data).unwrap();
+    assert!(!layout.continuous_mode);
+    let padding_before_field = if layout.version >= 7 { 32 } else { 24 };
+    let padding_after_field = padding_before_field + 16;
+    let old_padding_after = u64::from_le_bytes(
+        data[padding_after_field..(padding_after_field + 8)]
+            .try_into()
+            .unwrap(),
+    ) as usize;
+    let align = |x: usize| (page_size - (x % page_size)) % page_size;
+    let padding_before = align(layout.counters_offset);
+    let padding_after = align(layout.counters_len);
+    let counters_end = layout.counters_offset + layout.counters_len + old_padding_after;
+
+    let mut result = data[..layout.counters_offset].to_vec();
+    result[padding_before_field..(padding_before_field + 8)]
+        .copy_from_slice(&(padding_before as u64).to_le_bytes());
+    result[padding_after_field..(padding_after_field + 8)]
+        .copy_from_slice(&(padding_after as u64).to_le_bytes());
+    result.resize(result.len() + padding_before, 0);
+    result.extend_from_slice(&data[layout.counters_offset..][..layout.counters_len]);
+    result.resize(result.len() + padding_after, 0);
+    result.extend_from_slice(&data[counters_end..]);
+    result.resize(result.len() + align(result.len()), 0);
+    result
+}
+
+#[test]
+fn continuous_mode_profraws() {
+    for llvm_version in 11..=21 {
+        let file = get_data_dir(llvm_version).join("compressed.profraw");
+        if !file.exists() {
+            continue;
+        }
+        let data = std::fs::read(&file).unwrap();
+        let continuous = to_continuous_layout(&data, 4096);
+        let (_, layout) = RawInstrProf64::parse_layout(&continuous).unwrap();
+        assert!(layout.continuous_mode);
+        assert_eq!(layout.counters_offset % 4096, 0);
+
+        let expected = parse_bytes(&data).unwrap();
+        let profile = parse_bytes(&continuous).unwrap();
+        assert_eq!(profile.symtab, expected.symtab);
+        assert_eq!(profile.records().len(), expected.records().len());
+        for (record, expected) in profile.records().iter().zip(expected.records()) {
+            assert_eq!(record.name, expected.name);
+            assert_eq!(record.hash, expected.hash);
+            assert_eq!(record.counts(), expected.counts());
+            // The byte ranges have to be right in the new layout for merging
+            let range = &record.record.counts_bytes_offset;
+            let counts = continuous[(range.start as usize)..(range.end as usize)]
+                .chunks(8)
+                .map(|x| u64::from_le_bytes(x.try_into().unwrap()))
+                .collect::<Vec<_>>();
+            assert_eq!(&counts, record.counts());
+        }
+    }
+}
+
+#[test]
+fn value_profile_overlap() {
+    let base = parse(get_data_dir(18).join("overlap_1_vp.proftext")).unwrap();
+    let test = parse(get_data_dir(18).join("overlap_2_vp.proftext")).unwrap();
+
+    let result = overlap_profiles(&base, &test, false, &OverlapFilter::default());
+    let stats = result.program;
+    assert!(stats.valid);
+    assert_eq!(stats.overlap.num_entries, 1.0);
+    assert_eq!(stats.base.count_sum, 100000.0);
+    assert_eq!(stats.test.count_sum, 50000.0);
+    assert!((stats.overlap.count_sum - 0.8).abs() < 1e-9);
+
+    let indirect = ValueKind::IndirectCallTarget as usize;
+    assert_eq!(stats.base.value_counts[indirect], 100000.0);
+    assert_eq!(stats.test.value_counts[indirect], 50000.0);
+    assert!((stats.overlap.value_counts[indirect] - 0.8).abs() < 1e-9);
+
+    let memop = ValueKind::MemOpSize as usize;
+    assert_eq!(stats.base.value_counts[memop], 100000.0);
+    assert_eq!(stats.test.value_counts[memop], 5000.0);
+    assert!((stats.overlap.value_counts[memop] - 0.8).abs() < 1e-9);
+
+    let mut output = vec![];
+    stats.dump(&mut output).unwrap();
+    let output = String::from_utf8(output).unwrap();
+    assert!(output.contains("  IndirectCall profile overlap: 80.000%\n"));
+    assert!(output.contains("  MemOP profile test count sum: 5000\n"));
+}
+
+#[derive(Debug, Deserialize)]
+struct OverlapJson {
+    overlap: f64,
+    base_count: u64,
+    test_count: u64,
+    functions: Vec<OverlapJsonFunction>,
+}
+
+#[derive(Debug, Deserialize)]
+struct OverlapJsonFunction {
+    name: String,
+    overlap: f64,
+    base_count: u64,
+    test_count: u64,
+}
+
+fn assert_overlap_json_eq(actual: &OverlapJson, expected: &OverlapJson) {
+    assert!((actual.overlap - expected.overlap).abs() < 1e-9);
+    assert_eq!(actual.base_count, expected.base_count);
+    assert_eq!(actual.test_count, expected.test_count);
+    assert_eq!(actual.functions.len(), expected.functions.len());
+    for (actual, expected) in actual.functions.iter().zip(&expected.functions) {
+        assert_eq!(actual.name, expected.name);
+        assert!((actual.overlap - expected.overlap).abs() < 1e-9);
+        assert_eq!(actual.base_count, expected.base_count);
+        assert_eq!(actual.test_count, expected.test_count);
+    }
+}
+
+#[test]
+fn overlap_json() {
+    let golden = std::fs::read(data_root_dir().join("misc").join("overlap_1_2.json")).unwrap();
+    let expected: OverlapJson = serde_json::from_slice(&golden).unwrap();
+
+    let base_file = get_data_dir(18).join("overlap_1.proftext");
+    let test_file = get_data_dir(18).join("overlap_2.proftext");
+    let base = parse(&base_file).unwrap();
+    let test = parse(&test_file).unwrap();
+    let filter = OverlapFilter {
+        value_cutoff: 0,
+        name_filter: None,
+    };
+    let mut output = vec![];
+    overlap_profiles(&base, &test, false, &filter)
+        .write_json(&mut output)
+        .unwrap();
+    let actual: OverlapJson = serde_json::from_slice(&output).unwrap();
+    assert_overlap_json_eq(&actual, &expected);
+
+    let output = profparser(&[
+        "overlap",
+        "--json",
+        "--value-cutoff",
+        "0",
+        base_file.to_str().unwrap(),
+        test_file.to_str().unwrap(),
+    ]);
+    assert!(output.status.success());
+    let actual: OverlapJson = serde_json::from_slice(&output.stdout).unwrap();
+    assert_overlap_json_eq(&actual, &expected);
+}
+
+#[test]
+fn trailing_padding() {
+    let mut files = vec![
+        get_data_dir(18).join("compat.profdata.v10"),
+        get_data_dir(18).join("function-entry-coverage.profdata"),
+    ];
+    for version in 11..=21 {
+        files.push(get_data_dir(version).join("c-general.profraw"));
+        files.push(get_data_dir(version).join("basic.proftext"));
+    }
+    for file in &files {
+        let data = std::fs::read(file).unwrap();
+        let expected = parse_bytes(&data).unwrap();
+        for padding in [1, 7, 8, 4096] {
+            let mut padded = data.clone();
+            padded.resize(data.len() + padding, 0);
+            let actual = parse_bytes(&padded).unwrap_or_else(|e| {
+                panic!("{} with {} bytes padding: {}", file.display(), padding, e)
+            });
+            assert_eq!(actual.records(), expected.records(), "{}", file.display());
+        }
+    }
+}
+
+#[test]
+fn header_only_matches_parse() {
+    let mut files = vec![];
+    for name in [
+        "compat.profdata.v2",
+        "compat.profdata.v4",
+        "compat.profdata.v10",
+        "function-entry-coverage.profdata",
+        "weight-instr-bar.profdata",
+        "cs.proftext",
+    ] {
+        files.push(get_data_dir(18).join(name));
+    }
+    for version in 11..=21 {
+        for name in ["c-general.profraw", "compressed.profraw", "basic.proftext"] {
+            files.push(get_data_dir(version).join(name));
+        }
+    }
+    for file in &files {
+        let profile = parse(file).unwrap();
+        let info = parse_header_only(file).unwrap();
+        let name = file.display();
+        assert_eq!(info.version, profile.version(), "{}", name);
+        assert_eq!(info.level, profile.profile_level(), "{}", name);
+        assert_eq!(info.is_entry_first, profile.is_entry_first(), "{}", name);
+        assert_eq!(
+            info.is_byte_coverage,
+            profile.is_byte_coverage(),
+            "{}",
+            name
+        );
+        assert_eq!(info.fn_entry_only, profile.fn_entry_only(), "{}", name);
+        assert_eq!(
+            info.memory_profiling,
+            profile.has_memory_profile(),
+            "{}",
+            name
+        );
+        if let Some(num_functions) = info.num_functions {
+            assert_eq!(num_functions as usize, profile.records().len(), "{}", name);
+        }
+    }
+
+    // The variant flags of indexed profiles come from the version in the header. LLVM sets the
+    // single byte coverage flag for function entry coverage, `header-directives-1.profdata` is
+    // the proftext merged by llvm-profdata 14
+    let entry_coverage = get_data_dir(18).join("function-entry-coverage.profdata");
+    assert!(parse_header_only(&entry_coverage).unwrap().is_byte_coverage);
+    assert!(parse(&entry_coverage).unwrap().is_byte_coverage());
+    let entry_first = get_data_dir(14).join("header-directives-1.profdata");
+    assert!(parse_header_only(&entry_first).unwrap().is_entry_first);
+    let indexed = parse(&entry_first).unwrap();
+    let text = parse(get_data_dir(14).join("header-directives-1.proftext")).unwrap();
+    assert!(indexed.is_entry_first());
+    assert_eq!(indexed.records(), text.records());
+
+    let info = parse_header_only(get_data_dir(16).join("compressed.profraw")).unwrap();
+    assert_eq!(info.format, ProfileFormat::Raw);
+    assert_eq!(
+        info.binary_ids,
+        vec![vec![0x1e, 0xa5, 0xce, 0xed, 0x48, 0x24, 0x5c, 0x9e]]
+    );
+    let info = parse_header_only(get_data_dir(16).join("basic.proftext")).unwrap();
+    assert_eq!(info.format, ProfileFormat::Text);
+    assert_eq!(info.num_functions, None);
+    assert!(info.binary_ids.is_empty());
+}
+
+#[test]
+fn vtable_value_profiles() {
+    let profile = parse(get_data_dir(20).join("vtable-value-prof.proftext")).unwrap();
+    let main = profile.get_record("main").unwrap();
+    assert_eq!(main.num_value_sites(ValueKind::IndirectCallTarget), 2);
+    assert_eq!(main.num_value_sites(ValueKind::VTableTarget), 2);
+    let site = &main.record.value_sites(ValueKind::VTableTarget)[0];
+    let counts = site.iter().map(|x| x.count).collect::<Vec<_>>();
+    assert_eq!(counts, vec![750, 250]);
+    assert_eq!(site[1].value, compute_hash("_ZTV8Derived1"));
+}
+
+#[test]
+#[cfg(feature = "compression")]
+fn indexed_vtable_names() {
+    use llvm_profparser::instrumentation_profile::types::ValueTarget;
+
+    // The records of vtable-value-prof.proftext as a version 12 indexed profile with a compressed
+    // vtable names section
+    let indexed = parse(data_root_dir().join("misc").join("vtable-names.profdata")).unwrap();
+    assert_eq!(indexed.version(), Some(12));
+    assert_eq!(indexed.records().len(), 6);
+    let vtables = [
+        "vtable_prof.cc;_ZTVN12_GLOBAL__N_18Derived2E",
+        "_ZTV8Derived1",
+    ];
+    for vtable in vtables {
+        assert_eq!(
+            indexed.symtab.get(compute_hash(vtable)).map(String::as_str),
+            Some(vtable)
+        );
+    }
+
+    let text = parse(get_data_dir(20).join("vtable-value-prof.proftext")).unwrap();
+    let main = text.get_record("main").unwrap();
+    let targets = indexed.top_targets(&main.record, ValueKind::VTableTarget, 2);
+    assert_eq!(targets.len(), 2);
+    for site in &targets {
+        let names = site.iter().map(|(target, _)| target).collect::<Vec<_>>();
+        assert_eq!(
+            names,
+            vec![
+                &ValueTarget::Name(vtables[0].to_string()),
+                &ValueTarget::Name(vtables[1].to_string())
+            ]
+        );
+    }
+}
+
+#[test]
+#[cfg(feature = "compression")]
+fn compressed_indexed_names() {
+    // vtable-names.profdata with each vtable name in its own compressed block
+    let path = data_root_dir()
+        .join("misc")
+        .join("compressed-vtable-names.profdata");
+    let indexed = parse(&path).unwrap();
+    let expected = parse(data_root_dir().join("misc").join("vtable-names.profdata")).unwrap();
+    assert_eq!(indexed, expected);
+    for vtable in [
+        "vtable_prof.cc;_ZTVN12_GLOBAL__N_18Derived2E",
+        "_ZTV8Derived1",
+    ] {
+        assert_eq!(
+            indexed.symtab.get(compute_hash(vtable)).map(String::as_str),
+            Some(vtable)
+        );
+    }
+
+    // Breaking the zlib header of the first block is an error rather than a panic
+    let mut data = std::fs::read(&path).unwrap();
+    data[0x58a] = 0;
+    let err = parse_bytes(&data).unwrap_err();
+    assert!(
+        err.to_string().contains("invalid deflate stream"),
+        "{}",
+        err
+    );
+}
+
+#[test]
+#[cfg(not(feature = "compression"))]
+fn compressed_indexed_names_without_feature() {
+    let path = data_root_dir()
+        .join("misc")
+        .join("compressed-vtable-names.profdata");
+    let err = parse(path).unwrap_err();
+    assert!(
+        err.to_string()
+            .contains("`compression` feature is disabled"),
+        "{}",
+        err
+    );
+}
+
+#[test]
+fn strip_value_profiling() {
+    let mut profile = parse(get_data_dir(14).join("overlap_1_vp.proftext")).unwrap();
+    let counts = record_counts(&profile);
+    assert!(profile
+        .records()
+        .iter()
+        .any(|x| x.num_value_sites(ValueKind::IndirectCallTarget) > 0));
+
+    profile.strip_value_profiling();
+    for record in profile.records() {
+        for kind in ValueKind::ALL {
+            assert!(record.record.value_sites(kind).is_empty());
+        }
+    }
+    assert_eq!(record_counts(&profile), counts);
+
+    let mut text = vec![];
+    TextInstrProf.write(&profile, &mut text).unwrap();
+    let reparsed = parse_bytes(&text).unwrap();
+    assert!(reparsed.records().iter().all(|x| x.record.data.is_none()));
+    assert_eq!(record_counts(&reparsed), counts);
+}
+
+#[test]
+fn sorted_records_are_deterministic() {
+    let misc = data_root_dir().join("misc");
+    let raw = parse(misc.join("stable.profraw")).unwrap();
+    let mut text = vec![];
+    TextInstrProf.write(&raw, &mut text).unwrap();
+    let text = parse_bytes(&text).unwrap();
+
+    let order = |profile: &InstrumentationProfile| {
+        profile
+            .records_sorted()
+            .iter()
+            .map(|x| (x.name_unchecked(), x.hash_unchecked()))
+            .collect::<Vec<_>>()
+    };
+    let expected = order(&raw);
+    assert!(expected.len() > 1);
+    assert!(expected.windows(2).all(|x| x[0] <= x[1]));
+    assert_eq!(order(&text), expected);
+
+    // Merge order changes the record order but not the sorted order
+    let inputs = [
+        misc.join("premerge_1.profraw"),
+        misc.join("premerge_2.profraw"),
+    ];
+    let forward = merge_profiles(&inputs).unwrap();
+    let backward = merge_profiles(&[&inputs[1], &inputs[0]]).unwrap();
+    assert_eq!(order(&forward), order(&backward));
+}
+
+#[test]
+fn stored_and_computed_summaries() {
+    let indexed = parse(data_root_dir().join("misc").join("merged.profdata")).unwrap();
+    assert!(indexed.has_stored_summary());
+    let stored = indexed.summary();
+
+    // Version 3 indexed profiles predate the summary
+    let old_indexed = parse(get_data_dir(18).join("weight-instr-bar.profdata")).unwrap();
+    assert!(!old_indexed.has_stored_summary());
+    assert_eq!(
+        old_indexed.summary().num_fns as usize,
+        old_indexed.records().len()
+    );
+
+    let raw = parse(data_root_dir().join("misc").join("premerge_1.profraw")).unwrap();
+    assert!(!raw.has_stored_summary());
+    let computed = raw.summary();
+    assert_eq!(computed.num_fns as usize, raw.records().len());
+    assert_eq!(
+        computed.num_counts as usize,
+        raw.records()
+            .iter()
+            .map(|x| x.counts().len())
+            .sum::<usize>()
+    );
+
+    // Computing the summary from the records should match what llvm-profdata stored
+    let mut computed = indexed.clone();
+    computed.merge(&InstrumentationProfile::default()).unwrap();
+    assert!(!computed.has_stored_summary());
+    assert_eq!(computed.summary(), stored);
+}
+
+#[test]
+fn threaded_merge_is_deterministic() {
+    let premerge_1 = data_root_dir().join("misc").join("premerge_1.profraw");
+    let premerge_2 = data_root_dir().join("misc").join("premerge_2.profraw");
+    let out_dir = std::env::temp_dir().join("llvm_profparser_threaded_merge");
+    std::fs::create_dir_all(&out_dir).unwrap();
+
+    let mut outputs = vec![];
+    for jobs in ["1", "4"] {
+        let output = out_dir.join(format!("merged_{}.profraw", jobs));
+        let merge = profparser(&[
+            "merge",
+            "-i",
+            premerge_1.to_str().unwrap(),
+            "-i",
+            premerge_2.to_str().unwrap(),
+            &format!("--num-threads={}", jobs),
+            "-o",
+            output.to_str().unwrap(),
+        ]);
+        assert!(merge.status.success());
+        outputs.push(std::fs::read(&output).unwrap());
+    }
+    assert_eq!(outputs[0], outputs[1]);
+}
+
+#[test]
+fn merge_response_file() {
+    let premerge_1 = data_root_dir().join("misc").join("premerge_1.profraw");
+    let premerge_2 = data_root_dir().join("misc").join("premerge_2.profraw");
+    let dir = std::env::temp_dir().join("llvm_profparser_response_file");
+    std::fs::create_dir_all(&dir).unwrap();
+    let response_file = dir.join("inputs.txt");
+    std::fs::write(
+        &response_file,
+        format!(
+            "# Profiles from the first run\n{}\n\n   \n  # and the second\n  {}  \n",
+            premerge_1.display(),
+            premerge_2.display()
+        ),
+    )
+    .unwrap();
+
+    let inputs = read_response_file(&response_file).unwrap();
+    assert_eq!(
+        inputs,
+        vec![(1, premerge_1.clone()), (1, premerge_2.clone())]
+    );
+    let expected = merge_profiles(&[&premerge_1, &premerge_2]).unwrap();
+    let merged = merge_profiles_from_response_file(&response_file).unwrap();
+    assert_eq!(merged.records(), expected.records());
+
+    let missing = dir.join("missing.txt");
+    let err = merge_profiles_from_response_file(&missing).unwrap_err();
+    assert!(err.to_string().contains("missing.txt"));
+
+    // Inputs can be weighted like `--weighted-input`
+    let weighted_file = dir.join("weighted.txt");
+    std::fs::write(
+        &weighted_file,
+        format!("3,{}\n{}\n", premerge_1.display(), premerge_2.display()),
+    )
+    .unwrap();
+    let inputs = read_response_file(&weighted_file).unwrap();
+    assert_eq!(
+        inputs,
+        vec![(3, premerge_1.clone()), (1, premerge_2.clone())]
+    );
+    let mut expected = parse(&premerge_1).unwrap();
+    expected.scale(3, 1).unwrap();
+    expected.merge(&parse(&premerge_2).unwrap()).unwrap();
+    let merged = merge_profiles_from_response_file(&weighted_file).unwrap();
+    assert_eq!(merged.records(), expected.records());
+    assert_ne!(
+        merged.records(),
+        merge_profiles(&[&premerge_1, &premerge_2])
+            .unwrap()
+            .records()
+    );
+
+    for line in ["0,a.profraw", "x,a.profraw", "1,2,a.profraw"] {
+        std::fs::write(&weighted_file, format!("# comment\n{}\n", line)).unwrap();
+        let err = read_response_file(&weighted_file).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+        assert!(err.to_string().contains("weighted.txt:2:"), "{}", err);
+    }
+
+    let mut outputs = vec![];
+    for args in [
+        vec![format!("@{}", response_file.display())],
+        vec!["-f".to_string(), response_file.display().to_string()],
+        vec![
+            "-i".to_string(),
+            premerge_1.display().to_string(),
+            "-i".to_string(),
+            premerge_2.display().to_string(),
+        ],
+    ] {
+        let output = dir.join(format!("merged_{}.profraw", outputs.len()));
+        let mut command = vec!["merge"];
+        command.extend(args.iter().map(|x| x.as_str()));
+        command.extend(["-o", output.to_str().unwrap()]);
+        assert!(profparser(&command).status.success());
+        outputs.push(std::fs::read(&output).unwrap());
+    }
+    assert_eq!(outputs[0], outputs[2]);
+    assert_eq!(outputs[1], outputs[2]);
+}
+
+#[test]
+fn show_json() {
+    for file in [
+        get_data_dir(14).join("c-general.profraw"),
+        get_data_dir(14).join("overlap_1_vp.proftext"),
+    ] {
+        let output = profparser(&[
+            "show",
+            "--all-functions",
+            "--json",
+            "-i",
+            file.to_str().unwrap(),
+        ]);
+        assert!(output.status.success());
+        let json: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+
+        let profile = parse(&file).unwrap();
+        let records = profile.records_sorted();
+        let functions = json["functions"].as_array().unwrap();
+        assert_eq!(functions.len(), records.len());
+        for (function, record) in functions.iter().zip(&records) {
+            assert_eq!(function["name"], record.name_unchecked());
+            assert_eq!(function["hash"], record.hash_unchecked());
+            let counters = function["counters"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|x| x.as_u64().unwrap())
+                .collect::<Vec<_>>();
+            assert_eq!(counters, record.counts());
+            for (kind, key) in [
+                (ValueKind::IndirectCallTarget, "indirect_call_targets"),
+                (ValueKind::MemOpSize, "memop_sizes"),
+                (ValueKind::VTableTarget, "vtable_targets"),
+            ] {
+                let sites = function["value_sites"][key].as_array().unwrap();
+                assert_eq!(sites.len(), record.record.value_site_count(kind));
+            }
+        }
+        let summary = profile.display(ShowOptions::default()).to_string();
+        assert!(summary.contains(&format!(
+            "Total functions: {}",
+            json["summary"]["total_functions"]
+        )));
+        assert_eq!(json["version"].as_u64(), profile.version());
+    }
+}
+
+/// Name, hash and counts of every record so profiles can be compared ignoring the input format
+fn record_counts(profile: &InstrumentationProfile) -> HashSet<(String, u64, Vec<u64>)> {
+    profile
+        .records()
+        .iter()
+        .map(|x| (x.name_unchecked(), x.hash_unchecked(), x.counts().to_vec()))
+        .collect()
+}
+
+#[test]
+fn mixed_format_merging() {
+    let data = get_data_dir(14);
+    let raw = data.join("c-general.profraw");
+    let indexed = data.join("weight-instr-foo.profdata");
+
+    // The inputs don't share any functions so every record is kept as is
+    let mut expected = record_counts(&parse(&raw).unwrap());
+    expected.extend(record_counts(&parse(&indexed).unwrap()));
+
+    let merged = merge_profiles(&[&raw, &indexed]).unwrap();
+    assert_eq!(record_counts(&merged), expected);
+    let merged = merge_profiles(&[&indexed, &raw]).unwrap();
+    assert_eq!(record_counts(&merged), expected);
+
+    // c-general.profdata is `llvm-profdata merge c-general.profraw` so every function is in both
+    // inputs. `merge_profiles` doesn't add the counts, it keeps the counters of the first input
+    // and marks the records whose counters are the same in both as `zero`
+    let shared = data.join("c-general.profdata");
+    let single = parse(&raw).unwrap();
+    for files in [[&raw, &shared], [&shared, &raw]] {
+        let merged = merge_profiles(&files).unwrap();
+        assert_eq!(record_counts(&merged), record_counts(&single));
+        assert!(merged.records().iter().all(|x| x.record.zero));
+    }
+
+    // Adding the counts matches `llvm-profdata merge c-general.profraw c-general.profdata`
+    let llvm_merged = parse(data.join("c-general-mixed.profdata")).unwrap();
+    for files in [[&raw, &shared], [&shared, &raw]] {
+        let merged = merge_profiles_with_options(&files, &MergeOptions::default())
+            .unwrap()
+            .profile;
+        assert_eq!(record_counts(&merged), record_counts(&llvm_merged));
+        let doubled = single
+            .records()
+            .iter()
+            .map(|x| x.counts().iter().map(|x| x * 2).collect::<Vec<_>>())
+            .collect::<HashSet<_>>();
+        let counts = merged
+            .records()
+            .iter()
+            .map(|x| x.counts().to_vec())
+            .collect::<HashSet<_>>();
+        assert_eq!(counts, doubled);
+    }
+}
+
+#[test]
+fn mixed_format_merge_command() {
+    for (llvm_version, rustc_version) in &*SUPPORTED_LLVM_VERSIONS {
+        let data = get_data_dir(*llvm_version);
+        let raw = data.join("c-general.profraw");
+        let llvm_merge = |files: &[&PathBuf], output: &PathBuf| {
+            let llvm = Command::new("cargo")
+                .args(&[&format!("+{rustc_version}"), "profdata", "--", "merge"])
+                .args(files)
+                .arg("-o")
+                .arg(output)
+                .output()
+                .unwrap();
+            assert!(
+                llvm.status.success(),
+                "llvm-profdata {} merge failed: {}",
+                llvm_version,
+                String::from_utf8_lossy(&llvm.stderr)
+            );
+        };
+        // An indexed profile of the raw one so the inputs share every function
+        let indexed = PathBuf::from(format!("llvm_c_general_{}.profdata", llvm_version));
+        llvm_merge(&[&raw], &indexed);
+
+        for other in [data.join("weight-instr-foo.profdata"), indexed.clone()] {
+            let llvm_output = PathBuf::from(format!("llvm_mixed_{}.profdata", llvm_version));
+            llvm_merge(&[&raw, &other], &llvm_output);
+            let llvm_merged = parse(&llvm_output).unwrap();
+            // llvm-profdata adds the counts, see `mixed_format_merging` for `merge_profiles`
+            let rust_merged =
+                merge_profiles_with_options(&[&raw, &other], &MergeOptions::default())
+                    .unwrap()
+                    .profile;
+            assert!(!llvm_merged.records().is_empty());
+            assert_eq!(record_counts(&llvm_merged), record_counts(&rust_merged));
+        }
+    }
+}
+
+#[test]
+fn mcdc_bitmaps() {
+    // Version 10 raw profile with two functions, only `bar` has MC/DC bitmap bytes
+    let profile = parse(data_root_dir().join("misc").join("mcdc.profraw")).unwrap();
+    assert_eq!(profile.get_record("foo").unwrap().counts(), &[5]);
+    assert_eq!(profile.get_record("bar").unwrap().counts(), &[3, 2]);
+    let bitmaps = profile.mcdc_bitmaps();
+    assert_eq!(bitmaps.len(), 1);
+    assert_eq!(
+        bitmaps.get(&compute_hash("bar")),
+        Some(&vec![0b101, 0, 0x80])
+    );
+
+    let mut merged = profile.clone();
+    merged.merge(&profile).unwrap();
+    assert_eq!(merged.mcdc_bitmaps(), bitmaps);
+
+    for version in [18, 19] {
+        let profile = parse(get_data_dir(version).join("c-general.profraw")).unwrap();
+        assert!(profile.mcdc_bitmaps().is_empty());
+    }
+}
+
 #[test]
 fn check_raw_data_consistency() {
     let raw = data_root_dir().join("misc").join("stable.profraw");
@@ -504,3 +1304,1478 @@ fn hash_table_regression_check() {
     // correctly to prevent a regression.
     parse(&ferrocene).unwrap();
 }
+
+#[test]
+fn raw_profile_layout() {
+    let raw = data_root_dir().join("misc").join("premerge_1.profraw");
+    let data = std::fs::read(raw).unwrap();
+    let profile = parse_bytes(&data).unwrap();
+    let (_, layout) = RawInstrProf64::parse_layout(&data).unwrap();
+
+    assert_eq!(layout.version, 8);
+    assert_eq!(layout.functions.len(), profile.records().len());
+    for (function, record) in layout.functions.iter().zip(profile.records()) {
+        // Reading the counters using the exposed offsets should match what the parser read
+        let start = (layout.counters_offset as i64 + function.counters_offset) as usize;
+        let counts = data[start..]
+            .chunks_exact(8)
+            .take(function.num_counters as usize)
+            .map(|x| u64::from_le_bytes(x.try_into().unwrap()))
+            .collect::<Vec<_>>();
+        assert_eq!(counts, record.counts());
+        assert_eq!(Some(function.func_hash), record.hash);
+    }
+}
+
+#[test]
+fn raw_profile_bitmap_layout() {
+    // Version 8 predates the bitmap section
+    let data = std::fs::read(data_root_dir().join("misc").join("premerge_1.profraw")).unwrap();
+    let (_, layout) = RawInstrProf64::parse_layout(&data).unwrap();
+    assert_eq!(layout.bitmap_len, 0);
+    assert_eq!(layout.bitmap_offset, layout.names_offset);
+    assert!(layout.functions.iter().all(|x| x.bitmap_offset.is_none()));
+
+    let data = std::fs::read(data_root_dir().join("misc").join("mcdc.profraw")).unwrap();
+    let profile = parse_bytes(&data).unwrap();
+    let (_, layout) = RawInstrProf64::parse_layout(&data).unwrap();
+    assert!(layout.bitmap_len > 0);
+    assert!(layout.bitmap_offset >= layout.counters_offset + layout.counters_len);
+    assert!(layout.names_offset >= layout.bitmap_offset + layout.bitmap_len);
+
+    let mut with_bitmaps = 0;
+    for function in &layout.functions {
+        let offset = match function.bitmap_offset {
+            Some(offset) => offset,
+            None => {
+                assert_eq!(function.num_bitmap_bytes, 0);
+                continue;
+            }
+        };
+        with_bitmaps += 1;
+        // Reading the bitmap using the exposed offsets should match what the parser read
+        let start = (layout.bitmap_offset as i64 + offset) as usize;
+        let bitmap = &data[start..][..function.num_bitmap_bytes as usize];
+        assert_eq!(
+            profile
+                .mcdc_bitmaps()
+                .get(&function.name_ref)
+                .map(Vec::as_slice),
+            Some(bitmap)
+        );
+    }
+    assert_eq!(with_bitmaps, profile.mcdc_bitmaps().len());
+    assert!(with_bitmaps > 0);
+}
+
+#[test]
+fn profile_set_directory() {
+    let dir = data_root_dir().join("misc").join("multibin_merge");
+    let set = ProfileSet::from(dir.as_path());
+    let files = set.files().unwrap();
+    assert_eq!(files.len(), 4);
+    assert!(files.windows(2).all(|x| x[0] < x[1]));
+
+    let merged = set.merge_all().unwrap();
+    assert_eq!(merged, merge_profiles(&files).unwrap());
+
+    let summaries = set.summaries().unwrap();
+    assert_eq!(summaries.len(), 4);
+    for (file, summary) in &summaries {
+        assert_eq!(
+            summary.num_fns as usize,
+            parse(file).unwrap().records().len()
+        );
+    }
+
+    let symtab = set.union_symtab().unwrap();
+    for file in &files {
+        for (hash, name) in parse(file).unwrap().symtab().iter() {
+            assert_eq!(symtab.get(*hash), Some(name));
+        }
+    }
+
+    // A single file is a set of one
+    let single = ProfileSet::from(files[0].clone());
+    assert_eq!(single.files().unwrap(), vec![files[0].clone()]);
+}
+
+#[test]
+fn discover_run_profraws() {
+    let dir = data_root_dir().join("misc").join("discover");
+    let found = discover_profraws(&dir).unwrap();
+    let names = found
+        .iter()
+        .map(|x| x.file_name().unwrap().to_str().unwrap())
+        .collect::<Vec<_>>();
+    // The empty profraw, text profile, readme and nested directory are all skipped
+    assert_eq!(
+        names,
+        [
+            "cov.profraw.4243",
+            "default_8f3a1c2d4b5e6f70_0.profraw",
+            "prof-4242.profraw"
+        ]
+    );
+    for file in &found {
+        assert!(!parse(file).unwrap().records().is_empty());
+    }
+
+    assert!(discover_profraws(dir.join("missing")).is_err());
+}
+
+#[derive(Debug, Deserialize)]
+struct SummaryJson {
+    total_functions: usize,
+    max_function_count: u64,
+    max_internal_block_count: u64,
+    total_blocks: usize,
+    total_count: u64,
+    detailed_summary: Vec<SummaryJsonEntry>,
+}
+
+#[derive(Debug, Deserialize, PartialEq)]
+struct SummaryJsonEntry {
+    cutoff: u64,
+    min_count: u64,
+    num_counts: u64,
+}
+
+#[test]
+fn summary_command_json() {
+    let file = get_data_dir(14).join("c-general.profraw");
+    let output = profparser(&[
+        "summary",
+        "--detailed",
+        "--json",
+        "--cutoffs",
+        "800000,900000",
+        file.to_str().unwrap(),
+    ]);
+    assert!(output.status.success());
+    let summary: SummaryJson = serde_json::from_slice(&output.stdout).unwrap();
+
+    // Values reported by `llvm-profdata show --detailed-summary`
+    assert_eq!(summary.total_functions, 12);
+    assert_eq!(summary.max_function_count, 1);
+    assert_eq!(summary.max_internal_block_count, 100);
+    assert_eq!(summary.total_blocks, 121);
+    assert_eq!(summary.total_count, 2373);
+    assert_eq!(
+        summary.detailed_summary,
+        vec![
+            SummaryJsonEntry {
+                cutoff: 800_000,
+                min_count: 33,
+                num_counts: 31
+            },
+            SummaryJsonEntry {
+                cutoff: 900_000,
+                min_count: 17,
+                num_counts: 39
+            },
+        ]
+    );
+}
+
+#[test]
+fn compare_summaries() {
+    let base = parse(get_data_dir(14).join("overlap_1.proftext"))
+        .unwrap()
+        .summary();
+    let diff = base.compare(&base);
+    assert!(diff.is_unchanged());
+    assert_eq!(diff.total_count, 0);
+    assert_eq!(diff.max_function_count, 0);
+    assert_eq!(diff.detailed_summary.len(), base.detailed_summary.len());
+    assert!(diff
+        .detailed_summary
+        .iter()
+        .all(|x| x.min_count == 0 && x.num_counts == 0));
+
+    let other = parse(get_data_dir(14).join("overlap_2.proftext"))
+        .unwrap()
+        .summary();
+    let diff = base.compare(&other);
+    assert!(!diff.is_unchanged());
+    assert_eq!(
+        diff.total_count,
+        other.total_count as i64 - base.total_count as i64
+    );
+    assert_eq!(
+        diff.max_function_count,
+        other.max_function_count as i64 - base.max_function_count as i64
+    );
+    for (entry, (own, theirs)) in diff
+        .detailed_summary
+        .iter()
+        .zip(base.detailed_summary.iter().zip(&other.detailed_summary))
+    {
+        assert_eq!(entry.cutoff, own.cutoff);
+        assert_eq!(
+            entry.min_count,
+            theirs.min_count as i64 - own.min_count as i64
+        );
+    }
+    assert_eq!(other.compare(&base).total_count, -diff.total_count);
+}
+
+#[test]
+fn empty_profile_summary() {
+    let file = data_root_dir().join("misc").join("empty.proftext");
+    let profile = parse(&file).unwrap();
+    assert!(profile.records().is_empty());
+    assert_eq!(profile.summary().num_fns, 0);
+
+    let output = profparser(&[
+        "show",
+        "--topn",
+        "5",
+        "--show_detailed_summary",
+        "-i",
+        file.to_str().unwrap(),
+    ]);
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(stdout.contains("Total functions: 0"));
+    assert!(stdout.contains("Top 5 functions with the largest internal block counts"));
+    assert!(!stdout.contains("NaN"));
+
+    let output = profparser(&["summary", "--detailed", "--json", file.to_str().unwrap()]);
+    assert!(output.status.success());
+    let summary: SummaryJson = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(summary.total_functions, 0);
+    assert_eq!(summary.total_count, 0);
+    assert!(summary
+        .detailed_summary
+        .iter()
+        .all(|x| x.min_count == 0 && x.num_counts == 0));
+}
+
+#[test]
+fn counter_relocation_bias() {
+    // Copy of llvm-14/c-general.profraw with 0x40000 added to the header's counters delta
+    const BIAS: u64 = 0x40000;
+    let original = parse(get_data_dir(14).join("c-general.profraw")).unwrap();
+    let biased = std::fs::read(data_root_dir().join("misc").join("biased.profraw")).unwrap();
+    assert!(parse_bytes(&biased).is_err());
+
+    let (_, relocated) = RawInstrProf64::parse_bytes_with_bias(&biased, BIAS).unwrap();
+    assert_eq!(record_counts(&relocated), record_counts(&original));
+    assert!(RawInstrProf64::parse_bytes_with_bias(&biased, BIAS + 8).is_err());
+}
+
+#[test]
+fn binary_ids_len_past_eof() {
+    // The binary IDs length follows the magic and version from version 7
+    let mut data = std::fs::read(get_data_dir(14).join("c-general.profraw")).unwrap();
+    let len = data.len() as u64 + 1;
+    data[16..24].copy_from_slice(&len.to_le_bytes());
+    assert!(parse_bytes(&data).is_err());
+    assert!(RawInstrProf64::parse_layout(&data).is_err());
+    data[16..24].copy_from_slice(&u64::MAX.to_le_bytes());
+    assert!(parse_bytes(&data).is_err());
+}
+
+#[test]
+fn relocated_counters_out_of_order() {
+    // From version 8 each record's counters are found through its relative `CounterPtr`, so
+    // reversing the order of the counters and fixing up the pointers mustn't change the counts
+    for llvm_version in 14..=21 {
+        let data = std::fs::read(get_data_dir(llvm_version).join("c-general.profraw")).unwrap();
+        let (_, layout) = RawInstrProf64::parse_layout(&data).unwrap();
+        assert!(layout.version >= 8);
+        let mut reversed = data.clone();
+        let mut offset = layout.counters_len as i64;
+        for (i, function) in layout.functions.iter().enumerate() {
+            let len = function.num_counters as usize * 8;
+            offset -= len as i64;
+            let old = (layout.counters_offset as i64 + function.counters_offset) as usize;
+            let new = (layout.counters_offset as i64 + offset) as usize;
+            reversed[new..(new + len)].copy_from_slice(&data[old..(old + len)]);
+            // The counter pointer follows the name and function hashes
+            let ptr = layout.data_offset + i * layout.data_record_size + 16;
+            let counter_ptr = function
+                .counter_ptr
+                .wrapping_add((offset - function.counters_offset) as u64);
+            reversed[ptr..(ptr + 8)].copy_from_slice(&counter_ptr.to_le_bytes());
+        }
+        assert_ne!(reversed, data);
+        let expected = parse_bytes(&data).unwrap();
+        let profile = parse_bytes(&reversed).unwrap();
+        assert_eq!(record_counts(&profile), record_counts(&expected));
+    }
+
+    // Before version 8 the counters are read in order. LLVM 13 made the pointers relative
+    // without changing the version so its version 7 profiles can have either
+    let absolute = parse(get_data_dir(13).join("c-general.profraw")).unwrap();
+    let relative = parse(get_data_dir(13).join("compressed.profraw")).unwrap();
+    assert_eq!(record_counts(&relative), record_counts(&absolute));
+}
+
+#[test]
+fn profile_set_operations() {
+    let data = get_data_dir(14);
+    let foo = parse(data.join("foo3-1.proftext")).unwrap();
+    let foo_bar = parse(data.join("foo3bar3-1.proftext")).unwrap();
+
+    let both = foo_bar.intersect(&foo);
+    assert_eq!(both.records().len(), 1);
+    assert_eq!(both.get_record("foo").unwrap().counts(), &[2, 3, 5]);
+    assert!(both.get_record("bar").is_none());
+    assert!(both.symtab().iter().all(|(_, name)| name == "foo"));
+    assert_eq!(
+        foo.intersect(&foo_bar).get_record("foo").unwrap().counts(),
+        &[1, 2, 3]
+    );
+    assert!(foo
+        .intersect(&parse(data.join("bar3-1.proftext")).unwrap())
+        .records()
+        .is_empty());
+
+    // foo is in both with non-zero counts which are added together
+    let all = foo.union(&foo_bar).unwrap();
+    assert_eq!(all.records().len(), 2);
+    assert_eq!(all.get_record("foo").unwrap().counts(), &[3, 5, 8]);
+    assert_eq!(all.get_record("bar").unwrap().counts(), &[7, 11, 13]);
+    assert_eq!(
+        foo_bar
+            .union(&foo)
+            .unwrap()
+            .get_record("foo")
+            .unwrap()
+            .counts(),
+        &[3, 5, 8]
+    );
+}
+
+#[test]
+fn symtab_str_lookup() {
+    let profile = parse(get_data_dir(14).join("c-general.profraw")).unwrap();
+    let symtab = profile.symtab();
+    assert!(!symtab.is_empty());
+    for (hash, name) in symtab.iter() {
+        assert_eq!(symtab.get_str(*hash), Some(name.as_str()));
+        assert_eq!(
+            symtab.get_str(*hash).map(str::to_string),
+            symtab.get(*hash).cloned()
+        );
+    }
+    assert_eq!(symtab.get_str(0), None);
+}
+
+#[test]
+fn text_profile_with_bom() {
+    // Copy of llvm-14/foo3bar3-1.proftext with a UTF-8 BOM and blank lines at the start
+    let file = data_root_dir().join("misc").join("bom.proftext");
+    let data = std::fs::read(&file).unwrap();
+    assert!(data.starts_with(b"\xEF\xBB\xBF"));
+    assert!(TextInstrProf::has_format(data.as_slice()));
+
+    let expected = parse(get_data_dir(14).join("foo3bar3-1.proftext")).unwrap();
+    assert_eq!(parse(&file).unwrap(), expected);
+    assert_eq!(parse_as(&file, ProfileFormat::Text).unwrap(), expected);
+}
+
+#[test]
+fn skip_unknown_value_kinds() {
+    // Copy of llvm-14/overlap_1_vp.proftext with an extra value kind 7 between the known ones
+    let file = data_root_dir()
+        .join("misc")
+        .join("unknown-value-kind.proftext");
+    assert!(parse(&file).is_err());
+
+    let options = ParseOptions {
+        skip_unknown_value_kinds: true,
+    };
+    let expected = parse(get_data_dir(14).join("overlap_1_vp.proftext")).unwrap();
+    assert_eq!(parse_with_options(&file, &options).unwrap(), expected);
+}
+
+#[test]
+fn record_equality_ignores_layout() {
+    fn hash_of(record: &InstrProfRecord) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        record.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    // misc/c-general.proftext is `llvm-profdata merge --text` of llvm-14/c-general.profraw. Only
+    // the raw records know where their counters are in the file
+    let raw = parse(get_data_dir(14).join("c-general.profraw")).unwrap();
+    let text = parse(data_root_dir().join("misc").join("c-general.proftext")).unwrap();
+    assert!(raw
+        .records()
+        .iter()
+        .any(|x| !x.record.counts_bytes_offset.is_empty()));
+    let raw_records = raw.records().iter().collect::<HashSet<_>>();
+    let text_records = text.records().iter().collect::<HashSet<_>>();
+    assert!(!raw_records.is_empty());
+    assert_eq!(raw_records, text_records);
+
+    // Copy of llvm-14/overlap_1_vp.proftext with the values in each site swapped around
+    let vp = parse(get_data_dir(14).join("overlap_1_vp.proftext")).unwrap();
+    let reordered = parse(
+        data_root_dir()
+            .join("misc")
+            .join("overlap_1_vp-reordered.proftext"),
+    )
+    .unwrap();
+    let (a, b) = (&vp.records()[0].record, &reordered.records()[0].record);
+    assert_ne!(
+        a.value_sites(ValueKind::IndirectCallTarget),
+        b.value_sites(ValueKind::IndirectCallTarget)
+    );
+    assert_eq!(a, b);
+    assert_eq!(hash_of(a), hash_of(b));
+}
+
+#[test]
+fn validate_command() {
+    let validate_cmd = |args: &[&str], file: &PathBuf| {
+        let mut command = vec!["validate"];
+        command.extend(args);
+        command.push(file.to_str().unwrap());
+        profparser(&command)
+    };
+    let ferrocene = data_root_dir()
+        .join("misc")
+        .join("ferrocene-library-aarch64-apple-darwin.profdata");
+    let output = validate_cmd(&["--strict"], &ferrocene);
+    assert!(output.status.success());
+    assert!(output.stdout.is_empty());
+
+    // First 300 bytes of llvm-14/c-general.profraw
+    let truncated = data_root_dir().join("misc").join("truncated.profraw");
+    let output = validate_cmd(&[], &truncated);
+    assert!(!output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert_eq!(stdout.trim(), "error at offset 0x12c: profile is truncated");
+
+    // foo is in the profile twice with the same hash, only a failure when strict
+    let duplicated = get_data_dir(16).join("counter-mismatch-1.proftext");
+    assert!(validate_cmd(&[], &duplicated).status.success());
+    let output = validate_cmd(&["--strict"], &duplicated);
+    assert!(!output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(stdout.starts_with("warning: function foo"));
+}
+
+#[test]
+fn total_count_matches_llvm() {
+    // Values are the "Total count" from `llvm-profdata show --detailed-summary` for LLVM 14
+    let expected = [
+        ("c-general.profraw", 2373),
+        ("weight-instr-bar.profdata", 1300483325),
+        ("multiple-profdata-merge.proftext", 4200014),
+        ("cs.proftext", 0),
+    ];
+    for (file, total) in expected {
+        let profile = parse(get_data_dir(14).join(file)).unwrap();
+        assert_eq!(profile.total_count(), total, "{}", file);
+    }
+}
+
+#[test]
+fn raw_profile_without_names() {
+    // Copy of llvm-14/c-general.profraw with the names section overwritten with zeroes
+    let expected = parse(get_data_dir(14).join("c-general.profraw")).unwrap();
+    let profile = parse(data_root_dir().join("misc").join("zeroed-names.profraw")).unwrap();
+    assert!(profile.symtab.is_empty());
+    assert_eq!(profile.records().len(), expected.records().len());
+    for (record, expected) in profile.records().iter().zip(expected.records()) {
+        assert_eq!(record.name, None);
+        assert_eq!(record.name_hash, expected.name_hash);
+        assert_eq!(record.hash, expected.hash);
+        assert_eq!(record.counts(), expected.counts());
+    }
+
+    // The records are still matched by their hashes when merging
+    let mut merged = profile.clone();
+    merged
+        .merge_with_options(&profile, &MergeOptions::default())
+        .unwrap();
+    assert_eq!(merged.records().len(), profile.records().len());
+    for (record, single) in merged.records().iter().zip(profile.records()) {
+        let doubled = single.counts().iter().map(|x| x * 2).collect::<Vec<_>>();
+        assert_eq!(record.counts(), doubled.as_slice());
+    }
+}
+
+#[test]
+fn show_function_filter() {
+    let file = get_data_dir(14).join("c-general.profraw");
+    let output = profparser(&[
+        "show",
+        "--function=loops,switch",
+        "-i",
+        file.to_str().unwrap(),
+    ]);
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let shown = stdout
+        .lines()
+        .filter_map(|x| x.strip_prefix("  ")?.strip_suffix(':'))
+        .collect::<HashSet<_>>();
+    assert_eq!(
+        shown,
+        HashSet::from(["simple_loops", "boolop_loops", "switches", "big_switch"])
+    );
+    assert!(stdout.contains("Functions shown: 4"));
+    // The summary still covers every function
+    assert!(stdout.contains("Total functions: 12"));
+}
+
+#[test]
+fn counters_for_function() {
+    // Counts from `llvm-profdata show --function=simple_loops --counts`
+    let expected = [1, 100, 100, 75];
+    let hash = compute_hash("simple_loops");
+    for file in [
+        get_data_dir(14).join("c-general.profraw"),
+        data_root_dir().join("misc").join("c-general.proftext"),
+    ] {
+        let profile = parse(&file).unwrap();
+        assert_eq!(profile.counters_for("simple_loops"), Some(&expected[..]));
+        assert_eq!(profile.counters_for_hash(hash), Some(&expected[..]));
+        assert_eq!(profile.counters_for("not_a_function"), None);
+        let record = &profile.get_record("simple_loops").unwrap().record;
+        assert_eq!(record.entry_count(), Some(1));
+        assert_eq!(record.max_internal_block_count(), Some(100));
+        // main only has the entry counter
+        let record = &profile.get_record("main").unwrap().record;
+        assert_eq!(record.entry_count(), Some(1));
+        assert_eq!(record.max_internal_block_count(), None);
+        assert_eq!(
+            profile.counters_for_hash(compute_hash("not_a_function")),
+            None
+        );
+    }
+    // Without names only the hash lookup works
+    let unnamed = parse(data_root_dir().join("misc").join("zeroed-names.profraw")).unwrap();
+    assert_eq!(unnamed.counters_for("simple_loops"), None);
+    assert_eq!(unnamed.counters_for_hash(hash), Some(&expected[..]));
+}
+
+#[test]
+fn single_byte_coverage() {
+    // llvm-14/c-general.profraw rewritten with one byte counters, executed counters are 0 and
+    // the rest 0xff
+    let profile = parse(
+        data_root_dir()
+            .join("misc")
+            .join("single-byte-coverage.profraw"),
+    )
+    .unwrap();
+    let expected = parse(get_data_dir(14).join("c-general.profraw")).unwrap();
+    assert!(profile.is_single_byte_coverage());
+    assert!(!expected.is_single_byte_coverage());
+    assert_eq!(profile.records().len(), expected.records().len());
+    for (record, expected) in profile.records().iter().zip(expected.records()) {
+        assert_eq!(record.name, expected.name);
+        let covered = expected
+            .counts()
+            .iter()
+            .map(|x| (*x > 0) as u64)
+            .collect::<Vec<_>>();
+        assert_eq!(record.counts(), covered.as_slice());
+    }
+}
+
+#[test]
+fn truncated_single_byte_coverage() {
+    let file = data_root_dir()
+        .join("misc")
+        .join("single-byte-coverage.profraw");
+    let data = std::fs::read(&file).unwrap();
+    // No data at all is an empty text profile
+    for len in 1..data.len() {
+        assert!(parse_bytes(&data[..len]).is_err(), "{}", len);
+    }
+    let err = parse_bytes(&data[..300]).unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+    assert_eq!(err.to_string(), "truncated profile");
+
+    let dir = std::env::temp_dir().join("llvm_profparser_truncated_coverage");
+    std::fs::create_dir_all(&dir).unwrap();
+    let truncated = dir.join("truncated.profraw");
+    for len in [300, data.len() - 1] {
+        std::fs::write(&truncated, &data[..len]).unwrap();
+        let output = profparser(&["show", "-i", truncated.to_str().unwrap()]);
+        assert_eq!(output.status.code(), Some(1));
+    }
+}
+
+#[test]
+fn single_byte_covered_functions() {
+    let file = data_root_dir()
+        .join("misc")
+        .join("single-byte-coverage.profraw");
+    let mut profile = parse(&file).unwrap();
+    let expected = profile
+        .records()
+        .iter()
+        .map(|x| x.name.clone().unwrap())
+        .collect::<Vec<_>>();
+    assert_eq!(profile.covered_functions().collect::<Vec<_>>(), expected);
+
+    let output = profparser(&["show", "--covered", "-i", file.to_str().unwrap()]);
+    assert!(output.status.success());
+    let mut names = expected.join("\n");
+    names.push('\n');
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), names);
+
+    // A function whose bytes were never cleared didn't run
+    let record = profile.find_record_by_name_mut("jumps").unwrap();
+    record.record.counts.fill(0);
+    let record = profile.get_record("jumps").unwrap();
+    assert_eq!(
+        profile.coverage_status(&record.record),
+        CoverageStatus::NotCovered
+    );
+    assert!(!profile.covered_functions().any(|x| x == "jumps"));
+    let record = profile.get_record("main").unwrap();
+    assert_eq!(
+        profile.coverage_status(&record.record),
+        CoverageStatus::Covered
+    );
+}
+
+#[test]
+fn value_profiling_kinds_present() {
+    let kinds = |path: PathBuf| parse(path).unwrap().value_profiling_kinds_present();
+    assert!(kinds(get_data_dir(14).join("foo3-1.proftext")).is_empty());
+    assert_eq!(
+        kinds(get_data_dir(14).join("overlap_1_vp.proftext")),
+        HashSet::from([ValueKind::IndirectCallTarget, ValueKind::MemOpSize])
+    );
+    assert_eq!(
+        kinds(get_data_dir(20).join("vtable-value-prof.proftext")),
+        HashSet::from([ValueKind::IndirectCallTarget, ValueKind::VTableTarget])
+    );
+
+    let mut stripped = parse(get_data_dir(14).join("overlap_1_vp.proftext")).unwrap();
+    stripped.strip_value_profiling();
+    assert!(stripped.value_profiling_kinds_present().is_empty());
+}
+
+#[test]
+fn func_hash_matches_show() {
+    let file = get_data_dir(14).join("c-general.profraw");
+    let profile = parse(&file).unwrap();
+    let output = profparser(&["show", "--all-functions", "-i", file.to_str().unwrap()]);
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let mut lines = stdout.lines();
+    let mut checked = 0;
+    while let Some(line) = lines.next() {
+        if let Some(name) = line.strip_prefix("  ").and_then(|x| x.strip_suffix(':')) {
+            let hash = lines
+                .next()
+                .unwrap()
+                .trim()
+                .strip_prefix("Hash: 0x")
+                .unwrap();
+            let hash = u64::from_str_radix(hash, 16).unwrap();
+            let record = profile.get_record(name).unwrap();
+            assert_eq!(record.func_hash(), Some(hash));
+            assert_ne!(record.name_hash, Some(hash));
+            checked += 1;
+        }
+    }
+    assert_eq!(checked, profile.records().len());
+}
+
+#[test]
+fn merge_incompatible_kinds() {
+    let data = get_data_dir(14);
+    let ir = data.join("ir-basic.proftext");
+    let fe = data.join("fe-basic.proftext");
+    let err = merge_profiles(&[&ir, &fe]).unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+    assert_eq!(
+        err.to_string(),
+        "IR and Front-end instrumentation profiles can't be combined"
+    );
+    assert!(merge_profiles(&[&fe, &ir]).is_err());
+
+    let mut merged = parse(&ir).unwrap();
+    let fe = parse(&fe).unwrap();
+    assert_eq!(
+        merged.merge(&fe).unwrap_err(),
+        InstrProfError::KindMismatch(IncompatibilityReason::Level {
+            expected: InstrumentationLevel::Ir,
+            found: InstrumentationLevel::FrontEnd,
+        })
+    );
+    assert_eq!(merged, parse(&ir).unwrap());
+
+    let options = MergeOptions {
+        allow_kind_mismatch: true,
+        ..Default::default()
+    };
+    merged.merge_with_options(&fe, &options).unwrap();
+    assert!(merged.is_ir_level_profile());
+    assert!(merged.get_record("foo").is_some());
+    assert!(merged.get_record("foo2").is_some());
+
+    // Context sensitive profiles can be merged with ones which aren't
+    let cs = parse(data.join("cs.proftext")).unwrap();
+    let mut merged = parse(&ir).unwrap();
+    merged.merge(&cs).unwrap();
+    assert!(merged.has_csir_level_profile());
+}
+
+#[test]
+#[cfg(feature = "compression")]
+fn parse_gzip() {
+    let expected = parse(get_data_dir(14).join("c-general.profraw")).unwrap();
+    let gzipped = data_root_dir().join("misc").join("c-general.profraw.gz");
+    assert_eq!(parse(&gzipped).unwrap(), expected);
+    assert_eq!(parse_as(&gzipped, ProfileFormat::Raw).unwrap(), expected);
+}
+
+#[test]
+fn parse_directory() {
+    let dir = get_data_dir(14);
+    let err = parse(&dir).unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+    let message = err.to_string();
+    assert!(message.starts_with(&dir.display().to_string()));
+    assert!(message.contains("merge_profiles"));
+    assert!(message.contains("ProfileSet"));
+}
+
+#[test]
+fn profile_level_matches_flags() {
+    let data = get_data_dir(14);
+    let level = |name: &str| parse(data.join(name)).unwrap().profile_level();
+    assert_eq!(level("fe-basic.proftext"), ProfileLevel::FrontEnd);
+    assert_eq!(level("ir-basic.proftext"), ProfileLevel::Ir);
+    assert_eq!(level("cs.proftext"), ProfileLevel::ContextSensitiveIr);
+
+    let mut checked = 0;
+    for version in 11..=21 {
+        for entry in read_dir(get_data_dir(version)).unwrap() {
+            let path = entry.unwrap().path();
+            let ext = path.extension().and_then(OsStr::to_str);
+            if !matches!(ext, Some("proftext" | "profdata")) {
+                continue;
+            }
+            let profile = match parse(&path) {
+                Ok(profile) => profile,
+                Err(_) => continue,
+            };
+            let expected = match (
+                profile.is_ir_level_profile(),
+                profile.has_csir_level_profile(),
+            ) {
+                (false, false) => ProfileLevel::FrontEnd,
+                (true, false) => ProfileLevel::Ir,
+                (true, true) => ProfileLevel::ContextSensitiveIr,
+                (false, true) => panic!("context sensitive profiles are IR level"),
+            };
+            assert_eq!(profile.profile_level(), expected);
+            checked += 1;
+        }
+    }
+    assert!(checked > 100);
+}
+
+#[test]
+#[cfg(feature = "compression")]
+fn merge_dedup_inputs() {
+    let data = get_data_dir(14);
+    let file = data.join("c-general.profraw");
+    // Same file through a different path and the same contents gzipped
+    let same_file = data.join("..").join("llvm-14").join("c-general.profraw");
+    let same_contents = data_root_dir().join("misc").join("c-general.profraw.gz");
+    let files = [&file, &same_file, &same_contents];
+    let single = parse(&file).unwrap();
+
+    let merged = merge_profiles_with_options(&files, &MergeOptions::default()).unwrap();
+    assert!(merged.skipped_duplicates.is_empty());
+    assert_eq!(merged.profile.total_count(), single.total_count() * 3);
+
+    let options = MergeOptions {
+        dedup_inputs: true,
+        ..Default::default()
+    };
+    let merged = merge_profiles_with_options(&files, &options).unwrap();
+    assert_eq!(merged.skipped_duplicates, vec![same_file, same_contents]);
+    assert_eq!(record_counts(&merged.profile), record_counts(&single));
+}
+
+#[test]
+fn merge_max_functions() {
+    let file = get_data_dir(14).join("c-general.profraw");
+    let single = parse(&file).unwrap();
+    let options = MergeOptions {
+        max_functions: Some(3),
+        ..Default::default()
+    };
+    let merged = merge_profiles_with_options(&[&file, &file], &options).unwrap();
+    assert_eq!(merged.profile.records().len(), 3);
+    assert_eq!(merged.evicted_functions, single.records().len() - 3);
+
+    let max_count = |record: &NamedInstrProfRecord| record.counts().iter().copied().max();
+    let mut maxes = single
+        .records()
+        .iter()
+        .map(|x| max_count(x).unwrap_or_default())
+        .collect::<Vec<_>>();
+    maxes.sort_unstable_by(|a, b| b.cmp(a));
+    let coldest_kept = merged
+        .profile
+        .records()
+        .iter()
+        .filter_map(max_count)
+        .min()
+        .unwrap();
+    // Both inputs are the same so the counts are doubled
+    assert_eq!(coldest_kept, maxes[2] * 2);
+    for record in merged.profile.records() {
+        let single = single.get_record(record.name.as_deref().unwrap()).unwrap();
+        assert_eq!(max_count(record), max_count(single).map(|x| x * 2));
+    }
+    for record in single.records() {
+        if merged
+            .profile
+            .get_record(record.name.as_deref().unwrap())
+            .is_none()
+        {
+            assert!(max_count(record).unwrap_or_default() * 2 <= coldest_kept);
+        }
+    }
+
+    // Under the limit nothing is evicted
+    let options = MergeOptions {
+        max_functions: Some(single.records().len()),
+        ..Default::default()
+    };
+    let merged = merge_profiles_with_options(&[&file], &options).unwrap();
+    assert_eq!(merged.evicted_functions, 0);
+    assert_eq!(record_counts(&merged.profile), record_counts(&single));
+}
+
+#[test]
+#[cfg(feature = "archive")]
+fn merge_tar_archive() {
+    // A directory, a text file and the same profile as a profraw and proftext
+    let archive = std::fs::File::open(data_root_dir().join("misc").join("profiles.tar")).unwrap();
+    let merged = merge_profiles_from_tar(archive).unwrap();
+    let expected = merge_profiles(&[
+        get_data_dir(14).join("c-general.profraw"),
+        data_root_dir().join("misc").join("c-general.proftext"),
+    ])
+    .unwrap();
+    assert!(!merged.records().is_empty());
+    assert_eq!(record_counts(&merged), record_counts(&expected));
+}
+
+#[test]
+fn merge_pseudo_counts() {
+    let data = get_data_dir(20);
+    let hot = data.join("pseudo-count-hot.proftext");
+    let warm = data.join("pseudo-count-warm.proftext");
+    let counts = |files: &[&PathBuf]| {
+        let merged = merge_profiles_with_options(files, &MergeOptions::default())
+            .unwrap()
+            .profile;
+        merged.get_record("overflow").unwrap().counts().to_vec()
+    };
+    // The sentinels aren't added together, the hottest one is kept
+    assert_eq!(counts(&[&warm, &warm]), vec![u64::MAX - 1, 0, 0]);
+    assert_eq!(counts(&[&warm, &hot]), vec![u64::MAX, 0, 0]);
+    assert_eq!(counts(&[&hot, &warm]), vec![u64::MAX, 0, 0]);
+    assert_eq!(counts(&[&hot, &hot]), vec![u64::MAX, 0, 0]);
+
+    let merged = merge_profiles(&[&warm, &hot]).unwrap();
+    assert_eq!(
+        merged.get_record("overflow").unwrap().counts(),
+        &[u64::MAX, 0, 0]
+    );
+
+    // A pseudo count can't be combined with real counts
+    let mut profile = parse(&hot).unwrap();
+    let mut record = profile.records()[0].clone();
+    record.record.counts = vec![1, 2, 3];
+    let mut real = InstrumentationProfile::default();
+    real.push_record(record);
+    let err = profile.merge(&real).unwrap_err();
+    assert!(matches!(
+        err,
+        InstrProfError::PseudoCountMismatch { function: Some(ref name) } if name == "overflow"
+    ));
+}
+
+#[test]
+fn estimate_written_size() {
+    for name in ["c-general.profraw", "overlap_1_vp.proftext", "cs.proftext"] {
+        let profile = parse(get_data_dir(14).join(name)).unwrap();
+        let mut written = vec![];
+        TextInstrProf.write(&profile, &mut written).unwrap();
+        assert_eq!(
+            profile.estimate_size(ProfileFormat::Text).unwrap(),
+            written.len()
+        );
+    }
+    let profile = parse(get_data_dir(14).join("cs.proftext")).unwrap();
+    let err = profile.estimate_size(ProfileFormat::Binary).unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::Unsupported);
+}
+
+#[test]
+fn normalized_merge() {
+    // Same functions with a total count of 500000 and 50000
+    let big = get_data_dir(14).join("overlap_1.proftext");
+    let small = get_data_dir(14).join("overlap_2.proftext");
+    let merged = merge_profiles_with_options(&[&big, &small], &MergeOptions::default())
+        .unwrap()
+        .profile;
+    assert_eq!(merged.total_count(), 550000);
+
+    let options = MergeOptions {
+        normalize: true,
+        ..Default::default()
+    };
+    let merged = merge_profiles_with_options(&[&big, &small], &options)
+        .unwrap()
+        .profile;
+    assert_eq!(merged.total_count(), 1000000);
+    let big = parse(&big).unwrap();
+    let small = parse(&small).unwrap();
+    // Each input contributes 500000 and the small one is scaled by 10
+    let mut expected = HashMap::new();
+    for (profile, scale) in [(&big, 1), (&small, 10)] {
+        for record in profile.records() {
+            let counts = expected
+                .entry((record.name.clone(), record.hash))
+                .or_insert_with(|| vec![0; record.counts().len()]);
+            for (count, own) in counts.iter_mut().zip(record.counts()) {
+                *count += own * scale;
+            }
+        }
+    }
+    assert_eq!(merged.records().len(), expected.len());
+    for record in merged.records() {
+        assert_eq!(
+            record.counts(),
+            expected[&(record.name.clone(), record.hash)].as_slice()
+        );
+    }
+}
+
+#[test]
+fn show_text_matches_llvm() {
+    // Outputs of llvm-profdata 14 show --text --all-functions
+    for (input, showcs, expected) in [
+        ("overlap_1_vp.proftext", false, "overlap_1_vp.text.stdout"),
+        ("c-general.profraw", false, "c-general.text.stdout"),
+        ("cs.proftext", true, "cs.text.stdout"),
+    ] {
+        let file = get_data_dir(14).join(input);
+        let mut args = vec![
+            "show",
+            "--text",
+            "--all-functions",
+            "-i",
+            file.to_str().unwrap(),
+        ];
+        if showcs {
+            args.push("--showcs");
+        }
+        let output = profparser(&args);
+        assert!(output.status.success());
+        let expected =
+            std::fs::read_to_string(data_root_dir().join("misc").join(expected)).unwrap();
+        assert_eq!(String::from_utf8(output.stdout).unwrap(), expected);
+    }
+}
+
+#[test]
+fn show_value_sites() {
+    let file = get_data_dir(14).join("overlap_1_vp.proftext");
+    let show = |args: &[&str]| {
+        let mut command = vec!["show", "--all-functions"];
+        command.extend(args);
+        command.extend(["-i", file.to_str().unwrap()]);
+        let output = profparser(&command);
+        assert!(output.status.success());
+        String::from_utf8(output.stdout).unwrap()
+    };
+    // Same layout as llvm-profdata show -ic-targets -memop-sizes
+    let expected = std::fs::read_to_string(
+        data_root_dir()
+            .join("misc")
+            .join("overlap_1_vp.values.stdout"),
+    )
+    .unwrap();
+    assert_eq!(show(&["--ic-targets", "--memop-sizes"]), expected);
+
+    let ic_targets = show(&["--ic-targets"]);
+    assert!(ic_targets.contains("    Indirect Call Site Count: 1\n"));
+    assert!(ic_targets.contains("\t[  0, bar2,      60000 ] (60.00%)\n"));
+    assert!(ic_targets.contains("Statistics for indirect call sites profile:\n"));
+    assert!(!ic_targets.contains("Memory Intrinsic"));
+
+    let memop_sizes = show(&["--memop-sizes"]);
+    assert!(memop_sizes.contains("    Number of Memory Intrinsics Calls: 1\n"));
+    assert!(memop_sizes.contains("\t[  0,    1,      40000 ] (40.00%)\n"));
+    assert!(memop_sizes.contains("Statistics for memory intrinsic calls sizes profile:\n"));
+    assert!(!memop_sizes.contains("Indirect"));
+}
+
+#[test]
+fn local_function_source_files() {
+    let profile = parse(get_data_dir(14).join("c-general.profraw")).unwrap();
+    assert_eq!(profile.source_file("static_func"), Some("c-general.c"));
+    assert_eq!(
+        profile.source_file("c-general.c:static_func"),
+        Some("c-general.c")
+    );
+    assert_eq!(profile.source_file("main"), None);
+    assert_eq!(profile.source_file("not_a_function"), None);
+
+    let profile = parse(
+        data_root_dir()
+            .join("misc")
+            .join("local-functions.proftext"),
+    )
+    .unwrap();
+    assert_eq!(profile.source_file("helper"), Some("src/lib.c"));
+    assert_eq!(profile.source_file("src/lib.c;helper"), Some("src/lib.c"));
+    assert_eq!(profile.source_file("exported"), None);
+}
+
+#[test]
+fn clang_c_profraws() {
+    // c-general.profraw and compressed.profraw are written by clang instrumented C from the
+    // LLVM test suite, so there's nothing rustc specific in the names or layout. Counts were
+    // checked against `llvm-profdata show` for the versions it could read.
+    let expected_names = [
+        "big_switch",
+        "boolean_operators",
+        "boolop_loops",
+        "c-general.c:static_func",
+        "conditional_operator",
+        "conditionals",
+        "do_fallthrough",
+        "early_exits",
+        "jumps",
+        "main",
+        "simple_loops",
+        "switches",
+    ];
+    for version in 11..=21 {
+        let dir = get_data_dir(version);
+        let profile = parse(dir.join("c-general.profraw")).unwrap();
+        assert_eq!(profile.profile_level(), ProfileLevel::FrontEnd);
+        let mut names = profile
+            .records()
+            .iter()
+            .map(|x| x.name.as_deref().unwrap())
+            .collect::<Vec<_>>();
+        names.sort_unstable();
+        assert_eq!(names, expected_names, "llvm-{}", version);
+
+        let static_func = profile.find_record_by_name("c-general.c:static_func");
+        assert_eq!(static_func.unwrap().counts(), [1, 10]);
+        assert_eq!(profile.source_file("static_func"), Some("c-general.c"));
+        if version >= 13 {
+            let simple_loops = profile.find_record_by_name("simple_loops").unwrap();
+            assert_eq!(simple_loops.func_hash(), Some(0x00046d109c4436d1));
+            assert_eq!(simple_loops.counts(), [1, 100, 100, 75]);
+        }
+
+        // The same run with the names section compressed
+        let compressed = parse(dir.join("compressed.profraw")).unwrap();
+        assert_eq!(compressed.records_sorted(), profile.records_sorted());
+    }
+}
+
+#[test]
+fn show_profile_version() {
+    for (input, expected) in [
+        (
+            "c-general.profraw",
+            "Profile version: 8\nInstrumentation level: Front-end\n",
+        ),
+        (
+            "function-entry-coverage.profdata",
+            "Profile version: 7\nInstrumentation level: IR\n",
+        ),
+        (
+            "cs.proftext",
+            "Profile version: unknown\nInstrumentation level: Context sensitive IR\n",
+        ),
+    ] {
+        let file = get_data_dir(14).join(input);
+        let output = profparser(&["show", "--profile-version", "-i", file.to_str().unwrap()]);
+        assert!(output.status.success());
+        assert_eq!(String::from_utf8(output.stdout).unwrap(), expected);
+    }
+    // The variant flags aren't part of the version
+    let profile = parse(get_data_dir(14).join("function-entry-coverage.profdata")).unwrap();
+    assert_eq!(profile.version(), Some(7));
+}
+
+#[test]
+fn profile_counter_matrix() {
+    let profile = parse(get_data_dir(14).join("overlap_1.proftext")).unwrap();
+    let (names, counts) = profile.counter_matrix();
+    assert_eq!(names, ["bar", "bar1", "foo", "main"]);
+    assert_eq!(
+        counts,
+        vec![
+            vec![100000],
+            vec![100000],
+            vec![40000, 60000],
+            vec![200000, 0]
+        ]
+    );
+
+    let (names, counts) = InstrumentationProfile::default().counter_matrix();
+    assert!(names.is_empty());
+    assert!(counts.is_empty());
+}
+
+#[test]
+fn profile_count_matches_llvm() {
+    // `entry-first.show` is `llvm-profdata show --all-functions --counts` from llvm 14. LLVM's
+    // maximum function count is the largest entry count, for `foo` an internal block is larger
+    let data = get_data_dir(14);
+    let profile = parse(data.join("entry-first.proftext")).unwrap();
+    let expected = std::fs::read_to_string(data.join("entry-first.show")).unwrap();
+    let max_function_count = expected
+        .lines()
+        .find_map(|x| x.strip_prefix("Maximum function count: "))
+        .unwrap()
+        .parse::<u64>()
+        .unwrap();
+    let counts = profile
+        .records_sorted()
+        .iter()
+        .map(|x| profile.profile_count(&x.record))
+        .collect::<Vec<_>>();
+    assert_eq!(counts, [Some(10), Some(5)]);
+    assert_eq!(counts.iter().flatten().max(), Some(&max_function_count));
+
+    // Without entry first instrumentation the first counter isn't the entry block
+    let profile = parse(data.join("header-directives-2.proftext")).unwrap();
+    assert!(profile.is_ir_level_profile());
+    assert_eq!(profile.profile_count(&profile.records()[0].record), None);
+}
+
+#[test]
+fn merge_to_writer_matches_merge() {
+    let dir = data_root_dir().join("misc").join("multibin_merge");
+    let inputs = [
+        dir.join("bin_1.profraw"),
+        dir.join("bin_2.1.profraw"),
+        dir.join("bin_2.2.profraw"),
+        dir.join("bin_2.3.profraw"),
+    ];
+    let mut expected = vec![];
+    TextInstrProf
+        .write(&merge_profiles(&inputs).unwrap(), &mut expected)
+        .unwrap();
+
+    let mut streamed = vec![];
+    merge_profiles_to_writer(&inputs, ProfileFormat::Text, &mut streamed).unwrap();
+    assert_eq!(
+        String::from_utf8(streamed).unwrap(),
+        String::from_utf8(expected).unwrap()
+    );
+
+    let mut output = vec![];
+    let err = merge_profiles_to_writer(&inputs, ProfileFormat::Binary, &mut output).unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::Unsupported);
+    assert!(output.is_empty());
+}
+
+#[test]
+fn checksum_trailer() {
+    let misc = data_root_dir().join("misc");
+    let plain = parse(get_data_dir(14).join("overlap_1.proftext")).unwrap();
+    assert!(!plain.has_checksum());
+    assert!(plain.checksum_ok());
+
+    let good = parse(misc.join("checksum.proftext")).unwrap();
+    assert!(good.has_checksum());
+    assert!(good.checksum_ok());
+    assert_eq!(good.records(), plain.records());
+
+    // A changed counter still parses but the checksum catches it
+    let tampered = parse(misc.join("checksum-tampered.proftext")).unwrap();
+    assert!(tampered.has_checksum());
+    assert!(!tampered.checksum_ok());
+    assert_eq!(tampered.get_record("foo").unwrap().counts(), [40001, 60000]);
+
+    let mut written = vec![];
+    TextInstrProf
+        .write_with_checksum(&plain, &mut written)
+        .unwrap();
+    let reread = parse_bytes(&written).unwrap();
+    assert!(reread.has_checksum());
+    assert!(reread.checksum_ok());
+    assert_eq!(reread.records(), plain.records());
+
+    // A corrupted digest doesn't match either
+    let len = written.len();
+    written[len - 2] = if written[len - 2] == b'0' { b'1' } else { b'0' };
+    let corrupted = parse_bytes(&written).unwrap();
+    assert!(!corrupted.checksum_ok());
+    // The checksum is about the file so doesn't affect equality
+    assert_eq!(corrupted, reread);
+
+    // Truncating the file removes the trailer so only `has_checksum` notices
+    let trailer = written.iter().rposition(|x| *x == b'#').unwrap();
+    let truncated = parse_bytes(&written[..trailer]).unwrap();
+    assert!(!truncated.has_checksum());
+    assert!(truncated.checksum_ok());
+
+    // A merged profile isn't the file any more
+    let mut merged = tampered.clone();
+    merged.merge(&good).unwrap();
+    assert!(!merged.has_checksum());
+    assert!(merged.checksum_ok());
+}
+
+#[test]
+fn value_site_counts() {
+    let profile = parse(get_data_dir(21).join("vtable-value-prof.proftext")).unwrap();
+    let main = &profile.get_record("main").unwrap().record;
+    assert_eq!(main.value_site_count(ValueKind::IndirectCallTarget), 2);
+    assert_eq!(main.value_site_count(ValueKind::MemOpSize), 0);
+    assert_eq!(main.value_site_count(ValueKind::VTableTarget), 2);
+
+    // No value data at all
+    let create = &profile.get_record("_Z10createTypei").unwrap().record;
+    for kind in ValueKind::ALL {
+        assert_eq!(create.value_site_count(kind), 0);
+    }
+
+    let profile = parse(get_data_dir(14).join("overlap_1_vp.proftext")).unwrap();
+    let foo = &profile.get_record("foo").unwrap().record;
+    assert_eq!(foo.value_site_count(ValueKind::IndirectCallTarget), 1);
+    assert_eq!(foo.value_site_count(ValueKind::MemOpSize), 1);
+    assert_eq!(foo.value_site_count(ValueKind::VTableTarget), 0);
+}
+
+#[test]
+fn csv_export() {
+    let input = get_data_dir(14).join("overlap_1.proftext");
+    let expected =
+        std::fs::read_to_string(data_root_dir().join("misc").join("overlap_1.csv")).unwrap();
+
+    let output = profparser(&[
+        "show",
+        "--csv",
+        "--all-functions",
+        "-i",
+        input.to_str().unwrap(),
+    ]);
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), expected);
+}
+
+#[test]
+fn func_hashes() {
+    let profile = parse(get_data_dir(14).join("c-general.profraw")).unwrap();
+    let hashes = profile.func_hashes().collect::<Vec<_>>();
+    assert_eq!(hashes.len(), profile.records().len());
+    for ((name, hash), record) in hashes.iter().zip(profile.records()) {
+        assert_eq!(record.name.as_ref(), Some(name));
+        assert_eq!(record.func_hash(), Some(*hash));
+    }
+
+    // Unnamed records fall back to the symbol table
+    let mut profile = InstrumentationProfile::default();
+    profile
+        .symtab_mut()
+        .add_func_name_with_hash("foo".to_string(), compute_hash("foo"));
+    profile.push_record(NamedInstrProfRecord {
+        name: None,
+        name_hash: Some(compute_hash("foo")),
+        hash: Some(7),
+        ..Default::default()
+    });
+    profile.push_record(NamedInstrProfRecord {
+        name: None,
+        name_hash: Some(compute_hash("bar")),
+        hash: Some(8),
+        ..Default::default()
+    });
+    assert_eq!(
+        profile.func_hashes().collect::<Vec<_>>(),
+        [("foo".to_string(), 7)]
+    );
+}
+
+#[test]
+fn old_raw_versions() {
+    // Synthetic profiles, llvm-11/c-general.profraw (version 5) rewritten in the layout of each
+    // older version. See misc/synthetic/README.txt
+    let expected = parse(get_data_dir(11).join("c-general.profraw")).unwrap();
+    let synthetic = data_root_dir().join("misc").join("synthetic");
+    for version in 1..=4 {
+        let file = synthetic.join(format!("c-general.v{}.profraw", version));
+        let profile = parse(&file).unwrap();
+        assert_eq!(profile.version(), Some(version));
+        assert_eq!(profile.symtab, expected.symtab);
+        assert_eq!(profile.records(), expected.records());
+
+        let data = std::fs::read(&file).unwrap();
+        let (_, layout) = RawInstrProf64::parse_layout(&data).unwrap();
+        assert_eq!(layout.functions.len(), expected.records().len());
+        for (function, record) in layout.functions.iter().zip(expected.records()) {
+            assert_eq!(Some(function.name_ref), record.name_hash);
+            assert_eq!(Some(function.func_hash), record.hash);
+        }
+    }
+
+    // The separate value data section of version 2 isn't read so it's an error instead of the
+    // values being dropped
+    let mut data = std::fs::read(synthetic.join("c-general.v2.profraw")).unwrap();
+    data[64..72].copy_from_slice(&16u64.to_le_bytes());
+    data.extend([0; 16]);
+    let err = parse_bytes(&data).unwrap_err();
+    assert!(err
+        .to_string()
+        .contains("version 2 value profiling data isn't supported"));
+}
+
+#[test]
+fn hot_and_cold_functions() {
+    let mut profile = parse(get_data_dir(14).join("cutoff.proftext")).unwrap();
+    // 1000000 and 499500 make up 99% of the total, and all but 1 of the count is at or above 999
+    assert_eq!(
+        profile.hotness_thresholds(false),
+        HotnessThresholds {
+            hot: 499500,
+            cold: 999
+        }
+    );
+    assert!(profile.is_hot("main"));
+    assert!(!profile.is_cold("main"));
+    for name in ["foo", "bar"] {
+        assert!(!profile.is_hot(name));
+        assert!(profile.is_cold(name));
+    }
+    assert!(!profile.is_hot("not_a_function"));
+    assert!(!profile.is_cold("not_a_function"));
+
+    // The cached thresholds are recomputed once the counts change
+    let record = profile.find_record_by_name_mut("foo").unwrap();
+    record.record.counts = vec![1_000_000_000, 1];
+    assert!(profile.is_hot("foo"));
+    assert!(!profile.is_hot("main"));
+
+    let data = get_data_dir(20);
+    let hot = parse(data.join("pseudo-count-hot.proftext")).unwrap();
+    assert!(hot.is_hot("overflow"));
+    assert!(!hot.is_cold("overflow"));
+    let warm = parse(data.join("pseudo-count-warm.proftext")).unwrap();
+    assert!(!warm.is_hot("overflow"));
+    assert!(!warm.is_cold("overflow"));
+}
+
+#[test]
+fn hot_functions_match_llvm() {
+    // llvm-profdata 14 has no `-hot-func-list` for instrumentation profiles, so the `.below-hot`
+    // files are `show --detailed-summary --detailed-summary-cutoffs=990000,999999
+    // --value-cutoff=<hot> --list-below-cutoff` with the count at the 99% cutoff
+    let data = get_data_dir(14);
+    for name in ["cutoff", "overlap_1"] {
+        let profile = parse(data.join(format!("{}.proftext", name))).unwrap();
+        let expected = std::fs::read_to_string(data.join(format!("{}.below-hot", name))).unwrap();
+
+        let threshold = |cutoff: &str| {
+            expected
+                .lines()
+                .find(|x| x.ends_with(&format!(" {} percentage of the total counts.", cutoff)))
+                .and_then(|x| x.split(">= ").nth(1))
+                .and_then(|x| x.split(' ').next())
+                .and_then(|x| x.parse::<u64>().ok())
+                .unwrap()
+        };
+        assert_eq!(
+            profile.hotness_thresholds(false),
+            HotnessThresholds {
+                hot: threshold("99"),
+                cold: threshold("99.9999")
+            }
+        );
+
+        // The functions listed are those with a maximum count below the hot threshold
+        let below_hot = expected
+            .lines()
+            .filter_map(|x| x.strip_prefix("  "))
+            .filter_map(|x| {
+                let (name, rest) = x.split_once(": (Max = ")?;
+                let max = rest.split(' ').next()?.parse::<u64>().ok()?;
+                Some((name.to_string(), max))
+            })
+            .collect::<HashMap<_, _>>();
+        let names = profile
+            .records()
+            .iter()
+            .filter_map(|x| x.name.clone())
+            .collect::<Vec<_>>();
+        let not_hot = names
+            .iter()
+            .filter(|x| !profile.is_hot(x))
+            .cloned()
+            .collect::<HashSet<_>>();
+        assert_eq!(not_hot, below_hot.keys().cloned().collect(), "{}", name);
+
+        let cold = names
+            .iter()
+            .filter(|x| profile.is_cold(x))
+            .cloned()
+            .collect::<HashSet<_>>();
+        let expected_cold = below_hot
+            .iter()
+            .filter(|(_, max)| **max <= threshold("99.9999"))
+            .map(|(name, _)| name.clone())
+            .collect::<HashSet<_>>();
+        assert_eq!(cold, expected_cold, "{}", name);
+    }
+}
+
+#[test]
+fn display_matches_show() {
+    let show = |args: &[&str], file: &PathBuf| {
+        let mut command = vec!["show"];
+        command.extend(args);
+        command.extend(["-i", file.to_str().unwrap()]);
+        let output = profparser(&command);
+        assert!(output.status.success());
+        String::from_utf8(output.stdout).unwrap()
+    };
+    for name in ["c-general.profraw", "cs.proftext"] {
+        let file = get_data_dir(14).join(name);
+        let profile = parse(&file).unwrap();
+        assert_eq!(profile.to_string(), show(&[], &file));
+
+        let options = ShowOptions::default()
+            .all_functions(true)
+            .show_counts(true)
+            .topn(Some(3))
+            .detailed_summary(true);
+        assert_eq!(
+            profile.display(options).to_string(),
+            show(
+                &[
+                    "--all-functions",
+                    "--counts",
+                    "--topn",
+                    "3",
+                    "--show_detailed_summary"
+                ],
+                &file
+            )
+        );
+    }
+}